@@ -0,0 +1,134 @@
+//! Extraction throughput of the blocking and async extractors under each [`Strategy`], on
+//! synthetic archives of many small files and of a few large ones.
+//!
+//! To measure the `io-uring` feature, which only changes how the async extractor writes, save a
+//! baseline without it and compare against it:
+//!
+//! ```text
+//! cargo bench --bench extract -- --save-baseline tokio-fs async
+//! cargo bench --bench extract --features io-uring -- --baseline tokio-fs async
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use backhand_async::{ExtractOptions, Filter, IoOptions, SquashOptions, Strategy};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+const STRATEGIES: [Strategy; 3] = [Strategy::PerFile, Strategy::PerBlock, Strategy::Auto];
+
+struct Workload {
+    name: &'static str,
+    files: usize,
+    file_len: usize,
+}
+
+const WORKLOADS: [Workload; 2] = [
+    Workload {
+        name: "many-small-files",
+        files: 4096,
+        file_len: 4 << 10,
+    },
+    Workload {
+        name: "few-large-files",
+        files: 4,
+        file_len: 64 << 20,
+    },
+];
+
+/// Data that compresses about as well as typical file contents: runs of repeated words.
+fn contents(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let word = state.to_le_bytes();
+        for _ in 0..(state % 8) {
+            data.extend_from_slice(&word[..4]);
+        }
+    }
+    data.truncate(len);
+    data
+}
+
+/// Build the archive of `workload` under `dir`, returning its path.
+fn archive(dir: &Path, workload: &Workload) -> PathBuf {
+    let (src, archive) = (dir.join("src"), dir.join("archive.squashfs"));
+    for i in 0..workload.files {
+        let path = src.join(format!("{:02x}/{i}", i % 256));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents(i as u64, workload.file_len)).unwrap();
+    }
+    backhand_async::squash_blocking(&src, &archive, Filter::All, SquashOptions::default()).unwrap();
+    std::fs::remove_dir_all(&src).unwrap();
+    archive
+}
+
+/// An empty destination, removing what the previous iteration extracted.
+fn fresh(dest: &Path) -> PathBuf {
+    let _ = std::fs::remove_dir_all(dest);
+    dest.to_path_buf()
+}
+
+fn options(strategy: Strategy) -> ExtractOptions {
+    ExtractOptions::builder()
+        .io(IoOptions {
+            strategy,
+            ..Default::default()
+        })
+        .build()
+}
+
+fn extract(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let dir = std::env::temp_dir().join(format!("backhand-async-bench-{}", std::process::id()));
+    for workload in &WORKLOADS {
+        let work_dir = dir.join(workload.name);
+        let archive = archive(&work_dir, workload);
+        let dest = work_dir.join("dest");
+        let mut group = c.benchmark_group(workload.name);
+        group.sample_size(10).throughput(Throughput::Bytes(
+            (workload.files * workload.file_len) as u64,
+        ));
+        for strategy in STRATEGIES {
+            let id = format!("{strategy:?}");
+            group.bench_function(BenchmarkId::new("blocking", &id), |b| {
+                b.iter_batched(
+                    || fresh(&dest),
+                    |dest| {
+                        backhand_async::unsquash_blocking(
+                            &archive,
+                            dest,
+                            Filter::All,
+                            options(strategy),
+                        )
+                        .unwrap()
+                    },
+                    BatchSize::PerIteration,
+                );
+            });
+            group.bench_function(BenchmarkId::new("async", &id), |b| {
+                b.iter_batched(
+                    || fresh(&dest),
+                    |dest| {
+                        runtime
+                            .block_on(backhand_async::unsquash_async(
+                                &archive,
+                                dest,
+                                Filter::All,
+                                options(strategy),
+                            ))
+                            .unwrap()
+                    },
+                    BatchSize::PerIteration,
+                );
+            });
+        }
+        group.finish();
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, extract);
+criterion_main!(benches);
@@ -0,0 +1,805 @@
+use std::{
+    borrow::Cow,
+    io::{BufReader, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use backhand::{
+    BasicFile, BufReadSeek, DataSize, FilesystemReader, Fragment, Id, InnerNode, Node, Squashfs,
+    SquashfsFileReader,
+};
+use futures::{Stream, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    async_file::{AsyncSquashfsFile, EntryReader, CHUNK_LEN},
+    block_decoder::{BlockDecoder, DEFAULT_FRAGMENT_CACHE_BLOCKS},
+    cache::{AccessStats, ReadCounts},
+    compression::{Backend, CompressionOptions, Compressor, Kind},
+    counters::Counters,
+    diff::DiffReport,
+    executor::Executor,
+    paths::PathTable,
+    read_cache::ReadCache,
+    source::{AsyncReaderSource, BlockOn, ReaderSource, SourceReader, SquashSource},
+    trace,
+    xattr::Xattrs,
+    Entry, EntryInfo, EntryRef, Error, ExtractOptions, ExtractReport, Filter, Parsing,
+    SquashOptions, SquashReport,
+};
+
+/// Entries decoded ahead of the consumer of [`Archive::entries_async`] by default.
+pub const DEFAULT_PREFETCH_DEPTH: usize = 4;
+
+/// An estimate of the memory held by an [`Archive`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The parsed entries and tables.
+    pub metadata_bytes: u64,
+    /// What its [`SquashSource`] caches, which [`Archive::trim`] releases.
+    pub cache_bytes: u64,
+}
+
+/// What the superblock of an [`Archive`] records about it, see [`Archive::info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveInfo {
+    pub compressor: Compressor,
+    /// What this build decompresses data compressed with `compressor` with, when the archive is
+    /// read with the default [`Kind`]; see [`backend`](crate::compression::backend).
+    pub backend: Backend,
+    /// The options of the compressor, if the archive records any.
+    pub compression_options: Option<CompressionOptions>,
+    /// Size of the data blocks, from 4 KiB to 1 MiB.
+    pub block_size: u32,
+    pub inode_count: u32,
+    pub fragment_count: u32,
+    /// Distinct uids and gids of the entries.
+    pub id_count: u16,
+    /// The build time, which is pinned when the archive was built reproducibly.
+    pub created: SystemTime,
+    /// Bytes of the archive used by the filesystem.
+    pub bytes_used: u64,
+    /// Size of the archive, including the padding after `bytes_used`.
+    pub size: u64,
+    /// Bitwise OR of the squashfs superblock flags, e.g. 0x0040 when data was deduplicated.
+    pub flags: u16,
+}
+
+/// A squashfs archive parsed once, to extract, list and read from any number of times without
+/// re-reading its superblock and tables. Cloning it is cheap.
+#[derive(Clone)]
+pub struct Archive {
+    pub(crate) source: Arc<dyn SquashSource>,
+    pub(crate) filesystem: Arc<FilesystemReader<'static>>,
+    pub(crate) block_decoder: Option<Arc<BlockDecoder>>,
+    read_cache: Option<Arc<ReadCache>>,
+    reads: Option<Arc<ReadCounts>>,
+    pub(crate) fingerprint: Arc<OnceLock<String>>,
+    pub(crate) counters: Arc<Counters>,
+    /// The paths of the entries, whose full paths were dropped, with
+    /// [`compact_paths`](crate::IoOptions::compact_paths).
+    paths: Option<Arc<PathTable>>,
+}
+
+/// What an extraction reads from: an archive to open, or one opened already.
+pub(crate) enum Input {
+    Source(Arc<dyn SquashSource>),
+    Opened(Archive),
+}
+
+impl Input {
+    pub(crate) fn source(&self) -> &Arc<dyn SquashSource> {
+        match self {
+            Self::Source(source) => source,
+            Self::Opened(archive) => &archive.source,
+        }
+    }
+}
+
+impl std::fmt::Debug for Archive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Archive")
+            .field("source", &self.source.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Archive {
+    /// Open the archive at `squashfs` with the `kind`, `parsing` and `direct_io` of `options`.
+    pub fn open(squashfs: impl AsRef<Path>, options: ExtractOptions) -> Result<Self, Error> {
+        let source = crate::open_source(squashfs.as_ref(), &options)?;
+        Self::open_source(Arc::from(source), options)
+    }
+
+    /// Like [`open`](Self::open), reading the archive from any [`SquashSource`].
+    pub fn from_source(
+        source: impl SquashSource + 'static,
+        options: ExtractOptions,
+    ) -> Result<Self, Error> {
+        Self::open_source(Arc::new(source), options)
+    }
+
+    /// Like [`open`](Self::open), reading the archive from a seekable reader, such as a cursor
+    /// over an embedded byte slice.
+    pub fn from_reader(
+        reader: impl Read + Seek + Send + 'static,
+        options: ExtractOptions,
+    ) -> Result<Self, Error> {
+        Self::from_source(ReaderSource::new(reader)?, options)
+    }
+
+    /// Async flavor of [`from_reader`](Self::from_reader). Reads issued while extracting block on
+    /// the current tokio runtime, which must be multi-threaded.
+    pub async fn from_async_reader(
+        reader: impl AsyncRead + AsyncSeek + Unpin + Send + 'static,
+        options: ExtractOptions,
+    ) -> Result<Self, Error> {
+        let source = BlockOn::new(AsyncReaderSource::new(reader)).await?;
+        tokio::task::spawn_blocking(move || Self::from_source(source, options))
+            .await
+            .context("spawn blocking archive open task")?
+    }
+
+    /// Open the archive served at `url` by a server supporting range requests, fetching only the
+    /// parts of it that are read, in chunks of [`DEFAULT_CHUNK_LEN`](crate::source::DEFAULT_CHUNK_LEN)
+    /// of which up to `cache_bytes` are kept. Use [`from_source`](Self::from_source) with a
+    /// [`ChunkCache`](crate::source::ChunkCache) for other chunk sizes.
+    #[cfg(feature = "http")]
+    pub fn open_http(
+        url: impl Into<String>,
+        cache_bytes: u64,
+        options: ExtractOptions,
+    ) -> Result<Self, Error> {
+        use crate::source::{ChunkCache, HttpSource, DEFAULT_CHUNK_LEN};
+
+        let source = ChunkCache::new(HttpSource::open(url)?, DEFAULT_CHUNK_LEN, cache_bytes);
+        Self::from_source(source, options)
+    }
+
+    /// Async flavor of [`open`](Self::open).
+    pub async fn open_async(
+        squashfs: impl AsRef<Path>,
+        options: ExtractOptions,
+    ) -> Result<Self, Error> {
+        let squashfs = squashfs.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || Self::open(squashfs, options))
+            .await
+            .context("spawn blocking archive open task")?
+    }
+
+    fn open_source(
+        source: Arc<dyn SquashSource>,
+        mut options: ExtractOptions,
+    ) -> Result<Self, Error> {
+        let (kind, index, parsing) = (
+            options.io.kind.take(),
+            options.io.index.clone(),
+            options.policy.parsing,
+        );
+        let fragment_cache_blocks = options.io.fragment_cache_blocks;
+        let open = move |source: Arc<dyn SquashSource>| match (kind, index) {
+            (None, Some(index)) => {
+                Self::open_indexed(source, &index, parsing, fragment_cache_blocks)
+            }
+            (kind, _) => Self::open_with(source, kind, parsing, fragment_cache_blocks),
+        };
+        let archive = match options.io.fingerprint {
+            true => {
+                let (archive, fingerprint) = rayon::join(
+                    || open(Arc::clone(&source)),
+                    || crate::fingerprint::fingerprint(&*source),
+                );
+                let archive = archive?;
+                let _ = archive.fingerprint.set(fingerprint?);
+                archive
+            }
+            false => open(source)?,
+        };
+        Ok(archive.with_options(&options))
+    }
+
+    /// Open the archive from the sidecar `index` if it was saved from it, parsing it and saving
+    /// the index otherwise. Failing to load or save the index is not an error.
+    fn open_indexed(
+        source: Arc<dyn SquashSource>,
+        index: &Path,
+        parsing: Parsing,
+        fragment_cache_blocks: Option<usize>,
+    ) -> Result<Self> {
+        let kind = crate::format::resolve_kind(&*source, None)?;
+        let _span = trace::open(&source.name()).entered();
+        let started = Instant::now();
+        let counters = Arc::default();
+        match crate::sidecar::load(
+            Arc::clone(&source),
+            Kind::from_kind(&kind),
+            index,
+            &counters,
+        ) {
+            Ok(Some(filesystem)) => {
+                trace::opened(filesystem.root.nodes.len(), started.elapsed());
+                return Ok(Self::with_filesystem(
+                    source,
+                    filesystem,
+                    true,
+                    counters,
+                    fragment_cache_blocks,
+                ));
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!(index = %index.display(), "ignoring index: {e:#}"),
+        }
+        let filesystem = crate::open_filesystem_counted(
+            Arc::clone(&source),
+            Some(Kind::from_kind(&kind)),
+            parsing,
+            &counters,
+        )?;
+        trace::opened(filesystem.root.nodes.len(), started.elapsed());
+        if let Err(e) = crate::sidecar::save(&source, &kind, &filesystem, index) {
+            tracing::warn!(index = %index.display(), "cannot save index: {e:#}");
+        }
+        Ok(Self::with_filesystem(
+            source,
+            filesystem,
+            true,
+            counters,
+            fragment_cache_blocks,
+        ))
+    }
+
+    pub(crate) fn open_with(
+        source: Arc<dyn SquashSource>,
+        kind: Option<Kind>,
+        parsing: Parsing,
+        fragment_cache_blocks: Option<usize>,
+    ) -> Result<Self> {
+        let _span = trace::open(&source.name()).entered();
+        let started = Instant::now();
+        let default_kind = kind.is_none();
+        let counters = Arc::default();
+        let filesystem =
+            crate::open_filesystem_counted(Arc::clone(&source), kind, parsing, &counters)?;
+        trace::opened(filesystem.root.nodes.len(), started.elapsed());
+        Ok(Self::with_filesystem(
+            source,
+            filesystem,
+            default_kind,
+            counters,
+            fragment_cache_blocks,
+        ))
+    }
+
+    /// The archive read from `source`, given its parsed entries and tables. Blocks are decoded by
+    /// this crate only when it is read as the [`Kind`] its superblock tells.
+    fn with_filesystem(
+        source: Arc<dyn SquashSource>,
+        filesystem: FilesystemReader<'static>,
+        default_kind: bool,
+        counters: Arc<Counters>,
+        fragment_cache_blocks: Option<usize>,
+    ) -> Self {
+        let fragment_cache_blocks = fragment_cache_blocks.unwrap_or(DEFAULT_FRAGMENT_CACHE_BLOCKS);
+        let block_decoder = default_kind
+            .then(|| Arc::new(BlockDecoder::new(&source, &counters, fragment_cache_blocks)));
+        Self {
+            source,
+            filesystem: Arc::new(filesystem),
+            block_decoder,
+            read_cache: None,
+            reads: None,
+            fingerprint: Arc::default(),
+            counters,
+            paths: None,
+        }
+    }
+
+    /// Apply the options only reads use.
+    fn with_options(mut self, options: &ExtractOptions) -> Self {
+        if options.io.compact_paths {
+            self.compact_paths();
+        }
+        let read_cache = options.io.read_cache.clone();
+        let block_decoder = self
+            .block_decoder
+            .as_ref()
+            .map(|decoder| decoder.with_workers(options.io.block_decode_workers.unwrap_or(1)));
+        Self {
+            block_decoder,
+            read_cache: read_cache
+                .map(|dir| Arc::new(ReadCache::new(dir, options.io.read_siblings))),
+            reads: options.observability.access_stats.then(Arc::default),
+            ..self
+        }
+    }
+
+    /// Replace the full paths of the entries by a [`PathTable`].
+    fn compact_paths(&mut self) {
+        let Some(filesystem) = Arc::get_mut(&mut self.filesystem) else {
+            return;
+        };
+        let Some(paths) = PathTable::new(&filesystem.root.nodes) else {
+            tracing::warn!(
+                "cannot build path table of '{}', keeping full paths",
+                self.source.name()
+            );
+            return;
+        };
+        for node in &mut filesystem.root.nodes {
+            node.fullpath = PathBuf::new();
+        }
+        self.paths = Some(Arc::new(paths));
+    }
+
+    /// The path of entry `i`.
+    pub(crate) fn path_of(&self, i: usize) -> Cow<'_, Path> {
+        match &self.paths {
+            Some(paths) => Cow::Owned(paths.path(i)),
+            None => Cow::Borrowed(&self.filesystem.root.nodes[i].fullpath),
+        }
+    }
+
+    /// Entry `i`, copied out of the archive.
+    pub(crate) fn entry_info(&self, i: usize) -> EntryInfo {
+        EntryRef::with_path(&self.filesystem.root.nodes[i], self.path_of(i)).to_info()
+    }
+
+    /// The archive with the full path of every entry, which operations walking its entries
+    /// through backhand need: itself, unless it was opened with
+    /// [`compact_paths`](crate::IoOptions::compact_paths).
+    pub(crate) fn expanded(&self) -> Result<Cow<'_, Archive>> {
+        let Some(paths) = &self.paths else {
+            return Ok(Cow::Borrowed(self));
+        };
+        let compact = &self.filesystem;
+        let nodes = (compact.root.nodes.iter().enumerate())
+            .map(|(i, node)| Node {
+                fullpath: paths.path(i),
+                ..node.clone()
+            })
+            .collect();
+        let filesystem = crate::sidecar::assemble(
+            Arc::clone(&self.source),
+            Kind::from_kind(&compact.kind),
+            &self.counters,
+            compact.id_table.clone(),
+            compact.fragments.clone(),
+            nodes,
+        )
+        .with_context(|| format!("rebuild paths of '{}'", self.source.name()))?;
+        Ok(Cow::Owned(Self {
+            filesystem: Arc::new(filesystem),
+            paths: None,
+            ..self.clone()
+        }))
+    }
+
+    /// Like [`unsquash_blocking`](crate::unsquash_blocking). The `kind`, `parsing` and
+    /// `direct_io` of `options` were applied when opening the archive; `parsing` still decides
+    /// which entries are extracted.
+    pub fn extract_blocking(
+        &self,
+        dest: impl AsRef<Path>,
+        filter: Filter,
+        options: ExtractOptions,
+    ) -> Result<ExtractReport, Error> {
+        let executor = options
+            .io
+            .concurrency
+            .map_or(Executor::Rayon, Executor::Threads);
+        crate::unsquash_blocking_on(Input::Opened(self.clone()), dest, filter, options, executor)
+            .map_err(Error::from)
+    }
+
+    /// Async flavor of [`extract_blocking`](Self::extract_blocking).
+    pub async fn extract_async(
+        &self,
+        dest: impl AsRef<Path>,
+        filter: Filter,
+        options: ExtractOptions,
+    ) -> Result<ExtractReport, Error> {
+        crate::async_unsquash::unsquash_async_on(Input::Opened(self.clone()), dest, filter, options)
+            .await
+    }
+
+    /// The entries of the archive, like [`list_blocking`](crate::list_blocking).
+    pub fn list(&self) -> impl Iterator<Item = EntryInfo> + '_ {
+        (0..self.filesystem.root.nodes.len()).map(|i| self.entry_info(i))
+    }
+
+    /// Like [`for_each_entry`](crate::for_each_entry), borrowing each entry from the parsed
+    /// archive instead of allocating it.
+    pub fn for_each_entry(&self, mut visit: impl FnMut(&EntryRef<'_>)) {
+        let Some(paths) = &self.paths else {
+            for node in self.filesystem.files() {
+                visit(&EntryRef::new(node));
+            }
+            return;
+        };
+        let mut path = PathBuf::new();
+        for (i, node) in self.filesystem.files().enumerate() {
+            paths.path_into(i, &mut path);
+            visit(&EntryRef::with_path(node, Cow::Borrowed(&path)));
+        }
+    }
+
+    /// Like [`read_file_blocking`](crate::read_file_blocking).
+    pub fn read_file_blocking(&self, path: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+        let mut contents = Vec::new();
+        self.read_file_to(path, &mut contents)?;
+        Ok(contents)
+    }
+
+    /// Like [`read_file_to`](crate::read_file_to).
+    pub fn read_file_to(
+        &self,
+        path: impl AsRef<Path>,
+        mut writer: impl Write,
+    ) -> Result<u64, Error> {
+        let path = path.as_ref();
+        let file = self.file(path)?;
+        if let Some(cache) = &self.read_cache {
+            let cached = cache.file(self, path, &file)?;
+            let written = std::fs::File::open(&cached)
+                .and_then(|mut cached| std::io::copy(&mut cached, &mut writer))
+                .with_context(|| format!("read '{}'", cached.display()))?;
+            writer
+                .flush()
+                .with_context(|| format!("flush '{}'", path.display()))?;
+            return Ok(written);
+        }
+        let written = self.copy(&file, &mut writer);
+        self.check_unchanged()?;
+        let written = written.with_context(|| format!("read '{}'", path.display()))?;
+        writer
+            .flush()
+            .with_context(|| format!("flush '{}'", path.display()))?;
+        Ok(written)
+    }
+
+    /// Like [`read_file_async`](crate::read_file_async).
+    pub async fn read_file_async(
+        &self,
+        path: impl AsRef<Path>,
+        writer: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<u64, Error> {
+        let path = path.as_ref();
+        let file = self.file(path)?;
+        if self.read_cache.is_some() {
+            let (archive, owned) = (self.clone(), path.to_path_buf());
+            let cached = tokio::task::spawn_blocking(move || {
+                let cache = archive.read_cache.as_ref().expect("checked above");
+                cache.file(&archive, &owned, &file)
+            });
+            let cached = cached.await.context("spawn blocking read cache task")??;
+            let written = match tokio::fs::File::open(&cached).await {
+                Ok(mut cached) => tokio::io::copy(&mut cached, writer).await,
+                Err(e) => Err(e),
+            }
+            .with_context(|| format!("read '{}'", cached.display()))?;
+            writer
+                .flush()
+                .await
+                .with_context(|| format!("flush '{}'", path.display()))?;
+            return Ok(written);
+        }
+        let mut reader = AsyncSquashfsFile::spawn(
+            Arc::clone(&self.filesystem),
+            file,
+            self.block_decoder.clone(),
+            None,
+            None,
+            CHUNK_LEN,
+        );
+        let written = tokio::io::copy(&mut reader, writer).await;
+        self.check_unchanged()?;
+        let written = written.with_context(|| format!("read '{}'", path.display()))?;
+        writer
+            .flush()
+            .await
+            .with_context(|| format!("flush '{}'", path.display()))?;
+        Ok(written)
+    }
+
+    /// The entries of the archive matching `filter`, with their contents. Entries are decoded on
+    /// tokio's blocking thread pool, at most `prefetch_depth` (at least 1) of them ahead of the
+    /// consumer, so that a slow consumer bounds how much is held in memory. Dropping the stream
+    /// stops decoding. Must be called from within a tokio runtime.
+    pub fn entries_async(
+        &self,
+        filter: Filter,
+        prefetch_depth: usize,
+    ) -> impl Stream<Item = Result<Entry, Error>> {
+        let (tx, entries) = mpsc::channel(prefetch_depth.max(1));
+        let archive = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let len = archive.filesystem.root.nodes.len();
+            for i in (0..len).filter(|&i| filter.matches(&archive.path_of(i))) {
+                let entry = archive.entry(i);
+                let failed = entry.is_err();
+                if tx.blocking_send(entry.map_err(Error::from)).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        futures::stream::unfold(entries, |mut entries| async move {
+            let entry = entries.recv().await?;
+            Some((entry, entries))
+        })
+    }
+
+    /// The entries of the archive matching `filter`, each with a reader over its contents, for
+    /// piping them elsewhere without going through the filesystem. A file is decoded on tokio's
+    /// blocking thread pool once its entry is yielded, a bounded amount ahead of its reader;
+    /// dropping the reader stops decoding it. Must be polled from within a tokio runtime.
+    pub fn entries_stream(&self, filter: Filter) -> impl Stream<Item = (EntryInfo, EntryReader)> {
+        let archive = self.clone();
+        let len = archive.filesystem.root.nodes.len();
+        let nodes = (0..len).filter(move |&i| filter.matches(&archive.path_of(i)));
+        let archive = self.clone();
+        futures::stream::iter(nodes).map(move |i| {
+            let node = &archive.filesystem.root.nodes[i];
+            let reader = match &node.inner {
+                InnerNode::File(file) => EntryReader::File(AsyncSquashfsFile::spawn(
+                    Arc::clone(&archive.filesystem),
+                    file.basic.clone(),
+                    archive.block_decoder.clone(),
+                    None,
+                    None,
+                    CHUNK_LEN,
+                )),
+                InnerNode::Symlink(symlink) => EntryReader::Inline(std::io::Cursor::new(
+                    symlink.link.as_os_str().as_encoded_bytes().to_vec(),
+                )),
+                _ => EntryReader::Inline(std::io::Cursor::default()),
+            };
+            (archive.entry_info(i), reader)
+        })
+    }
+
+    fn entry(&self, i: usize) -> Result<Entry> {
+        let node = &self.filesystem.root.nodes[i];
+        let mut contents = Vec::new();
+        match &node.inner {
+            InnerNode::File(file) => {
+                self.copy(&file.basic, &mut contents)
+                    .with_context(|| format!("read '{}'", self.path_of(i).display()))?;
+            }
+            InnerNode::Symlink(symlink) => {
+                contents.extend_from_slice(symlink.link.as_os_str().as_encoded_bytes())
+            }
+            _ => {}
+        }
+        Ok(Entry {
+            info: self.entry_info(i),
+            contents,
+        })
+    }
+
+    pub(crate) fn copy(&self, file: &BasicFile, writer: &mut impl Write) -> Result<u64> {
+        match &self.block_decoder {
+            Some(decoder) => decoder.copy(&self.filesystem, file, writer),
+            None => {
+                std::io::copy(&mut self.filesystem.file(file).reader(), writer).map_err(Into::into)
+            }
+        }
+    }
+
+    /// The extended attributes of the entries of the archive.
+    pub(crate) fn xattrs(&self) -> Result<Xattrs> {
+        anyhow::ensure!(
+            self.block_decoder.is_some(),
+            "extracting xattrs is only supported with the default kind"
+        );
+        Xattrs::read(&*self.source, self.filesystem.compressor)
+            .with_context(|| format!("read xattrs of '{}'", self.source.name()))
+    }
+
+    /// The superblock of the archive, e.g. to check that an image meets a policy before
+    /// extracting it.
+    pub fn info(&self) -> Result<ArchiveInfo, Error> {
+        let name = self.source.name();
+        let mut reader: Box<dyn BufReadSeek> =
+            Box::new(BufReader::new(SourceReader::new(Arc::clone(&self.source))));
+        let (superblock, compression_options) =
+            Squashfs::superblock_and_compression_options(&mut reader, &self.filesystem.kind)
+                .with_context(|| format!("parse superblock of '{name}'"))?;
+        let size = self
+            .source
+            .size()
+            .with_context(|| format!("get size of '{name}'"))?;
+        Ok(ArchiveInfo {
+            compressor: superblock.compressor,
+            backend: crate::compression::backend(superblock.compressor),
+            compression_options,
+            block_size: superblock.block_size,
+            inode_count: superblock.inode_count,
+            fragment_count: superblock.frag_count,
+            id_count: superblock.id_count,
+            created: UNIX_EPOCH + Duration::from_secs(superblock.mod_time.into()),
+            bytes_used: superblock.bytes_used,
+            size,
+            flags: superblock.flags,
+        })
+    }
+
+    /// An estimate of the memory held by the archive, shared by its clones. Fragments backhand
+    /// caches when reading with a non-default [`Kind`] are not counted.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let filesystem = &self.filesystem;
+        let nodes = &filesystem.root.nodes;
+        let node_bytes: usize = nodes
+            .iter()
+            .map(|node| {
+                node.fullpath.capacity()
+                    + match &node.inner {
+                        InnerNode::File(file) => {
+                            file.basic.block_sizes.capacity() * size_of::<DataSize>()
+                        }
+                        InnerNode::Symlink(symlink) => symlink.link.capacity(),
+                        _ => 0,
+                    }
+            })
+            .sum();
+        let tables = filesystem.id_table.capacity() * size_of::<Id>()
+            + filesystem
+                .fragments
+                .as_ref()
+                .map_or(0, |fragments| fragments.capacity() * size_of::<Fragment>());
+        let paths = self.paths.as_ref().map_or(0, |paths| paths.heap_bytes());
+        let metadata_bytes =
+            nodes.capacity() * size_of::<Node<SquashfsFileReader>>() + node_bytes + tables + paths;
+        MemoryUsage {
+            metadata_bytes: metadata_bytes as u64,
+            cache_bytes: self.source.cached_bytes(),
+        }
+    }
+
+    /// How often each file was read through the archive and its clones, and how often its caches
+    /// hit, when opened with [`access_stats`](crate::ObservabilityOptions::access_stats). The
+    /// snapshot serializes, e.g. to export it to a metrics system.
+    pub fn access_stats(&self) -> Option<AccessStats> {
+        let reads = self.reads.as_ref()?;
+        let entries = reads.entries();
+        Some(AccessStats {
+            reads: entries.iter().map(|entry| entry.reads).sum(),
+            entries,
+            read_cache: self
+                .read_cache
+                .as_ref()
+                .map(|cache| cache.stats())
+                .unwrap_or_default(),
+            block_cache: self.source.cache_stats(),
+            fragment_cache: self
+                .block_decoder
+                .as_ref()
+                .map(|decoder| decoder.fragment_cache_stats())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Running totals of the bytes read from the archive and of the entries and bytes extracted
+    /// from it, through it and its clones.
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Like [`diff`](crate::diff::diff), from this archive to `new`.
+    pub fn diff(
+        &self,
+        new: &Archive,
+        filter: &Filter,
+        hash_contents: bool,
+    ) -> Result<DiffReport, Error> {
+        crate::diff::diff(self, new, filter, hash_contents)
+    }
+
+    /// Write to `dst` this archive with the tree at `src` merged into it, e.g. new
+    /// `index/<crate>` and `salts/<crate>` files. Files of `src` replace those at the same path,
+    /// keeping their mode, ownership and mtime, and its directories are merged into existing
+    /// ones; replacing an entry by one of another kind fails. The archive keeps its compressor and
+    /// block size, ignoring those of `options`, so that unchanged files are copied without being
+    /// decompressed. `dst` may be the path the archive was opened from, which it is renamed over
+    /// once complete.
+    pub fn append(
+        &self,
+        src: impl AsRef<Path>,
+        dst: impl AsRef<Path>,
+        options: SquashOptions,
+    ) -> Result<SquashReport, Error> {
+        Ok(crate::squash::append(
+            &self.expanded()?,
+            src.as_ref(),
+            dst.as_ref(),
+            &options,
+        )?)
+    }
+
+    /// Async flavor of [`append`](Self::append), on tokio's blocking thread pool.
+    pub async fn append_async(
+        &self,
+        src: impl AsRef<Path>,
+        dst: impl AsRef<Path>,
+        options: SquashOptions,
+    ) -> Result<SquashReport, Error> {
+        let (archive, src, dst) = (
+            self.clone(),
+            src.as_ref().to_path_buf(),
+            dst.as_ref().to_path_buf(),
+        );
+        tokio::task::spawn_blocking(move || archive.append(src, dst, options))
+            .await
+            .context("spawn blocking append task")?
+    }
+
+    /// Fail with [`Error::ArchiveChanged`] if the archive was modified in place since it was
+    /// opened, after which it must be opened again.
+    pub fn check_unchanged(&self) -> Result<(), Error> {
+        Ok(crate::source::check_unchanged(&*self.source)?)
+    }
+
+    /// Release what the source of the archive caches.
+    pub fn trim(&self) {
+        self.source.trim();
+    }
+
+    /// The entry at `path`, in O(log n).
+    pub fn find(&self, path: impl AsRef<Path>) -> Option<EntryRef<'_>> {
+        let i = self.index(path.as_ref())?;
+        Some(EntryRef::with_path(
+            &self.filesystem.root.nodes[i],
+            self.path_of(i),
+        ))
+    }
+
+    /// The entry at `prefix` and every entry under it, in O(log n) plus the number of entries.
+    pub fn find_under(&self, prefix: impl AsRef<Path>) -> impl Iterator<Item = EntryRef<'_>> {
+        let prefix = Path::new("/").join(prefix);
+        let nodes = &self.filesystem.root.nodes;
+        let start = match &self.paths {
+            Some(paths) => paths.partition_point(&prefix),
+            None => nodes.partition_point(|node| node.fullpath < prefix),
+        };
+        (start..nodes.len())
+            .map(|i| EntryRef::with_path(&nodes[i], self.path_of(i)))
+            .take_while(move |entry| entry.path.starts_with(&prefix))
+    }
+
+    pub(crate) fn node(&self, path: &Path) -> Option<&Node<SquashfsFileReader>> {
+        self.index(path).map(|i| &self.filesystem.root.nodes[i])
+    }
+
+    /// Backhand sorts nodes by path, and paths sort by component, so that the entries under a
+    /// directory follow it.
+    fn index(&self, path: &Path) -> Option<usize> {
+        // archive paths are absolute
+        let path: PathBuf = Path::new("/").join(path);
+        match &self.paths {
+            Some(paths) => paths.find(&path),
+            None => (self.filesystem.root.nodes)
+                .binary_search_by(|node| node.fullpath.cmp(&path))
+                .ok(),
+        }
+    }
+
+    /// The regular file at `path`.
+    fn file(&self, path: &Path) -> Result<BasicFile> {
+        let name = self.source.name();
+        let i = self
+            .index(path)
+            .with_context(|| format!("no '{}' in '{name}'", path.display()))?;
+        let InnerNode::File(file) = &self.filesystem.root.nodes[i].inner else {
+            anyhow::bail!("'{}' in '{name}' is not a regular file", path.display());
+        };
+        if let Some(reads) = &self.reads {
+            reads.record(&self.path_of(i));
+        }
+        Ok(file.basic.clone())
+    }
+}
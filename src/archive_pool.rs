@@ -0,0 +1,237 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+};
+
+use crate::{Archive, Error, ExtractOptions};
+
+/// Archives opened on demand by path, holding at most `capacity` of them open and closing the
+/// least recently used first. Closed archives are opened again on their next access, and the
+/// handles already given out stay usable after they are evicted.
+#[derive(Debug)]
+pub struct ArchivePool {
+    capacity: usize,
+    pub(crate) options: ExtractOptions,
+    inner: Mutex<Inner>,
+    #[cfg(all(feature = "notify", target_os = "linux"))]
+    pub(crate) watches: std::sync::OnceLock<Arc<crate::pool_watch::Watches>>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Inner {
+    pub(crate) open: HashMap<PathBuf, (Archive, u64)>,
+    /// Last use tick to path, oldest first.
+    lru: BTreeMap<u64, PathBuf>,
+    tick: u64,
+}
+
+impl ArchivePool {
+    /// Archives are opened with the `kind`, `parsing` and `direct_io` of `options`.
+    pub fn new(capacity: usize, options: ExtractOptions) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            options,
+            inner: Mutex::default(),
+            #[cfg(all(feature = "notify", target_os = "linux"))]
+            watches: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// The archive at `path`, opened if it is not already, or again if it was modified in place
+    /// since.
+    pub fn get(&self, path: impl AsRef<Path>) -> Result<Archive, Error> {
+        let path = path.as_ref();
+        if let Some(archive) = self.cached(path) {
+            return Ok(archive);
+        }
+        let archive = Archive::open(path, self.options.clone())?;
+        Ok(self.insert(path, archive))
+    }
+
+    /// Async flavor of [`get`](Self::get).
+    pub async fn get_async(&self, path: impl AsRef<Path>) -> Result<Archive, Error> {
+        let path = path.as_ref();
+        if let Some(archive) = self.cached(path) {
+            return Ok(archive);
+        }
+        let archive = Archive::open_async(path, self.options.clone()).await?;
+        Ok(self.insert(path, archive))
+    }
+
+    /// The archive open for `path`, closing it if it changed.
+    fn cached(&self, path: &Path) -> Option<Archive> {
+        let archive = self.lock().touch(path)?;
+        if archive.check_unchanged().is_ok() {
+            return Some(archive);
+        }
+        let mut inner = self.lock();
+        if inner
+            .open
+            .get(path)
+            .is_some_and(|(open, _)| Arc::ptr_eq(&open.source, &archive.source))
+        {
+            inner.remove(path);
+        }
+        None
+    }
+
+    /// Close the archive at `path`, e.g. because it changed.
+    pub fn remove(&self, path: impl AsRef<Path>) {
+        self.lock().remove(path.as_ref());
+    }
+
+    pub fn clear(&self) {
+        *self.lock() = Inner::default();
+    }
+
+    /// Number of archives held open.
+    pub fn len(&self) -> usize {
+        self.lock().open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Open `new_path` and serve it for `path`, e.g. to roll a snapshot while requests keep being
+    /// served from the one open until then, returning it. Fails without changing anything if
+    /// `new_path` cannot be opened. Once evicted, `path` itself is opened again, so rename the
+    /// new snapshot over it for the swap to last.
+    pub fn swap_archive(
+        &self,
+        path: impl AsRef<Path>,
+        new_path: impl AsRef<Path>,
+    ) -> Result<Archive, Error> {
+        let archive = Archive::open(new_path, self.options.clone())?;
+        Ok(self.replace(path.as_ref(), archive))
+    }
+
+    /// Async flavor of [`swap_archive`](Self::swap_archive).
+    pub async fn swap_archive_async(
+        &self,
+        path: impl AsRef<Path>,
+        new_path: impl AsRef<Path>,
+    ) -> Result<Archive, Error> {
+        let archive = Archive::open_async(new_path, self.options.clone()).await?;
+        Ok(self.replace(path.as_ref(), archive))
+    }
+
+    pub(crate) fn replace(&self, path: &Path, archive: Archive) -> Archive {
+        self.watch_path(path);
+        let mut inner = self.lock();
+        inner.remove(path);
+        inner.insert(self.capacity, path, archive.clone());
+        archive
+    }
+
+    /// Keep `archive`, unless another caller opened `path` meanwhile, in which case theirs is
+    /// returned.
+    fn insert(&self, path: &Path, archive: Archive) -> Archive {
+        self.watch_path(path);
+        let mut inner = self.lock();
+        if let Some(archive) = inner.touch(path) {
+            return archive;
+        }
+        inner.insert(self.capacity, path, archive.clone());
+        archive
+    }
+
+    pub(crate) fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[cfg_attr(
+        not(all(feature = "notify", target_os = "linux")),
+        allow(unused_variables)
+    )]
+    fn watch_path(&self, path: &Path) {
+        #[cfg(all(feature = "notify", target_os = "linux"))]
+        if let Some(watches) = self.watches.get() {
+            watches.add(path);
+        }
+    }
+}
+
+impl Inner {
+    fn touch(&mut self, path: &Path) -> Option<Archive> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (archive, last_used) = self.open.get_mut(path)?;
+        let last_used = std::mem::replace(last_used, tick);
+        let archive = archive.clone();
+        let path = self
+            .lru
+            .remove(&last_used)
+            .expect("every archive has an lru slot");
+        self.lru.insert(tick, path);
+        Some(archive)
+    }
+
+    /// Keep `archive` for `path`, closing the least recently used archives to stay within
+    /// `capacity`.
+    fn insert(&mut self, capacity: usize, path: &Path, archive: Archive) {
+        while self.open.len() >= capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            self.open.remove(&oldest);
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        self.lru.insert(tick, path.to_path_buf());
+        self.open.insert(path.to_path_buf(), (archive, tick));
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some((_, last_used)) = self.open.remove(path) {
+            self.lru.remove(&last_used);
+        }
+    }
+}
+
+/// An archive that can be replaced by a newer snapshot while it is being read. Readers take the
+/// current archive with [`current`](Self::current), and keep reading the one they took after it
+/// is swapped out.
+#[derive(Debug)]
+pub struct SharedArchive {
+    current: RwLock<Archive>,
+    options: ExtractOptions,
+}
+
+impl SharedArchive {
+    /// Open the archive at `path` with the `kind`, `parsing` and `direct_io` of `options`, which
+    /// the archives swapped in later are opened with as well.
+    pub fn open(path: impl AsRef<Path>, options: ExtractOptions) -> Result<Self, Error> {
+        Ok(Self {
+            current: RwLock::new(Archive::open(path, options.clone())?),
+            options,
+        })
+    }
+
+    pub fn current(&self) -> Archive {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Open and parse the archive at `new_path`, then switch readers to it, returning the
+    /// archive it replaces. Fails without switching if `new_path` cannot be opened.
+    pub fn swap_archive(&self, new_path: impl AsRef<Path>) -> Result<Archive, Error> {
+        let archive = Archive::open(new_path, self.options.clone())?;
+        Ok(self.replace(archive))
+    }
+
+    /// Async flavor of [`swap_archive`](Self::swap_archive), opening the archive on tokio's
+    /// blocking thread pool.
+    pub async fn swap_archive_async(&self, new_path: impl AsRef<Path>) -> Result<Archive, Error> {
+        let archive = Archive::open_async(new_path, self.options.clone()).await?;
+        Ok(self.replace(archive))
+    }
+
+    fn replace(&self, archive: Archive) -> Archive {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        std::mem::replace(&mut *current, archive)
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    future::Future,
+    io::{self, BufWriter, Write},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use backhand::{BasicFile, FilesystemReader};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::{
+    block_decoder::BlockDecoder,
+    profile::{self, Stage, Timed},
+    progress::{EntryEvents, Reporting},
+    recompress::{Encoder, Recompress},
+    runtime::{self, Runtime},
+};
+
+/// Size of the chunks handed from the decoding thread to the reader.
+pub(crate) const CHUNK_LEN: usize = 128 * 1024;
+/// Decoded chunks buffered ahead of the reader.
+pub(crate) const CHUNKS_AHEAD: usize = 4;
+
+/// [`AsyncRead`] over the contents of a file in a squashfs archive. The file is decoded on tokio's
+/// blocking thread pool, a bounded number of chunks ahead of the reader.
+pub struct AsyncSquashfsFile {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    decoder: Option<JoinHandle<()>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncSquashfsFile {
+    /// Start decoding `file`. Must be called from within a tokio runtime.
+    pub fn new(filesystem: Arc<FilesystemReader<'static>>, file: &BasicFile) -> Self {
+        Self::spawn(filesystem, file.clone(), None, None, None, CHUNK_LEN)
+    }
+
+    /// Like [`new`](Self::new), decoding with `block_decoder` if given, yielding the contents
+    /// recompressed with `recompress` in chunks of `chunk_len` bytes and reporting decoded chunks
+    /// to `events`.
+    pub(crate) fn spawn(
+        filesystem: Arc<FilesystemReader<'static>>,
+        file: BasicFile,
+        block_decoder: Option<Arc<BlockDecoder>>,
+        recompress: Option<Recompress>,
+        events: Option<EntryEvents>,
+        chunk_len: usize,
+    ) -> Self {
+        let (tx, chunks) = mpsc::channel(CHUNKS_AHEAD);
+        let profiler = profile::current();
+        let decoder = tokio::task::spawn_blocking(move || {
+            let res = profile::scoped(profiler.as_ref(), || {
+                decode(
+                    &filesystem,
+                    &file,
+                    block_decoder.as_deref(),
+                    recompress,
+                    events,
+                    chunk_len,
+                    &tx,
+                )
+            });
+            if let Err(e) = res {
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+        Self {
+            chunks,
+            decoder: Some(decoder),
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+fn decode(
+    filesystem: &FilesystemReader<'_>,
+    file: &BasicFile,
+    block_decoder: Option<&BlockDecoder>,
+    recompress: Option<Recompress>,
+    events: Option<EntryEvents>,
+    chunk_len: usize,
+    tx: &mpsc::Sender<io::Result<Vec<u8>>>,
+) -> io::Result<()> {
+    let mut writer = Encoder::new(BufWriter::with_capacity(chunk_len, Sender(tx)), recompress)?;
+    let mut reporting = Reporting::new(&mut writer, events);
+    match block_decoder {
+        Some(decoder) => {
+            decoder
+                .copy(filesystem, file, &mut reporting)
+                .map_err(io::Error::other)?;
+        }
+        None => {
+            let file = filesystem.file(file);
+            let mut reader = Timed::new(Stage::Decompress, file.reader());
+            std::io::copy(&mut reader, &mut reporting)?;
+        }
+    }
+    writer.finish()
+}
+
+/// A file of an archive decoded a chunk of blocks at a time for the async extractors, each chunk
+/// on a blocking task of its own once the previous one was written, so that a large file does not
+/// hold a blocking thread throughout and cancellation and throttling act between chunks. Only for
+/// the default [`Kind`](crate::compression::Kind), without recompression.
+pub(crate) struct ChunkedFile {
+    filesystem: Arc<FilesystemReader<'static>>,
+    file: Arc<BasicFile>,
+    decoder: Arc<BlockDecoder>,
+    events: Option<EntryEvents>,
+    blocks_per_chunk: usize,
+    /// The next block to decode, and its offset in the archive.
+    next: usize,
+    offset: u64,
+    done: bool,
+}
+
+impl ChunkedFile {
+    /// Decode `file` in chunks of about `chunk_len` bytes, and at least as many blocks as
+    /// `decoder` decodes at once.
+    pub(crate) fn new(
+        filesystem: Arc<FilesystemReader<'static>>,
+        file: BasicFile,
+        decoder: Arc<BlockDecoder>,
+        events: Option<EntryEvents>,
+        chunk_len: usize,
+    ) -> Self {
+        let blocks_per_chunk = (chunk_len / filesystem.block_size as usize).max(decoder.workers());
+        Self {
+            offset: u64::from(file.blocks_start),
+            filesystem,
+            file: Arc::new(file),
+            decoder,
+            events,
+            blocks_per_chunk,
+            next: 0,
+            done: false,
+        }
+    }
+
+    /// The next chunk of the file, decoded on `runtime`, or `None` once all of it was.
+    pub(crate) async fn next(&mut self, runtime: &dyn Runtime) -> io::Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let (first, offset) = (self.next, self.offset);
+        let count = self
+            .blocks_per_chunk
+            .min(self.file.block_sizes.len() - first);
+        let (filesystem, file) = (Arc::clone(&self.filesystem), Arc::clone(&self.file));
+        let (decoder, events) = (Arc::clone(&self.decoder), self.events.take());
+        let decoded = runtime::unblock(runtime, move || {
+            let mut chunk = Reporting::new(Vec::new(), events);
+            let res = decoder.copy_chunk(&filesystem, &file, first, offset, count, &mut chunk);
+            (res, chunk.into_parts())
+        });
+        let (res, (chunk, events)) = decoded.await.map_err(io::Error::other)?;
+        self.events = events;
+        res.map_err(io::Error::other)?;
+        let blocks = &self.file.block_sizes[first..first + count];
+        self.offset += blocks.iter().map(|b| u64::from(b.size())).sum::<u64>();
+        self.next += count;
+        self.done = self.next == self.file.block_sizes.len();
+        Ok(Some(chunk))
+    }
+}
+
+pub(crate) struct Sender<'a>(pub(crate) &'a mpsc::Sender<io::Result<Vec<u8>>>);
+
+impl Write for Sender<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.blocking_send(Ok(buf.to_vec())).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "squashfs file reader dropped")
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for AsyncSquashfsFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos < this.chunk.len() {
+                let n = buf.remaining().min(this.chunk.len() - this.pos);
+                buf.put_slice(&this.chunk[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.chunks.poll_recv(cx)) {
+                Some(Ok(chunk)) => (this.chunk, this.pos) = (chunk, 0),
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                // a decoder that panicked also closes the channel, which must not look like EOF
+                None => {
+                    let Some(decoder) = &mut this.decoder else {
+                        return Poll::Ready(Ok(()));
+                    };
+                    let res = ready!(Pin::new(decoder).poll(cx));
+                    this.decoder = None;
+                    if let Err(e) = res {
+                        let e = match e.try_into_panic() {
+                            Ok(panic) => crate::report::panic_error(panic),
+                            Err(e) => e.into(),
+                        };
+                        return Poll::Ready(Err(io::Error::other(e)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// [`AsyncRead`] over the contents of an entry streamed by
+/// [`Archive::entries_stream`](crate::Archive::entries_stream).
+pub enum EntryReader {
+    /// A regular file, decoded as it is read.
+    File(AsyncSquashfsFile),
+    /// The target of a symlink, or nothing for other kinds.
+    Inline(io::Cursor<Vec<u8>>),
+}
+
+impl AsyncRead for EntryReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::File(file) => Pin::new(file).poll_read(cx, buf),
+            Self::Inline(contents) => Pin::new(contents).poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    };
+
+    use futures::future::BoxFuture;
+
+    use super::*;
+    use crate::{
+        cancel::CancelToken,
+        progress::{ExtractEvent, Progress},
+        runtime::Tokio,
+        testing::{self, TestArchive},
+        Error, ExtractOptions, Filter, IoOptions, Strategy,
+    };
+
+    const BLOCK: usize = 128 << 10;
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Decoding one block at a time, so that each chunk is a block.
+    fn one_block_at_a_time(read_buffer: usize) -> ExtractOptions {
+        ExtractOptions {
+            io: IoOptions {
+                read_buffer: Some(read_buffer),
+                strategy: Strategy::PerFile,
+                block_decode_workers: Some(1),
+                concurrency: Some(1),
+                ..IoOptions::default()
+            },
+            ..ExtractOptions::default()
+        }
+    }
+
+    /// Counts the blocking tasks run through it.
+    #[derive(Debug, Default)]
+    struct Counting(AtomicUsize);
+
+    impl Runtime for Counting {
+        fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Tokio.spawn_blocking(f)
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_files_in_chunks() {
+        let archive = TestArchive::new(vec![
+            testing::file("blocks", contents(3 * BLOCK)),
+            testing::file("blocks-and-fragment", contents(4 * BLOCK + 1000)),
+            testing::file("fragment", contents(1000)),
+            testing::file("empty", ""),
+        ]);
+        for read_buffer in [None, Some(1), Some(BLOCK), Some(2 * BLOCK + 1)] {
+            let dest = archive.scratch(&format!("dest-{read_buffer:?}"));
+            let options = ExtractOptions {
+                io: IoOptions {
+                    read_buffer,
+                    ..IoOptions::default()
+                },
+                ..ExtractOptions::default()
+            };
+            crate::unsquash_async(archive.path(), &dest, Filter::All, options)
+                .await
+                .unwrap();
+            for (name, len) in [
+                ("blocks", 3 * BLOCK),
+                ("blocks-and-fragment", 4 * BLOCK + 1000),
+                ("fragment", 1000),
+                ("empty", 0),
+            ] {
+                let extracted = std::fs::read(dest.join(name)).unwrap();
+                assert!(extracted == contents(len), "{name} with {read_buffer:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_each_chunk_on_a_blocking_task_of_its_own() {
+        let archive = TestArchive::new(vec![testing::file("large", contents(8 * BLOCK))]);
+        let mut tasks = Vec::new();
+        for read_buffer in [BLOCK, 8 * BLOCK] {
+            let counting = Arc::new(Counting::default());
+            let mut options = one_block_at_a_time(read_buffer);
+            options.io.runtime = Some(counting.clone());
+            let dest = archive.scratch(&format!("dest-{read_buffer}"));
+            crate::unsquash_async(archive.path(), &dest, Filter::All, options)
+                .await
+                .unwrap();
+            assert!(std::fs::read(dest.join("large")).unwrap() == contents(8 * BLOCK));
+            tasks.push(counting.0.load(Ordering::Relaxed));
+        }
+        // the other blocking work is the same either way
+        assert_eq!(tasks[0] - tasks[1], 7, "{tasks:?}");
+    }
+
+    #[tokio::test]
+    async fn cancels_between_chunks() {
+        let len = 32 * BLOCK;
+        let archive = TestArchive::new(vec![testing::file("large", contents(len))]);
+        let (cancel, decoded) = (CancelToken::new(), Arc::new(AtomicU64::new(0)));
+        let progress = Progress::with_hook({
+            let (cancel, decoded) = (cancel.clone(), Arc::clone(&decoded));
+            move |event: &ExtractEvent<'_>| {
+                if event.path == Path::new("/large") {
+                    decoded.fetch_max(event.bytes_written, Ordering::Relaxed);
+                    cancel.cancel();
+                }
+            }
+        });
+        let mut options = one_block_at_a_time(BLOCK);
+        options.cancel = Some(cancel);
+        options.observability.progress = Some(Arc::new(progress));
+        let dest = archive.scratch("dest");
+        let err = crate::unsquash_async(archive.path(), &dest, Filter::All, options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{err}");
+        // cancelled once a chunk was decoded, without decoding the rest of the file
+        let decoded = decoded.load(Ordering::Relaxed);
+        assert!(decoded > 0 && decoded < len as u64, "{decoded}");
+    }
+}
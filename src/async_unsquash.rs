@@ -1,93 +1,843 @@
 use std::{
     collections::HashSet,
-    os::unix::fs::PermissionsExt,
-    path::{Component, Path},
+    io::{self, SeekFrom},
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
-use backhand::{FilesystemReader, InnerNode, Node, Squashfs, SquashfsFileReader, SquashfsSymlink};
-use futures::{stream::FuturesUnordered, StreamExt};
+use backhand::{FilesystemReader, InnerNode, Node, SquashfsFileReader, SquashfsSymlink};
+use futures::FutureExt;
+use tokio::{
+    io::{AsyncSeek, AsyncWrite, AsyncWriteExt},
+    task::JoinSet,
+};
+use tracing::Instrument;
+
+use crate::{
+    archive::{Archive, Input},
+    async_file::{AsyncSquashfsFile, ChunkedFile},
+    block_decoder::BlockDecoder,
+    cancel::CancelToken,
+    classify::{self, ErrorClass, ErrorClassifier},
+    compression::Kind,
+    confine::{self, EscapePolicy},
+    conflict::{self, Conflict},
+    counters::{Counters, Counting},
+    digest::{Hasher, Hashing},
+    durability::{self, Durability},
+    error::Error,
+    filter::{self, Filter},
+    hardlink,
+    mechanisms::Detected,
+    metadata::{self, EntryMetadata, Metadata, MetadataError},
+    options::{
+        DurabilityOptions, ExtractOptions, IoOptions, MetadataErrorPolicy, NodeOptions,
+        ObservabilityOptions, OverwritePolicy, PolicyOptions, Quota, DEFAULT_READ_BUFFER,
+        DEFAULT_SLOW_ENTRY_THRESHOLD, DEFAULT_WRITE_BUFFER,
+    },
+    parsing, platform,
+    profile::{self, Profiled, Profiler, Stage, Timed},
+    progress::{EntryEvents, Progress},
+    reapi::{self, Digest},
+    recompress::{self, Recompress, RecompressManifest},
+    report::{self, DryRun, ExtractReport, ExtractSummary, MetadataWarning, Tally, Unrecoverable},
+    resume::{self, Journal},
+    runtime::{self, Runtime, Tokio},
+    shard::{self, ShardManifest},
+    slow_entry,
+    source::{self, SquashSource},
+    space,
+    sparse::{self, Sparse},
+    special::Special,
+    staging::Staging,
+    throttle::{Throttle, Throttled},
+    trace,
+    xattr::{self, Xattr, Xattrs},
+};
 
 pub async fn unsquash_tpcii_async(
     squashfs: impl AsRef<Path>,
     dest: impl AsRef<Path>,
     crates_filter: Option<HashSet<String>>,
-) -> Result<()> {
-    let (squashfs_path, dest) = (squashfs.as_ref().to_path_buf(), dest.as_ref().to_path_buf());
+) -> Result<ExtractSummary, Error> {
+    unsquash_tpcii_async_with_options(squashfs, dest, crates_filter, ExtractOptions::default())
+        .await
+        .map(|report| report.summary)
+}
 
-    anyhow::ensure!(
-        matches!(tokio::fs::try_exists(&squashfs_path).await, Ok(true)),
-        "specified squashfs archive does not exist: '{}'",
-        squashfs_path.display(),
+/// Like [`unsquash_tpcii_async`], but reading the archive as the given [`Kind`], which selects
+/// the squashfs variant and the decompressor implementation.
+pub async fn unsquash_tpcii_async_with_kind(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    crates_filter: Option<HashSet<String>>,
+    kind: Kind,
+) -> Result<ExtractSummary, Error> {
+    let options = ExtractOptions {
+        io: IoOptions {
+            kind: Some(kind),
+            ..IoOptions::default()
+        },
+        ..Default::default()
+    };
+    unsquash_tpcii_async_with_options(squashfs, dest, crates_filter, options)
+        .await
+        .map(|report| report.summary)
+}
+
+/// Like [`unsquash_tpcii_async`], configured through [`ExtractOptions`].
+pub async fn unsquash_tpcii_async_with_options(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    crates_filter: Option<HashSet<String>>,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    unsquash_async(squashfs, dest, crate::tpcii_filter(crates_filter), options).await
+}
+
+/// Like [`unsquash_tpcii_async_with_options`], reading the archive from any [`SquashSource`]. Use
+/// [`BlockOn`](crate::source::BlockOn) for an
+/// [`AsyncSquashSource`](crate::source::AsyncSquashSource).
+pub async fn unsquash_tpcii_async_from_source(
+    source: impl SquashSource + 'static,
+    dest: impl AsRef<Path>,
+    crates_filter: Option<HashSet<String>>,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    let filter = crate::tpcii_filter(crates_filter);
+    unsquash_async_from_source(source, dest, filter, options).await
+}
+
+/// Async flavor of [`unsquash_blocking`](crate::unsquash_blocking). Entries are extracted on
+/// tasks spawned onto the current tokio runtime, so that they run on all of its workers; the
+/// returned future is `Send`, and `'static` when the arguments are, so it can be spawned itself.
+/// It may also be awaited outside of tokio, see [`runtime`](crate::runtime).
+pub async fn unsquash_async(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    let squashfs_path = squashfs.as_ref();
+    let (path, runtime) = (squashfs_path.to_path_buf(), runtime_of(&options));
+    let exists = runtime::unblock(&*runtime, move || path.try_exists()).await;
+    if !matches!(exists, Ok(Ok(true))) {
+        return Err(Error::ArchiveNotFound(squashfs_path.to_path_buf()));
+    }
+
+    let source = crate::open_source(squashfs_path, &options)?;
+    unsquash_async_from_source(source, dest, filter, options).await
+}
+
+/// Like [`unsquash_async`], reading the archive from any [`SquashSource`]. Use
+/// [`BlockOn`](crate::source::BlockOn) for an
+/// [`AsyncSquashSource`](crate::source::AsyncSquashSource).
+pub async fn unsquash_async_from_source(
+    source: impl SquashSource + 'static,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    unsquash_async_on(Input::Source(Arc::new(source)), dest, filter, options).await
+}
+
+pub(crate) async fn unsquash_async_on(
+    input: Input,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    let dest = dest.as_ref().to_path_buf();
+    let profiler = Profiler::new(options.observability.profile);
+    let extraction = unsquash_on(input, dest, filter, options);
+    let mut report = runtime::compat(Profiled::new(extraction, profiler.clone())).await?;
+    report.profile = profiler.map(|profiler| profiler.profile());
+    Ok(report)
+}
+
+/// The [`Runtime`] the blocking work of an extraction with `options` runs on.
+pub(crate) fn runtime_of(options: &ExtractOptions) -> Arc<dyn Runtime> {
+    options
+        .io
+        .runtime
+        .clone()
+        .unwrap_or_else(|| Arc::new(Tokio))
+}
+
+async fn unsquash_on(
+    input: Input,
+    dest: PathBuf,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    let runtime = runtime_of(&options);
+    #[cfg(feature = "audit")]
+    let audit = match options.observability.audit {
+        Some(sink) => {
+            let (source, filter, dest) = (Arc::clone(input.source()), filter.clone(), dest.clone());
+            let audit = runtime::unblock(&*runtime, move || {
+                crate::audit::Audit::start(sink, &source, &filter, &dest)
+            });
+            Some(audit.await.context("spawn blocking audit task")?)
+        }
+        None => None,
+    };
+    // a dry run leaves no tree to finish
+    let (read_only, nar_hash) = match options.dry_run {
+        true => (None, false),
+        false => (options.durability.read_only, options.observability.nar_hash),
+    };
+    let (source, started) = (Arc::clone(input.source()), Instant::now());
+    let res = extract_async(input, dest.clone(), filter, options)
+        .instrument(trace::extract(&dest))
+        .await;
+    // garbage read from a changed archive may have failed the extraction, or not
+    let unchanged = runtime::unblock(&*runtime, move || source::check_unchanged(&*source));
+    let mut res = unchanged
+        .await
+        .context("spawn blocking archive check task")?
+        .and(res);
+    if let Ok(report) = &mut res {
+        let finish = runtime::unblock(&*runtime, move || {
+            crate::finish_tree(&dest, read_only, nar_hash)
+        });
+        match finish.await.context("spawn blocking tree finishing task")? {
+            Ok(hash) => {
+                report.nar_hash = hash;
+                report.summary.duration = started.elapsed();
+            }
+            Err(e) => res = Err(e),
+        }
+    }
+    #[cfg(feature = "audit")]
+    if let Some(audit) = audit {
+        audit.finish(&res);
+    }
+    res.map_err(Error::from)
+}
+
+async fn extract_async(
+    input: Input,
+    dest: PathBuf,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport> {
+    let quota = Quota::new(&options);
+    let throttle = options.io.max_bytes_per_sec.map(Throttle::new);
+    let max_dest_bytes = options.policy.max_dest_bytes;
+    #[cfg(feature = "sqlite")]
+    let (catalog, extracted_at) = (
+        options.observability.catalog.clone(),
+        std::time::SystemTime::now(),
     );
+    let metadata = Metadata::new(&options);
+    let runtime = runtime_of(&options);
+    #[cfg(feature = "tar")]
+    let tar_options = crate::tar_fallback::check_options(&options);
+    let ExtractOptions {
+        io:
+            IoOptions {
+                kind,
+                mechanisms,
+                concurrency,
+                strategy,
+                block_decode_workers,
+                fragment_cache_blocks,
+                ordering,
+                write_buffer,
+                read_buffer,
+                ..
+            },
+        policy:
+            PolicyOptions {
+                check_space,
+                overwrite,
+                salvage,
+                quarantine,
+                error_classifier,
+                strict_filter,
+                parsing,
+                allow_special_files,
+                escape,
+                metadata_errors,
+                extract_xattrs,
+                ..
+            },
+        durability:
+            DurabilityOptions {
+                cleanup_partial,
+                fsync: durability,
+                resume,
+                atomic,
+                ..
+            },
+        observability:
+            ObservabilityOptions {
+                progress,
+                slow_entry_threshold,
+                sha256sums,
+                hash,
+                digests,
+                ..
+            },
+        shard_levels,
+        subtree,
+        recompress,
+        async_filter,
+        cancel,
+        dry_run,
+        ..
+    } = options;
+    let subtree = subtree.as_deref();
+
+    if filter.is_empty() {
+        return Ok(ExtractReport::default());
+    }
 
-    let crates_filter = crates_filter.map(|filter| {
-        filter
-            .into_iter()
-            .filter_map(|krate| {
-                let index_path = Path::new("/index").join(&krate);
-                let salt_path = Path::new("/salts").join(&krate);
-
-                let paths_iter = index_path
-                    .ancestors()
-                    .chain(salt_path.ancestors())
-                    .map(|p| p.to_path_buf())
-                    .collect::<Vec<_>>();
-                Some(paths_iter)
+    let archive = match input {
+        Input::Opened(archive) => runtime::unblock(&*runtime, move || {
+            Ok::<_, anyhow::Error>(archive.expanded()?.into_owned())
+        })
+        .await
+        .context("spawn blocking path rebuilding task")??,
+        Input::Source(source) => {
+            #[cfg(feature = "tar")]
+            {
+                let (source, dest) = (Arc::clone(&source), dest.clone());
+                let filter = filter.clone();
+                let fallback = runtime::unblock(&*runtime, move || {
+                    let Some(format) = crate::tar_fallback::detect(&*source)? else {
+                        return Ok(None);
+                    };
+                    tar_options?;
+                    crate::tar_fallback::extract(source, format, &dest, &filter).map(Some)
+                })
+                .await
+                .context("spawn blocking tar extraction task")??;
+                if let Some(report) = fallback {
+                    return Ok(report);
+                }
+            }
+
+            let open_started = Instant::now();
+            let span = tracing::Span::current();
+            let archive = runtime::unblock(&*runtime, move || {
+                span.in_scope(|| Archive::open_with(source, kind, parsing, fragment_cache_blocks))
             })
-            .flatten()
-            .collect::<HashSet<_>>()
+            .await
+            .context("spawn blocking squashfs read task")??;
+            profile::record(Stage::Open, open_started.elapsed());
+            archive
+        }
+    };
+    #[cfg(feature = "sqlite")]
+    let archive_name = archive.source.name();
+    let xattrs = match extract_xattrs {
+        true => {
+            let archive = archive.clone();
+            let xattrs = runtime::unblock(&*runtime, move || archive.xattrs());
+            Some(xattrs.await.context("spawn blocking xattr read task")??)
+        }
+        false => None,
+    };
+    anyhow::ensure!(
+        resume.is_none() || !atomic,
+        "resuming atomic extractions is not supported"
+    );
+    let journal = match resume.filter(|_| !dry_run) {
+        Some(path) => {
+            let archive = archive.clone();
+            let journal = runtime::unblock(&*runtime, move || {
+                Journal::open(&path, archive.fingerprint()?)
+            });
+            Some(
+                journal
+                    .await
+                    .context("spawn blocking resume journal task")??,
+            )
+        }
+        None => None,
+    };
+    let Archive {
+        filesystem,
+        block_decoder,
+        counters,
+        ..
+    } = archive;
+
+    let nodes: Vec<&Node<_>> = profile::timed(Stage::Plan, || {
+        filesystem
+            .files()
+            .filter(|node| filter.matches(&node.fullpath))
+            .filter(|node| subtree.is_none_or(|subtree| node.fullpath.starts_with(subtree)))
+            .collect()
+    });
+    let unmatched_filter = filter::unmatched(&filter, filesystem.files(), strict_filter)?;
+    let nodes = parsing::supported_nodes(nodes, parsing, allow_special_files)?;
+    let nodes = match &async_filter {
+        Some(async_filter) => {
+            let plan_started = Instant::now();
+            let nodes = async_filter.select(nodes).await;
+            profile::record(Stage::Plan, plan_started.elapsed());
+            nodes
+        }
+        None => nodes,
+    };
+    if dry_run {
+        return Ok(ExtractReport {
+            dry_run: Some(DryRun::plan(
+                &dest,
+                &nodes,
+                shard_levels,
+                recompress,
+                subtree,
+            )),
+            unmatched_filter,
+            ..ExtractReport::default()
+        });
+    }
+    #[cfg(feature = "sqlite")]
+    let published = dest.clone();
+    let staging = match atomic {
+        true => {
+            let dest = dest.clone();
+            let staging = runtime::unblock(&*runtime, move || Staging::new(&dest));
+            Some(staging.await.context("spawn blocking staging dir task")??)
+        }
+        false => None,
+    };
+    let dest = staging
+        .as_ref()
+        .map_or(dest, |staging| staging.path().to_path_buf());
+    let dirs = conflict::dirs(&dest, &nodes, shard_levels, recompress, subtree);
+    let skipped = runtime::unblock(&*runtime, move || conflict::prepare_dirs(overwrite, dirs))
+        .await
+        .context("spawn blocking dir conflict task")??;
+    let selected = nodes.len();
+    let mut nodes: Vec<_> = nodes
+        .into_iter()
+        .filter(|node| !skipped.iter().any(|dir| node.fullpath.starts_with(dir)))
+        .collect();
+    let sequential = ordering.sort(&mut nodes);
+    trace::filtered(filesystem.root.nodes.len(), selected, nodes.len());
+    let tally = Tally::default();
+    tally.skipped((selected - nodes.len()) as u64);
+    space::check(&dest, &nodes, max_dest_bytes, check_space)?;
+    if let Some(progress) = &progress {
+        progress.plan(&nodes);
+    }
+    let shard_manifest =
+        shard_levels.map(|levels| ShardManifest::build(levels, recompress, &nodes));
+    let concurrency = match sequential {
+        true => 1,
+        false => concurrency
+            .or_else(|| std::thread::available_parallelism().ok().map(Into::into))
+            .unwrap_or(1)
+            .max(1),
+    };
+    let block_decoder = block_decoder.map(|decoder| {
+        decoder.with_workers(strategy.block_workers(&nodes, concurrency, block_decode_workers))
     });
 
-    if crates_filter.as_ref().is_some_and(|f| f.is_empty()) {
-        return Ok(());
+    let shared = Arc::new(Shared {
+        dest: dest.clone(),
+        filesystem: Arc::clone(&filesystem),
+        block_decoder,
+        quota,
+        throttle,
+        shard_levels,
+        subtree: subtree.map(Path::to_path_buf),
+        overwrite,
+        recompress,
+        progress,
+        cancel: cancel.clone(),
+        cleanup_partial,
+        metadata,
+        file_digests: digests.then(|| Mutex::new(Vec::new())),
+        mechanisms: Arc::new(Detected::new(mechanisms)),
+        journal,
+        xattrs,
+        escape,
+        write_buffer: write_buffer.unwrap_or(DEFAULT_WRITE_BUFFER),
+        read_buffer: read_buffer.unwrap_or(DEFAULT_READ_BUFFER),
+        durability,
+        tally,
+        counters,
+        salvage,
+        error_classifier,
+        metadata_errors,
+        slow_entry_threshold: slow_entry_threshold.unwrap_or(DEFAULT_SLOW_ENTRY_THRESHOLD),
+        in_flight: trace::InFlight::default(),
+        runtime: Arc::clone(&runtime),
+    });
+    let (batch, links) = hardlink::plan(&nodes, shared.mechanisms.hardlinks());
+    let (mut batch, symlinks) = confine::defer_symlinks(batch, sequential);
+    let (mut links, mut symlinks) = (Some(links), Some(symlinks));
+    let mut limit = concurrency;
+    let (parent, profiler) = (tracing::Span::current(), profile::current());
+    trace::started(nodes.len(), concurrency);
+    let (mut unrecoverable, mut metadata_warnings) = (Vec::new(), Vec::new());
+    let mut extracted = Vec::new();
+    loop {
+        // aborted when dropped along with this future
+        let mut tasks = JoinSet::new();
+        let mut pending = batch.iter();
+        loop {
+            while tasks.len() < limit {
+                let Some(&node) = pending.next() else {
+                    break;
+                };
+                let span = trace::entry(&parent, node);
+                let entry = extract_entry(Arc::clone(&shared), node.clone()).instrument(span);
+                tasks.spawn(Profiled::new(entry, profiler.clone()));
+            }
+            let Some(joined) = tasks.join_next().await else {
+                break;
+            };
+            let (node, res, class) = match joined {
+                Ok(done) => done,
+                Err(e) => match e.try_into_panic() {
+                    Ok(panic) => std::panic::resume_unwind(panic),
+                    Err(e) => return Err(e).context("join entry task"),
+                },
+            };
+            CancelToken::check(cancel.as_ref())?;
+            match res {
+                Ok(warning) => {
+                    if warning.is_some() {
+                        shared.tally.extracted(&node);
+                    }
+                    metadata_warnings.extend(warning);
+                    if salvage {
+                        extracted.push(node.fullpath.clone());
+                    }
+                }
+                Err(e) if class == Some(ErrorClass::Ignorable) => {
+                    tracing::warn!(path = %node.fullpath.display(), "ignoring failed entry: {e:#}");
+                    shared.tally.skipped(1);
+                }
+                Err(e) if salvage && class != Some(ErrorClass::Fatal) => {
+                    shared.tally.skipped(1);
+                    let dest_path =
+                        shard::dest_path(&dest, &node, shard_levels, recompress, subtree);
+                    unrecoverable.push(Unrecoverable::new(
+                        &node,
+                        &e,
+                        dest_path,
+                        quarantine.as_deref(),
+                    ));
+                }
+                Err(e) => return Err(Error::entry(&node, e).into()),
+            }
+        }
+        if let Some(links) = links.take() {
+            batch = hardlink::link_async(&dest, &links, shared.node_options()).await?;
+            continue;
+        }
+        let Some(symlinks) = symlinks.take() else {
+            break;
+        };
+        // one at a time, see `confine::defer_symlinks`
+        (batch, limit) = (symlinks, 1);
+    }
+    // every entry task finished
+    let Shared {
+        file_digests,
+        mechanisms,
+        journal,
+        tally,
+        in_flight,
+        ..
+    } = Arc::into_inner(shared).expect("entry tasks finished");
+    in_flight.finish();
+    let dirs = metadata.dirs(&dest, &nodes, shard_levels, recompress, subtree);
+    if !dirs.is_empty() {
+        let warnings = runtime::unblock(&*runtime, move || {
+            metadata::finish_dirs(dirs, metadata_errors)
+        });
+        metadata_warnings.extend(
+            warnings
+                .await
+                .context("spawn blocking dir metadata task")??,
+        );
+    }
+    let dirs = durability.dirs(&dest, &nodes, shard_levels, recompress, subtree);
+    if !dirs.is_empty() {
+        runtime::unblock(&*runtime, move || durability::sync_dirs(dirs))
+            .await
+            .context("spawn blocking dir fsync task")??;
     }
 
-    let filesystem = tokio::task::spawn_blocking(move || {
-        let squashfs_f = std::fs::File::open(&squashfs_path)
-            .with_context(|| format!("open squashfs '{}'", squashfs_path.display()))?;
-        let squashfs_buf = std::io::BufReader::new(squashfs_f);
-        let squashfs = Squashfs::from_reader(squashfs_buf)
-            .with_context(|| format!("read squashfs '{}'", squashfs_path.display()))?;
+    if let Some(manifest) = shard_manifest {
+        tokio::fs::create_dir_all(&dest)
+            .await
+            .with_context(|| format!("create dir '{}'", dest.display()))?;
+        let path = dest.join(shard::SHARD_MANIFEST);
+        tokio::fs::write(&path, manifest.to_json()?)
+            .await
+            .with_context(|| format!("write shard manifest '{}'", path.display()))?;
+    }
+    if let Some(recompress) = recompress {
+        let manifest = RecompressManifest::build(&dest, &nodes, recompress, shard_levels, subtree)?;
+        let path = dest.join(recompress::RECOMPRESS_MANIFEST);
+        tokio::fs::write(&path, manifest.to_json()?)
+            .await
+            .with_context(|| format!("write recompression manifest '{}'", path.display()))?;
+    }
+    if sha256sums {
+        let dest_paths = nodes
+            .iter()
+            .filter_map(|node| shard::dest_path(&dest, node, shard_levels, recompress, subtree))
+            .collect();
+        let dest = dest.clone();
+        runtime::unblock(&*runtime, move || {
+            crate::sums::write_sums(&dest, dest_paths, hash)
+        })
+        .await
+        .context("spawn blocking sums write task")??;
+    }
+    let digests = match file_digests {
+        Some(file_digests) => {
+            let files = file_digests
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner);
+            let dest = dest.clone();
+            let digests = runtime::unblock(&*runtime, move || reapi::tree_digests(&dest, files));
+            Some(digests.await.context("spawn blocking tree digest task")??)
+        }
+        None => None,
+    };
+    if let Some(staging) = staging {
+        runtime::unblock(&*runtime, move || staging.commit())
+            .await
+            .context("spawn blocking staging commit task")??;
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(catalog) = catalog {
+        let dest_paths = nodes
+            .iter()
+            .filter_map(|node| {
+                shard::dest_path(&published, node, shard_levels, recompress, subtree)
+            })
+            .collect();
+        runtime::unblock(&*runtime, move || {
+            crate::catalog::write_catalog(&catalog, &archive_name, dest_paths, extracted_at)
+        })
+        .await
+        .context("spawn blocking catalog write task")??;
+    }
 
-        let filesystem = squashfs
-            .into_filesystem_reader()
-            .with_context(|| format!("convert squashfs to reader '{}'", squashfs_path.display()))?;
-        Ok::<_, anyhow::Error>(filesystem)
+    unrecoverable.sort_by(|a, b| a.path.cmp(&b.path));
+    extracted.sort_unstable();
+    if let Some(journal) = journal.filter(|_| unrecoverable.is_empty()) {
+        runtime::unblock(&*runtime, move || journal.finish())
+            .await
+            .context("spawn blocking resume journal removal task")??;
+    }
+    Ok(ExtractReport {
+        summary: tally.summary(),
+        unrecoverable,
+        extracted,
+        metadata_warnings,
+        digests,
+        mechanisms: Some(mechanisms.used()),
+        unmatched_filter,
+        ..ExtractReport::default()
     })
-    .await
-    .context("spawn blocking squashfs read task")??;
-
-    let nodes: Vec<&Node<_>> = filesystem
-        .files()
-        .filter(|node| {
-            crates_filter
-                .as_ref()
-                .map(|f| f.contains(&node.fullpath))
-                .unwrap_or(true)
-        })
-        .collect();
+}
 
-    let mut futs: FuturesUnordered<_> = nodes
-        .into_iter()
-        .map(|node| extract_node(&dest, &filesystem, node))
-        .collect();
-    while let Some(res) = futs.next().await {
-        res?;
+/// What the entries of an extraction share, owned so that each can be extracted on a task of
+/// its own.
+struct Shared {
+    dest: PathBuf,
+    filesystem: Arc<FilesystemReader<'static>>,
+    block_decoder: Option<Arc<BlockDecoder>>,
+    quota: Option<Quota>,
+    throttle: Option<Throttle>,
+    shard_levels: Option<u8>,
+    subtree: Option<PathBuf>,
+    overwrite: OverwritePolicy,
+    recompress: Option<Recompress>,
+    progress: Option<Arc<Progress>>,
+    cancel: Option<CancelToken>,
+    cleanup_partial: bool,
+    metadata: Metadata,
+    file_digests: Option<Mutex<Vec<(PathBuf, Digest)>>>,
+    mechanisms: Arc<Detected>,
+    journal: Option<Journal>,
+    xattrs: Option<Xattrs>,
+    escape: EscapePolicy,
+    write_buffer: usize,
+    read_buffer: usize,
+    durability: Durability,
+    tally: Tally,
+    counters: Arc<Counters>,
+    salvage: bool,
+    error_classifier: Option<ErrorClassifier>,
+    metadata_errors: MetadataErrorPolicy,
+    slow_entry_threshold: Duration,
+    in_flight: trace::InFlight,
+    runtime: Arc<dyn Runtime>,
+}
+
+impl Shared {
+    fn node_options(&self) -> NodeOptions<'_> {
+        NodeOptions {
+            quota: self.quota.as_ref(),
+            throttle: self.throttle.as_ref(),
+            shard_levels: self.shard_levels,
+            subtree: self.subtree.as_deref(),
+            overwrite: self.overwrite,
+            recompress: self.recompress,
+            progress: self.progress.as_ref(),
+            cancel: self.cancel.as_ref(),
+            cleanup_partial: self.cleanup_partial,
+            metadata: self.metadata,
+            digests: self.file_digests.as_ref(),
+            mechanisms: &self.mechanisms,
+            journal: self.journal.as_ref(),
+            xattrs: self.xattrs.as_ref(),
+            escape: self.escape,
+            write_buffer: self.write_buffer,
+            read_buffer: self.read_buffer,
+            durability: self.durability,
+            tally: &self.tally,
+            counters: &self.counters,
+        }
     }
+}
+
+/// Extract `node`, retrying it as classified, returning it along with the outcome.
+async fn extract_entry(
+    shared: Arc<Shared>,
+    node: Node<SquashfsFileReader>,
+) -> (
+    Node<SquashfsFileReader>,
+    Result<Option<MetadataWarning>>,
+    Option<ErrorClass>,
+) {
+    if let Err(e) = CancelToken::check(shared.cancel.as_ref()) {
+        return (node, Err(e), None);
+    }
+    let _task = shared.in_flight.start(&tracing::Span::current());
+    let started = Instant::now();
+    let mut attempts = 0;
+    // not counted as extraction time by the slow entry warning
+    let mut backed_off = Duration::ZERO;
+    let (res, class) = loop {
+        let extract = extract_node(
+            &shared.dest,
+            &shared.filesystem,
+            shared.block_decoder.as_ref(),
+            shared.node_options(),
+            &*shared.runtime,
+            &node,
+        );
+        let res = match shared.salvage {
+            true => AssertUnwindSafe(extract)
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|panic| Err(report::panic_error(panic))),
+            false => extract.await,
+        };
+        let classified =
+            classify::classify(shared.error_classifier.as_ref(), &res, &node, attempts);
+        let Some(backoff) = classified.retry else {
+            break (res, classified.class);
+        };
+        tracing::warn!(path = %node.fullpath.display(), ?backoff, "retrying entry");
+        tokio::time::sleep(backoff).await;
+        backed_off += backoff;
+        attempts += 1;
+    };
+    let res = match attempts {
+        0 => res,
+        _ => res.with_context(|| format!("gave up after {} attempts", attempts + 1)),
+    };
+    trace::entry_done(&node, &res, started.elapsed(), attempts + 1);
+    let res = match res {
+        Err(e)
+            if shared.metadata_errors == MetadataErrorPolicy::Warn && MetadataError::caused(&e) =>
+        {
+            Ok(Some(MetadataWarning::new(node.fullpath.clone(), &e)))
+        }
+        res => res.map(|()| None),
+    };
+    let elapsed = started.elapsed().saturating_sub(backed_off);
+    slow_entry::warn_if_slow(&node, elapsed, shared.slow_entry_threshold);
+    shared.counters.record_entry(&node, &res);
+    if let Some(progress) = &shared.progress {
+        progress.record(&node);
+    }
+    (node, res, class)
+}
 
-    Ok(())
+async fn apply_metadata(
+    runtime: &dyn Runtime,
+    metadata: EntryMetadata,
+    xattrs: Option<Arc<[Xattr]>>,
+    path: PathBuf,
+) -> Result<()> {
+    runtime::unblock(runtime, move || {
+        metadata.apply(&path)?;
+        match xattrs {
+            Some(xattrs) => xattr::restore(&xattrs, &path),
+            None => Ok(()),
+        }
+    })
+    .await
+    .context("spawn blocking metadata task")?
 }
 
 #[inline]
 async fn extract_node(
     root: impl AsRef<Path>,
-    filesystem: &FilesystemReader<'_>,
+    filesystem: &Arc<FilesystemReader<'static>>,
+    block_decoder: Option<&Arc<BlockDecoder>>,
+    options: NodeOptions<'_>,
+    runtime: &dyn Runtime,
     node: &Node<SquashfsFileReader>,
 ) -> anyhow::Result<()> {
-    let path = &node.fullpath;
-    let fullpath = path.strip_prefix(Component::RootDir).unwrap_or(path);
-    let dest_path = root.as_ref().join(fullpath);
+    let NodeOptions {
+        quota,
+        throttle,
+        shard_levels,
+        subtree,
+        overwrite,
+        recompress,
+        progress,
+        cancel,
+        cleanup_partial,
+        metadata,
+        digests,
+        journal,
+        mechanisms,
+        xattrs,
+        escape,
+        durability,
+        tally,
+        counters,
+        ..
+    } = options;
+    let root = root.as_ref();
+    let Some(dest_path) = shard::dest_path(root, node, shard_levels, recompress, subtree) else {
+        return Ok(());
+    };
+    confine::check_path(root, &dest_path, node)?;
+    if let Some(digest) = journal.and_then(|journal| journal.done(node)) {
+        let (dest_path, digest) = (dest_path.clone(), digest.clone());
+        let intact = runtime::unblock(runtime, move || resume::intact(&dest_path, &digest));
+        if intact.await.context("spawn blocking resume check task")? {
+            tally.skipped(1);
+            return Ok(());
+        }
+    }
+    let node_xattrs = xattrs.and_then(|xattrs| xattrs.get(&node.fullpath));
 
     tokio::fs::create_dir_all(
         dest_path
@@ -97,29 +847,284 @@ async fn extract_node(
     .await
     .with_context(|| format!("create dir to unpack '{}'", dest_path.display()))?;
 
+    let existing = match tokio::fs::symlink_metadata(&dest_path).await {
+        Ok(existing) => Some(existing),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("stat '{}'", dest_path.display())),
+    };
+    match conflict::resolve(overwrite, node, &dest_path, existing.as_ref())? {
+        Conflict::None => {}
+        Conflict::Skip => {
+            tally.skipped(1);
+            return Ok(());
+        }
+        Conflict::Reuse => {
+            tally.reused();
+            return Ok(());
+        }
+        Conflict::Replace { dir: true } => tokio::fs::remove_dir_all(&dest_path)
+            .await
+            .with_context(|| format!("remove dir '{}'", dest_path.display()))?,
+        Conflict::Replace { dir: false } => tokio::fs::remove_file(&dest_path)
+            .await
+            .with_context(|| format!("remove '{}'", dest_path.display()))?,
+    }
+
     match &node.inner {
         InnerNode::File(file) => {
-            let fd = std::fs::File::create(&dest_path)
-                .with_context(|| format!("create file to unpack: '{}'", dest_path.display()))?;
-            let mut writer = std::io::BufWriter::with_capacity(file.basic.file_size as usize, &fd);
-            let file = filesystem.file(&file.basic);
-            let mut reader = file.reader();
-
-            // FIXME: Move this into spawn_blocking. We cannot use `tokio::io::copy` because
-            // SquashfsReadFile doesn't implement AsyncRead
-            std::io::copy(&mut reader, &mut writer)
+            let reservation = match quota {
+                Some(quota) => match quota.reserve(u64::from(file.basic.file_size))? {
+                    Some(reservation) => Some(reservation),
+                    None => {
+                        tracing::warn!(path = %dest_path.display(), "skipping file over quota");
+                        tally.skipped(1);
+                        return Ok(());
+                    }
+                },
+                None => None,
+            };
+
+            // created synchronously, so that it is cleaned up when the future is dropped
+            let new_file = mechanisms.create(&dest_path, cleanup_partial)?;
+            let sparse = mechanisms.sparse() && recompress.is_none();
+            if recompress.is_none() && !(sparse && sparse::has_holes(&file.basic)) {
+                mechanisms.preallocate(new_file.file(), file.basic.file_size.into(), &dest_path)?;
+            }
+            let fd = new_file
+                .file()
+                .try_clone()
+                .with_context(|| format!("duplicate fd of '{}'", dest_path.display()))?;
+            let (copied, fd) = match block_decoder {
+                Some(decoder) if options.verbatim(node) => {
+                    let (filesystem, decoder) = (Arc::clone(filesystem), Arc::clone(decoder));
+                    let (basic, mechanisms) = (file.basic.clone(), Arc::clone(mechanisms));
+                    let path = dest_path.clone();
+                    let copy = runtime::unblock(runtime, move || {
+                        decoder
+                            .copy_uncompressed(&filesystem, &basic, &mechanisms, &fd, &path)
+                            .with_context(|| format!("copy file into '{}'", path.display()))
+                            .map(|copied| (copied, fd))
+                    });
+                    copy.await.context("spawn blocking file copy task")??
+                }
+                _ => (false, fd),
+            };
+            let mut hasher = (digests.is_some() || journal.is_some()).then(Hasher::default);
+            if !copied {
+                let fd = FileWriter::new(fd)
+                    .with_context(|| format!("open '{}' for writing", dest_path.display()))?;
+                let mut writer = tokio::io::BufWriter::with_capacity(
+                    options.write_buffer(file.basic.file_size),
+                    Hashing::new(
+                        Counting(
+                            Throttled::new(
+                                Timed::new(Stage::Write, Sparse::new(fd, sparse)),
+                                throttle,
+                            ),
+                            Arc::clone(counters),
+                        ),
+                        hasher.as_mut(),
+                    ),
+                );
+                let events = EntryEvents::new(progress, node);
+                let copy = async {
+                    match block_decoder.filter(|_| recompress.is_none()) {
+                        Some(decoder) => {
+                            let mut chunks = ChunkedFile::new(
+                                Arc::clone(filesystem),
+                                file.basic.clone(),
+                                Arc::clone(decoder),
+                                events,
+                                options.read_buffer,
+                            );
+                            while let Some(chunk) = chunks.next(runtime).await? {
+                                writer.write_all(&chunk).await?;
+                                tokio::task::yield_now().await;
+                            }
+                        }
+                        None => {
+                            let mut reader = AsyncSquashfsFile::spawn(
+                                Arc::clone(filesystem),
+                                file.basic.clone(),
+                                block_decoder.cloned(),
+                                recompress,
+                                events,
+                                options.read_buffer,
+                            );
+                            tokio::io::copy(&mut reader, &mut writer).await?;
+                        }
+                    }
+                    writer.flush().await
+                };
+                tokio::select! {
+                    res = copy => res,
+                    () = CancelToken::until_cancelled(cancel) => return CancelToken::check(cancel),
+                }
                 .with_context(|| format!("extract file into '{}'", dest_path.display()))?;
-            tokio::fs::set_permissions(&dest_path, std::fs::Permissions::from_mode(0o644))
+            }
+            let digest = hasher.map(Hasher::finish);
+            if let (Some(digests), Some(digest)) = (digests, &digest) {
+                digests
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push((dest_path.clone(), digest.clone()));
+            }
+            let entry_metadata = metadata.entry(node);
+            let size = u64::from(file.basic.file_size);
+            let charged = reservation.is_some();
+            let (applied, written) = runtime::unblock(runtime, move || {
+                let fd = new_file.file();
+                // the file may end in a hole
+                if sparse && !copied {
+                    fd.set_len(size)
+                        .with_context(|| format!("extend '{}'", dest_path.display()))?;
+                }
+                let applied =
+                    entry_metadata
+                        .apply_file(fd, &dest_path)
+                        .and_then(|()| match node_xattrs {
+                            Some(xattrs) => xattr::restore_file(&xattrs, fd, &dest_path),
+                            None => Ok(()),
+                        });
+                let mut written = None;
+                if applied.is_ok() || metadata.tolerates_errors() {
+                    durability.sync_file(fd, &dest_path)?;
+                    if charged {
+                        written = Some(
+                            fd.metadata()
+                                .with_context(|| format!("stat '{}'", dest_path.display()))?,
+                        );
+                    }
+                    new_file.publish()?;
+                }
+                Ok::<_, anyhow::Error>((applied, written))
+            })
+            .await
+            .context("spawn blocking file publishing task")??;
+            if let (Some(reservation), Some(written)) = (reservation, written) {
+                reservation.charge(&written);
+            }
+            applied?;
+            if let (Some(journal), Some(digest)) = (journal, digest) {
+                journal.record(node, digest)?;
+            }
+        }
+        InnerNode::Symlink(SquashfsSymlink { link }) => {
+            if !platform::SYMLINKS {
+                tracing::debug!(path = %dest_path.display(), "skipping symlink");
+                tally.skipped(1);
+                return Ok(());
+            }
+            let link = shard::link_target(filesystem, node, link, shard_levels, recompress);
+            confine::check_symlink(root, &dest_path, &link, escape, node)?;
+            let (target, path) = (link.into_owned(), dest_path.clone());
+            runtime::unblock(runtime, move || crate::symlink(&target, &path))
                 .await
-                .with_context(|| format!("chmod 0o644 '{}'", dest_path.display()))?;
+                .context("spawn blocking symlink task")?
+                .with_context(|| format!("symlink file into '{}'", dest_path.display()))?;
+            apply_metadata(runtime, metadata.entry(node), node_xattrs, dest_path).await?;
+        }
+        InnerNode::Dir(_) => {
+            tokio::fs::create_dir_all(&dest_path)
+                .await
+                .with_context(|| format!("create dir into '{}'", dest_path.display()))?;
+            let chmod_started = Instant::now();
+            let path = dest_path.clone();
+            runtime::unblock(runtime, move || platform::set_mode(&path, 0o755))
+                .await
+                .context("spawn blocking chmod task")?
+                .with_context(|| format!("chmod 0o755 '{}'", dest_path.display()))
+                .map_err(MetadataError::from)?;
+            profile::record(Stage::Chmod, chmod_started.elapsed());
+            if let Some(xattrs) = node_xattrs {
+                runtime::unblock(runtime, move || xattr::restore(&xattrs, &dest_path))
+                    .await
+                    .context("spawn blocking xattr task")??;
+            }
+        }
+        InnerNode::CharacterDevice(_)
+        | InnerNode::BlockDevice(_)
+        | InnerNode::NamedPipe
+        | InnerNode::Socket => {
+            let special = Special::of(node).expect("node is a special file");
+            let path = dest_path.clone();
+            let created = runtime::unblock(runtime, move || special.create(&path))
+                .await
+                .context("spawn blocking mknod task")??;
+            if !created {
+                tally.skipped(1);
+                return Ok(());
+            }
+            apply_metadata(runtime, metadata.entry(node), node_xattrs, dest_path).await?;
         }
-        InnerNode::Symlink(SquashfsSymlink { link }) => unimplemented!(),
-        InnerNode::Dir(_) => unimplemented!(),
-        InnerNode::CharacterDevice(_) => unimplemented!(),
-        InnerNode::BlockDevice(_) => unimplemented!(),
-        InnerNode::NamedPipe => unimplemented!(),
-        InnerNode::Socket => unimplemented!(),
     }
 
+    tally.extracted(node);
     Result::<(), anyhow::Error>::Ok(())
 }
+
+/// Where [`extract_node`] writes file contents: through io_uring with the `io-uring` feature
+/// where the kernel supports it, through `tokio::fs` otherwise.
+enum FileWriter {
+    Tokio(tokio::fs::File),
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Uring(crate::uring::UringFile),
+}
+
+impl FileWriter {
+    fn new(fd: std::fs::File) -> io::Result<Self> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = crate::uring::Ring::get() {
+            return ring.open(fd).map(Self::Uring);
+        }
+        Ok(Self::Tokio(tokio::fs::File::from_std(fd)))
+    }
+}
+
+impl AsyncWrite for FileWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tokio(file) => Pin::new(file).poll_write(cx, buf),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Self::Uring(file) => Pin::new(file).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tokio(file) => Pin::new(file).poll_flush(cx),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Self::Uring(file) => Pin::new(file).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tokio(file) => Pin::new(file).poll_shutdown(cx),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Self::Uring(file) => Pin::new(file).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncSeek for FileWriter {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        match self.get_mut() {
+            Self::Tokio(file) => Pin::new(file).start_seek(position),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Self::Uring(file) => Pin::new(file).start_seek(position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<u64>> {
+        match self.get_mut() {
+            Self::Tokio(file) => Pin::new(file).poll_complete(cx),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Self::Uring(file) => Pin::new(file).poll_complete(cx),
+        }
+    }
+}
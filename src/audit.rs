@@ -0,0 +1,250 @@
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    digest,
+    source::{SourceReader, SquashSource},
+    ExtractReport, Filter,
+};
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "backhand-async";
+/// `LOG_AUTHPRIV`, shifted into place.
+const SYSLOG_FACILITY: u8 = 10 << 3;
+
+/// Where to send the [`AuditRecord`] of each extraction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditSink {
+    /// The local syslog daemon, through `/dev/log`, with the record as JSON.
+    Syslog,
+    /// The systemd journal, with each part of the record in a `BACKHAND_*` field.
+    Journald,
+}
+
+/// Who extracted which archive, when, where to and with what result.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub uid: u32,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub pid: u32,
+    /// Seconds since the Unix epoch at which the extraction started.
+    pub started_at: u64,
+    pub archive: String,
+    /// Hex-encoded SHA-256 of the archive, if it could be read.
+    pub archive_sha256: Option<String>,
+    pub filter: String,
+    pub dest: PathBuf,
+    pub succeeded: bool,
+    pub error: Option<String>,
+    /// Number of entries skipped in salvage mode.
+    pub unrecoverable: usize,
+}
+
+/// An extraction being audited.
+pub(crate) struct Audit {
+    sink: AuditSink,
+    record: AuditRecord,
+}
+
+impl Audit {
+    /// Describe the extraction about to start, hashing the archive it reads.
+    pub(crate) fn start(
+        sink: AuditSink,
+        source: &Arc<dyn SquashSource>,
+        filter: &Filter,
+        dest: &Path,
+    ) -> Self {
+        let started_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let archive_sha256 = archive_sha256(source)
+            .inspect_err(|e| tracing::warn!("audit: {e:#}"))
+            .ok();
+        let user = user();
+        Self {
+            sink,
+            record: AuditRecord {
+                uid: user.0,
+                user: user.1,
+                host: host(),
+                pid: std::process::id(),
+                started_at,
+                archive: source.name(),
+                archive_sha256,
+                filter: describe(filter),
+                dest: dest.to_path_buf(),
+                succeeded: false,
+                error: None,
+                unrecoverable: 0,
+            },
+        }
+    }
+
+    /// Complete the record with the outcome of the extraction and send it. Failing to send it is
+    /// logged rather than failing the extraction.
+    pub(crate) fn finish(mut self, res: &Result<ExtractReport>) {
+        match res {
+            Ok(report) => {
+                self.record.succeeded = true;
+                self.record.unrecoverable = report.unrecoverable.len();
+            }
+            Err(e) => self.record.error = Some(format!("{e:#}")),
+        }
+        if let Err(e) = self.send() {
+            tracing::warn!("audit: {e:#}");
+        }
+    }
+
+    fn send(&self) -> Result<()> {
+        let (socket, message) = match self.sink {
+            AuditSink::Syslog => (SYSLOG_SOCKET, self.syslog_message()?),
+            AuditSink::Journald => (JOURNALD_SOCKET, self.journald_message()?),
+        };
+        #[cfg(unix)]
+        let sent = std::os::unix::net::UnixDatagram::unbound()
+            .and_then(|sock| sock.send_to(&message, socket))
+            .map(drop);
+        #[cfg(not(unix))]
+        let sent = {
+            let _ = message;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "audit sinks are only supported on Unix",
+            ))
+        };
+        sent.with_context(|| format!("send audit record to '{socket}'"))
+    }
+
+    fn priority(&self) -> u8 {
+        match self.record.succeeded {
+            true => 6,  // LOG_INFO
+            false => 3, // LOG_ERR
+        }
+    }
+
+    fn syslog_message(&self) -> Result<Vec<u8>> {
+        let json = serde_json::to_string(&self.record).context("serialize audit record")?;
+        let pri = SYSLOG_FACILITY | self.priority();
+        Ok(format!("<{pri}>{IDENTIFIER}[{}]: {json}", self.record.pid).into_bytes())
+    }
+
+    /// Encode the record in the journal's native protocol.
+    fn journald_message(&self) -> Result<Vec<u8>> {
+        let record = &self.record;
+        let message = match &record.error {
+            None => format!(
+                "extracted '{}' into '{}'",
+                record.archive,
+                record.dest.display()
+            ),
+            Some(e) => format!(
+                "failed to extract '{}' into '{}': {e}",
+                record.archive,
+                record.dest.display()
+            ),
+        };
+        let mut out = Vec::new();
+        journal_field(&mut out, "MESSAGE", &message);
+        journal_field(&mut out, "PRIORITY", &self.priority().to_string());
+        journal_field(&mut out, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        let fields = serde_json::to_value(record).context("serialize audit record")?;
+        for (key, value) in fields.as_object().into_iter().flatten() {
+            let value = match value {
+                serde_json::Value::Null => continue,
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            journal_field(
+                &mut out,
+                &format!("BACKHAND_{}", key.to_uppercase()),
+                &value,
+            );
+        }
+        Ok(out)
+    }
+}
+
+fn archive_sha256(source: &Arc<dyn SquashSource>) -> Result<String> {
+    let reader = BufReader::with_capacity(1 << 20, SourceReader::new(Arc::clone(source)));
+    digest::sha256_reader(reader).with_context(|| format!("hash archive '{}'", source.name()))
+}
+
+/// The uid and name of the user running this process. Off Unix, there are no uids and the name
+/// comes from the environment.
+fn user() -> (u32, Option<String>) {
+    #[cfg(unix)]
+    {
+        let uid = nix::unistd::getuid();
+        let name = nix::unistd::User::from_uid(uid)
+            .ok()
+            .flatten()
+            .map(|user| user.name);
+        (uid.as_raw(), name)
+    }
+    #[cfg(not(unix))]
+    {
+        (0, std::env::var("USERNAME").ok())
+    }
+}
+
+fn host() -> Option<String> {
+    #[cfg(unix)]
+    {
+        nix::unistd::gethostname()
+            .ok()
+            .map(|host| host.to_string_lossy().into_owned())
+    }
+    #[cfg(not(unix))]
+    {
+        std::env::var("COMPUTERNAME").ok()
+    }
+}
+
+fn describe(filter: &Filter) -> String {
+    match filter {
+        Filter::All => "all".to_owned(),
+        Filter::Paths(paths) => {
+            let mut paths: Vec<_> = paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            paths.sort_unstable();
+            format!("paths: {}", paths.join(" "))
+        }
+        Filter::Crates { crates, .. } => {
+            let mut crates: Vec<_> = crates.iter().map(String::as_str).collect();
+            crates.sort_unstable();
+            format!("crates: {}", crates.join(" "))
+        }
+        Filter::Glob { patterns, .. } => format!("glob: {}", patterns.join(" ")),
+        Filter::Regex(regex) => format!("regex: {}", regex.as_str()),
+        Filter::Prefix(prefix) => format!("prefix: {}", prefix.display()),
+        Filter::Predicate(_) => "predicate".to_owned(),
+        Filter::Folded(folding, filter) => format!("{} ({folding:?})", describe(filter)),
+    }
+}
+
+/// Values containing a newline must use the length-prefixed form.
+fn journal_field(out: &mut Vec<u8>, key: &str, value: &str) {
+    out.extend_from_slice(key.as_bytes());
+    match value.contains('\n') {
+        true => {
+            out.push(b'\n');
+            out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        }
+        false => out.push(b'='),
+    }
+    out.extend_from_slice(value.as_bytes());
+    out.push(b'\n');
+}
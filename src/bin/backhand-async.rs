@@ -0,0 +1,394 @@
+use std::{
+    io::{IsTerminal, Write},
+    path::PathBuf,
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use backhand_async::{
+    diff::EntryDiff,
+    progress::Progress,
+    verify::{Manifest, MismatchKind},
+    Archive, ExtractOptions, Filter, HashAlgorithm, ObservabilityOptions, PathFolding,
+    PolicyOptions,
+};
+
+const USAGE: &str = "\
+usage: backhand-async <command> [args]
+
+commands:
+  ls [-l] <archive>                          list the entries of an archive
+  extract [--salvage] [--ignore-case] [--filter <glob>]... <archive> <dest>
+                                             extract an archive, or the entries matching a glob,
+                                             in any case with --ignore-case, skipping entries
+                                             that fail with --salvage
+  verify [--hash sha256|sha512|blake3] [--sums <SUMS>] <archive> <dest>
+                                             check files extracted into dest against the archive,
+                                             or against a sha256sum, sha512sum or b3sum manifest
+  diff [--hash] <old> <new>                  list the entries that differ between two archives
+  check <archive>                            decode every block of an archive, listing what fails
+  selftest                                   check that extraction works on this machine
+
+exits with 1 if the command fails or finds a difference, 2 if the command line is malformed";
+
+/// How often the progress bar is redrawn.
+const REDRAW: Duration = Duration::from_millis(100);
+
+/// A malformed command line, reported along with [`USAGE`].
+#[derive(Debug)]
+struct Usage(String);
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Usage {}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(args).await {
+        Ok(code) => code,
+        Err(e) => match e.downcast::<Usage>() {
+            Ok(Usage(message)) => {
+                eprintln!("backhand-async: {message}\n\n{USAGE}");
+                ExitCode::from(2)
+            }
+            Err(e) => {
+                eprintln!("backhand-async: {e:#}");
+                ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+async fn run(args: Vec<String>) -> Result<ExitCode> {
+    let Some((command, args)) = args.split_first() else {
+        return Err(usage("missing command"));
+    };
+    let mut args = Args::new(args);
+    match command.as_str() {
+        "ls" => {
+            let long = args.flag("-l");
+            let [archive] = args.positional(["archive"])?;
+            ls(archive, long).await
+        }
+        "extract" => {
+            let salvage = args.flag("--salvage");
+            let ignore_case = args.flag("--ignore-case");
+            let filters = args.values("--filter")?;
+            let [archive, dest] = args.positional(["archive", "dest"])?;
+            extract(archive, dest, filters, ignore_case, salvage).await
+        }
+        "verify" => {
+            let sums = args.values("--sums")?.pop();
+            let hash = match args.values("--hash")?.pop() {
+                Some(hash) => hash
+                    .parse()
+                    .map_err(|e: anyhow::Error| usage(e.to_string()))?,
+                None => HashAlgorithm::default(),
+            };
+            let [archive, dest] = args.positional(["archive", "dest"])?;
+            verify(archive, dest, sums.map(PathBuf::from), hash).await
+        }
+        "diff" => {
+            let hash = args.flag("--hash");
+            let [old, new] = args.positional(["old", "new"])?;
+            diff(old, new, hash).await
+        }
+        "check" => {
+            let [archive] = args.positional(["archive"])?;
+            check(archive).await
+        }
+        "selftest" => {
+            args.positional([])?;
+            tokio::task::spawn_blocking(backhand_async::selftest)
+                .await
+                .context("spawn blocking selftest task")??;
+            println!("selftest passed");
+            Ok(ExitCode::SUCCESS)
+        }
+        "-h" | "--help" | "help" => {
+            println!("{USAGE}");
+            Ok(ExitCode::SUCCESS)
+        }
+        command => Err(usage(format!("unknown command '{command}'"))),
+    }
+}
+
+fn usage(message: impl Into<String>) -> anyhow::Error {
+    Usage(message.into()).into()
+}
+
+async fn ls(archive: String, long: bool) -> Result<ExitCode> {
+    let archive = Archive::open_async(archive, ExtractOptions::default()).await?;
+    let mut out = std::io::stdout().lock();
+    for entry in archive.list() {
+        let written = match long {
+            true => writeln!(
+                out,
+                "{:<16} {:04o} {:>5} {:>5} {:>12} {}",
+                entry.kind.to_string(),
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                entry.size,
+                entry.path.display()
+            ),
+            false => writeln!(out, "{}", entry.path.display()),
+        };
+        if let Err(e) = written {
+            // e.g. piped into head
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                break;
+            }
+            return Err(e).context("write listing");
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn extract(
+    archive: String,
+    dest: String,
+    filters: Vec<String>,
+    ignore_case: bool,
+    salvage: bool,
+) -> Result<ExitCode> {
+    let filter = match filters.is_empty() {
+        true => Filter::All,
+        false => Filter::glob(&filters)
+            .context("parse --filter")?
+            .folded(PathFolding {
+                case_insensitive: ignore_case,
+                nfc: true,
+            }),
+    };
+    let progress = Arc::new(Progress::new());
+    let options = ExtractOptions::builder()
+        .policy(PolicyOptions {
+            salvage,
+            ..Default::default()
+        })
+        .observability(ObservabilityOptions {
+            progress: Some(Arc::clone(&progress)),
+            ..Default::default()
+        })
+        .build();
+    let bar = std::io::stderr()
+        .is_terminal()
+        .then(|| tokio::spawn(draw(Arc::clone(&progress))));
+    let res = backhand_async::unsquash_async(archive, &dest, filter, options).await;
+    if let Some(bar) = bar {
+        bar.abort();
+        render(&progress);
+        eprintln!();
+    }
+    let report = res?;
+    for entry in &report.unrecoverable {
+        eprintln!("unrecoverable: {}: {}", entry.path.display(), entry.error);
+    }
+    for pattern in &report.unmatched_filter {
+        eprintln!("warning: --filter '{pattern}' matched nothing");
+    }
+    let summary = report.summary;
+    eprintln!(
+        "extracted {} files, {} dirs, {} symlinks, {} bytes into '{dest}' in {:.1?}, skipped {}, \
+         reused {}",
+        summary.files,
+        summary.dirs,
+        summary.symlinks,
+        summary.bytes_written,
+        summary.duration,
+        summary.skipped,
+        summary.reused
+    );
+    Ok(match report.unrecoverable.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+async fn draw(progress: Arc<Progress>) {
+    let mut redraw = tokio::time::interval(REDRAW);
+    loop {
+        redraw.tick().await;
+        render(&progress);
+    }
+}
+
+fn render(progress: &Progress) {
+    const WIDTH: usize = 30;
+    let snapshot = progress.snapshot();
+    let fraction = snapshot.fraction();
+    let filled = (fraction * WIDTH as f64) as usize;
+    eprint!(
+        "\r[{}{}] {:>3}% {}/{} entries {}/{} MiB",
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled.min(WIDTH)),
+        (fraction * 100.0) as u32,
+        snapshot.entries_done,
+        snapshot.entries_total,
+        snapshot.bytes_done >> 20,
+        snapshot.bytes_total >> 20,
+    );
+}
+
+async fn verify(
+    archive: String,
+    dest: String,
+    sums: Option<PathBuf>,
+    hash: HashAlgorithm,
+) -> Result<ExitCode> {
+    let manifest = match sums {
+        Some(sums) => {
+            let sums = tokio::fs::read_to_string(&sums)
+                .await
+                .with_context(|| format!("read '{}'", sums.display()))?;
+            Manifest::from_sums(&sums)?
+        }
+        None => Manifest::Archive,
+    };
+    let options = ExtractOptions::builder()
+        .observability(ObservabilityOptions {
+            hash,
+            ..Default::default()
+        })
+        .build();
+    let report = backhand_async::verify::verify_async(archive, dest, manifest, options).await?;
+    for mismatch in &report.mismatches {
+        let path = mismatch.path.display();
+        match &mismatch.kind {
+            MismatchKind::Missing => println!("missing      {path}"),
+            MismatchKind::NotAFile => println!("not a file   {path}"),
+            MismatchKind::Hash { expected, actual } => {
+                println!(
+                    "{:<12} {path}: expected {expected}, found {actual}",
+                    hash.name()
+                )
+            }
+        }
+    }
+    eprintln!(
+        "{} files verified, {} mismatches",
+        report.verified,
+        report.mismatches.len()
+    );
+    Ok(match report.is_ok() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+async fn check(archive: String) -> Result<ExitCode> {
+    let report = backhand_async::check::check_async(archive, ExtractOptions::default()).await?;
+    for file in &report.unreadable {
+        println!("unreadable   {}: {}", file.path.display(), file.error);
+    }
+    for fragment in &report.unreadable_fragments {
+        println!(
+            "unreadable   fragment {}: {}",
+            fragment.index, fragment.error
+        );
+    }
+    if let Some(error) = &report.xattrs {
+        println!("unreadable   xattrs: {error}");
+    }
+    eprintln!(
+        "{} entries, {} files ({} bytes) and {} fragments decoded, {} unreadable",
+        report.entries,
+        report.files,
+        report.bytes,
+        report.fragments,
+        report.unreadable.len()
+            + report.unreadable_fragments.len()
+            + usize::from(report.xattrs.is_some())
+    );
+    Ok(match report.is_ok() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+async fn diff(old: String, new: String, hash: bool) -> Result<ExitCode> {
+    let options = ExtractOptions::default();
+    let old = Archive::open_async(old, options.clone()).await?;
+    let new = Archive::open_async(new, options).await?;
+    let report = tokio::task::spawn_blocking(move || old.diff(&new, &Filter::All, hash))
+        .await
+        .context("spawn blocking diff task")??;
+    for entry in &report.entries {
+        let path = entry.path().display();
+        match entry {
+            EntryDiff::Added { kind, .. } => println!("+ {path} ({kind})"),
+            EntryDiff::Removed { kind, .. } => println!("- {path} ({kind})"),
+            EntryDiff::Changed { changes, .. } => {
+                let changes: Vec<_> = changes
+                    .iter()
+                    .map(|change| serde_json::to_string(change).unwrap_or_default())
+                    .collect();
+                println!("~ {path} {}", changes.join(" "))
+            }
+        }
+    }
+    eprintln!(
+        "{} added, {} removed, {} changed",
+        report.added, report.removed, report.changed
+    );
+    Ok(match report.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+/// The arguments of a command, taken out as they are parsed.
+struct Args(Vec<String>);
+
+impl Args {
+    fn new(args: &[String]) -> Self {
+        Self(args.to_vec())
+    }
+
+    fn flag(&mut self, name: &str) -> bool {
+        let len = self.0.len();
+        self.0.retain(|arg| arg != name);
+        self.0.len() != len
+    }
+
+    /// Every value of the option `name`, given as `name value` or `name=value`.
+    fn values(&mut self, name: &str) -> Result<Vec<String>> {
+        let mut values = Vec::new();
+        let mut rest = Vec::new();
+        let mut args = std::mem::take(&mut self.0).into_iter();
+        while let Some(arg) = args.next() {
+            if arg == name {
+                match args.next() {
+                    Some(value) => values.push(value),
+                    None => return Err(usage(format!("{name} needs a value"))),
+                }
+            } else if let Some(value) = arg.strip_prefix(name).and_then(|v| v.strip_prefix('=')) {
+                values.push(value.to_owned());
+            } else {
+                rest.push(arg);
+            }
+        }
+        self.0 = rest;
+        Ok(values)
+    }
+
+    /// The remaining arguments, which must be exactly the positional arguments `names`.
+    fn positional<const N: usize>(self, names: [&str; N]) -> Result<[String; N]> {
+        if let Some(option) = self.0.iter().find(|arg| arg.starts_with('-')) {
+            return Err(usage(format!("unknown option '{option}'")));
+        }
+        match <[String; N]>::try_from(self.0) {
+            Ok(args) => Ok(args),
+            Err(args) if args.len() < N => Err(usage(format!("missing <{}>", names[args.len()]))),
+            Err(args) => Err(usage(format!("unexpected argument '{}'", args[N]))),
+        }
+    }
+}
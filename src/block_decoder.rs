@@ -0,0 +1,481 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{IoSliceMut, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex, PoisonError,
+    },
+};
+
+use anyhow::{Context, Result};
+use backhand::{
+    compression::{CompressionAction, DefaultCompressor},
+    BasicFile, DataSize, FilesystemReader, Fragment,
+};
+use rayon::prelude::*;
+
+use crate::{
+    cache::CacheStats,
+    counters::Counters,
+    mechanisms::Detected,
+    profile::{self, Stage},
+    source::{self, SquashSource},
+};
+
+/// Decompressed fragment blocks an archive keeps by default; see
+/// [`IoOptions::fragment_cache_blocks`](crate::IoOptions::fragment_cache_blocks).
+pub(crate) const DEFAULT_FRAGMENT_CACHE_BLOCKS: usize = 16;
+
+/// Reads file data with positional reads, so workers extracting different files never contend on a
+/// shared, seeking reader.
+pub(crate) struct BlockDecoder {
+    archive: Arc<dyn SquashSource>,
+    counters: Arc<Counters>,
+    workers: usize,
+    fragments: Arc<FragmentCache>,
+}
+
+impl BlockDecoder {
+    /// A decoder of the blocks of `archive`, keeping `fragment_cache_blocks` decompressed
+    /// fragment blocks, which decodes the blocks of a file one at a time until told otherwise by
+    /// [`with_workers`](Self::with_workers).
+    pub(crate) fn new(
+        archive: &Arc<dyn SquashSource>,
+        counters: &Arc<Counters>,
+        fragment_cache_blocks: usize,
+    ) -> Self {
+        Self {
+            archive: Arc::clone(archive),
+            counters: Arc::clone(counters),
+            workers: 1,
+            fragments: Arc::new(FragmentCache::new(fragment_cache_blocks)),
+        }
+    }
+
+    /// This decoder, decoding the blocks of a file with `workers` workers, e.g. as a
+    /// [`Strategy`](crate::Strategy) decided for one extraction. The fragment cache is shared.
+    pub(crate) fn with_workers(self: &Arc<Self>, workers: usize) -> Arc<Self> {
+        let workers = workers.max(1);
+        if workers == self.workers {
+            return Arc::clone(self);
+        }
+        Arc::new(Self {
+            archive: Arc::clone(&self.archive),
+            counters: Arc::clone(&self.counters),
+            workers,
+            fragments: Arc::clone(&self.fragments),
+        })
+    }
+
+    /// How many blocks of a file are decoded at once.
+    pub(crate) fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Lookups of the decompressed fragment blocks, see
+    /// [`fragment_cache_blocks`](crate::IoOptions::fragment_cache_blocks).
+    pub(crate) fn fragment_cache_stats(&self) -> CacheStats {
+        self.fragments.stats()
+    }
+
+    pub(crate) fn copy(
+        &self,
+        filesystem: &FilesystemReader<'_>,
+        file: &BasicFile,
+        writer: &mut impl Write,
+    ) -> Result<u64> {
+        let written = match self.workers > 1 && file.block_sizes.len() > self.workers {
+            true => self.copy_blocks_pipelined(filesystem, file, writer)?,
+            false => {
+                let offset = u64::from(file.blocks_start);
+                self.copy_blocks(filesystem, file, 0, offset, &file.block_sizes, writer)?
+            }
+        };
+        Ok(written + self.copy_tail(filesystem, file, writer)?)
+    }
+
+    /// Like [`copy`](Self::copy), for the `count` data blocks of `file` from block `first`,
+    /// stored at `offset` in the archive, followed by the tail of the file if they are its last
+    /// blocks. For decoding a file a chunk at a time.
+    pub(crate) fn copy_chunk(
+        &self,
+        filesystem: &FilesystemReader<'_>,
+        file: &BasicFile,
+        first: usize,
+        offset: u64,
+        count: usize,
+        writer: &mut impl Write,
+    ) -> Result<u64> {
+        let blocks = file
+            .block_sizes
+            .get(first..first + count)
+            .context("chunk is past the last block of the file")?;
+        let written = self.copy_blocks(filesystem, file, first, offset, blocks, writer)?;
+        match first + count == file.block_sizes.len() {
+            true => Ok(written + self.copy_tail(filesystem, file, writer)?),
+            false => Ok(written),
+        }
+    }
+
+    /// Copy the end of `file` stored in a fragment, if any.
+    fn copy_tail(
+        &self,
+        filesystem: &FilesystemReader<'_>,
+        file: &BasicFile,
+        writer: &mut impl Write,
+    ) -> Result<u64> {
+        let block_size = filesystem.block_size as usize;
+        let file_size = file.file_size as usize;
+        let tail = file_size.saturating_sub(file.block_sizes.len() * block_size);
+        if tail == 0 || file.frag_index == u32::MAX {
+            return Ok(0);
+        }
+        let fragment_data = self.fragment(filesystem, file.frag_index)?;
+        let start = file.block_offset as usize;
+        let tail_data = fragment_data
+            .get(start..start + tail)
+            .context("fragment is shorter than the file tail")?;
+        writer.write_all(tail_data).context("write fragment data")?;
+        Ok(tail as u64)
+    }
+
+    /// Copy `file` into `dst` straight from the archive with [`Detected::copy_extents`] if it is
+    /// stored uncompressed in a local archive, returning whether it was. Files with sparse blocks
+    /// are left to [`copy`](Self::copy).
+    pub(crate) fn copy_uncompressed(
+        &self,
+        filesystem: &FilesystemReader<'_>,
+        file: &BasicFile,
+        mechanisms: &Detected,
+        dst: &File,
+        path: &Path,
+    ) -> Result<bool> {
+        let Some(src) = self.archive.file() else {
+            return Ok(false);
+        };
+        if file
+            .block_sizes
+            .iter()
+            .any(|block| !block.uncompressed() || block.size() == 0)
+        {
+            return Ok(false);
+        }
+        let blocks_len: u64 = file.block_sizes.iter().map(|b| u64::from(b.size())).sum();
+        let mut extents = vec![(u64::from(file.blocks_start), blocks_len)];
+        let tail = u64::from(file.file_size).saturating_sub(blocks_len);
+        if tail > 0 {
+            let Some(fragment) = filesystem
+                .fragments
+                .as_ref()
+                .and_then(|fragments| fragments.get(file.frag_index as usize))
+                .filter(|fragment| fragment.size.uncompressed())
+            else {
+                return Ok(false);
+            };
+            extents.push((fragment.start + u64::from(file.block_offset), tail));
+        }
+        let copied = profile::timed(Stage::Write, || {
+            mechanisms.copy_extents(src, &extents, dst, path)
+        })?;
+        if copied {
+            let len = extents.iter().map(|&(_, len)| len as usize).sum();
+            self.counters.add_bytes_read(len);
+            self.counters.add_bytes_written(len);
+        }
+        Ok(copied)
+    }
+
+    /// The decompressed contents of the fragment block at `frag_index`, from the cache if another
+    /// file in it was read recently.
+    pub(crate) fn fragment(
+        &self,
+        filesystem: &FilesystemReader<'_>,
+        frag_index: u32,
+    ) -> Result<Arc<[u8]>> {
+        let fragment = filesystem
+            .fragments
+            .as_ref()
+            .and_then(|fragments| fragments.get(frag_index as usize))
+            .context("file has trailing data but no fragment")?;
+        self.fragments.get_or_decode(fragment.start, || {
+            self.decode_fragment(filesystem, fragment)
+        })
+    }
+
+    fn decode_fragment(
+        &self,
+        filesystem: &FilesystemReader<'_>,
+        fragment: &Fragment,
+    ) -> Result<Vec<u8>> {
+        let mut raw = vec![0; fragment.size.size() as usize];
+        profile::timed(Stage::Read, || {
+            source::read_exact_at(&*self.archive, &mut raw, fragment.start)
+        })
+        .with_context(|| format!("read fragment at offset {}", fragment.start))?;
+        if fragment.size.uncompressed() {
+            return Ok(raw);
+        }
+        let mut out = Vec::with_capacity(filesystem.block_size as usize);
+        profile::timed(Stage::Decompress, || {
+            DefaultCompressor.decompress(&raw, &mut out, filesystem.compressor)
+        })
+        .context("decompress fragment")?;
+        Ok(out)
+    }
+
+    /// Copy the data `blocks` of `file` from block `first`, stored at `offset` in the archive,
+    /// reading then decompressing a window of blocks at a time.
+    fn copy_blocks(
+        &self,
+        filesystem: &FilesystemReader<'_>,
+        file: &BasicFile,
+        first: usize,
+        mut offset: u64,
+        blocks: &[DataSize],
+        writer: &mut impl Write,
+    ) -> Result<u64> {
+        let mut written = 0;
+        let profiler = profile::current();
+        for (window_idx, window) in blocks.chunks(self.workers).enumerate() {
+            let (raw, window_len) = self.read_window(window, offset)?;
+            offset += window_len;
+
+            let decoded = raw
+                .into_par_iter()
+                .zip(window)
+                .enumerate()
+                .map(|(i, (bytes, block))| {
+                    profile::scoped(profiler.as_ref(), || {
+                        decode_block(
+                            filesystem,
+                            file,
+                            first + window_idx * self.workers + i,
+                            bytes,
+                            block,
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            for block in decoded {
+                writer.write_all(&block).context("write decoded block")?;
+                written += block.len() as u64;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Copy the data blocks of `file` through a pipeline: a thread reading windows of blocks
+    /// ahead, `workers` threads decompressing them, and this one writing them in order, with
+    /// bounded channels in between so that the stages overlap. Dedicated threads are used as
+    /// this may run on a rayon worker, which must not block on other rayon tasks.
+    fn copy_blocks_pipelined(
+        &self,
+        filesystem: &FilesystemReader<'_>,
+        file: &BasicFile,
+        writer: &mut impl Write,
+    ) -> Result<u64> {
+        type Decoded = mpsc::SyncSender<Result<Vec<u8>>>;
+
+        let depth = self.workers * 2;
+        let (jobs_tx, jobs) = mpsc::sync_channel::<(usize, Vec<u8>, &DataSize, Decoded)>(depth);
+        let (order_tx, order) = mpsc::sync_channel(depth);
+        let jobs = Mutex::new(jobs);
+        let profiler = profile::current();
+        let profiler = profiler.as_ref();
+        std::thread::scope(|scope| {
+            for _ in 0..self.workers {
+                scope.spawn(|| {
+                    profile::scoped(profiler, || loop {
+                        let job = jobs.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                        let Ok((block_idx, bytes, block, decoded)) = job else {
+                            break;
+                        };
+                        let decoded_block = decode_block(filesystem, file, block_idx, bytes, block);
+                        let _ = decoded.send(decoded_block);
+                    })
+                });
+            }
+            scope.spawn(move || {
+                profile::scoped(profiler, || {
+                    let mut offset = u64::from(file.blocks_start);
+                    for (window_idx, window) in file.block_sizes.chunks(self.workers).enumerate() {
+                        let raw = match self.read_window(window, offset) {
+                            Ok((raw, window_len)) => {
+                                offset += window_len;
+                                raw
+                            }
+                            Err(e) => {
+                                let (decoded, block) = mpsc::sync_channel(1);
+                                let _ = decoded.send(Err(e));
+                                let _ = order_tx.send(block);
+                                return;
+                            }
+                        };
+                        for (i, (bytes, block)) in raw.into_iter().zip(window).enumerate() {
+                            let (decoded, decoded_block) = mpsc::sync_channel(1);
+                            let block_idx = window_idx * self.workers + i;
+                            // a closed channel means the writer failed, or the decoders with it
+                            if order_tx.send(decoded_block).is_err()
+                                || jobs_tx.send((block_idx, bytes, block, decoded)).is_err()
+                            {
+                                return;
+                            }
+                        }
+                    }
+                })
+            });
+
+            let mut written = 0;
+            for block in order {
+                let block = block.recv().context("block decoder exited")??;
+                writer.write_all(&block).context("write decoded block")?;
+                written += block.len() as u64;
+            }
+            Ok(written)
+        })
+    }
+
+    /// Read the raw `window` of blocks at `offset`, returning them and their total length.
+    fn read_window(&self, window: &[DataSize], offset: u64) -> Result<(Vec<Vec<u8>>, u64)> {
+        let window_len: u64 = window.iter().map(|b| u64::from(b.size())).sum();
+        let mut raw: Vec<Vec<u8>> = window.iter().map(|b| vec![0; b.size() as usize]).collect();
+        let mut bufs: Vec<IoSliceMut<'_>> =
+            raw.iter_mut().map(|buf| IoSliceMut::new(buf)).collect();
+        profile::timed(Stage::Read, || {
+            source::read_exact_vectored_at(&*self.archive, &mut bufs, offset)
+        })
+        .with_context(|| format!("read data blocks at offset {offset}"))?;
+        drop(bufs);
+        self.counters.add_bytes_read(window_len as usize);
+        Ok((raw, window_len))
+    }
+}
+
+/// Decompressed fragment blocks by offset in the archive, least recently used first. Archives
+/// keep few of them, so a list is scanned rather than indexed.
+struct FragmentCache {
+    capacity: usize,
+    blocks: Mutex<VecDeque<(u64, Slot)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// A cached block, empty while it is first decoded or if that failed.
+type Slot = Arc<Mutex<Option<Arc<[u8]>>>>;
+
+impl FragmentCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The block at `offset`, decoded with `decode` unless cached. Workers asking for a block
+    /// being decoded wait for it rather than decoding it again.
+    fn get_or_decode(
+        &self,
+        offset: u64,
+        decode: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<Arc<[u8]>> {
+        if self.capacity == 0 {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return decode().map(Arc::from);
+        }
+        let slot = self.slot(offset);
+        let mut cached = slot.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(block) = &*cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Arc::clone(block));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let block: Arc<[u8]> = decode()?.into();
+        *cached = Some(Arc::clone(&block));
+        Ok(block)
+    }
+
+    /// The slot of the block at `offset`, marked as the most recently used, evicting the least
+    /// recently used one if the cache is full.
+    fn slot(&self, offset: u64) -> Slot {
+        let mut blocks = self.blocks.lock().unwrap_or_else(PoisonError::into_inner);
+        let slot = blocks
+            .iter()
+            .position(|(start, _)| *start == offset)
+            .and_then(|i| blocks.remove(i))
+            .map_or_else(Slot::default, |(_, slot)| slot);
+        blocks.push_back((offset, Arc::clone(&slot)));
+        if blocks.len() > self.capacity {
+            blocks.pop_front();
+        }
+        slot
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Decompress the data block `block_idx` of `file`, read as `bytes`.
+fn decode_block(
+    filesystem: &FilesystemReader<'_>,
+    file: &BasicFile,
+    block_idx: usize,
+    bytes: Vec<u8>,
+    block: &DataSize,
+) -> Result<Vec<u8>> {
+    let block_size = filesystem.block_size as usize;
+    if bytes.is_empty() {
+        // sparse block
+        let file_size = file.file_size as usize;
+        return Ok(vec![0; block_size.min(file_size - block_idx * block_size)]);
+    }
+    if block.uncompressed() {
+        return Ok(bytes);
+    }
+    let mut out = Vec::with_capacity(block_size);
+    profile::timed(Stage::Decompress, || {
+        DefaultCompressor.decompress(&bytes, &mut out, filesystem.compressor)
+    })
+    .context("decompress data block")?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::{self, TestArchive},
+        Archive, ExtractOptions, IoOptions, ObservabilityOptions,
+    };
+
+    #[test]
+    fn caches_fragments_as_opened() {
+        let archive = TestArchive::new(vec![testing::file("a", "a"), testing::file("b", "b")]);
+        let fragment_cache = |fragment_cache_blocks| {
+            let options = ExtractOptions {
+                io: IoOptions {
+                    fragment_cache_blocks,
+                    ..IoOptions::default()
+                },
+                observability: ObservabilityOptions {
+                    access_stats: true,
+                    ..ObservabilityOptions::default()
+                },
+                ..ExtractOptions::default()
+            };
+            let opened = Archive::open(archive.path(), options).unwrap();
+            assert_eq!(opened.read_file_blocking("/a").unwrap(), b"a");
+            assert_eq!(opened.read_file_blocking("/b").unwrap(), b"b");
+            opened.access_stats().unwrap().fragment_cache
+        };
+        assert_eq!(fragment_cache(None).hits, 1);
+        assert_eq!(fragment_cache(Some(0)).hits, 0);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Lookups of a cache that found what they looked for, and the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Share of the lookups that hit, from 0 to 1; 0 without lookups.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+/// How often the files of an [`Archive`](crate::Archive) were read and its caches hit, to size
+/// the caches from real traffic; see [`Archive::access_stats`](crate::Archive::access_stats).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccessStats {
+    /// Reads of files, through any of the clones of the archive.
+    pub reads: u64,
+    /// Reads by path, most read first.
+    pub entries: Vec<EntryReads>,
+    /// Lookups of the [`read_cache`](crate::IoOptions::read_cache) directory.
+    pub read_cache: CacheStats,
+    /// Lookups of the chunks the source of the archive keeps, e.g. a
+    /// [`ChunkCache`](crate::source::ChunkCache).
+    pub block_cache: CacheStats,
+    /// Lookups of the decompressed fragment blocks the archive keeps, see
+    /// [`fragment_cache_blocks`](crate::IoOptions::fragment_cache_blocks).
+    #[serde(default)]
+    pub fragment_cache: CacheStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryReads {
+    pub path: PathBuf,
+    pub reads: u64,
+}
+
+/// Reads by path, counted for [`AccessStats`].
+#[derive(Debug, Default)]
+pub(crate) struct ReadCounts(Mutex<HashMap<PathBuf, u64>>);
+
+impl ReadCounts {
+    pub(crate) fn record(&self, path: &Path) {
+        let mut reads = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match reads.get_mut(path) {
+            Some(n) => *n += 1,
+            None => {
+                reads.insert(path.to_path_buf(), 1);
+            }
+        }
+    }
+
+    /// Most read first, then by path.
+    pub(crate) fn entries(&self) -> Vec<EntryReads> {
+        let reads = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut entries: Vec<_> = reads
+            .iter()
+            .map(|(path, &reads)| EntryReads {
+                path: path.clone(),
+                reads,
+            })
+            .collect();
+        entries.sort_by(|a, b| b.reads.cmp(&a.reads).then_with(|| a.path.cmp(&b.path)));
+        entries
+    }
+}
+
+/// Decoded file contents keyed by their fullpath in the archive, bounded by total size and evicted
+/// least recently used first. Entries older than the optional TTL are decoded again.
+#[derive(Debug)]
+pub struct EntryCache {
+    max_bytes: u64,
+    ttl: Option<Duration>,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<PathBuf, Entry>,
+    /// Last use tick to path, oldest first.
+    lru: BTreeMap<u64, PathBuf>,
+    bytes: u64,
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    data: Arc<[u8]>,
+    inserted: Instant,
+    last_used: u64,
+}
+
+impl EntryCache {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            ttl: None,
+            inner: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        Self {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Arc<[u8]>> {
+        let data = self.lookup(path);
+        let counter = match data {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        data
+    }
+
+    fn lookup(&self, path: &Path) -> Option<Arc<[u8]>> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let expired = inner
+            .entries
+            .get(path)
+            .map(|entry| self.ttl.is_some_and(|ttl| entry.inserted.elapsed() > ttl))?;
+        if expired {
+            inner.remove(path);
+            return None;
+        }
+        inner.touch(path)
+    }
+
+    /// Cache `data` for `path`, evicting the least recently used entries to make room. Contents
+    /// larger than the whole cache are not kept.
+    pub fn insert(&self, path: PathBuf, data: Arc<[u8]>) {
+        let len = data.len() as u64;
+        if len > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.remove(&path);
+        while inner.bytes + len > self.max_bytes {
+            let Some((_, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.remove(&oldest);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.lru.insert(tick, path.clone());
+        inner.bytes += len;
+        inner.entries.insert(
+            path,
+            Entry {
+                data,
+                inserted: Instant::now(),
+                last_used: tick,
+            },
+        );
+    }
+
+    /// The cached contents of `path`, or the result of `decode` (which is then cached).
+    pub fn get_or_try_insert_with(
+        &self,
+        path: &Path,
+        decode: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<Arc<[u8]>> {
+        if let Some(data) = self.get(path) {
+            return Ok(data);
+        }
+        let data: Arc<[u8]> = decode()?.into();
+        self.insert(path.to_path_buf(), Arc::clone(&data));
+        Ok(data)
+    }
+
+    /// Total size of the cached contents.
+    /// Lookups through [`get`](Self::get) since the cache was created.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .bytes
+    }
+
+    pub fn clear(&self) {
+        *self.inner.lock().unwrap_or_else(PoisonError::into_inner) = Inner::default();
+    }
+}
+
+impl Inner {
+    fn touch(&mut self, path: &Path) -> Option<Arc<[u8]>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(path)?;
+        let path = self
+            .lru
+            .remove(&entry.last_used)
+            .expect("every entry has an lru slot");
+        self.lru.insert(tick, path);
+        entry.last_used = tick;
+        Some(Arc::clone(&entry.data))
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.lru.remove(&entry.last_used);
+            self.bytes -= entry.data.len() as u64;
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use anyhow::Result;
+use tokio::sync::Notify;
+
+use crate::Error;
+
+/// Cooperative cancellation of an extraction. Entries that have not started yet are not
+/// extracted once [`cancel`](Self::cancel) is called, and the extraction fails. The async
+/// extractors also abort the files they are copying.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Complete once [`cancel`](Self::cancel) has been called.
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.0.notify.notified();
+            tokio::pin!(notified);
+            // register before checking, so that a cancel in between is not missed
+            notified.as_mut().enable();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    pub(crate) fn check(token: Option<&Self>) -> Result<()> {
+        match token.is_some_and(Self::is_cancelled) {
+            true => Err(Error::Cancelled.into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Like [`cancelled`](Self::cancelled), never completing without a token.
+    pub(crate) async fn until_cancelled(token: Option<&Self>) {
+        match token {
+            Some(token) => token.cancelled().await,
+            None => std::future::pending().await,
+        }
+    }
+}
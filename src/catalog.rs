@@ -0,0 +1,96 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+
+use crate::{digest, platform};
+
+struct Row {
+    path: PathBuf,
+    size: u64,
+    mode: u32,
+    sha256: Option<String>,
+}
+
+/// Record every entry extracted under `dest_paths` in the `entries` table of the SQLite database
+/// at `db_path`, creating it if needed. Entries that were not written (e.g. trimmed by the quota)
+/// are skipped, and entries already present from an earlier extraction are replaced.
+pub(crate) fn write_catalog(
+    db_path: &Path,
+    archive: &str,
+    dest_paths: Vec<PathBuf>,
+    extracted_at: SystemTime,
+) -> Result<()> {
+    use rayon::prelude::*;
+
+    let rows = dest_paths
+        .into_par_iter()
+        .map(catalog_row)
+        .filter_map(Result::transpose)
+        .collect::<Result<Vec<_>>>()?;
+
+    let extracted_at = extracted_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+
+    let mut conn = Connection::open(db_path)
+        .with_context(|| format!("open catalog '{}'", db_path.display()))?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS entries (
+            path TEXT PRIMARY KEY,
+            size INTEGER NOT NULL,
+            mode INTEGER NOT NULL,
+            sha256 TEXT,
+            archive TEXT NOT NULL,
+            extracted_at INTEGER NOT NULL
+        )",
+    )
+    .with_context(|| format!("create catalog schema in '{}'", db_path.display()))?;
+
+    let tx = conn.transaction().context("begin catalog transaction")?;
+    {
+        let mut insert = tx
+            .prepare(
+                "INSERT OR REPLACE INTO entries (path, size, mode, sha256, archive, extracted_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )
+            .context("prepare catalog insert")?;
+        for row in rows {
+            insert
+                .execute(params![
+                    row.path.to_string_lossy(),
+                    row.size as i64,
+                    row.mode,
+                    row.sha256,
+                    archive,
+                    extracted_at,
+                ])
+                .with_context(|| format!("catalog '{}'", row.path.display()))?;
+        }
+    }
+    tx.commit()
+        .with_context(|| format!("commit catalog '{}'", db_path.display()))
+}
+
+fn catalog_row(path: PathBuf) -> Result<Option<Row>> {
+    let metadata = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("stat '{}'", path.display())),
+    };
+    let sha256 = match metadata.is_file() {
+        true => Some(digest::sha256_file(&path)?),
+        false => None,
+    };
+
+    Ok(Some(Row {
+        size: metadata.len(),
+        mode: platform::mode(&metadata),
+        sha256,
+        path,
+    }))
+}
@@ -0,0 +1,137 @@
+//! Checking that every part of an archive decodes, without extracting it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use backhand::InnerNode;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{report, Archive, Error, ExtractOptions};
+
+/// The outcome of [`check_blocking`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckReport {
+    /// Entries of the archive, whose inodes and directory tables decoded.
+    pub entries: u64,
+    /// Regular files whose data blocks and fragments were decoded.
+    pub files: u64,
+    /// Size of the files decoded.
+    pub bytes: u64,
+    /// Fragment blocks decoded, including those no file refers to.
+    pub fragments: u64,
+    /// Files that could not be decoded, sorted by path.
+    pub unreadable: Vec<UnreadableFile>,
+    /// Fragment blocks that could not be decoded, sorted by index.
+    pub unreadable_fragments: Vec<UnreadableFragment>,
+    /// Why the xattr table could not be decoded, if it could not.
+    pub xattrs: Option<String>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.unreadable.is_empty() && self.unreadable_fragments.is_empty() && self.xattrs.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnreadableFile {
+    /// Path of the file in the archive.
+    pub path: PathBuf,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnreadableFragment {
+    pub index: u32,
+    pub error: String,
+}
+
+/// Decode all of `squashfs`: its superblock, inode, directory, id and fragment tables when
+/// opening it with the `kind`, `parsing` and `direct_io` of `options`, then the data blocks and
+/// fragments of every file, every fragment block, and the xattr table, on the global rayon pool.
+/// Nothing is written. Failing to open the archive fails the check; the parts that fail to decode
+/// afterwards are reported, so that e.g. an uploaded image can be validated in full.
+///
+/// Fragment blocks and xattrs are only checked separately for archives opened with the default
+/// [`Kind`](crate::compression::Kind).
+pub fn check_blocking(
+    squashfs: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<CheckReport, Error> {
+    Archive::open(squashfs, options)?.check()
+}
+
+/// Async flavor of [`check_blocking`].
+pub async fn check_async(
+    squashfs: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<CheckReport, Error> {
+    let archive = Archive::open_async(squashfs, options).await?;
+    tokio::task::spawn_blocking(move || archive.check())
+        .await
+        .context("spawn blocking check task")?
+}
+
+impl Archive {
+    /// Like [`check_blocking`], for an archive already opened.
+    pub fn check(&self) -> Result<CheckReport, Error> {
+        let archive = self.expanded()?;
+        let filesystem = &*archive.filesystem;
+        let mut report = CheckReport {
+            entries: filesystem.root.nodes.len() as u64,
+            ..CheckReport::default()
+        };
+        let files: Vec<_> = filesystem
+            .files()
+            .filter_map(|node| match &node.inner {
+                InnerNode::File(file) => Some((node, file)),
+                _ => None,
+            })
+            .collect();
+        let decoded: Vec<_> = files
+            .into_par_iter()
+            .map(|(node, file)| {
+                let res = report::catch_panic(|| {
+                    self.copy(&file.basic, &mut std::io::sink())
+                        .with_context(|| format!("decode '{}'", node.fullpath.display()))
+                });
+                (node, res)
+            })
+            .collect();
+        for (node, res) in decoded {
+            match res {
+                Ok(bytes) => {
+                    report.files += 1;
+                    report.bytes += bytes;
+                }
+                Err(e) => report.unreadable.push(UnreadableFile {
+                    path: node.fullpath.clone(),
+                    error: format!("{e:#}"),
+                }),
+            }
+        }
+        report.unreadable.sort_by(|a, b| a.path.cmp(&b.path));
+
+        if let Some(decoder) = &self.block_decoder {
+            let count = filesystem.fragments.as_ref().map_or(0, Vec::len) as u32;
+            let failed: Vec<_> = (0..count)
+                .into_par_iter()
+                .filter_map(|index| {
+                    let res = report::catch_panic(|| decoder.fragment(filesystem, index));
+                    res.err().map(|e| UnreadableFragment {
+                        index,
+                        error: format!("{e:#}"),
+                    })
+                })
+                .collect();
+            report.fragments = u64::from(count) - failed.len() as u64;
+            report.unreadable_fragments = failed;
+            if let Err(e) = self.xattrs() {
+                report.xattrs = Some(format!("{e:#}"));
+            }
+        }
+        self.check_unchanged()?;
+        Ok(report)
+    }
+}
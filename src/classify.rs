@@ -0,0 +1,154 @@
+use std::{fmt, io, sync::Arc, time::Duration};
+
+use backhand::{Node, SquashfsFileReader};
+use serde::{Deserialize, Serialize};
+
+use crate::EntryKind;
+
+/// How to handle an entry that failed to extract because of an I/O error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// Fail the extraction, even in [salvage](crate::PolicyOptions::salvage) mode.
+    Fatal,
+    /// Extract the entry again, up to [`ErrorClassifier::retries`] times, before handling the
+    /// error as if it was not classified.
+    Retryable,
+    /// Skip the entry with a warning, without listing it as unrecoverable.
+    Ignorable,
+}
+
+type Classify = dyn Fn(&io::Error, EntryKind) -> Option<ErrorClass> + Send + Sync;
+
+/// Maps the I/O error an entry failed with, and the kind of the entry, to an [`ErrorClass`].
+/// Unclassified errors fail the extraction, or are salvaged in
+/// [salvage](crate::PolicyOptions::salvage) mode.
+#[derive(Clone)]
+pub struct ErrorClassifier {
+    classify: Arc<Classify>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl fmt::Debug for ErrorClassifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ErrorClassifier")
+            .field("retries", &self.retries)
+            .field("backoff", &self.backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ErrorClassifier {
+    /// Retries [retryable](ErrorClass::Retryable) entries 3 times, waiting 10ms, then twice as
+    /// long after each attempt.
+    pub fn new(
+        classify: impl Fn(&io::Error, EntryKind) -> Option<ErrorClass> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            classify: Arc::new(classify),
+            retries: 3,
+            backoff: Duration::from_millis(10),
+        }
+    }
+
+    /// Retries entries failing with errors that network filesystems return transiently (`EIO`,
+    /// `EAGAIN`, `ETIMEDOUT`, `ESTALE`, `ENOSPC` while space is reclaimed, ...), and leaves
+    /// others unclassified. Off Unix, errors are told apart by their kind only.
+    pub fn transient_io() -> Self {
+        Self::new(|error, _| {
+            #[cfg(unix)]
+            let transient = {
+                use nix::errno::Errno;
+
+                let errno = Errno::from_raw(error.raw_os_error()?);
+                matches!(
+                    errno,
+                    Errno::EIO
+                        | Errno::EAGAIN
+                        | Errno::EINTR
+                        | Errno::ETIMEDOUT
+                        | Errno::ESTALE
+                        | Errno::ENOSPC
+                        | Errno::ECONNRESET
+                        | Errno::EHOSTUNREACH
+                )
+            };
+            #[cfg(not(unix))]
+            let transient = matches!(
+                error.kind(),
+                io::ErrorKind::WouldBlock
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::StorageFull
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::HostUnreachable
+            );
+            transient.then_some(ErrorClass::Retryable)
+        })
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How long to wait before the first retry.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+}
+
+/// How the failure of an entry is handled, and how long to wait before extracting it again if it
+/// is worth retrying.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Classified {
+    pub(crate) class: Option<ErrorClass>,
+    pub(crate) retry: Option<Duration>,
+}
+
+/// Classify the outcome of extracting `node` for the `attempts`th time, counting from 0.
+pub(crate) fn classify(
+    classifier: Option<&ErrorClassifier>,
+    res: &anyhow::Result<()>,
+    node: &Node<SquashfsFileReader>,
+    attempts: u32,
+) -> Classified {
+    let (Some(classifier), Err(error)) = (classifier, res) else {
+        return Classified::default();
+    };
+    let Some(io) = io_error(error) else {
+        return Classified::default();
+    };
+    let class = (classifier.classify)(&io, EntryKind::of(&node.inner));
+    let retry = match class {
+        Some(ErrorClass::Retryable) if attempts < classifier.retries => {
+            Some(classifier.backoff.saturating_mul(1 << attempts.min(16)))
+        }
+        _ => None,
+    };
+    Classified { class, retry }
+}
+
+/// The I/O error, or errno from a syscall, that caused `error`.
+fn io_error(error: &anyhow::Error) -> Option<io::Error> {
+    error.chain().find_map(|cause| {
+        if let Some(io) = cause.downcast_ref::<io::Error>() {
+            return Some(match io.raw_os_error() {
+                Some(errno) => io::Error::from_raw_os_error(errno),
+                None => io::Error::from(io.kind()),
+            });
+        }
+        #[cfg(unix)]
+        {
+            cause
+                .downcast_ref::<nix::errno::Errno>()
+                .map(|errno| io::Error::from_raw_os_error(*errno as i32))
+        }
+        #[cfg(not(unix))]
+        {
+            None
+        }
+    })
+}
@@ -0,0 +1,73 @@
+pub use backhand::{
+    compression::{CompressionAction, CompressionOptions, Compressor, DefaultCompressor},
+    kind::Kind,
+    CompressionExtra, FilesystemCompressor,
+};
+
+/// Decompressors built into this crate, as selected by the `gzip`, `xz`, `zstd`, and `lzo`
+/// features. Archives using anything else need a custom [`CompressionAction`] passed through a
+/// [`Kind`].
+pub fn compiled_decompressors() -> &'static [Compressor] {
+    &[
+        Compressor::None,
+        #[cfg(feature = "gzip")]
+        Compressor::Gzip,
+        #[cfg(feature = "xz")]
+        Compressor::Xz,
+        #[cfg(feature = "zstd")]
+        Compressor::Zstd,
+        #[cfg(feature = "lzo")]
+        Compressor::Lzo,
+    ]
+}
+
+/// Decompression implementation used for a [`Compressor`] in this build and on this CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Data is stored uncompressed and copied as-is.
+    Passthrough,
+    /// Pure-Rust inflate from `miniz_oxide`.
+    Miniz,
+    /// SIMD-accelerated inflate from zlib-ng, enabled with the `zlib-ng` feature.
+    ZlibNg,
+    /// liblzma through `xz2`.
+    Liblzma,
+    /// libzstd, which dispatches to its BMI2 decoding loop at runtime when the CPU supports it.
+    /// `bmi2` only tells whether CPUID reports BMI2: whether libzstd was built with its dynamic
+    /// dispatch, and so actually uses that loop, cannot be queried.
+    Zstd { bmi2: bool },
+    /// Pure-Rust LZO from `rust-lzo`.
+    Lzo,
+    /// Not compiled in; extraction fails unless a custom [`CompressionAction`] handles it.
+    Unavailable,
+}
+
+/// Which [`Backend`] extracting data compressed with `compressor` will use.
+pub fn backend(compressor: Compressor) -> Backend {
+    match compressor {
+        Compressor::None => Backend::Passthrough,
+        #[cfg(feature = "zlib-ng")]
+        Compressor::Gzip => Backend::ZlibNg,
+        #[cfg(all(feature = "gzip", not(feature = "zlib-ng")))]
+        Compressor::Gzip => Backend::Miniz,
+        #[cfg(feature = "xz")]
+        Compressor::Xz => Backend::Liblzma,
+        #[cfg(feature = "zstd")]
+        Compressor::Zstd => Backend::Zstd { bmi2: has_bmi2() },
+        #[cfg(feature = "lzo")]
+        Compressor::Lzo => Backend::Lzo,
+        _ => Backend::Unavailable,
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn has_bmi2() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("bmi2")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        false
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    path::{Component, Path},
+};
+
+use anyhow::{Context, Result};
+use backhand::{InnerNode, Node, SquashfsFileReader};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Whether extracted symlinks may point outside of the destination. Either way, entries are
+/// never written outside of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EscapePolicy {
+    /// Fail symlinks whose target is absolute or climbs out of the destination, possibly through
+    /// other symlinks, with [`Error::Escape`]. Opt into it for untrusted archives.
+    Strict,
+    /// Extract symlinks whatever their target, as they always were, e.g. for root filesystems
+    /// with absolute links.
+    #[default]
+    AllowEscape,
+}
+
+/// Most symlinks a kernel resolves in one path, beyond which it fails with `ELOOP`.
+const MAX_SYMLINKS: usize = 40;
+
+/// Split the symlinks off `nodes`, to be extracted one at a time once everything else is, unless
+/// the extraction is `sequential` anyway. Entries extracted in parallel then never race with the
+/// creation of a symlink among the directories leading to them, which [`check_path`] could miss,
+/// and [`check_symlink`] resolves each target against the tree it ends up in.
+pub(crate) fn defer_symlinks<'a>(
+    nodes: Vec<&'a Node<SquashfsFileReader>>,
+    sequential: bool,
+) -> (
+    Vec<&'a Node<SquashfsFileReader>>,
+    Vec<&'a Node<SquashfsFileReader>>,
+) {
+    match sequential {
+        true => (nodes, Vec::new()),
+        false => nodes
+            .into_iter()
+            .partition(|node| !matches!(node.inner, InnerNode::Symlink(_))),
+    }
+}
+
+/// Fail with [`Error::Escape`] if writing `node` to `dest_path` would write outside of `root`:
+/// its path climbs out of it, or one of the directories leading to it under `root` is a
+/// symlink, e.g. extracted by an earlier entry or left in the destination. Only sound while no
+/// symlink is being created under `root` concurrently; see [`defer_symlinks`].
+pub(crate) fn check_path(
+    root: &Path,
+    dest_path: &Path,
+    node: &Node<SquashfsFileReader>,
+) -> Result<()> {
+    let relative = dest_path.strip_prefix(root).unwrap_or(dest_path);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(escape(node, dest_path));
+    }
+    let mut dir = root.to_path_buf();
+    let Some(parent) = relative.parent() else {
+        return Ok(());
+    };
+    for component in parent.components() {
+        dir.push(component);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(stat) if stat.file_type().is_symlink() => return Err(escape(node, &dir)),
+            Ok(_) => {}
+            // created as a directory from here down
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e).with_context(|| format!("stat '{}'", dir.display())),
+        }
+    }
+    Ok(())
+}
+
+/// Fail with [`Error::Escape`] if the symlink `node`, extracted to `dest_path` under `root`,
+/// points outside of `root` and `policy` is [strict](EscapePolicy::Strict). Its target is resolved
+/// through the symlinks already extracted, so the symlinks extracted after it must be checked
+/// too, one at a time.
+pub(crate) fn check_symlink(
+    root: &Path,
+    dest_path: &Path,
+    target: &Path,
+    policy: EscapePolicy,
+    node: &Node<SquashfsFileReader>,
+) -> Result<()> {
+    if policy == EscapePolicy::AllowEscape {
+        return Ok(());
+    }
+    let relative = dest_path.strip_prefix(root).unwrap_or(dest_path);
+    let outside = resolves_outside(root, relative, target)
+        .with_context(|| format!("resolve symlink '{}'", dest_path.display()))?;
+    match outside {
+        true => Err(escape(node, target)),
+        false => Ok(()),
+    }
+}
+
+/// A component of a symlink target.
+enum Step {
+    Up,
+    Into(OsString),
+}
+
+/// The components of `target`, or `None` if it is absolute.
+fn steps(target: &Path) -> Option<Vec<Step>> {
+    target
+        .components()
+        .filter_map(|component| match component {
+            Component::RootDir | Component::Prefix(_) => Some(None),
+            Component::CurDir => None,
+            Component::ParentDir => Some(Some(Step::Up)),
+            Component::Normal(name) => Some(Some(Step::Into(name.to_owned()))),
+        })
+        .collect()
+}
+
+/// Whether the symlink at `relative` under `root`, pointing to `target`, resolves outside of
+/// `root`, following the symlinks under it as the kernel would, e.g. `a/l -> ../..` with
+/// `a -> b/c`. Past a component that is missing or not a directory, which a symlink extracted
+/// later could take the place of, climbing back up counts as resolving outside.
+pub(crate) fn resolves_outside(
+    root: &Path,
+    relative: &Path,
+    target: &Path,
+) -> std::io::Result<bool> {
+    let parent = relative.parent().unwrap_or(Path::new(""));
+    let (mut resolved, mut depth) = (root.join(parent), parent.components().count());
+    let Some(target) = steps(target) else {
+        return Ok(true);
+    };
+    let mut pending = VecDeque::from(target);
+    let (mut missing, mut hops) = (false, 0);
+    while let Some(step) = pending.pop_front() {
+        let name = match step {
+            Step::Up if missing || depth == 0 => return Ok(true),
+            Step::Up => {
+                resolved.pop();
+                depth -= 1;
+                continue;
+            }
+            Step::Into(name) => name,
+        };
+        resolved.push(&name);
+        depth += 1;
+        if missing {
+            continue;
+        }
+        match std::fs::symlink_metadata(&resolved) {
+            Ok(stat) if stat.file_type().is_symlink() => {
+                hops += 1;
+                // a loop, which resolves to nothing
+                if hops > MAX_SYMLINKS {
+                    return Ok(false);
+                }
+                let Some(link) = steps(&std::fs::read_link(&resolved)?) else {
+                    return Ok(true);
+                };
+                resolved.pop();
+                depth -= 1;
+                for step in link.into_iter().rev() {
+                    pending.push_front(step);
+                }
+            }
+            Ok(stat) => missing = !stat.is_dir(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => missing = true,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+fn escape(node: &Node<SquashfsFileReader>, through: &Path) -> anyhow::Error {
+    Error::Escape {
+        path: node.fullpath.clone(),
+        through: through.to_path_buf(),
+    }
+    .into()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{
+        testing::{dir, file, symlink, TestArchive},
+        unsquash_async, unsquash_blocking, ExtractOptions, ExtractReport, Filter, PolicyOptions,
+    };
+
+    /// Extract the entries of `archive` selected by `filter` with both extractors, into the
+    /// `blocking` and `async` scratch directories, returning the path in the archive of the entry
+    /// that escaped, if any.
+    async fn escaped(
+        archive: &TestArchive,
+        filter: Filter,
+        escape: EscapePolicy,
+    ) -> Option<PathBuf> {
+        let escape_of = |res: Result<ExtractReport, Error>| match res {
+            Ok(_) => None,
+            Err(Error::Escape { path, .. }) => Some(path),
+            Err(e) => panic!("extraction failed: {e:#}"),
+        };
+        let options = ExtractOptions {
+            policy: PolicyOptions {
+                escape,
+                ..PolicyOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        let dest = archive.scratch("blocking");
+        let blocking = unsquash_blocking(archive.path(), &dest, filter.clone(), options.clone());
+        let dest = archive.scratch("async");
+        let async_ = unsquash_async(archive.path(), &dest, filter, options).await;
+        let blocking = escape_of(blocking);
+        assert_eq!(blocking, escape_of(async_));
+        blocking
+    }
+
+    #[tokio::test]
+    async fn rejects_chained_symlinks() {
+        // lexically, `a/b/m` points to `a`, but `l` is `a` itself
+        let archive = TestArchive::new(vec![
+            dir("a/b"),
+            symlink("a/b/l", ".."),
+            symlink("a/b/m", "l/../.."),
+        ]);
+        assert_eq!(
+            escaped(&archive, Filter::All, EscapePolicy::Strict).await,
+            Some(PathBuf::from("/a/b/m"))
+        );
+        assert_eq!(
+            escaped(&archive, Filter::All, EscapePolicy::AllowEscape).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_symlinks_through_the_root() {
+        let archive = TestArchive::new(vec![symlink("b", "."), symlink("c/x", "../b/..")]);
+        assert_eq!(
+            escaped(&archive, Filter::All, EscapePolicy::Strict).await,
+            Some(PathBuf::from("/c/x"))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_symlinks_through_later_symlinks() {
+        // whichever is extracted first, `l` climbs out through `y` once both are
+        let archive = TestArchive::new(vec![symlink("l", "y/.."), symlink("y", ".")]);
+        assert_eq!(
+            escaped(&archive, Filter::All, EscapePolicy::Strict).await,
+            Some(PathBuf::from("/l"))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_absolute_symlinks() {
+        let archive = TestArchive::new(vec![file("f", "x"), symlink("l", "/etc/passwd")]);
+        assert_eq!(
+            escaped(&archive, Filter::All, EscapePolicy::Strict).await,
+            Some(PathBuf::from("/l"))
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_symlinks_resolving_inside() {
+        let archive = TestArchive::new(vec![
+            file("a/c/f", "x"),
+            symlink("a/b/l", ".."),
+            symlink("a/m", "b/l/c/f"),
+            symlink("a/n", "../a/./c"),
+        ]);
+        assert_eq!(
+            escaped(&archive, Filter::All, EscapePolicy::Strict).await,
+            None
+        );
+        for dest in ["blocking", "async"] {
+            let dest = archive.scratch(dest);
+            assert_eq!(std::fs::read(dest.join("a/m")).unwrap(), b"x");
+            assert_eq!(std::fs::read(dest.join("a/n/f")).unwrap(), b"x");
+        }
+    }
+
+    #[tokio::test]
+    async fn does_not_write_through_symlinks_in_dest() {
+        let archive = TestArchive::new(vec![file("a/f", "x")]);
+        let outside = archive.scratch("outside");
+        std::fs::create_dir(&outside).unwrap();
+        for dest in ["blocking", "async"] {
+            let dest = archive.scratch(dest);
+            std::fs::create_dir(&dest).unwrap();
+            std::os::unix::fs::symlink(&outside, dest.join("a")).unwrap();
+        }
+        // `/a` itself is not selected, so the symlink in its place is kept
+        let filter = Filter::Paths([PathBuf::from("/a/f")].into());
+        assert_eq!(
+            escaped(&archive, filter, EscapePolicy::AllowEscape).await,
+            Some(PathBuf::from("/a/f"))
+        );
+        assert!(!outside.join("f").exists());
+    }
+
+    #[test]
+    fn resolves_through_existing_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::os::unix::fs::symlink("b", root.join("a/l")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("a/up")).unwrap();
+        std::os::unix::fs::symlink("q", root.join("a/p")).unwrap();
+        std::os::unix::fs::symlink("p", root.join("a/q")).unwrap();
+        let outside = |relative: &str, target: &str| {
+            resolves_outside(root, Path::new(relative), Path::new(target)).unwrap()
+        };
+        assert!(!outside("x", "a/l/.."));
+        assert!(!outside("a/x", "l/../../a"));
+        assert!(outside("x", "a/up/.."));
+        assert!(outside("a/x", "up/../a"));
+        assert!(outside("a/x", "missing/.."));
+        assert!(!outside("a/x", "missing/deeper"));
+        assert!(!outside("a/x", "p/.."));
+        assert!(outside("a/x", "/a"));
+    }
+}
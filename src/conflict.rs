@@ -0,0 +1,145 @@
+use std::{
+    fs::Metadata,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use backhand::{InnerNode, Node, SquashfsFileReader};
+
+use crate::{platform, recompress::Recompress, shard, Error, OverwritePolicy};
+
+/// What to do about what is already at the destination of an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Conflict {
+    /// Nothing is in the way: create the entry, or write a file over the existing one.
+    None,
+    Skip,
+    /// An identical file is already there: keep it.
+    Reuse,
+    /// Remove what is there first, recursively if it is a directory.
+    Replace {
+        dir: bool,
+    },
+}
+
+/// Decide how to extract `node` to `dest_path`, where `existing` was found. Directories merge
+/// with existing directories whatever the policy.
+pub(crate) fn resolve(
+    policy: OverwritePolicy,
+    node: &Node<SquashfsFileReader>,
+    dest_path: &Path,
+    existing: Option<&Metadata>,
+) -> Result<Conflict> {
+    let size = match &node.inner {
+        InnerNode::File(file) => Some(u64::from(file.basic.file_size)),
+        _ => None,
+    };
+    let dir = matches!(node.inner, InnerNode::Dir(_));
+    decide(policy, size, dir, node.header.mtime, dest_path, existing)
+}
+
+/// Like [`resolve`], for a file of `size` bytes, a directory, or another kind of entry.
+fn decide(
+    policy: OverwritePolicy,
+    size: Option<u64>,
+    dir: bool,
+    mtime: u32,
+    dest_path: &Path,
+    existing: Option<&Metadata>,
+) -> Result<Conflict> {
+    let Some(existing) = existing else {
+        return Ok(Conflict::None);
+    };
+    let file_type = existing.file_type();
+    if dir && file_type.is_dir() {
+        return Ok(Conflict::None);
+    }
+    let replace = match policy {
+        OverwritePolicy::Overwrite => true,
+        OverwritePolicy::Error => {
+            return Err(Error::DestinationExists(dest_path.to_path_buf()).into())
+        }
+        OverwritePolicy::Skip => false,
+        OverwritePolicy::OverwriteIfNewer => i64::from(mtime) > platform::mtime(existing),
+        OverwritePolicy::SkipIdentical
+            if file_type.is_file()
+                && size == Some(existing.len())
+                && i64::from(mtime) == platform::mtime(existing) =>
+        {
+            return Ok(Conflict::Reuse)
+        }
+        OverwritePolicy::SkipIdentical => true,
+    };
+    Ok(match (replace, size.is_some() && file_type.is_file()) {
+        (false, _) => Conflict::Skip,
+        // truncated when it is created
+        (true, true) => Conflict::None,
+        (true, false) => Conflict::Replace {
+            dir: file_type.is_dir(),
+        },
+    })
+}
+
+/// A directory to extract, for [`prepare_dirs`].
+pub(crate) struct Dir {
+    path: PathBuf,
+    dest_path: PathBuf,
+    mtime: u32,
+}
+
+/// The directories among `nodes`, shallowest first.
+pub(crate) fn dirs(
+    dest: &Path,
+    nodes: &[&Node<SquashfsFileReader>],
+    shard_levels: Option<u8>,
+    recompress: Option<Recompress>,
+    subtree: Option<&Path>,
+) -> Vec<Dir> {
+    let mut dirs: Vec<_> = nodes
+        .iter()
+        .filter(|node| matches!(node.inner, InnerNode::Dir(_)))
+        .filter_map(|node| {
+            Some(Dir {
+                path: node.fullpath.clone(),
+                dest_path: shard::dest_path(dest, node, shard_levels, recompress, subtree)?,
+                mtime: node.header.mtime,
+            })
+        })
+        .collect();
+    dirs.sort_by_key(|dir| dir.path.components().count());
+    dirs
+}
+
+/// Resolve what is in the way of `dirs` before extracting anything, as their entries may be
+/// extracted before them. Returns the paths in the archive of the directories to skip, along with
+/// everything under them.
+pub(crate) fn prepare_dirs(policy: OverwritePolicy, dirs: Vec<Dir>) -> Result<Vec<PathBuf>> {
+    let mut skipped: Vec<PathBuf> = Vec::new();
+    for dir in dirs {
+        if skipped.iter().any(|skipped| dir.path.starts_with(skipped)) {
+            continue;
+        }
+        let existing = match std::fs::symlink_metadata(&dir.dest_path) {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("stat '{}'", dir.dest_path.display())),
+        };
+        match decide(
+            policy,
+            None,
+            true,
+            dir.mtime,
+            &dir.dest_path,
+            Some(&existing),
+        )? {
+            Conflict::None | Conflict::Reuse => {}
+            Conflict::Skip => {
+                tracing::debug!(path = %dir.dest_path.display(), "keeping existing entry");
+                skipped.push(dir.path);
+            }
+            Conflict::Replace { .. } => std::fs::remove_file(&dir.dest_path)
+                .with_context(|| format!("remove '{}'", dir.dest_path.display()))?,
+        }
+    }
+    Ok(skipped)
+}
@@ -0,0 +1,149 @@
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use backhand::{Node, SquashfsFileReader};
+
+use crate::{metrics, EntryKind};
+
+/// Cheap running totals over the reads and extractions of an [`Archive`](crate::Archive) and its
+/// clones, see [`Archive::counters`](crate::Archive::counters), meant to be polled by embedders.
+#[derive(Debug, Default)]
+pub struct Counters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    entries: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// A point-in-time copy of [`Counters`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CountersSnapshot {
+    /// Bytes read from squashfs archives.
+    pub bytes_read: u64,
+    /// Bytes of file data written to destinations.
+    pub bytes_written: u64,
+    /// Entries (files, directories, symlinks) extracted successfully.
+    pub entries: u64,
+    /// Entries whose extraction failed.
+    pub errors: u64,
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> CountersSnapshot {
+        CountersSnapshot {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            entries: self.entries.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn add_bytes_read(&self, n: usize) {
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+        metrics::with(|metrics| metrics.bytes_read(n as u64));
+    }
+
+    pub(crate) fn add_bytes_written(&self, n: usize) {
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+        metrics::with(|metrics| metrics.bytes_written(n as u64));
+    }
+
+    pub(crate) fn record_entry<T, E>(&self, node: &Node<SquashfsFileReader>, res: &Result<T, E>) {
+        let counter = match res {
+            Ok(_) => &self.entries,
+            Err(_) => &self.errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        metrics::with(|metrics| metrics.entry(EntryKind::of(&node.inner), res.is_ok()));
+    }
+}
+
+/// Counts the bytes read or written through it into its [`Counters`].
+pub(crate) struct Counting<T>(pub(crate) T, pub(crate) Arc<Counters>);
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.0.read(buf)?;
+        self.1.add_bytes_read(n);
+        Ok(n)
+    }
+}
+
+impl<S: Seek> Seek for Counting<S> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for Counting<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.0).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.1.add_bytes_written(n);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.1.add_bytes_written(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::{self, TestArchive},
+        Archive, ExtractOptions, Filter,
+    };
+
+    #[test]
+    fn counts_per_archive() {
+        let archive = TestArchive::new(vec![
+            testing::dir("a"),
+            testing::file("a/b", "bytes"),
+            testing::symlink("a/c", "b"),
+        ]);
+        let counted = Archive::open(archive.path(), ExtractOptions::default()).unwrap();
+        let other = Archive::open(archive.path(), ExtractOptions::default()).unwrap();
+        let opened = other.counters().snapshot();
+        assert!(opened.bytes_read > 0);
+
+        let dest = archive.scratch("dest");
+        counted
+            .clone()
+            .extract_blocking(&dest, Filter::All, ExtractOptions::default())
+            .unwrap();
+        let snapshot = counted.counters().snapshot();
+        assert_eq!(snapshot.entries, counted.list().count() as u64);
+        assert_eq!(snapshot.errors, 0);
+        assert_eq!(snapshot.bytes_written, 5);
+        assert_eq!(other.counters().snapshot(), opened);
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Component, Path},
+};
+
+use anyhow::{Context, Result};
+use backhand::{InnerNode, Node, SquashfsFileReader};
+
+use crate::ExtractOptions;
+
+const S_IFIFO: u32 = 0o010000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFSOCK: u32 = 0o140000;
+
+/// Repackage the (optionally filtered) archive contents as a newc cpio archive, as used for
+/// initramfs images, preserving modes, ownership, mtimes and symlinks. Only
+/// [`IoOptions::kind`](crate::IoOptions::kind) and
+/// [`PolicyOptions::parsing`](crate::PolicyOptions::parsing) apply.
+pub fn unsquash_tpcii_to_cpio(
+    squashfs: impl AsRef<Path>,
+    out: impl Write,
+    crates_filter: Option<HashSet<String>>,
+    options: ExtractOptions,
+) -> Result<()> {
+    let squashfs_path = squashfs.as_ref();
+    anyhow::ensure!(
+        squashfs_path.exists(),
+        "specified squashfs archive does not exist: '{}'",
+        squashfs_path.display(),
+    );
+
+    let filter = crate::tpcii_filter(crates_filter);
+    let filesystem =
+        crate::open_filesystem_path(squashfs_path, options.io.kind, options.policy.parsing)?;
+
+    let mut out = CpioWriter {
+        out: std::io::BufWriter::new(out),
+        ino: 0,
+    };
+    let nodes = filesystem
+        .files()
+        .filter(|node| filter.matches(&node.fullpath));
+    for node in nodes {
+        let name = node
+            .fullpath
+            .strip_prefix(Component::RootDir)
+            .unwrap_or(&node.fullpath);
+        let name = match name.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => name,
+        };
+
+        match &node.inner {
+            InnerNode::File(file) => {
+                let size = file.basic.file_size;
+                out.header(node, name, S_IFREG, size, 0)?;
+                let mut reader = filesystem.file(&file.basic).reader();
+                let copied = std::io::copy(&mut reader, &mut out.out)
+                    .with_context(|| format!("copy '{}' into cpio", node.fullpath.display()))?;
+                anyhow::ensure!(
+                    copied == u64::from(size),
+                    "'{}' decompressed to {copied} bytes, expected {size}",
+                    node.fullpath.display()
+                );
+                out.pad(size)?;
+            }
+            InnerNode::Symlink(symlink) => {
+                let target = symlink.link.as_os_str().as_encoded_bytes();
+                out.header(node, name, S_IFLNK, target.len() as u32, 0)?;
+                out.out.write_all(target)?;
+                out.pad(target.len() as u32)?;
+            }
+            InnerNode::Dir(_) => out.header(node, name, S_IFDIR, 0, 0)?,
+            InnerNode::CharacterDevice(dev) => {
+                out.header(node, name, S_IFCHR, 0, dev.device_number)?
+            }
+            InnerNode::BlockDevice(dev) => out.header(node, name, S_IFBLK, 0, dev.device_number)?,
+            InnerNode::NamedPipe => out.header(node, name, S_IFIFO, 0, 0)?,
+            InnerNode::Socket => out.header(node, name, S_IFSOCK, 0, 0)?,
+        }
+    }
+
+    out.trailer()?;
+    out.out.flush().context("flush cpio archive")
+}
+
+struct CpioWriter<W: Write> {
+    out: W,
+    ino: u32,
+}
+
+impl<W: Write> CpioWriter<W> {
+    fn header(
+        &mut self,
+        node: &Node<SquashfsFileReader>,
+        name: &Path,
+        file_type: u32,
+        size: u32,
+        rdev: u32,
+    ) -> Result<()> {
+        let header = &node.header;
+        let nlink = if file_type == S_IFDIR { 2 } else { 1 };
+        // squashfs stores device numbers in the kernel's `new_encode_dev` layout.
+        let (rdev_major, rdev_minor) = (
+            (rdev & 0xfff00) >> 8,
+            (rdev & 0xff) | ((rdev >> 12) & 0xfff00),
+        );
+        self.ino += 1;
+        self.entry(
+            name.as_os_str().as_encoded_bytes(),
+            [
+                self.ino,
+                file_type | u32::from(header.permissions & 0o7777),
+                header.uid,
+                header.gid,
+                nlink,
+                header.mtime,
+                size,
+                0,
+                0,
+                rdev_major,
+                rdev_minor,
+            ],
+        )
+        .with_context(|| format!("write cpio header for '{}'", node.fullpath.display()))
+    }
+
+    fn trailer(&mut self) -> Result<()> {
+        self.entry(b"TRAILER!!!", [0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .context("write cpio trailer")
+    }
+
+    /// `fields` are ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor and
+    /// rdevminor; namesize and check are filled in here.
+    fn entry(&mut self, name: &[u8], fields: [u32; 11]) -> std::io::Result<()> {
+        let mut header = String::with_capacity(110);
+        header.push_str("070701");
+        for field in fields.into_iter().chain([name.len() as u32 + 1, 0]) {
+            header.push_str(&format!("{field:08X}"));
+        }
+        self.out.write_all(header.as_bytes())?;
+        self.out.write_all(name)?;
+        self.out.write_all(&[0])?;
+        self.pad(header.len() as u32 + name.len() as u32 + 1)
+    }
+
+    fn pad(&mut self, len: u32) -> std::io::Result<()> {
+        let padding = (4 - len % 4) % 4;
+        self.out.write_all(&[0; 3][..padding as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestArchive};
+
+    /// An entry of a newc archive: its name, mode, nlink and contents.
+    type Entry = (String, u32, u32, Vec<u8>);
+
+    /// The entries of the newc archive `bytes`, checking its headers and padding, up to and
+    /// including its trailer, which must end it.
+    fn parse(bytes: &[u8]) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        let mut at = 0;
+        loop {
+            assert_eq!(at % 4, 0, "header at {at} is not aligned");
+            let header = std::str::from_utf8(&bytes[at..at + 110]).unwrap();
+            assert_eq!(&header[..6], "070701");
+            let field = |i: usize| u32::from_str_radix(&header[6 + i * 8..][..8], 16).unwrap();
+            let (mode, nlink, size, name_len, check) = (
+                field(1),
+                field(4),
+                field(6) as usize,
+                field(11) as usize,
+                field(12),
+            );
+            assert_eq!(check, 0);
+            let name = &bytes[at + 110..at + 110 + name_len];
+            assert_eq!(name.last(), Some(&0), "name is not nul-terminated");
+            let name = String::from_utf8(name[..name_len - 1].to_vec()).unwrap();
+            at = padded(bytes, at + 110 + name_len);
+            let contents = bytes[at..at + size].to_vec();
+            at = padded(bytes, at + size);
+            let trailer = name == "TRAILER!!!";
+            entries.push((name, mode, nlink, contents));
+            if trailer {
+                assert_eq!(at, bytes.len(), "bytes after the trailer");
+                return entries;
+            }
+        }
+    }
+
+    /// Where the next entry starts after one ending at `end`, checking that it is padded with
+    /// zeros.
+    fn padded(bytes: &[u8], end: usize) -> usize {
+        let next = end.next_multiple_of(4);
+        assert!(bytes[end..next].iter().all(|&b| b == 0), "padding at {end}");
+        next
+    }
+
+    fn cpio(archive: &TestArchive, crates: Option<&[&str]>) -> Vec<Entry> {
+        let crates = crates.map(|crates| crates.iter().map(|name| name.to_string()).collect());
+        let mut out = Vec::new();
+        unsquash_tpcii_to_cpio(archive.path(), &mut out, crates, Default::default()).unwrap();
+        parse(&out)
+    }
+
+    #[test]
+    fn writes_newc_entries() {
+        let archive = TestArchive::new(vec![
+            testing::file("index/se/rd/serde", "serde"),
+            testing::symlink("index/se/rd/serde-link", "serde"),
+            testing::dir("salts"),
+        ]);
+        let entry = |name: &str, mode: u32, nlink: u32, contents: &str| {
+            (name.to_string(), mode, nlink, contents.as_bytes().to_vec())
+        };
+        assert_eq!(
+            cpio(&archive, None),
+            [
+                entry(".", S_IFDIR | 0o755, 2, ""),
+                entry("index", S_IFDIR | 0o755, 2, ""),
+                entry("index/se", S_IFDIR | 0o755, 2, ""),
+                entry("index/se/rd", S_IFDIR | 0o755, 2, ""),
+                entry("index/se/rd/serde", S_IFREG | 0o644, 1, "serde"),
+                entry("index/se/rd/serde-link", S_IFLNK | 0o777, 1, "serde"),
+                entry("salts", S_IFDIR | 0o755, 2, ""),
+                entry("TRAILER!!!", 0, 1, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_selected_crates() {
+        let archive = TestArchive::new(vec![
+            testing::file("index/se/rd/serde", "serde index"),
+            testing::file("salts/se/rd/serde", "serde salt"),
+            testing::file("index/an/yh/anyhow", "anyhow index"),
+        ]);
+        let names: Vec<_> = cpio(&archive, Some(&["se/rd/serde"]))
+            .into_iter()
+            .map(|(name, ..)| name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ".",
+                "index",
+                "index/se",
+                "index/se/rd",
+                "index/se/rd/serde",
+                "salts",
+                "salts/se",
+                "salts/se/rd",
+                "salts/se/rd/serde",
+                "TRAILER!!!",
+            ]
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use backhand::InnerNode;
+
+use crate::{platform, report::Tally, Archive, Error, ExtractOptions, ExtractSummary, Filter};
+
+/// Where [`unsquash_to_fs`] writes entries. Paths are relative to the root of the destination,
+/// e.g. `index/se/rd/serde`, and files and symlinks already at a path are replaced.
+pub trait DestFs: Send + Sync {
+    /// Create the directory at `path`, and its missing parents.
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+    /// Create the file at `path` in an existing directory, written through the returned
+    /// [`DestFile`].
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn DestFile + '_>>;
+    /// Create a symlink at `path` pointing to `target`.
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()>;
+    /// Set the mode of the file or directory at `path`.
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()>;
+}
+
+/// A file being written into a [`DestFs`].
+pub trait DestFile: Write + Send {
+    /// Complete the file once all of its contents were written. A file dropped without being
+    /// finished may be left incomplete, or not at all.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Writes into a directory of the local filesystem. Off Unix, symlinks are skipped and modes
+/// only set the read-only attribute.
+#[derive(Debug, Clone)]
+pub struct OsFs {
+    root: PathBuf,
+}
+
+impl OsFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl DestFs for OsFs {
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(self.root.join(path))
+    }
+
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn DestFile + '_>> {
+        let file = File::create(self.root.join(path))?;
+        Ok(Box::new(BufWriter::new(file)))
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        if !platform::SYMLINKS {
+            tracing::debug!(path = %path.display(), "skipping symlink");
+            return Ok(());
+        }
+        let path = self.root.join(path);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        platform::symlink(target, &path)
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        platform::set_mode(&self.root.join(path), mode)
+    }
+}
+
+impl DestFile for BufWriter<File> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.into_inner()
+            .map(drop)
+            .map_err(io::IntoInnerError::into_error)
+    }
+}
+
+/// An entry of a [`MemoryFs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryEntry {
+    Dir { mode: u32 },
+    File { contents: Vec<u8>, mode: u32 },
+    Symlink { target: PathBuf },
+}
+
+/// Keeps what is written into it in memory, e.g. to check what an extraction does without
+/// touching the disk. Clones share their entries.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFs {
+    entries: Arc<Mutex<BTreeMap<PathBuf, MemoryEntry>>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The entry at `path`, relative to the root.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<MemoryEntry> {
+        self.lock().get(path.as_ref()).cloned()
+    }
+
+    /// All the entries, by path relative to the root.
+    pub fn entries(&self) -> BTreeMap<PathBuf, MemoryEntry> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<PathBuf, MemoryEntry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fail unless the parent of `path` is a directory, as creating an entry would on disk.
+    fn check_parent(entries: &BTreeMap<PathBuf, MemoryEntry>, path: &Path) -> io::Result<()> {
+        match path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            None => Ok(()),
+            Some(parent) => match entries.get(parent) {
+                Some(MemoryEntry::Dir { .. }) => Ok(()),
+                Some(_) => Err(io::Error::other(format!(
+                    "'{}' is not a directory",
+                    parent.display()
+                ))),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no directory '{}'", parent.display()),
+                )),
+            },
+        }
+    }
+}
+
+impl DestFs for MemoryFs {
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.lock();
+        let mut dir = PathBuf::new();
+        for component in path.components() {
+            dir.push(component);
+            match entries.get(&dir) {
+                Some(MemoryEntry::Dir { .. }) => {}
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("'{}' is not a directory", dir.display()),
+                    ))
+                }
+                None => {
+                    entries.insert(dir.clone(), MemoryEntry::Dir { mode: 0o755 });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn DestFile + '_>> {
+        Self::check_parent(&self.lock(), path)?;
+        Ok(Box::new(MemoryFile {
+            fs: self,
+            path: path.to_path_buf(),
+            contents: Vec::new(),
+        }))
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let mut entries = self.lock();
+        Self::check_parent(&entries, path)?;
+        let target = target.to_path_buf();
+        entries.insert(path.to_path_buf(), MemoryEntry::Symlink { target });
+        Ok(())
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        match self.lock().get_mut(path) {
+            Some(MemoryEntry::Dir { mode: old } | MemoryEntry::File { mode: old, .. }) => {
+                *old = mode;
+                Ok(())
+            }
+            // like chmod, which follows symlinks, the target would be changed
+            Some(MemoryEntry::Symlink { .. }) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no entry '{}'", path.display()),
+            )),
+        }
+    }
+}
+
+/// A file of a [`MemoryFs`], added to it once finished.
+struct MemoryFile<'a> {
+    fs: &'a MemoryFs,
+    path: PathBuf,
+    contents: Vec<u8>,
+}
+
+impl Write for MemoryFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.contents.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DestFile for MemoryFile<'_> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let Self { fs, path, contents } = *self;
+        let file = MemoryEntry::File {
+            contents,
+            mode: 0o644,
+        };
+        fs.lock().insert(path, file);
+        Ok(())
+    }
+}
+
+/// Extract the entries of `squashfs` selected by `filter` into `fs`, with their modes and
+/// symlink targets, reading the archive with the `kind`, `parsing` and `direct_io` of `options`.
+/// Device nodes, named pipes and sockets are skipped.
+///
+/// This is a portable alternative to [`unsquash_blocking`](crate::unsquash_blocking), which
+/// writes with filesystem-specific mechanisms (reflinks, `O_TMPFILE`, ownership, xattrs) that a
+/// [`DestFs`] does not offer, and supports none of its other options.
+pub fn unsquash_to_fs(
+    squashfs: impl AsRef<Path>,
+    fs: &dyn DestFs,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractSummary, Error> {
+    Archive::open(squashfs, options)?.extract_to_fs(fs, &filter)
+}
+
+impl Archive {
+    /// Like [`unsquash_to_fs`].
+    pub fn extract_to_fs(&self, fs: &dyn DestFs, filter: &Filter) -> Result<ExtractSummary, Error> {
+        let started = Instant::now();
+        let mut summary = extract(&self.expanded()?, fs, filter)?;
+        summary.duration = started.elapsed();
+        Ok(summary)
+    }
+}
+
+fn extract(archive: &Archive, fs: &dyn DestFs, filter: &Filter) -> Result<ExtractSummary> {
+    let tally = Tally::default();
+    // set once their contents are written, which a read-only mode would prevent
+    let mut dir_modes = Vec::new();
+    let nodes = archive
+        .filesystem
+        .files()
+        .filter(|node| filter.matches(&node.fullpath));
+    for node in nodes {
+        let path = node
+            .fullpath
+            .strip_prefix(Component::RootDir)
+            .unwrap_or(&node.fullpath);
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        let mode = u32::from(node.header.permissions & 0o7777);
+        let res = match &node.inner {
+            InnerNode::Dir(_) => {
+                dir_modes.push((path, mode));
+                fs.create_dir(path)
+            }
+            InnerNode::File(file) => create_parent(fs, path).and_then(|()| {
+                let mut out = fs.create_file(path)?;
+                archive
+                    .copy(&file.basic, &mut out)
+                    .map_err(io::Error::other)?;
+                out.finish()?;
+                fs.set_permissions(path, mode)
+            }),
+            InnerNode::Symlink(symlink) => {
+                create_parent(fs, path).and_then(|()| fs.symlink(&symlink.link, path))
+            }
+            _ => {
+                tracing::debug!(path = %node.fullpath.display(), "skipping special file");
+                tally.skipped(1);
+                continue;
+            }
+        };
+        res.with_context(|| format!("extract '{}'", node.fullpath.display()))?;
+        tally.extracted(node);
+    }
+    // children first, so that a read-only parent does not keep them from being changed
+    for (path, mode) in dir_modes.into_iter().rev() {
+        fs.set_permissions(path, mode)
+            .with_context(|| format!("set mode of '{}'", path.display()))?;
+    }
+    Ok(tally.summary())
+}
+
+/// Create the parent directory of `path` unless it is the root, for entries selected without
+/// their parents.
+fn create_parent(fs: &dyn DestFs, path: &Path) -> io::Result<()> {
+    match path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        Some(parent) => fs.create_dir(parent),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    borrow::{Borrow, Cow},
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use backhand::{DataSize, FilesystemReader, InnerNode, Node, SquashfsFileReader};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compression::Kind, digest, metadata::Metadata, platform, recompress, shard, sums, Archive,
+    EntryKind, Error, ExtractOptions, Filter,
+};
+
+/// How an entry differs between two trees, by its absolute path in the archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "diff", rename_all = "snake_case")]
+pub enum EntryDiff {
+    /// Only in the new tree.
+    Added {
+        path: PathBuf,
+        kind: EntryKind,
+    },
+    /// Only in the old tree.
+    Removed {
+        path: PathBuf,
+        kind: EntryKind,
+    },
+    Changed {
+        path: PathBuf,
+        changes: Vec<Change>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    /// The entry was replaced by one of another kind, which makes any other change moot.
+    Kind {
+        from: EntryKind,
+        to: EntryKind,
+    },
+    Mode {
+        from: u32,
+        to: u32,
+    },
+    Size {
+        from: u64,
+        to: u64,
+    },
+    /// Same size, different contents, or a different symlink target.
+    Content,
+    /// Only compared between archives.
+    Owner {
+        from: (u32, u32),
+        to: (u32, u32),
+    },
+    /// Only compared between archives.
+    Mtime {
+        from: u32,
+        to: u32,
+    },
+}
+
+/// The outcome of [`diff`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffReport {
+    pub added: u64,
+    pub removed: u64,
+    pub changed: u64,
+    /// Sorted by path.
+    pub entries: Vec<EntryDiff>,
+}
+
+impl DiffReport {
+    fn new(entries: Vec<EntryDiff>) -> Self {
+        let mut report = Self::default();
+        for entry in &entries {
+            match entry {
+                EntryDiff::Added { .. } => report.added += 1,
+                EntryDiff::Removed { .. } => report.removed += 1,
+                EntryDiff::Changed { .. } => report.changed += 1,
+            }
+        }
+        report.entries = entries;
+        report
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl EntryDiff {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Added { path, .. } | Self::Removed { path, .. } | Self::Changed { path, .. } => {
+                path
+            }
+        }
+    }
+}
+
+/// The entries selected by `filter` that differ between the archives `old` and `new`, sorted by
+/// path. Both are read with the `kind`, `parsing` and `direct_io` of `options`.
+pub fn diff_archives(
+    old: impl AsRef<Path>,
+    new: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<Vec<EntryDiff>, Error> {
+    let old = Archive::open(old, options.clone())?;
+    let new = Archive::open(new, options)?;
+    Ok(diff(&old, &new, &filter, true)?.entries)
+}
+
+/// The entries selected by `filter` that were added, removed or changed from `old` to `new`,
+/// e.g. between two published snapshots. With `hash_contents`, files of the same size are
+/// compared by the SHA-256 of their contents; otherwise only by the sizes of their compressed
+/// blocks, which misses changes that compress to the same sizes.
+pub fn diff(
+    old: &Archive,
+    new: &Archive,
+    filter: &Filter,
+    hash_contents: bool,
+) -> Result<DiffReport, Error> {
+    let (old, new) = (old.expanded()?, new.expanded()?);
+    let old_entries = archive_entries(&old.filesystem, filter);
+    let new_entries = archive_entries(&new.filesystem, filter);
+    let diffs = diff_entries(
+        &old_entries,
+        &new_entries,
+        |old_node, new_node| match hash_contents {
+            true => Ok(archive_sha256(&old.filesystem, old_node)?
+                == archive_sha256(&new.filesystem, new_node)?),
+            false => Ok(block_sizes(old_node) == block_sizes(new_node)),
+        },
+    )?;
+    Ok(DiffReport::new(diffs))
+}
+
+/// The entries selected by `filter` that differ between `archive` and the tree extracted from it
+/// into `dir` with `options`, sorted by path. Modes are compared to those the
+/// [`PermissionPolicy`](crate::PermissionPolicy) of `options` gives, and `dir` entries not from
+/// the archive are [added](EntryDiff::Added), apart from the manifests written at its root.
+pub fn diff_archive_dir(
+    archive: impl AsRef<Path>,
+    dir: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<Vec<EntryDiff>, Error> {
+    let dir = dir.as_ref();
+    if options.recompress.is_some() {
+        return Err(
+            anyhow::anyhow!("cannot diff recompressed destination '{}'", dir.display()).into(),
+        );
+    }
+    let filesystem = open(archive.as_ref(), &options)?;
+    let metadata = Metadata::new(&options);
+    let nodes = archive_entries(&filesystem, &filter);
+    let old_entries = nodes
+        .iter()
+        .map(|(path, (node, stat))| {
+            let stat = Stat {
+                mode: stat.mode.map(|_| metadata.entry(node).mode()),
+                ..stat.clone()
+            };
+            (*path, (*node, stat))
+        })
+        .collect();
+
+    let mut new_entries = BTreeMap::new();
+    for (path, (node, _)) in &nodes {
+        let Some(dest_path) = shard::dest_path(
+            dir,
+            node,
+            options.shard_levels,
+            None,
+            options.subtree.as_deref(),
+        ) else {
+            continue;
+        };
+        if let Some(stat) = dir_stat(&dest_path)? {
+            new_entries.insert(Cow::Borrowed(*path), (dest_path, stat));
+        }
+    }
+    // sharded destinations have no layout to find entries that are not from the archive in
+    if options.shard_levels.is_none() && dir.is_dir() {
+        let mut extra = Vec::new();
+        walk(dir, dir, &mut extra)?;
+        for (relative, dest_path) in extra {
+            let path = Path::new("/").join(&relative);
+            let manifest = [
+                sums::SHA256SUMS,
+                sums::SHA512SUMS,
+                sums::B3SUMS,
+                shard::SHARD_MANIFEST,
+                recompress::RECOMPRESS_MANIFEST,
+            ]
+            .iter()
+            .any(|name| relative == Path::new(name));
+            if manifest || new_entries.contains_key(path.as_path()) || !filter.matches(&path) {
+                continue;
+            }
+            if let Some(stat) = dir_stat(&dest_path)? {
+                new_entries.insert(Cow::Owned(path), (dest_path, stat));
+            }
+        }
+    }
+
+    let diffs = diff_entries(&old_entries, &new_entries, |node, dest_path| {
+        Ok(archive_sha256(&filesystem, node)? == digest::sha256_file(dest_path)?)
+    })?;
+    Ok(diffs)
+}
+
+/// What is compared of an entry.
+#[derive(Debug, Clone)]
+struct Stat {
+    kind: EntryKind,
+    size: u64,
+    /// Left out for symlinks, whose mode is meaningless on Linux.
+    mode: Option<u32>,
+    target: Option<PathBuf>,
+    /// Uid and gid, left out for extracted trees.
+    owner: Option<(u32, u32)>,
+    /// Left out for extracted trees.
+    mtime: Option<u32>,
+}
+
+/// Compare two sets of entries keyed by path, calling `same_contents` on regular files of the same
+/// size in both. Paths are only copied for the entries that differ.
+fn diff_entries<K, L, A, B>(
+    old: &BTreeMap<K, (A, Stat)>,
+    new: &BTreeMap<L, (B, Stat)>,
+    same_contents: impl Fn(&A, &B) -> Result<bool> + Sync,
+) -> Result<Vec<EntryDiff>>
+where
+    K: Borrow<Path> + Ord + Sync,
+    L: Borrow<Path> + Ord + Sync,
+    A: Sync,
+    B: Sync,
+{
+    let paths: BTreeSet<&Path> = old
+        .keys()
+        .map(Borrow::borrow)
+        .chain(new.keys().map(Borrow::borrow))
+        .collect();
+    let paths: Vec<_> = paths.into_iter().collect();
+    paths
+        .into_par_iter()
+        .map(|path| {
+            let diff = match (old.get(path), new.get(path)) {
+                (Some((_, stat)), None) => Some(EntryDiff::Removed {
+                    path: path.to_path_buf(),
+                    kind: stat.kind,
+                }),
+                (None, Some((_, stat))) => Some(EntryDiff::Added {
+                    path: path.to_path_buf(),
+                    kind: stat.kind,
+                }),
+                (Some((old, old_stat)), Some((new, new_stat))) => {
+                    let changes = changes(old_stat, new_stat, || same_contents(old, new))
+                        .with_context(|| format!("compare '{}'", path.display()))?;
+                    (!changes.is_empty()).then(|| EntryDiff::Changed {
+                        path: path.to_path_buf(),
+                        changes,
+                    })
+                }
+                (None, None) => unreachable!("path comes from either side"),
+            };
+            Ok(diff)
+        })
+        .filter_map(Result::transpose)
+        .collect()
+}
+
+fn changes(
+    old: &Stat,
+    new: &Stat,
+    same_contents: impl FnOnce() -> Result<bool>,
+) -> Result<Vec<Change>> {
+    if old.kind != new.kind {
+        return Ok(vec![Change::Kind {
+            from: old.kind,
+            to: new.kind,
+        }]);
+    }
+    let mut changes = Vec::new();
+    if let (Some(from), Some(to)) = (old.mode, new.mode) {
+        if from != to {
+            changes.push(Change::Mode { from, to });
+        }
+    }
+    if let (Some(from), Some(to)) = (old.owner, new.owner) {
+        if from != to {
+            changes.push(Change::Owner { from, to });
+        }
+    }
+    if let (Some(from), Some(to)) = (old.mtime, new.mtime) {
+        if from != to {
+            changes.push(Change::Mtime { from, to });
+        }
+    }
+    if old.size != new.size {
+        changes.push(Change::Size {
+            from: old.size,
+            to: new.size,
+        });
+    } else if old.target != new.target || (old.kind == EntryKind::File && !same_contents()?) {
+        changes.push(Change::Content);
+    }
+    Ok(changes)
+}
+
+fn open(squashfs: &Path, options: &ExtractOptions) -> Result<FilesystemReader<'static>> {
+    let source = crate::open_source(squashfs, options)?;
+    let kind = options.io.kind.as_ref().map(Kind::from_kind);
+    crate::open_filesystem(Arc::new(source), kind, options.policy.parsing)
+}
+
+fn archive_entries<'a>(
+    filesystem: &'a FilesystemReader<'static>,
+    filter: &Filter,
+) -> BTreeMap<&'a Path, (&'a Node<SquashfsFileReader>, Stat)> {
+    filesystem
+        .files()
+        .filter(|node| filter.matches(&node.fullpath))
+        .map(|node| {
+            let header = &node.header;
+            let (size, target) = match &node.inner {
+                InnerNode::File(file) => (file.basic.file_size.into(), None),
+                InnerNode::Symlink(symlink) => (
+                    symlink.link.as_os_str().len() as u64,
+                    Some(symlink.link.clone()),
+                ),
+                _ => (0, None),
+            };
+            let kind = EntryKind::of(&node.inner);
+            let stat = Stat {
+                kind,
+                size,
+                mode: (kind != EntryKind::Symlink)
+                    .then_some(u32::from(header.permissions & 0o7777)),
+                target,
+                owner: Some((header.uid, header.gid)),
+                mtime: Some(header.mtime),
+            };
+            (node.fullpath.as_path(), (node, stat))
+        })
+        .collect()
+}
+
+fn archive_sha256(
+    filesystem: &FilesystemReader<'_>,
+    node: &Node<SquashfsFileReader>,
+) -> Result<String> {
+    let InnerNode::File(file) = &node.inner else {
+        anyhow::bail!("'{}' is not a file", node.fullpath.display());
+    };
+    digest::sha256_reader(filesystem.file(&file.basic).reader())
+        .with_context(|| format!("hash '{}'", node.fullpath.display()))
+}
+
+fn block_sizes(node: &Node<SquashfsFileReader>) -> Option<&[DataSize]> {
+    match &node.inner {
+        InnerNode::File(file) => Some(&file.basic.block_sizes),
+        _ => None,
+    }
+}
+
+fn dir_stat(path: &Path) -> Result<Option<Stat>> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("stat '{}'", path.display())),
+    };
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_file() {
+        EntryKind::File
+    } else if file_type.is_dir() {
+        EntryKind::Dir
+    } else if file_type.is_symlink() {
+        EntryKind::Symlink
+    } else {
+        special_kind(file_type)
+    };
+    let target = match kind {
+        EntryKind::Symlink => Some(
+            std::fs::read_link(path)
+                .with_context(|| format!("read symlink '{}'", path.display()))?,
+        ),
+        _ => None,
+    };
+    Ok(Some(Stat {
+        kind,
+        size: match kind {
+            EntryKind::File => metadata.len(),
+            EntryKind::Symlink => target.as_ref().map_or(0, |t| t.as_os_str().len() as u64),
+            _ => 0,
+        },
+        mode: (kind != EntryKind::Symlink).then_some(platform::mode(&metadata)),
+        target,
+        owner: None,
+        mtime: None,
+    }))
+}
+
+#[cfg(unix)]
+fn special_kind(file_type: std::fs::FileType) -> EntryKind {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_char_device() {
+        EntryKind::CharacterDevice
+    } else if file_type.is_block_device() {
+        EntryKind::BlockDevice
+    } else if file_type.is_fifo() {
+        EntryKind::NamedPipe
+    } else {
+        EntryKind::Socket
+    }
+}
+
+/// There are no special files off Unix, so nothing can be one.
+#[cfg(not(unix))]
+fn special_kind(_file_type: std::fs::FileType) -> EntryKind {
+    EntryKind::Socket
+}
+
+/// Collect every path under `path`, relative to `root`, not following symlinks.
+fn walk(root: &Path, path: &Path, out: &mut Vec<(PathBuf, PathBuf)>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(path).with_context(|| format!("read dir '{}'", path.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("read dir '{}'", path.display()))?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+        let is_dir = entry
+            .file_type()
+            .with_context(|| format!("stat '{}'", path.display()))?
+            .is_dir();
+        out.push((relative, path.clone()));
+        if is_dir {
+            walk(root, &path, out)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestArchive};
+
+    fn archives() -> (TestArchive, TestArchive) {
+        let old = TestArchive::new(vec![
+            testing::file("same", "kept"),
+            testing::file("edited", "abc"),
+            testing::file("grown", "a"),
+            testing::dir("gone"),
+            testing::symlink("link", "t1"),
+        ]);
+        let new = TestArchive::new(vec![
+            testing::file("same", "kept"),
+            testing::file("edited", "xyz"),
+            testing::file("grown", "abcd"),
+            testing::file("fresh", "new"),
+            testing::symlink("link", "t2"),
+        ]);
+        (old, new)
+    }
+
+    #[test]
+    fn diffs_archives() {
+        let (old, new) = archives();
+        let diffs = diff_archives(
+            old.path(),
+            new.path(),
+            Filter::All,
+            ExtractOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            diffs,
+            [
+                EntryDiff::Changed {
+                    path: "/edited".into(),
+                    changes: vec![Change::Content],
+                },
+                EntryDiff::Added {
+                    path: "/fresh".into(),
+                    kind: EntryKind::File,
+                },
+                EntryDiff::Removed {
+                    path: "/gone".into(),
+                    kind: EntryKind::Dir,
+                },
+                EntryDiff::Changed {
+                    path: "/grown".into(),
+                    changes: vec![Change::Size { from: 1, to: 4 }],
+                },
+                EntryDiff::Changed {
+                    path: "/link".into(),
+                    changes: vec![Change::Content],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_and_filters_diffs() {
+        let (old, new) = archives();
+        let old = Archive::open(old.path(), ExtractOptions::default()).unwrap();
+        let new = Archive::open(new.path(), ExtractOptions::default()).unwrap();
+        let report = diff(&old, &new, &Filter::All, true).unwrap();
+        assert_eq!((report.added, report.removed, report.changed), (1, 1, 3));
+
+        let filter = Filter::Prefix("/grown".into());
+        let report = diff(&old, &new, &filter, true).unwrap();
+        assert_eq!(
+            report
+                .entries
+                .iter()
+                .map(EntryDiff::path)
+                .collect::<Vec<_>>(),
+            [Path::new("/grown")]
+        );
+        assert!(diff(&old, &old, &Filter::All, true).unwrap().is_empty());
+    }
+}
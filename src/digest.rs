@@ -0,0 +1,222 @@
+use std::{
+    io::{Read, Write},
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256, Sha512};
+use tokio::io::AsyncWrite;
+
+use crate::reapi::Digest;
+
+/// The hash used to verify extracted files, in
+/// [`ObservabilityOptions::hash`](crate::ObservabilityOptions::hash).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// Name of the manifest of the extracted files hashed with it, as its `*sum -c` expects.
+    pub fn sums_file(&self) -> &'static str {
+        match self {
+            Self::Sha256 => crate::sums::SHA256SUMS,
+            Self::Sha512 => crate::sums::SHA512SUMS,
+            Self::Blake3 => crate::sums::B3SUMS,
+        }
+    }
+
+    /// Hex-encoded hash of everything `reader` yields.
+    pub(crate) fn hash_reader(&self, mut reader: impl Read) -> std::io::Result<String> {
+        let mut hasher = AnyHasher::new(*self);
+        std::io::copy(&mut reader, &mut hasher)?;
+        Ok(hasher.finish())
+    }
+
+    pub(crate) fn hash_file(&self, path: &Path) -> Result<String> {
+        use anyhow::Context as _;
+
+        let file =
+            std::fs::File::open(path).with_context(|| format!("open '{}'", path.display()))?;
+        self.hash_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("hash '{}'", path.display()))
+    }
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            "blake3" => Ok(Self::Blake3),
+            _ => anyhow::bail!("unknown hash algorithm '{s}', expected sha256, sha512 or blake3"),
+        }
+    }
+}
+
+/// A hasher of any [`HashAlgorithm`], fed by writing to it.
+pub(crate) enum AnyHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl AnyHasher {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Self::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    /// Hex-encoded.
+    pub(crate) fn finish(self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+impl Write for AnyHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Sha256(hasher) => hasher.update(buf),
+            Self::Sha512(hasher) => hasher.update(buf),
+            Self::Blake3(hasher) => drop(hasher.update(buf)),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hex-encoded SHA-256 of everything `reader` yields.
+pub(crate) fn sha256_reader(mut reader: impl Read) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub(crate) fn sha256_file(path: &Path) -> Result<String> {
+    use anyhow::Context as _;
+
+    let file = std::fs::File::open(path).with_context(|| format!("open '{}'", path.display()))?;
+    sha256_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("hash '{}'", path.display()))
+}
+
+/// SHA-256 and length of the bytes written through a [`Hashing`] writer.
+#[derive(Default)]
+pub(crate) struct Hasher {
+    sha256: Sha256,
+    size: u64,
+}
+
+impl Hasher {
+    pub(crate) fn finish(self) -> Digest {
+        Digest {
+            hash: format!("{:x}", self.sha256.finalize()),
+            size_bytes: self.size,
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.sha256.update(buf);
+        self.size += buf.len() as u64;
+    }
+}
+
+/// Feeds what is written through it to a [`Hasher`], if given.
+pub(crate) struct Hashing<'a, W> {
+    inner: W,
+    hasher: Option<&'a mut Hasher>,
+}
+
+impl<'a, W> Hashing<'a, W> {
+    pub(crate) fn new(inner: W, hasher: Option<&'a mut Hasher>) -> Self {
+        Self { inner, hasher }
+    }
+}
+
+impl<W: Write> Write for Hashing<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Hashing<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(hasher)) = (&res, &mut this.hasher) {
+            hasher.update(&buf[..*n]);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_with_each_algorithm() {
+        let data = b"backhand";
+        assert_eq!(
+            HashAlgorithm::Sha256.hash_reader(&data[..]).unwrap(),
+            format!("{:x}", Sha256::digest(data))
+        );
+        assert_eq!(
+            HashAlgorithm::Blake3.hash_reader(&data[..]).unwrap(),
+            blake3::hash(data).to_hex().as_str()
+        );
+        assert_eq!(
+            "blake3".parse::<HashAlgorithm>().unwrap(),
+            HashAlgorithm::Blake3
+        );
+        assert_eq!(HashAlgorithm::Blake3.sums_file(), "B3SUMS");
+    }
+}
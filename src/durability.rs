@@ -0,0 +1,196 @@
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use backhand::{Node, SquashfsFileReader};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{recompress::Recompress, shard};
+
+/// What is flushed to stable storage before an extraction reports success, for callers treating
+/// its completion as a commit point that must survive a power failure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Nothing: the kernel writes the data back when it sees fit.
+    #[default]
+    None,
+    /// Every extracted file is fsynced before it is moved into place.
+    FsyncFiles,
+    /// Like [`FsyncFiles`](Self::FsyncFiles), and the directories holding the extracted entries
+    /// are fsynced once every entry was extracted, so that the entries themselves persist.
+    /// Directories cannot be synced off Unix.
+    FsyncFilesAndDirs,
+}
+
+impl Durability {
+    /// Fsync the extracted `file` at `path` if asked to.
+    pub(crate) fn sync_file(self, file: &File, path: &Path) -> Result<()> {
+        if self == Self::None {
+            return Ok(());
+        }
+        file.sync_all()
+            .with_context(|| format!("fsync '{}'", path.display()))?;
+        synced(path);
+        Ok(())
+    }
+
+    /// The directories to fsync once `nodes` were extracted into `dest`: `dest`, its parent, and
+    /// the parent of every entry.
+    pub(crate) fn dirs(
+        self,
+        dest: &Path,
+        nodes: &[&Node<SquashfsFileReader>],
+        shard_levels: Option<u8>,
+        recompress: Option<Recompress>,
+        subtree: Option<&Path>,
+    ) -> Vec<PathBuf> {
+        if self != Self::FsyncFilesAndDirs || !cfg!(unix) {
+            return Vec::new();
+        }
+        let mut dirs: BTreeSet<_> = nodes
+            .iter()
+            .filter_map(|node| shard::dest_path(dest, node, shard_levels, recompress, subtree))
+            .filter_map(|dest_path| dest_path.parent().map(Path::to_path_buf))
+            .collect();
+        dirs.insert(dest.to_path_buf());
+        dirs.extend(
+            dest.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .map(Path::to_path_buf),
+        );
+        dirs.into_iter().collect()
+    }
+}
+
+/// Fsync `dirs`, as returned by [`Durability::dirs`].
+pub(crate) fn sync_dirs(dirs: Vec<PathBuf>) -> Result<()> {
+    dirs.par_iter().try_for_each(|dir| {
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("fsync dir '{}'", dir.display()))?;
+        synced(dir);
+        Ok(())
+    })
+}
+
+/// Every path fsynced so far, in order, for the tests to check what extractions synced.
+#[cfg(test)]
+static SYNCED: std::sync::Mutex<Vec<PathBuf>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(test)]
+fn synced(path: &Path) {
+    SYNCED.lock().unwrap().push(path.to_path_buf());
+}
+
+#[cfg(not(test))]
+fn synced(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use backhand::{InnerNode, NodeHeader, SquashfsDir, SquashfsSymlink};
+
+    use super::*;
+    use crate::{
+        testing::{self, TestArchive},
+        DurabilityOptions, ExtractOptions, Filter,
+    };
+
+    fn node(path: &str, dir: bool) -> Node<SquashfsFileReader> {
+        Node {
+            fullpath: PathBuf::from(path),
+            header: NodeHeader::new(0o755, 0, 0, 0),
+            inner: match dir {
+                true => InnerNode::Dir(SquashfsDir::default()),
+                false => InnerNode::Symlink(SquashfsSymlink {
+                    link: PathBuf::from("target"),
+                }),
+            },
+        }
+    }
+
+    #[test]
+    fn lists_dirs_to_sync() {
+        let nodes = [node("/a", true), node("/a/b", false), node("/c", false)];
+        let nodes: Vec<_> = nodes.iter().collect();
+        let dirs = |durability: Durability| {
+            durability.dirs(Path::new("/dest/x"), &nodes, None, None, None)
+        };
+        assert!(dirs(Durability::None).is_empty());
+        assert!(dirs(Durability::FsyncFiles).is_empty());
+        let expected: Vec<PathBuf> = match cfg!(unix) {
+            true => ["/dest", "/dest/x", "/dest/x/a"].map(PathBuf::from).into(),
+            false => Vec::new(),
+        };
+        assert_eq!(dirs(Durability::FsyncFilesAndDirs), expected);
+    }
+
+    /// What was fsynced in `dest`, and `dest` and its parent, in the order it was.
+    fn synced_in(dest: &Path) -> Vec<PathBuf> {
+        let synced = SYNCED.lock().unwrap();
+        synced
+            .iter()
+            .filter(|path| path.starts_with(dest) || Some(path.as_path()) == dest.parent())
+            .cloned()
+            .collect()
+    }
+
+    fn sorted(paths: &[PathBuf]) -> Vec<PathBuf> {
+        let mut paths = paths.to_vec();
+        paths.sort();
+        paths
+    }
+
+    #[tokio::test]
+    async fn extracts_durably() {
+        let archive = TestArchive::new(vec![
+            testing::file("a/b", "b"),
+            testing::symlink("a/c", "b"),
+            testing::file("d", "d"),
+        ]);
+        for durability in [
+            Durability::None,
+            Durability::FsyncFiles,
+            Durability::FsyncFilesAndDirs,
+        ] {
+            let options = || ExtractOptions {
+                durability: DurabilityOptions {
+                    fsync: durability,
+                    ..DurabilityOptions::default()
+                },
+                ..ExtractOptions::default()
+            };
+            let blocking = archive.scratch(&format!("blocking-{durability:?}"));
+            crate::unsquash_blocking(archive.path(), &blocking, Filter::All, options()).unwrap();
+            let nonblocking = archive.scratch(&format!("async-{durability:?}"));
+            crate::unsquash_async(archive.path(), &nonblocking, Filter::All, options())
+                .await
+                .unwrap();
+            for dest in [blocking, nonblocking] {
+                assert_eq!(std::fs::read(dest.join("a/c")).unwrap(), b"b");
+                let synced = synced_in(&dest);
+                let files = [dest.join("a/b"), dest.join("d")];
+                let dirs: Vec<_> = match (durability, cfg!(unix)) {
+                    (Durability::FsyncFilesAndDirs, true) => {
+                        let parent = dest.parent().unwrap().to_path_buf();
+                        vec![parent, dest.clone(), dest.join("a")]
+                    }
+                    _ => Vec::new(),
+                };
+                if durability == Durability::None {
+                    assert!(synced.is_empty(), "{synced:?}");
+                    continue;
+                }
+                // every file, but not the symlink, and then the directories holding them
+                assert_eq!(synced.len(), files.len() + dirs.len(), "{synced:?}");
+                let (synced_files, synced_dirs) = synced.split_at(files.len());
+                assert_eq!(sorted(synced_files), files);
+                assert_eq!(sorted(synced_dirs), dirs);
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::{collections::HashSet, path::Path, process::Command};
+
+use anyhow::{Context, Result};
+
+use crate::{unsquash_tpcii_blocking_with_options, ExtractOptions, ExtractReport};
+
+/// Extract the (optionally filtered) archive contents into a read-only EROFS image at `image`.
+///
+/// The entries are staged in a directory next to `image` and packed with `mkfs.erofs`, which must
+/// be on `PATH` (it ships with erofs-utils).
+pub fn unsquash_tpcii_to_erofs(
+    squashfs: impl AsRef<Path>,
+    image: impl AsRef<Path>,
+    crates_filter: Option<HashSet<String>>,
+    options: ExtractOptions,
+) -> Result<ExtractReport> {
+    let image = image.as_ref();
+    let mut staging = image.as_os_str().to_owned();
+    staging.push(format!(".staging-{}", std::process::id()));
+    let staging = Path::new(&staging);
+
+    let res = unsquash_tpcii_blocking_with_options(squashfs, staging, crates_filter, options)
+        .map_err(anyhow::Error::from)
+        .and_then(|report| {
+            std::fs::create_dir_all(staging)
+                .with_context(|| format!("create staging dir '{}'", staging.display()))?;
+            mkfs_erofs(image, staging)?;
+            Ok(report)
+        });
+    let cleanup = match staging.exists() {
+        true => std::fs::remove_dir_all(staging)
+            .with_context(|| format!("remove staging dir '{}'", staging.display())),
+        false => Ok(()),
+    };
+    res.and_then(|report| cleanup.map(|()| report))
+}
+
+fn mkfs_erofs(image: &Path, staging: &Path) -> Result<()> {
+    let status = Command::new("mkfs.erofs")
+        .arg("--quiet")
+        .arg(image)
+        .arg(staging)
+        .status()
+        .context("run mkfs.erofs (is erofs-utils installed?)")?;
+    anyhow::ensure!(
+        status.success(),
+        "mkfs.erofs '{}' failed: {status}",
+        image.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestArchive};
+
+    /// Offset of the EROFS superblock in the image.
+    const SUPERBLOCK: usize = 1024;
+    const MAGIC: u32 = 0xe0f5_e1e2;
+    const S_IFMT: u16 = 0o170000;
+    const S_IFDIR: u16 = 0o040000;
+
+    fn installed(tool: &str) -> bool {
+        Command::new(tool).arg("--help").output().is_ok()
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn packs_extracted_entries() {
+        if !installed("mkfs.erofs") {
+            return;
+        }
+        let archive = TestArchive::new(vec![
+            testing::file("index/se/rd/serde", "serde index"),
+            testing::file("salts/se/rd/serde", "serde salt"),
+            testing::file("index/an/yh/anyhow", "anyhow index"),
+        ]);
+        let image = archive.scratch("image.erofs");
+        let crates = HashSet::from([String::from("se/rd/serde")]);
+        unsquash_tpcii_to_erofs(archive.path(), &image, Some(crates), Default::default()).unwrap();
+
+        let bytes = std::fs::read(&image).unwrap();
+        let superblock = &bytes[SUPERBLOCK..SUPERBLOCK + 128];
+        assert_eq!(u32_at(superblock, 0), MAGIC);
+        let block_bits = superblock[12];
+        assert!((9..=16).contains(&block_bits), "block size 2^{block_bits}");
+        // the root, index, salts, their se and rd directories, and the two files of serde
+        assert_eq!(
+            u64::from_le_bytes(superblock[16..24].try_into().unwrap()),
+            9
+        );
+        let (root_nid, meta_block) = (u16_at(superblock, 14), u32_at(superblock, 40));
+        let root = (meta_block as usize) << block_bits | usize::from(root_nid) * 32;
+        // compact and extended inodes both start with their format and have their mode at 4
+        assert_eq!(u16_at(&bytes, root + 4) & S_IFMT, S_IFDIR);
+
+        let mut staging = image.as_os_str().to_owned();
+        staging.push(format!(".staging-{}", std::process::id()));
+        assert!(!Path::new(&staging).exists());
+
+        if !installed("fsck.erofs") {
+            return;
+        }
+        let extracted = archive.scratch("extracted");
+        let status = Command::new("fsck.erofs")
+            .arg(format!("--extract={}", extracted.display()))
+            .arg(&image)
+            .status()
+            .unwrap();
+        assert!(status.success(), "fsck.erofs failed: {status}");
+        assert_eq!(
+            std::fs::read(extracted.join("index/se/rd/serde")).unwrap(),
+            b"serde index"
+        );
+        assert_eq!(
+            std::fs::read(extracted.join("salts/se/rd/serde")).unwrap(),
+            b"serde salt"
+        );
+        assert!(!extracted.join("index/an").exists());
+    }
+}
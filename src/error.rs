@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+use backhand::{Node, SquashfsFileReader};
+
+use crate::{EntryKind, FormatError};
+
+/// Why an extraction, listing or read failed. Errors without a more specific variant are kept as
+/// [`Error::Other`], with their full context.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("specified squashfs archive does not exist: '{}'", .0.display())]
+    ArchiveNotFound(PathBuf),
+    #[error(transparent)]
+    Format(#[from] FormatError),
+    /// A device node, named pipe or socket in strict [`Parsing`](crate::Parsing).
+    #[error("cannot extract {kind} '{}'", path.display())]
+    UnsupportedNode { path: PathBuf, kind: EntryKind },
+    /// The tpcii archive has no index entry for the crate, see
+    /// [`extract_crate_blocking`](crate::tpcii::extract_crate_blocking).
+    #[error("crate '{0}' not found in tpcii archive")]
+    CrateNotFound(String),
+    /// Entries of the [`Filter`](crate::Filter) matched nothing in the archive, see
+    /// [`PolicyOptions::strict_filter`](crate::PolicyOptions::strict_filter).
+    #[error("filter entries matched nothing in the archive: {}", .0.join(", "))]
+    UnmatchedFilter(Vec<String>),
+    #[error("destination quota of {max} bytes exceeded ({needed} bytes would be written)")]
+    QuotaExceeded { max: u64, needed: u64 },
+    /// Something is already at the destination of an entry, see
+    /// [`OverwritePolicy::Error`](crate::OverwritePolicy::Error).
+    #[error("'{}' already exists", .0.display())]
+    DestinationExists(PathBuf),
+    #[error("extraction cancelled")]
+    Cancelled,
+    /// The archive was modified in place while it was being read, so what was read from it
+    /// cannot be trusted.
+    #[error("squashfs archive '{0}' changed while it was being read")]
+    ArchiveChanged(String),
+    /// The entry at `path` in the archive would be written, or point as a symlink, outside of
+    /// the destination, through `through`; see [`EscapePolicy`](crate::EscapePolicy).
+    #[error("'{}' escapes the destination through '{}'", path.display(), through.display())]
+    Escape { path: PathBuf, through: PathBuf },
+    /// Extracting the entry at `path` in the archive failed.
+    #[error("failed to extract {kind} '{}'", path.display())]
+    Entry {
+        path: PathBuf,
+        kind: EntryKind,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl Error {
+    /// The I/O error that caused this one, if any.
+    pub fn io_error(&self) -> Option<&std::io::Error> {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(self);
+        while let Some(error) = source {
+            if let Some(io) = error.downcast_ref::<std::io::Error>() {
+                return Some(io);
+            }
+            source = error.source();
+        }
+        None
+    }
+
+    pub(crate) fn entry(node: &Node<SquashfsFileReader>, source: anyhow::Error) -> Self {
+        Self::Entry {
+            path: node.fullpath.clone(),
+            kind: EntryKind::of(&node.inner),
+            source,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        // errors raised as an `Error` keep their variant, wherever they were raised
+        let error = match error.downcast::<Self>() {
+            // e.g. a cancellation noticed while extracting an entry
+            Ok(Self::Entry { source, .. }) if source.is::<Self>() => return Self::from(source),
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        match error.downcast::<FormatError>() {
+            Ok(error) => Self::Format(error),
+            Err(error) => Self::Other(error),
+        }
+    }
+}
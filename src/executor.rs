@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use anyhow::{Context, Result};
+
+use crate::profile;
+
+/// Runs the per-entry work of an extraction.
+#[derive(Clone, Copy)]
+pub(crate) enum Executor<'a> {
+    /// The global rayon pool.
+    Rayon,
+    /// A rayon pool of this many threads, just for this extraction.
+    Threads(usize),
+    /// Threads of this extraction, each holding a slot of a [`FairShare`] shared with other
+    /// extractions while it works on an entry.
+    Fair(&'a FairShare, u64),
+    /// The calling thread, one entry after the other in order, for an
+    /// [`Ordering`](crate::Ordering) other than `Unordered`.
+    Sequential,
+}
+
+impl Executor<'_> {
+    /// How many entries run at once.
+    pub(crate) fn concurrency(self) -> usize {
+        match self {
+            Self::Rayon => rayon::current_num_threads(),
+            Self::Threads(threads) => threads.max(1),
+            Self::Fair(share, _) => share.slots,
+            Self::Sequential => 1,
+        }
+    }
+
+    /// Apply `f` to every item until it fails.
+    pub(crate) fn try_for_each<T: Sync>(
+        self,
+        items: &[T],
+        f: impl Fn(&T) -> Result<()> + Send + Sync,
+    ) -> Result<()> {
+        use rayon::prelude::*;
+
+        // the threads below work on behalf of the calling one
+        let profiler = profile::current();
+        let f = |item: &T| profile::scoped(profiler.as_ref(), || f(item));
+        let (share, job) = match self {
+            Self::Rayon => return items.par_iter().try_for_each(f),
+            Self::Threads(threads) => {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads.max(1))
+                    .thread_name(|i| format!("extract-{i}"))
+                    .build()
+                    .context("build extraction thread pool")?;
+                return pool.install(|| items.par_iter().try_for_each(f));
+            }
+            Self::Fair(share, job) => (share, job),
+            Self::Sequential => return items.iter().try_for_each(f),
+        };
+        let next = AtomicUsize::new(0);
+        let failed = Mutex::new(None);
+        std::thread::scope(|scope| {
+            for _ in 0..share.slots.min(items.len()) {
+                scope.spawn(|| loop {
+                    let _slot = share.acquire(job);
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+                    if let Err(e) = f(item) {
+                        failed
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .get_or_insert(e);
+                        // stop the other threads too
+                        next.store(items.len(), Ordering::Relaxed);
+                        break;
+                    }
+                });
+            }
+        });
+        match failed.into_inner().unwrap_or_else(PoisonError::into_inner) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A fixed number of slots for entry-level work, granted round-robin across jobs: a free slot
+/// goes to the waiting job that holds the fewest, so a job with many entries cannot starve one
+/// with few.
+pub(crate) struct FairShare {
+    slots: usize,
+    state: Mutex<FairState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct FairState {
+    held: usize,
+    /// Slots held by each job with a slot or a waiter.
+    per_job: HashMap<u64, usize>,
+    /// Waiting (job, ticket) pairs, oldest first.
+    waiting: Vec<(u64, u64)>,
+    next_ticket: u64,
+}
+
+pub(crate) struct Slot<'a> {
+    share: &'a FairShare,
+    job: u64,
+}
+
+impl FairShare {
+    pub(crate) fn new(slots: usize) -> Self {
+        Self {
+            slots: slots.max(1),
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FairState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn acquire(&self, job: u64) -> Slot<'_> {
+        let mut state = self.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push((job, ticket));
+        state.per_job.entry(job).or_default();
+        loop {
+            let next = state
+                .waiting
+                .iter()
+                .min_by_key(|(job, ticket)| (state.per_job[job], *ticket))
+                .map(|&(_, ticket)| ticket);
+            if state.held < self.slots && next == Some(ticket) {
+                state.waiting.retain(|&(_, t)| t != ticket);
+                state.held += 1;
+                *state.per_job.entry(job).or_default() += 1;
+                return Slot { share: self, job };
+            }
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.share.lock();
+        state.held -= 1;
+        let job = self.job;
+        let held = state.per_job.get_mut(&job).expect("slot holder is tracked");
+        *held -= 1;
+        if *held == 0 && !state.waiting.iter().any(|&(j, _)| j == job) {
+            state.per_job.remove(&job);
+        }
+        self.share.changed.notify_all();
+    }
+}
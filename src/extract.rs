@@ -0,0 +1,17 @@
+//! Extracting archives into a directory, and the options and policies doing so takes.
+
+pub use crate::{
+    async_unsquash::{unsquash_async, unsquash_async_from_source},
+    confine::EscapePolicy,
+    digest::HashAlgorithm,
+    options::{
+        DurabilityOptions, ExtractOptions, ExtractOptionsBuilder, IdMap, IdRange, IoOptions,
+        MetadataErrorPolicy, ObservabilityOptions, OverwritePolicy, PermissionPolicy,
+        PolicyOptions, QuotaPolicy, Strategy, DEFAULT_READ_BUFFER, DEFAULT_SLOW_ENTRY_THRESHOLD,
+        DEFAULT_WRITE_BUFFER, OVERFLOW_ID,
+    },
+    overlay::{unsquash_overlay_async, unsquash_overlay_blocking},
+    parsing::Parsing,
+    unsquash_blocking, unsquash_blocking_from_source,
+    unsquasher::Unsquasher,
+};
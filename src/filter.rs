@@ -0,0 +1,512 @@
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use backhand::{Node, SquashfsFileReader};
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{platform, EntryInfo, Error};
+
+/// Selects which archive entries to extract, by fullpath (e.g. `/index/se/rd/serde`).
+///
+/// Only the selected entries are extracted; the parent directories of selected entries are
+/// created as needed, but keep their archive modes only if they are selected themselves.
+#[derive(Clone, Default)]
+pub enum Filter {
+    #[default]
+    All,
+    Paths(HashSet<PathBuf>),
+    /// The index entries (`/index/<crate>`) and salts (`/salts/<crate>`) of these tpcii crates,
+    /// named by their path under `/index`, e.g. `se/rd/serde`, with their parent directories.
+    /// Built with [`Filter::crates`].
+    Crates {
+        crates: HashSet<String>,
+        paths: HashSet<PathBuf>,
+    },
+    /// Entries matching any of the patterns, built with [`Filter::glob`].
+    Glob {
+        patterns: Vec<String>,
+        set: GlobSet,
+    },
+    /// Entries whose fullpath the regex matches anywhere; anchor it to match whole paths.
+    Regex(regex::bytes::Regex),
+    /// The entry at this path and everything under it.
+    Prefix(PathBuf),
+    Predicate(Arc<dyn Fn(&Path) -> bool + Send + Sync>),
+    /// Another filter comparing paths as [`PathFolding`] says, built with [`Filter::folded`].
+    Folded(PathFolding, Box<Filter>),
+}
+
+/// How the paths of the archive are compared to those of a [`Filter`], e.g. to find the entries
+/// of crate names typed by users. Paths that are not valid UTF-8 are only folded to ASCII
+/// lowercase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathFolding {
+    /// Compare paths by their Unicode lowercase.
+    pub case_insensitive: bool,
+    /// Compare paths in Unicode Normalization Form C, so that a precomposed character matches
+    /// the same character spelled with combining marks.
+    pub nfc: bool,
+}
+
+impl PathFolding {
+    /// `path` as it is compared.
+    pub fn fold<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        if !self.case_insensitive && !self.nfc {
+            return Cow::Borrowed(path);
+        }
+        let Some(utf8) = path.to_str() else {
+            return match self.case_insensitive {
+                true => Cow::Owned(PathBuf::from(platform::os_string_from_vec(
+                    path.as_os_str().as_encoded_bytes().to_ascii_lowercase(),
+                ))),
+                false => Cow::Borrowed(path),
+            };
+        };
+        Cow::Owned(PathBuf::from(self.fold_str(utf8)))
+    }
+
+    fn fold_str(&self, s: &str) -> String {
+        let s = match self.nfc {
+            true => s.nfc().collect(),
+            false => s.to_owned(),
+        };
+        match self.case_insensitive {
+            // lowercasing may decompose, e.g. the dotted capital I
+            true if self.nfc => s.to_lowercase().nfc().collect(),
+            true => s.to_lowercase(),
+            false => s,
+        }
+    }
+}
+
+impl Filter {
+    pub fn predicate(predicate: impl Fn(&Path) -> bool + Send + Sync + 'static) -> Self {
+        Self::Predicate(Arc::new(predicate))
+    }
+
+    /// The index entries and salts of the tpcii `crates`, with their parent directories.
+    pub fn crates(crates: impl IntoIterator<Item = String>) -> Self {
+        let crates: HashSet<String> = crates.into_iter().collect();
+        let paths = crates
+            .iter()
+            .flat_map(|name| {
+                let index = Path::new("/index").join(name);
+                let salt = Path::new("/salts").join(name);
+                let ancestors: Vec<_> = index
+                    .ancestors()
+                    .chain(salt.ancestors())
+                    .map(Path::to_path_buf)
+                    .collect();
+                ancestors
+            })
+            .collect();
+        Self::Crates { crates, paths }
+    }
+
+    /// Entries matching any of `patterns`, e.g. `/index/serde*`. `*` and `?` do not match `/`,
+    /// `**` matches any number of directories.
+    pub fn glob<S: AsRef<str>>(
+        patterns: impl IntoIterator<Item = S>,
+    ) -> Result<Self, globset::Error> {
+        let patterns: Vec<String> = patterns
+            .into_iter()
+            .map(|pattern| pattern.as_ref().to_owned())
+            .collect();
+        let mut set = GlobSetBuilder::new();
+        for pattern in &patterns {
+            set.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+        }
+        Ok(Self::Glob {
+            set: set.build()?,
+            patterns,
+        })
+    }
+
+    pub fn regex(regex: &str) -> Result<Self, regex::Error> {
+        regex::bytes::Regex::new(regex).map(Self::Regex)
+    }
+
+    pub fn prefix(prefix: impl AsRef<Path>) -> Self {
+        Self::Prefix(prefix.as_ref().to_path_buf())
+    }
+
+    /// This filter, comparing paths as `folding` says: the paths, prefixes and glob patterns of
+    /// the filter are folded the same way as those of the archive, regexes match
+    /// case-insensitively, and predicates are given folded paths.
+    pub fn folded(self, folding: PathFolding) -> Self {
+        if folding == PathFolding::default() {
+            return self;
+        }
+        let filter = match self {
+            Self::Paths(paths) => Self::Paths(
+                paths
+                    .iter()
+                    .map(|path| folding.fold(path).into_owned())
+                    .collect(),
+            ),
+            Self::Crates { crates, .. } => {
+                Self::crates(crates.iter().map(|name| folding.fold_str(name)))
+            }
+            Self::Prefix(prefix) => Self::Prefix(folding.fold(&prefix).into_owned()),
+            Self::Glob { patterns, set } => {
+                let folded = patterns.iter().map(|pattern| folding.fold_str(pattern));
+                // should folding make a pattern invalid, it is matched as it was
+                Self::glob(folded).unwrap_or(Self::Glob { patterns, set })
+            }
+            Self::Regex(regex) => {
+                let folded = regex::bytes::RegexBuilder::new(regex.as_str())
+                    .case_insensitive(folding.case_insensitive)
+                    .build();
+                Self::Regex(folded.unwrap_or(regex))
+            }
+            filter => filter,
+        };
+        Self::Folded(folding, Box::new(filter))
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        match self {
+            Self::All => true,
+            Self::Paths(paths) => paths.contains(path),
+            Self::Crates { paths, .. } => paths.contains(path),
+            Self::Glob { set, .. } => set.is_match(path),
+            Self::Regex(regex) => regex.is_match(path.as_os_str().as_encoded_bytes()),
+            Self::Prefix(prefix) => path.starts_with(prefix),
+            Self::Predicate(predicate) => predicate(path),
+            Self::Folded(folding, filter) => filter.matches(&folding.fold(path)),
+        }
+    }
+
+    /// Whether the filter is known not to match anything.
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            Self::Paths(paths) => paths.is_empty(),
+            Self::Crates { crates, .. } => crates.is_empty(),
+            Self::Glob { patterns, .. } => patterns.is_empty(),
+            Self::Folded(_, filter) => filter.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// The entries of the filter that match none of `paths`: its paths, crate names, glob
+    /// patterns or prefix, sorted. Regexes and predicates are not tracked.
+    fn unmatched<'a>(&self, paths: &mut dyn Iterator<Item = Cow<'a, Path>>) -> Vec<String> {
+        let mut unmatched: Vec<String> = match self {
+            Self::All | Self::Regex(_) | Self::Predicate(_) => Vec::new(),
+            Self::Paths(filter) => {
+                let mut left: HashSet<&Path> = filter.iter().map(PathBuf::as_path).collect();
+                for path in paths {
+                    left.remove(&*path);
+                    if left.is_empty() {
+                        break;
+                    }
+                }
+                left.iter().map(|path| path.display().to_string()).collect()
+            }
+            Self::Crates { crates, .. } => {
+                let mut left: HashSet<&str> = crates.iter().map(String::as_str).collect();
+                for path in paths {
+                    if let Some(name) = crate_name(&path) {
+                        left.remove(name);
+                    }
+                    if left.is_empty() {
+                        break;
+                    }
+                }
+                left.into_iter().map(str::to_owned).collect()
+            }
+            Self::Glob { patterns, set } => {
+                let mut matched = vec![false; patterns.len()];
+                for path in paths {
+                    for i in set.matches(&*path) {
+                        matched[i] = true;
+                    }
+                }
+                patterns
+                    .iter()
+                    .zip(matched)
+                    .filter_map(|(pattern, matched)| (!matched).then(|| pattern.clone()))
+                    .collect()
+            }
+            Self::Prefix(prefix) => match paths.any(|path| path.starts_with(prefix)) {
+                true => Vec::new(),
+                false => vec![prefix.display().to_string()],
+            },
+            Self::Folded(folding, filter) => filter
+                .unmatched(&mut paths.map(|path| Cow::Owned(folding.fold(&path).into_owned()))),
+        };
+        unmatched.sort_unstable();
+        unmatched
+    }
+}
+
+/// The name of the tpcii crate whose index entry or salt may be at `path`: its path under
+/// `/index` or `/salts`.
+fn crate_name(path: &Path) -> Option<&str> {
+    let name = path
+        .strip_prefix("/index")
+        .or_else(|_| path.strip_prefix("/salts"))
+        .ok()?;
+    name.to_str().filter(|name| !name.is_empty())
+}
+
+/// The entries of `filter` that match nothing in `nodes`, for
+/// [`ExtractReport::unmatched_filter`](crate::ExtractReport::unmatched_filter), failing with
+/// [`Error::UnmatchedFilter`] instead if `strict`.
+pub(crate) fn unmatched<'a>(
+    filter: &Filter,
+    nodes: impl Iterator<Item = &'a Node<SquashfsFileReader>>,
+    strict: bool,
+) -> Result<Vec<String>, Error> {
+    let unmatched = filter.unmatched(&mut nodes.map(|node| Cow::Borrowed(node.fullpath.as_path())));
+    match strict && !unmatched.is_empty() {
+        true => Err(Error::UnmatchedFilter(unmatched)),
+        false => Ok(unmatched),
+    }
+}
+
+impl From<HashSet<PathBuf>> for Filter {
+    fn from(paths: HashSet<PathBuf>) -> Self {
+        Self::Paths(paths)
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => f.write_str("All"),
+            Self::Paths(paths) => f.debug_tuple("Paths").field(paths).finish(),
+            Self::Crates { crates, .. } => f.debug_tuple("Crates").field(crates).finish(),
+            Self::Glob { patterns, .. } => f.debug_tuple("Glob").field(patterns).finish(),
+            Self::Regex(regex) => f.debug_tuple("Regex").field(&regex.as_str()).finish(),
+            Self::Prefix(prefix) => f.debug_tuple("Prefix").field(prefix).finish(),
+            Self::Predicate(_) => f.write_str("Predicate(..)"),
+            Self::Folded(folding, filter) => f
+                .debug_tuple("Folded")
+                .field(folding)
+                .field(filter)
+                .finish(),
+        }
+    }
+}
+
+/// Decides asynchronously whether to extract each entry selected by the [`Filter`], e.g. by
+/// consulting an external service. Only the async extractors support it; the predicate is
+/// evaluated for every entry before any is extracted, a bounded number of entries at once.
+#[derive(Clone)]
+pub struct AsyncFilter {
+    predicate: Arc<dyn Fn(&EntryInfo) -> BoxFuture<'static, bool> + Send + Sync>,
+    concurrency: usize,
+}
+
+impl AsyncFilter {
+    /// Predicates evaluated at once unless set with [`concurrency`](Self::concurrency).
+    pub const DEFAULT_CONCURRENCY: usize = 16;
+
+    pub fn new<F, Fut>(predicate: F) -> Self
+    where
+        F: Fn(&EntryInfo) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        Self {
+            predicate: Arc::new(move |entry| predicate(entry).boxed()),
+            concurrency: Self::DEFAULT_CONCURRENCY,
+        }
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// The `nodes` the predicate accepts, in order.
+    pub(crate) async fn select<'a>(
+        &self,
+        nodes: Vec<&'a Node<SquashfsFileReader>>,
+    ) -> Vec<&'a Node<SquashfsFileReader>> {
+        let accepted: Vec<bool> = futures::stream::iter(&nodes)
+            .map(|&node| (self.predicate)(&EntryInfo::new(node)))
+            .buffered(self.concurrency)
+            .collect()
+            .await;
+        nodes
+            .into_iter()
+            .zip(accepted)
+            .filter_map(|(node, accepted)| accepted.then_some(node))
+            .collect()
+    }
+}
+
+impl fmt::Debug for AsyncFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncFilter")
+            .field("concurrency", &self.concurrency)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, TestArchive},
+        ExtractOptions, PolicyOptions,
+    };
+
+    fn unmatched(filter: &Filter, paths: &[&str]) -> Vec<String> {
+        filter.unmatched(&mut paths.iter().map(|path| Cow::Borrowed(Path::new(path))))
+    }
+
+    #[test]
+    fn selects_nested_crates_and_their_parents() {
+        let filter = Filter::crates(["se/rd/serde".to_owned(), "3/a/abc".to_owned()]);
+        for path in [
+            "/",
+            "/index",
+            "/index/se",
+            "/index/se/rd",
+            "/index/se/rd/serde",
+            "/salts/se/rd/serde",
+            "/index/3/a/abc",
+        ] {
+            assert!(filter.matches(Path::new(path)), "{path}");
+        }
+        for path in [
+            "/index/se/rd/serde-derive",
+            "/index/se/rd/serde/nested",
+            "/index/serde",
+            "/other/se/rd/serde",
+        ] {
+            assert!(!filter.matches(Path::new(path)), "{path}");
+        }
+        assert!(Filter::crates([]).is_empty());
+        assert!(!Filter::crates([]).matches(Path::new("/index")));
+    }
+
+    #[test]
+    fn lists_unmatched_entries() {
+        let paths = [
+            "/",
+            "/index",
+            "/index/se",
+            "/index/se/rd",
+            "/index/se/rd/serde",
+            "/salts",
+            "/salts/an/yh/anyhow",
+        ];
+        // a crate with only a salt is in the archive
+        let crates =
+            ["se/rd/sedre", "se/rd/serde", "an/yh/anyhow", "an/yh/anyhwo"].map(str::to_owned);
+        assert_eq!(
+            unmatched(&Filter::crates(crates), &paths),
+            ["an/yh/anyhwo", "se/rd/sedre"]
+        );
+        let exact = HashSet::from(["/index/se".into(), "/index/missing".into()]);
+        assert_eq!(unmatched(&Filter::Paths(exact), &paths), ["/index/missing"]);
+        let glob = Filter::glob(["/index/**/serde", "/salts/**/serde"]).unwrap();
+        assert_eq!(unmatched(&glob, &paths), ["/salts/**/serde"]);
+        assert_eq!(unmatched(&Filter::prefix("/crates"), &paths), ["/crates"]);
+        assert!(unmatched(&Filter::prefix("/salts/an"), &paths).is_empty());
+        // regexes and predicates are not tracked
+        let regex = Filter::Regex(regex::bytes::Regex::new("^/nothing$").unwrap());
+        assert!(unmatched(&regex, &paths).is_empty());
+
+        let folding = PathFolding {
+            case_insensitive: true,
+            ..PathFolding::default()
+        };
+        let folded = Filter::crates(["SE/RD/Serde".to_owned(), "Typo".to_owned()]).folded(folding);
+        assert_eq!(unmatched(&folded, &paths), ["typo"]);
+    }
+
+    #[tokio::test]
+    async fn reports_or_fails_on_unmatched_crates() {
+        let archive = TestArchive::new(vec![
+            testing::file("index/se/rd/serde", "serde"),
+            testing::file("salts/se/rd/serde", "salt"),
+            testing::file("index/se/rd/serde-derive", "serde-derive"),
+        ]);
+        let crates = || {
+            Some(HashSet::from([
+                "se/rd/serde".to_owned(),
+                "se/rd/sedre".to_owned(),
+            ]))
+        };
+
+        let dest = archive.scratch("lenient");
+        let report = crate::unsquash_tpcii_blocking_with_options(
+            archive.path(),
+            &dest,
+            crates(),
+            ExtractOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.unmatched_filter, ["se/rd/sedre"]);
+        assert_eq!(
+            std::fs::read(dest.join("index/se/rd/serde")).unwrap(),
+            b"serde"
+        );
+        assert_eq!(
+            std::fs::read(dest.join("salts/se/rd/serde")).unwrap(),
+            b"salt"
+        );
+        assert!(!dest.join("index/se/rd/serde-derive").exists());
+        let dest = archive.scratch("lenient-async");
+        let report = crate::unsquash_tpcii_async_with_options(
+            archive.path(),
+            &dest,
+            crates(),
+            ExtractOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.unmatched_filter, ["se/rd/sedre"]);
+        assert!(dest.join("salts/se/rd/serde").exists());
+        // a dry run reports them without extracting anything
+        let dest = archive.scratch("dry-run");
+        let dry_run = ExtractOptions {
+            dry_run: true,
+            ..ExtractOptions::default()
+        };
+        let report =
+            crate::unsquash_tpcii_blocking_with_options(archive.path(), &dest, crates(), dry_run)
+                .unwrap();
+        assert_eq!(report.unmatched_filter, ["se/rd/sedre"]);
+        assert!(!dest.exists());
+
+        let strict = || ExtractOptions {
+            policy: PolicyOptions {
+                strict_filter: true,
+                ..PolicyOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        let dest = archive.scratch("blocking");
+        let err =
+            crate::unsquash_tpcii_blocking_with_options(archive.path(), &dest, crates(), strict())
+                .unwrap_err();
+        assert!(
+            matches!(&err, Error::UnmatchedFilter(names) if names == &["se/rd/sedre"]),
+            "{err}"
+        );
+        assert!(!dest.join("index").exists());
+        let dest = archive.scratch("async");
+        let err =
+            crate::unsquash_tpcii_async_with_options(archive.path(), &dest, crates(), strict())
+                .await
+                .unwrap_err();
+        assert!(
+            matches!(&err, Error::UnmatchedFilter(names) if names == &["se/rd/sedre"]),
+            "{err}"
+        );
+        assert!(!dest.join("index").exists());
+    }
+}
@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    source::{self, SquashSource},
+    Archive, Error, ExtractOptions,
+};
+
+/// Length of the chunks of the archive read while the previous one is hashed.
+const CHUNK_LEN: u64 = 16 << 20;
+
+/// The BLAKE3 hash of the archive at `squashfs`, read with the `direct_io` of `options`, as
+/// printed by `b3sum`. Each chunk of it is hashed on all cores while the next one is read.
+pub fn fingerprint_blocking(
+    squashfs: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<String, Error> {
+    let source = crate::open_source(squashfs.as_ref(), &options)?;
+    Ok(fingerprint(&source)?)
+}
+
+/// Async flavor of [`fingerprint_blocking`].
+pub async fn fingerprint_async(
+    squashfs: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<String, Error> {
+    let squashfs = squashfs.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || fingerprint_blocking(squashfs, options))
+        .await
+        .context("spawn blocking fingerprint task")?
+}
+
+impl Archive {
+    /// Like [`fingerprint_blocking`], computed once for the archive and its clones, while it is
+    /// parsed when opened with [`fingerprint`](crate::IoOptions::fingerprint).
+    pub fn fingerprint(&self) -> Result<String, Error> {
+        if let Some(fingerprint) = self.fingerprint.get() {
+            return Ok(fingerprint.clone());
+        }
+        let fingerprint = fingerprint(&*self.source)?;
+        Ok(self.fingerprint.get_or_init(|| fingerprint).clone())
+    }
+}
+
+pub(crate) fn fingerprint(source: &dyn SquashSource) -> Result<String> {
+    let len = source
+        .size()
+        .with_context(|| format!("stat '{}'", source.name()))?;
+    let read = |offset: u64, chunk: &mut Vec<u8>| -> Result<()> {
+        chunk.resize(CHUNK_LEN.min(len - offset) as usize, 0);
+        source::read_exact_at(source, chunk, offset)
+            .with_context(|| format!("read '{}' at {offset}", source.name()))
+    };
+    let mut hasher = blake3::Hasher::new();
+    let (mut chunk, mut next) = (Vec::new(), Vec::new());
+    read(0, &mut chunk)?;
+    let mut offset = chunk.len() as u64;
+    // the next chunk is read while this one is hashed
+    while !chunk.is_empty() {
+        let (read, _) = rayon::join(|| read(offset, &mut next), || hasher.update_rayon(&chunk));
+        read?;
+        offset += next.len() as u64;
+        std::mem::swap(&mut chunk, &mut next);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestArchive};
+
+    #[test]
+    fn is_blake3_of_archive() {
+        let archive = TestArchive::new(vec![testing::file("a", vec![7; 1 << 16])]);
+        let expected = blake3::hash(&std::fs::read(archive.path()).unwrap());
+        let fingerprint = fingerprint_blocking(archive.path(), ExtractOptions::default()).unwrap();
+        assert_eq!(fingerprint, expected.to_hex().as_str());
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use backhand::InnerNode;
+use serde::{Deserialize, Serialize};
+
+use crate::{metadata::Metadata, shard, Archive, Error, ExtractOptions, Filter};
+
+/// The outcome of [`fix_permissions_blocking`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FixPermissionsReport {
+    /// Entries whose metadata was changed.
+    pub fixed: u64,
+    /// Entries that already had the wanted metadata.
+    pub unchanged: u64,
+    /// Entries of the archive not found in the destination.
+    pub missing: u64,
+}
+
+/// Give the entries of the archive at `squashfs` selected by `filter` that were extracted into
+/// `dest` the mode, and the ownership and mtime if asked, that extracting them with `options`
+/// would, without extracting them again. Entries that already have them are left alone. Useful
+/// to recover trees extracted with another [`PermissionPolicy`](crate::PermissionPolicy).
+pub fn fix_permissions_blocking(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<FixPermissionsReport, Error> {
+    Archive::open(squashfs, options.clone())?.fix_permissions(dest, &filter, &options)
+}
+
+/// Async flavor of [`fix_permissions_blocking`].
+pub async fn fix_permissions_async(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<FixPermissionsReport, Error> {
+    let (squashfs, dest) = (squashfs.as_ref().to_path_buf(), dest.as_ref().to_path_buf());
+    tokio::task::spawn_blocking(move || fix_permissions_blocking(squashfs, dest, filter, options))
+        .await
+        .context("spawn blocking permission fixup task")?
+}
+
+impl Archive {
+    /// Like [`fix_permissions_blocking`].
+    pub fn fix_permissions(
+        &self,
+        dest: impl AsRef<Path>,
+        filter: &Filter,
+        options: &ExtractOptions,
+    ) -> Result<FixPermissionsReport, Error> {
+        Ok(fix(&self.expanded()?, dest.as_ref(), filter, options)?)
+    }
+}
+
+fn fix(
+    archive: &Archive,
+    dest: &Path,
+    filter: &Filter,
+    options: &ExtractOptions,
+) -> Result<FixPermissionsReport> {
+    let metadata = Metadata::new(options);
+    let (shard_levels, recompress) = (options.shard_levels, options.recompress);
+    let subtree = options.subtree.as_deref();
+    let mut entries: Vec<_> = archive
+        .filesystem
+        .files()
+        .filter(|node| filter.matches(&node.fullpath))
+        .filter_map(|node| {
+            let dest_path = shard::dest_path(dest, node, shard_levels, recompress, subtree)?;
+            Some((node, dest_path))
+        })
+        .collect();
+    // directories last, deepest first, so that a restrictive mode does not lock out their
+    // entries
+    entries.sort_by_key(|(node, dest_path)| match node.inner {
+        InnerNode::Dir(_) => std::cmp::Reverse(dest_path.components().count()),
+        _ => std::cmp::Reverse(usize::MAX),
+    });
+
+    let mut report = FixPermissionsReport::default();
+    for (node, dest_path) in entries {
+        let stat = match std::fs::symlink_metadata(&dest_path) {
+            Ok(stat) => stat,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                report.missing += 1;
+                continue;
+            }
+            Err(e) => return Err(e).with_context(|| format!("stat '{}'", dest_path.display())),
+        };
+        let wanted = metadata.entry(node);
+        match wanted.matches(&stat) {
+            true => report.unchanged += 1,
+            false => {
+                wanted.apply(&dest_path)?;
+                report.fixed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+use backhand::kind::{BE_V4_0, LE_V4_0};
+
+use crate::{
+    compression::Kind,
+    source::{self, SquashSource},
+};
+
+/// Byte order of a squashfs superblock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// Why an archive cannot be read. Returned inside the [`anyhow::Error`] of the extraction
+/// functions, so callers can `downcast_ref` to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    /// A squashfs revision other than 4.0, which is the only one backhand reads.
+    UnsupportedVersion {
+        major: u16,
+        minor: u16,
+        endianness: Endianness,
+    },
+    /// A vendor squashfs fork identified by its non-standard magic.
+    VendorVariant { magic: [u8; 4] },
+    /// Not a squashfs archive at all.
+    UnsupportedFormat(Format),
+}
+
+/// What a file that is not a squashfs archive looks like, going by its magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    /// ext2, ext3 or ext4.
+    Ext,
+    Erofs,
+    Cramfs,
+    Cpio,
+    Zip,
+    Iso9660,
+    Unknown,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tar => "a tar archive",
+            Self::Gzip => "gzip-compressed data (e.g. a .tar.gz)",
+            Self::Zstd => "zstd-compressed data (e.g. a .tar.zst)",
+            Self::Xz => "xz-compressed data (e.g. a .tar.xz)",
+            Self::Bzip2 => "bzip2-compressed data (e.g. a .tar.bz2)",
+            Self::Ext => "an ext2/3/4 filesystem image",
+            Self::Erofs => "an EROFS image",
+            Self::Cramfs => "a cramfs image",
+            Self::Cpio => "a cpio archive",
+            Self::Zip => "a zip archive",
+            Self::Iso9660 => "an ISO 9660 image",
+            Self::Unknown => "an unrecognized format",
+        })
+    }
+}
+
+/// Identify the format of a file from its first bytes, of which [`SNIFF_LEN`] are enough.
+pub(crate) fn sniff(header: &[u8]) -> Format {
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+    if at(0, &[0x1f, 0x8b]) {
+        Format::Gzip
+    } else if at(0, &[0x28, 0xb5, 0x2f, 0xfd]) {
+        Format::Zstd
+    } else if at(0, &[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        Format::Xz
+    } else if at(0, b"BZh") {
+        Format::Bzip2
+    } else if at(0, &[0x45, 0x3d, 0xcd, 0x28]) || at(0, &[0x28, 0xcd, 0x3d, 0x45]) {
+        Format::Cramfs
+    } else if at(0, b"070701") || at(0, b"070702") || at(0, b"070707") {
+        Format::Cpio
+    } else if at(0, b"PK\x03\x04") {
+        Format::Zip
+    } else if at(257, b"ustar") {
+        Format::Tar
+    } else if at(1024, &[0xe2, 0xe1, 0xf5, 0xe0]) {
+        Format::Erofs
+    } else if at(1080, &[0x53, 0xef]) {
+        Format::Ext
+    } else if at(32769, b"CD001") {
+        Format::Iso9660
+    } else {
+        Format::Unknown
+    }
+}
+
+/// Number of leading bytes [`sniff`] looks at.
+pub(crate) const SNIFF_LEN: usize = 32774;
+
+/// Read up to [`SNIFF_LEN`] bytes from the start of `source`.
+pub(crate) fn read_header(source: &dyn SquashSource) -> Result<Vec<u8>> {
+    let read = || {
+        let len = source.size()?.min(SNIFF_LEN as u64) as usize;
+        let mut header = vec![0; len];
+        source::read_exact_at(source, &mut header, 0)?;
+        Ok::<_, std::io::Error>(header)
+    };
+    read().with_context(|| format!("read header of '{}'", source.name()))
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion {
+                major,
+                minor,
+                endianness,
+            } => write!(
+                f,
+                "squashfs {major}.{minor} ({endianness:?} endian) is not supported, only 4.0 is; \
+                 convert the image with `unsquashfs` (or `sasquatch`) followed by `mksquashfs`",
+            ),
+            Self::VendorVariant { magic } => write!(
+                f,
+                "vendor squashfs variant with magic {:?} is not supported; extract it with \
+                 `sasquatch` and repack with `mksquashfs`",
+                String::from_utf8_lossy(magic),
+            ),
+            Self::UnsupportedFormat(format) => {
+                write!(f, "expected a squashfs archive, found {format}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// Pick the [`Kind`] to read `source` with from its superblock, unless one is given.
+/// Big-endian images are read as standard big-endian 4.0; AVM images need an explicit kind.
+pub(crate) fn resolve_kind(source: &dyn SquashSource, kind: Option<Kind>) -> Result<Kind> {
+    if let Some(kind) = kind {
+        return Ok(kind);
+    }
+
+    let header = read_header(source)?;
+    let (endianness, inner) = match header.get(..4).unwrap_or_default() {
+        b"hsqs" => (Endianness::Little, LE_V4_0),
+        b"sqsh" => (Endianness::Big, BE_V4_0),
+        magic @ (b"qshs" | b"shsq" | b"hsqt" | b"tqsh" | b"sqlz") => {
+            let magic = magic.try_into().expect("magic is 4 bytes");
+            return Err(FormatError::VendorVariant { magic }.into());
+        }
+        _ => return Err(FormatError::UnsupportedFormat(sniff(&header)).into()),
+    };
+    let version = |offset: usize| {
+        let bytes = [header.get(offset), header.get(offset + 1)].map(|b| b.copied().unwrap_or(0));
+        match endianness {
+            Endianness::Little => u16::from_le_bytes(bytes),
+            Endianness::Big => u16::from_be_bytes(bytes),
+        }
+    };
+    let (major, minor) = (version(28), version(30));
+    if (major, minor) != (4, 0) {
+        return Err(FormatError::UnsupportedVersion {
+            major,
+            minor,
+            endianness,
+        }
+        .into());
+    }
+
+    Ok(Kind::from_const(inner).expect("backhand kinds are valid"))
+}
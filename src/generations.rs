@@ -0,0 +1,292 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Name of the symlink in a destination root pointing at the generation in use.
+pub const CURRENT: &str = "current";
+
+/// Outcome of [`gc`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcReport {
+    /// Generations removed, or that would be removed in a dry run.
+    pub removed: Vec<PathBuf>,
+    pub kept: Vec<PathBuf>,
+}
+
+/// Create a new, empty generation directory in `dest_root`, named after the current time so that
+/// generations sort by age.
+pub fn new_generation(dest_root: impl AsRef<Path>) -> Result<PathBuf> {
+    let dest_root = dest_root.as_ref();
+    std::fs::create_dir_all(dest_root)
+        .with_context(|| format!("create dir '{}'", dest_root.display()))?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = dest_root.join(format!("{}.{:09}", now.as_secs(), now.subsec_nanos()));
+    std::fs::create_dir(&path)
+        .with_context(|| format!("create generation dir '{}'", path.display()))?;
+    Ok(path)
+}
+
+/// The generation directories in `dest_root` with their creation time, oldest first. Anything
+/// else in `dest_root` is ignored.
+pub fn generations(dest_root: impl AsRef<Path>) -> Result<Vec<(PathBuf, SystemTime)>> {
+    let dest_root = dest_root.as_ref();
+    let mut generations = Vec::new();
+    let entries = std::fs::read_dir(dest_root)
+        .with_context(|| format!("read dir '{}'", dest_root.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("read dir '{}'", dest_root.display()))?;
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("stat '{}'", entry.path().display()))?;
+        // symlinks, including `current`, are never generations themselves
+        if !file_type.is_dir() {
+            continue;
+        }
+        if let Some(created) = entry.file_name().to_str().and_then(parse_generation) {
+            generations.push((entry.path(), created));
+        }
+    }
+    generations.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    Ok(generations)
+}
+
+/// The generation the [`CURRENT`] symlink in `dest_root` points at, if any.
+pub fn current(dest_root: impl AsRef<Path>) -> Result<Option<PathBuf>> {
+    let link = dest_root.as_ref().join(CURRENT);
+    match std::fs::read_link(&link) {
+        Ok(target) => Ok(target.file_name().map(|name| dest_root.as_ref().join(name))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("read symlink '{}'", link.display())),
+    }
+}
+
+/// Remove all but the newest `keep` generations in `dest_root`, never removing the one
+/// [`CURRENT`] points at. With `dry_run`, only report what would be removed.
+pub fn gc(dest_root: impl AsRef<Path>, keep: usize, dry_run: bool) -> Result<GcReport> {
+    let dest_root = dest_root.as_ref();
+    let current = current(dest_root)?;
+    let mut generations = generations(dest_root)?;
+    let old = generations.len().saturating_sub(keep);
+    let mut report = GcReport::default();
+    for (i, (path, _)) in generations.drain(..).enumerate() {
+        if i >= old || current.as_ref() == Some(&path) {
+            report.kept.push(path);
+            continue;
+        }
+        if !dry_run {
+            crate::protect::unprotect(&path)?;
+            std::fs::remove_dir_all(&path)
+                .with_context(|| format!("remove generation '{}'", path.display()))?;
+            tracing::info!(path = %path.display(), "removed old generation");
+        }
+        report.removed.push(path);
+    }
+    Ok(report)
+}
+
+fn parse_generation(name: &str) -> Option<SystemTime> {
+    let (secs, nanos) = name.split_once('.')?;
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !all_digits(secs) || nanos.len() != 9 || !all_digits(nanos) {
+        return None;
+    }
+    let elapsed = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    SystemTime::UNIX_EPOCH.checked_add(elapsed)
+}
+
+/// Atomically point the `live_symlink` at `staging_dir` by renaming a new symlink over it, then
+/// fsync their parent so the switch survives a crash. Returns what `live_symlink` pointed at
+/// before, if anything.
+///
+/// The link is relative when `staging_dir` is next to `live_symlink`, as with [`new_generation`]
+/// and [`CURRENT`], so that the whole root can be moved.
+///
+/// Publishing fails off Unix, where symlinks are not supported.
+pub fn publish(
+    staging_dir: impl AsRef<Path>,
+    live_symlink: impl AsRef<Path>,
+) -> Result<Option<PathBuf>> {
+    let (staging_dir, live_symlink) = (staging_dir.as_ref(), live_symlink.as_ref());
+    let name = live_symlink
+        .file_name()
+        .with_context(|| format!("no file name in '{}'", live_symlink.display()))?;
+    let parent = match live_symlink.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    anyhow::ensure!(
+        staging_dir.is_dir(),
+        "cannot publish '{}': not a directory",
+        staging_dir.display()
+    );
+    let target = match (staging_dir.parent(), staging_dir.file_name()) {
+        (Some(staging_parent), Some(staging_name)) if same_dir(staging_parent, parent) => {
+            PathBuf::from(staging_name)
+        }
+        _ => std::fs::canonicalize(staging_dir)
+            .with_context(|| format!("resolve '{}'", staging_dir.display()))?,
+    };
+    let previous = match std::fs::read_link(live_symlink) {
+        Ok(previous) => Some(previous),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(e).with_context(|| format!("read symlink '{}'", live_symlink.display()))
+        }
+    };
+
+    // unique within the process too, for threads publishing to the same link at once
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = parent.join(tmp_name);
+    crate::platform::symlink(&target, &tmp)
+        .with_context(|| format!("create symlink '{}'", tmp.display()))?;
+    if let Err(e) = std::fs::rename(&tmp, live_symlink) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e).with_context(|| format!("replace symlink '{}'", live_symlink.display()));
+    }
+    std::fs::File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("fsync dir '{}'", parent.display()))?;
+    tracing::info!(
+        link = %live_symlink.display(),
+        target = %target.display(),
+        "published extraction"
+    );
+    Ok(previous)
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    let a = if a.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        a
+    };
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn publishes_from_several_threads() {
+        let root = tempfile::tempdir().unwrap();
+        let live = root.path().join(CURRENT);
+        let generations: Vec<_> = (0..8)
+            .map(|i| {
+                let dir = root.path().join(format!("generation-{i}"));
+                std::fs::create_dir(&dir).unwrap();
+                dir
+            })
+            .collect();
+        std::thread::scope(|scope| {
+            for dir in &generations {
+                let live = &live;
+                scope.spawn(move || publish(dir, live).unwrap());
+            }
+        });
+        let target = std::fs::read_link(&live).unwrap();
+        assert!(generations
+            .iter()
+            .any(|dir| dir.file_name() == Some(target.as_os_str())));
+        // no temporary link was left behind
+        let entries = std::fs::read_dir(root.path()).unwrap().count();
+        assert_eq!(entries, generations.len() + 1);
+    }
+
+    /// A root with generations made at 100, 200, 300 and 400 seconds past the epoch, the oldest
+    /// being [`CURRENT`], next to a directory, a file and a symlink which are not generations.
+    #[cfg(unix)]
+    fn root() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        for secs in [100, 200, 300, 400] {
+            std::fs::create_dir(root.path().join(format!("{secs}.000000000"))).unwrap();
+        }
+        std::fs::create_dir(root.path().join("other")).unwrap();
+        std::fs::write(root.path().join("notes"), "notes").unwrap();
+        std::os::unix::fs::symlink("200.000000000", root.path().join("previous")).unwrap();
+        std::os::unix::fs::symlink("100.000000000", root.path().join(CURRENT)).unwrap();
+        root
+    }
+
+    #[cfg(unix)]
+    fn names(root: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn collects_old_generations() {
+        let root = root();
+        let path = |name: &str| root.path().join(name);
+        let report = gc(root.path(), 2, false).unwrap();
+        // the current generation is kept even though it is the oldest
+        assert_eq!(report.removed, [path("200.000000000")]);
+        assert_eq!(
+            report.kept,
+            [
+                path("100.000000000"),
+                path("300.000000000"),
+                path("400.000000000")
+            ]
+        );
+        assert_eq!(
+            names(root.path()),
+            [
+                "100.000000000",
+                "300.000000000",
+                "400.000000000",
+                "current",
+                "notes",
+                "other",
+                "previous",
+            ]
+        );
+        // the symlink to the removed generation is left dangling rather than removed
+        assert!(std::fs::symlink_metadata(path("previous")).is_ok());
+
+        let report = gc(root.path(), 0, false).unwrap();
+        assert_eq!(
+            report.removed,
+            [path("300.000000000"), path("400.000000000")]
+        );
+        assert_eq!(report.kept, [path("100.000000000")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn removes_nothing_in_dry_runs() {
+        let root = root();
+        let before = names(root.path());
+        let report = gc(root.path(), 1, true).unwrap();
+        let path = |name: &str| root.path().join(name);
+        assert_eq!(
+            report.removed,
+            [path("200.000000000"), path("300.000000000")]
+        );
+        assert_eq!(report.kept, [path("100.000000000"), path("400.000000000")]);
+        assert_eq!(names(root.path()), before);
+    }
+}
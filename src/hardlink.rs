@@ -0,0 +1,321 @@
+use std::{
+    collections::{hash_map, HashMap},
+    fs::Metadata,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use backhand::{InnerNode, Node, SquashfsFileReader};
+
+use crate::{
+    cancel::CancelToken,
+    confine,
+    conflict::{self, Conflict},
+    options::NodeOptions,
+    resume, shard,
+};
+
+/// A file sharing its data and metadata with an earlier file of the archive, extracted as a
+/// hardlink to it.
+pub(crate) struct Link<'a> {
+    node: &'a Node<SquashfsFileReader>,
+    target: &'a Node<SquashfsFileReader>,
+}
+
+/// Split `nodes` into those to extract, and files to link to one of them once they are.
+///
+/// Backhand does not expose inode numbers, so hardlinks are recognized by sharing their data
+/// and metadata. Identical files that squashfs deduplicated are linked as well, which is why
+/// [`Mechanisms::hardlinks`](crate::mechanisms::Mechanisms::hardlinks) is off by default.
+pub(crate) fn plan<'a>(
+    nodes: &[&'a Node<SquashfsFileReader>],
+    enabled: bool,
+) -> (Vec<&'a Node<SquashfsFileReader>>, Vec<Link<'a>>) {
+    if !enabled {
+        return (nodes.to_vec(), Vec::new());
+    }
+    let mut targets = HashMap::new();
+    let (mut extracted, mut links) = (Vec::new(), Vec::new());
+    for &node in nodes {
+        let file = match &node.inner {
+            InnerNode::File(file) if file.basic.file_size > 0 => &file.basic,
+            _ => {
+                extracted.push(node);
+                continue;
+            }
+        };
+        let header = &node.header;
+        let key = (
+            file.blocks_start,
+            file.frag_index,
+            file.block_offset,
+            file.file_size,
+            header.permissions,
+            header.uid,
+            header.gid,
+            header.mtime,
+        );
+        match targets.entry(key) {
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(node);
+                extracted.push(node);
+            }
+            hash_map::Entry::Occupied(entry) => links.push(Link {
+                node,
+                target: entry.get(),
+            }),
+        }
+    }
+    (extracted, links)
+}
+
+impl Link<'_> {
+    /// Where the target was extracted, and where to link it.
+    fn paths(&self, dest: &Path, options: NodeOptions<'_>) -> Option<(PathBuf, PathBuf)> {
+        let NodeOptions {
+            shard_levels,
+            recompress,
+            subtree,
+            ..
+        } = options;
+        Some((
+            shard::dest_path(dest, self.target, shard_levels, recompress, subtree)?,
+            shard::dest_path(dest, self.node, shard_levels, recompress, subtree)?,
+        ))
+    }
+
+    /// What to do about what is at the destination of the link.
+    fn resolve(
+        &self,
+        options: NodeOptions<'_>,
+        dest_path: &Path,
+        existing: Option<&Metadata>,
+    ) -> Result<Conflict> {
+        Ok(
+            match conflict::resolve(options.overwrite, self.node, dest_path, existing)? {
+                // unlike writing a file, linking does not replace an existing one
+                Conflict::None if existing.is_some() => Conflict::Replace { dir: false },
+                conflict => conflict,
+            },
+        )
+    }
+
+    /// Whether a previous extraction journaled the link, which is still in place.
+    fn resumed(&self, options: NodeOptions<'_>, dest_path: &Path) -> bool {
+        options
+            .journal
+            .and_then(|journal| journal.done(self.node))
+            .is_some_and(|digest| resume::intact(dest_path, digest))
+    }
+
+    fn linked(&self, options: NodeOptions<'_>) -> Result<()> {
+        if let Some(journal) = options.journal {
+            journal.record_link(self.node, self.target)?;
+        }
+        options.tally.linked();
+        self.done(options);
+        Ok(())
+    }
+
+    fn done(&self, options: NodeOptions<'_>) {
+        options.counters.record_entry(self.node, &Ok::<_, ()>(()));
+        if let Some(progress) = options.progress {
+            progress.record(self.node);
+        }
+    }
+}
+
+/// Link each of `links` to its target, once the targets are extracted into `dest`. Returns the
+/// files which could not be linked, to be extracted as copies.
+pub(crate) fn link_blocking<'a>(
+    dest: &Path,
+    links: &[Link<'a>],
+    options: NodeOptions<'_>,
+) -> Result<Vec<&'a Node<SquashfsFileReader>>> {
+    let mut copies = Vec::new();
+    for link in links {
+        CancelToken::check(options.cancel)?;
+        let Some((original, dest_path)) = link.paths(dest, options) else {
+            continue;
+        };
+        confine::check_path(dest, &dest_path, link.node)?;
+        if !options.mechanisms.hardlinks() {
+            copies.push(link.node);
+            continue;
+        }
+        if link.resumed(options, &dest_path) {
+            options.tally.skipped(1);
+            continue;
+        }
+        let parent = dest_path
+            .parent()
+            .expect("path is guaranteed to contain a parent");
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("create dir to unpack '{}'", dest_path.display()))?;
+        let existing = match std::fs::symlink_metadata(&dest_path) {
+            Ok(existing) => Some(existing),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("stat '{}'", dest_path.display())),
+        };
+        match link.resolve(options, &dest_path, existing.as_ref())? {
+            Conflict::Skip => {
+                options.tally.skipped(1);
+                link.done(options);
+                continue;
+            }
+            Conflict::Reuse => {
+                options.tally.reused();
+                link.done(options);
+                continue;
+            }
+            Conflict::None => {}
+            Conflict::Replace { dir: true } => std::fs::remove_dir_all(&dest_path)
+                .with_context(|| format!("remove dir '{}'", dest_path.display()))?,
+            Conflict::Replace { dir: false } => std::fs::remove_file(&dest_path)
+                .with_context(|| format!("remove '{}'", dest_path.display()))?,
+        }
+        let res = std::fs::hard_link(&original, &dest_path);
+        match options.mechanisms.linked(res, &original, &dest_path)? {
+            true => link.linked(options)?,
+            false => copies.push(link.node),
+        }
+    }
+    Ok(copies)
+}
+
+/// Async flavor of [`link_blocking`].
+pub(crate) async fn link_async<'a>(
+    dest: &Path,
+    links: &[Link<'a>],
+    options: NodeOptions<'_>,
+) -> Result<Vec<&'a Node<SquashfsFileReader>>> {
+    let mut copies = Vec::new();
+    for link in links {
+        CancelToken::check(options.cancel)?;
+        let Some((original, dest_path)) = link.paths(dest, options) else {
+            continue;
+        };
+        confine::check_path(dest, &dest_path, link.node)?;
+        if !options.mechanisms.hardlinks() {
+            copies.push(link.node);
+            continue;
+        }
+        if let Some(digest) = options.journal.and_then(|journal| journal.done(link.node)) {
+            let (dest_path, digest) = (dest_path.clone(), digest.clone());
+            let intact = tokio::task::spawn_blocking(move || resume::intact(&dest_path, &digest));
+            if intact.await.context("spawn blocking resume check task")? {
+                options.tally.skipped(1);
+                continue;
+            }
+        }
+        let parent = dest_path
+            .parent()
+            .expect("path is guaranteed to contain a parent");
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("create dir to unpack '{}'", dest_path.display()))?;
+        let existing = match tokio::fs::symlink_metadata(&dest_path).await {
+            Ok(existing) => Some(existing),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("stat '{}'", dest_path.display())),
+        };
+        match link.resolve(options, &dest_path, existing.as_ref())? {
+            Conflict::Skip => {
+                options.tally.skipped(1);
+                link.done(options);
+                continue;
+            }
+            Conflict::Reuse => {
+                options.tally.reused();
+                link.done(options);
+                continue;
+            }
+            Conflict::None => {}
+            Conflict::Replace { dir: true } => tokio::fs::remove_dir_all(&dest_path)
+                .await
+                .with_context(|| format!("remove dir '{}'", dest_path.display()))?,
+            Conflict::Replace { dir: false } => tokio::fs::remove_file(&dest_path)
+                .await
+                .with_context(|| format!("remove '{}'", dest_path.display()))?,
+        }
+        let res = tokio::fs::hard_link(&original, &dest_path).await;
+        match options.mechanisms.linked(res, &original, &dest_path)? {
+            true => link.linked(options)?,
+            false => copies.push(link.node),
+        }
+    }
+    Ok(copies)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::{
+        mechanisms::Mechanisms,
+        reapi::Digest,
+        resume::Journal,
+        testing::{self, TestArchive},
+        Archive, ExtractOptions, Filter, IoOptions,
+    };
+
+    /// Two files of the same whole blocks, which squashfs stores once.
+    fn archive() -> TestArchive {
+        let contents = vec![1; 256 << 10];
+        TestArchive::new(vec![
+            testing::file("a", contents.clone()),
+            testing::file("b", contents),
+        ])
+    }
+
+    fn extract(archive: &TestArchive, hardlinks: bool) -> (u64, u64) {
+        let dest = archive.scratch("dest");
+        let options = ExtractOptions {
+            io: IoOptions {
+                mechanisms: Mechanisms {
+                    hardlinks,
+                    ..Mechanisms::default()
+                },
+                ..IoOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        crate::unsquash_blocking(archive.path(), &dest, Filter::All, options).unwrap();
+        let ino = |name| std::fs::metadata(dest.join(name)).unwrap().ino();
+        (ino("a"), ino("b"))
+    }
+
+    #[test]
+    fn copies_identical_files_by_default() {
+        let (a, b) = extract(&archive(), Mechanisms::default().hardlinks);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn links_identical_files_when_enabled() {
+        let (a, b) = extract(&archive(), true);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn journals_links_as_their_target() {
+        let archive = archive();
+        let opened = Archive::open(archive.path(), ExtractOptions::default()).unwrap();
+        let (a, b) = (
+            opened.node(Path::new("a")).unwrap(),
+            opened.node(Path::new("b")).unwrap(),
+        );
+        let (path, fingerprint) = (archive.scratch("journal"), opened.fingerprint().unwrap());
+        let digest = Digest {
+            hash: "0".repeat(64),
+            size_bytes: 256 << 10,
+        };
+        let journal = Journal::open(&path, fingerprint.clone()).unwrap();
+        journal.record(a, digest.clone()).unwrap();
+        journal.record_link(b, a).unwrap();
+        drop(journal);
+        let journal = Journal::open(&path, fingerprint).unwrap();
+        assert_eq!(journal.done(b), Some(&digest));
+    }
+}
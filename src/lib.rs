@@ -1,85 +1,808 @@
 use std::{
     collections::HashSet,
-    os::unix::fs::PermissionsExt,
-    path::{Component, Path},
+    path::Path,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
 use backhand::{FilesystemReader, InnerNode, Node, Squashfs, SquashfsFileReader, SquashfsSymlink};
 
+use crate::{
+    archive::Input,
+    block_decoder::BlockDecoder,
+    cancel::CancelToken,
+    classify::ErrorClass,
+    compression::Kind,
+    conflict::Conflict,
+    counters::{Counters, Counting},
+    digest::{Hasher, Hashing},
+    executor::Executor,
+    mechanisms::Detected,
+    metadata::{Metadata, MetadataError},
+    options::{
+        NodeOptions, Quota, DEFAULT_READ_BUFFER, DEFAULT_SLOW_ENTRY_THRESHOLD, DEFAULT_WRITE_BUFFER,
+    },
+    profile::{self, Profiler, Stage, Timed},
+    progress::{EntryEvents, Reporting},
+    protect::ReadOnly,
+    recompress::{Encoder, RecompressManifest},
+    report::Tally,
+    resume::Journal,
+    shard::ShardManifest,
+    source::{FileSource, SourceReader, SquashSource},
+    sparse::Sparse,
+    special::Special,
+    staging::Staging,
+    throttle::{Throttle, Throttled},
+};
+
+mod archive;
+mod archive_pool;
+pub mod async_file;
 mod async_unsquash;
+#[cfg(feature = "audit")]
+pub mod audit;
+mod block_decoder;
+pub mod cache;
+pub mod cancel;
+#[cfg(feature = "sqlite")]
+mod catalog;
+pub mod check;
+pub mod classify;
+pub mod compression;
+mod confine;
+mod conflict;
+pub mod counters;
+mod cpio;
+mod dest_fs;
+pub mod diff;
+mod digest;
+mod durability;
+mod erofs;
+mod error;
+mod executor;
+pub mod extract;
+pub mod filter;
+mod fingerprint;
+mod fix_permissions;
+mod format;
+pub mod generations;
+mod hardlink;
+mod list;
+pub mod mechanisms;
+mod metadata;
+pub mod metrics;
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+mod mount;
+pub mod nar;
+#[cfg(feature = "object-store")]
+mod object_store_dest;
+pub mod oplog;
+mod options;
+mod overlay;
+mod parsing;
+mod paths;
+mod platform;
+pub mod pool;
+mod pool_journal;
+#[cfg(all(feature = "notify", target_os = "linux"))]
+mod pool_watch;
+mod prefetch;
+pub mod preflight;
+pub mod prelude;
+pub mod profile;
+pub mod progress;
+pub mod protect;
+mod read;
+mod read_cache;
+pub mod reapi;
+pub mod recompress;
+pub mod report;
+mod resume;
+pub mod runtime;
+mod selftest;
+pub mod shard;
+mod sidecar;
+pub mod sink;
+mod slow_entry;
+pub mod snapshots;
+pub mod source;
+mod space;
+mod sparse;
+mod special;
+mod squash;
+mod staging;
+pub mod sums;
+#[cfg(feature = "tar")]
+mod tar_fallback;
+#[cfg(test)]
+mod testing;
+mod throttle;
+#[cfg(feature = "tar")]
+mod to_tar;
+pub mod tpcii;
+mod trace;
+mod transcode;
+mod unsquasher;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+pub mod validate;
+pub mod verify;
+mod xattr;
+
+pub use archive::{Archive, ArchiveInfo, MemoryUsage, DEFAULT_PREFETCH_DEPTH};
+pub use archive_pool::{ArchivePool, SharedArchive};
+pub use async_unsquash::{
+    unsquash_async, unsquash_async_from_source, unsquash_tpcii_async,
+    unsquash_tpcii_async_from_source, unsquash_tpcii_async_with_kind,
+    unsquash_tpcii_async_with_options,
+};
+pub use confine::EscapePolicy;
+pub use cpio::unsquash_tpcii_to_cpio;
+pub use dest_fs::{unsquash_to_fs, DestFile, DestFs, MemoryEntry, MemoryFs, OsFs};
+pub use digest::HashAlgorithm;
+pub use durability::Durability;
+pub use erofs::unsquash_tpcii_to_erofs;
+pub use error::Error;
+pub use filter::{AsyncFilter, Filter, PathFolding};
+pub use fingerprint::{fingerprint_async, fingerprint_blocking};
+pub use fix_permissions::{fix_permissions_async, fix_permissions_blocking, FixPermissionsReport};
+pub use format::{Endianness, Format, FormatError};
+pub use list::{for_each_entry, list_async, list_blocking, Entry, EntryInfo, EntryKind, EntryRef};
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+pub use mount::{mount, spawn_mount, Mount};
+#[cfg(feature = "object-store")]
+pub use object_store_dest::{unsquash_to_object_store, ObjectStoreDest};
+pub use options::{
+    DurabilityOptions, ExtractOptions, ExtractOptionsBuilder, IdMap, IdRange, IoOptions,
+    MetadataErrorPolicy, ObservabilityOptions, Ordering, OverwritePolicy, PermissionPolicy,
+    PolicyOptions, QuotaPolicy, Strategy, DEFAULT_READ_BUFFER, DEFAULT_SLOW_ENTRY_THRESHOLD,
+    DEFAULT_WRITE_BUFFER, OVERFLOW_ID,
+};
+pub use overlay::{unsquash_overlay_async, unsquash_overlay_blocking};
+pub use parsing::Parsing;
+#[cfg(all(feature = "notify", target_os = "linux"))]
+pub use pool_watch::{PoolEvent, PoolEvents, PoolWatcher};
+pub use prefetch::prefetch_tpcii;
+pub use read::{read_file_async, read_file_blocking, read_file_to};
+pub use report::{
+    DryRun, ExtractReport, ExtractSummary, MetadataWarning, PlannedEntry, Unrecoverable,
+};
+pub use selftest::selftest;
+pub use squash::{
+    squash_async, squash_blocking, squash_entries_async, squash_entries_blocking, SquashOptions,
+    SquashReport,
+};
+#[cfg(feature = "tar")]
+pub use to_tar::{to_tar_async, to_tar_blocking};
+pub use transcode::transcode;
+pub use unsquasher::Unsquasher;
 
 pub fn unsquash_tpcii_blocking(
     squashfs: impl AsRef<Path>,
     dest: impl AsRef<Path>,
     crates_filter: Option<HashSet<String>>,
-) -> Result<()> {
-    use rayon::prelude::*;
+) -> Result<ExtractSummary, Error> {
+    unsquash_tpcii_blocking_with_options(squashfs, dest, crates_filter, ExtractOptions::default())
+        .map(|report| report.summary)
+}
+
+/// Like [`unsquash_tpcii_blocking`], but reading the archive as the given [`Kind`], which selects
+/// the squashfs variant and the decompressor implementation.
+pub fn unsquash_tpcii_blocking_with_kind(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    crates_filter: Option<HashSet<String>>,
+    kind: Kind,
+) -> Result<ExtractSummary, Error> {
+    let options = ExtractOptions {
+        io: IoOptions {
+            kind: Some(kind),
+            ..IoOptions::default()
+        },
+        ..Default::default()
+    };
+    unsquash_tpcii_blocking_with_options(squashfs, dest, crates_filter, options)
+        .map(|report| report.summary)
+}
+
+/// Like [`unsquash_tpcii_blocking`], configured through [`ExtractOptions`].
+pub fn unsquash_tpcii_blocking_with_options(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    crates_filter: Option<HashSet<String>>,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    unsquash_blocking(squashfs, dest, tpcii_filter(crates_filter), options)
+}
+
+/// Like [`unsquash_tpcii_blocking_with_options`], reading the archive from any [`SquashSource`].
+pub fn unsquash_tpcii_blocking_from_source(
+    source: impl SquashSource + 'static,
+    dest: impl AsRef<Path>,
+    crates_filter: Option<HashSet<String>>,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    unsquash_blocking_from_source(source, dest, tpcii_filter(crates_filter), options)
+}
+
+/// Extract the entries of `squashfs` selected by `filter` into `dest`, mirroring the archive
+/// layout.
+pub fn unsquash_blocking(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    let source = open_source(squashfs.as_ref(), &options)?;
+    unsquash_blocking_from_source(source, dest, filter, options)
+}
+
+/// Like [`unsquash_blocking`], stopping once `shutdown` is set, e.g. by a signal handler, with
+/// the report of what was extracted until then; see [`ExtractOptions::shutdown`].
+pub fn unsquash_blocking_with_shutdown(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+    shutdown: Arc<AtomicBool>,
+) -> Result<ExtractReport, Error> {
+    let options = ExtractOptions {
+        shutdown: Some(shutdown),
+        ..options
+    };
+    unsquash_blocking(squashfs, dest, filter, options)
+}
+
+pub(crate) fn open_source(
+    squashfs_path: &Path,
+    options: &ExtractOptions,
+) -> Result<Box<dyn SquashSource>> {
+    if !squashfs_path.exists() {
+        return Err(Error::ArchiveNotFound(squashfs_path.to_path_buf()).into());
+    }
 
-    let (squashfs_path, dest) = (squashfs.as_ref(), dest.as_ref());
+    #[cfg(feature = "mmap")]
+    if options.io.mmap {
+        return Ok(Box::new(source::MmapSource::open(squashfs_path)?));
+    }
+    Ok(match options.io.direct_io {
+        true => Box::new(FileSource::open_direct(squashfs_path)?),
+        false => Box::new(FileSource::open(squashfs_path)?),
+    })
+}
+
+/// Like [`unsquash_blocking`], reading the archive from any [`SquashSource`].
+pub fn unsquash_blocking_from_source(
+    source: impl SquashSource + 'static,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    let executor = options
+        .io
+        .concurrency
+        .map_or(Executor::Rayon, Executor::Threads);
+    let input = Input::Source(Arc::new(source));
+    unsquash_blocking_on(input, dest, filter, options, executor).map_err(Error::from)
+}
+
+pub(crate) fn unsquash_blocking_on(
+    input: Input,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+    executor: Executor<'_>,
+) -> Result<ExtractReport> {
+    let dest = dest.as_ref();
+    #[cfg(feature = "audit")]
+    let audit = options
+        .observability
+        .audit
+        .map(|sink| audit::Audit::start(sink, input.source(), &filter, dest));
+    // a dry run leaves no tree to finish
+    let (read_only, nar_hash) = match options.dry_run {
+        true => (None, false),
+        false => (options.durability.read_only, options.observability.nar_hash),
+    };
+    let (source, started) = (Arc::clone(input.source()), Instant::now());
+    let profiler = Profiler::new(options.observability.profile);
+    let res = profile::scoped(profiler.as_ref(), || {
+        trace::extract(dest).in_scope(|| extract_blocking(input, dest, filter, options, executor))
+    });
+    // garbage read from a changed archive may have failed the extraction, or not
+    let res = source::check_unchanged(&*source)
+        .and(res)
+        .and_then(|mut report| {
+            if !report.interrupted {
+                report.nar_hash = finish_tree(dest, read_only, nar_hash)?;
+            }
+            report.summary.duration = started.elapsed();
+            report.profile = profiler.map(|profiler| profiler.profile());
+            Ok(report)
+        });
+    #[cfg(feature = "audit")]
+    if let Some(audit) = audit {
+        audit.finish(&res);
+    }
+    res
+}
+
+/// Hash the extracted tree if `nar_hash`, then protect it if `read_only`.
+pub(crate) fn finish_tree(
+    dest: &Path,
+    read_only: Option<ReadOnly>,
+    nar_hash: bool,
+) -> Result<Option<String>> {
+    let hash = match nar_hash {
+        true => Some(nar::nar_hash(dest)?),
+        false => None,
+    };
+    if let Some(read_only) = read_only {
+        protect::protect(dest, read_only)?;
+    }
+    Ok(hash)
+}
+
+fn extract_blocking(
+    input: Input,
+    dest: &Path,
+    filter: Filter,
+    options: ExtractOptions,
+    executor: Executor<'_>,
+) -> Result<ExtractReport> {
+    let quota = Quota::new(&options);
+    let throttle = options.io.max_bytes_per_sec.map(Throttle::new);
+    let max_dest_bytes = options.policy.max_dest_bytes;
+    #[cfg(feature = "sqlite")]
+    let (catalog, extracted_at) = (
+        options.observability.catalog.clone(),
+        std::time::SystemTime::now(),
+    );
+    let metadata = Metadata::new(&options);
+    #[cfg(feature = "tar")]
+    let tar_options = tar_fallback::check_options(&options);
+    let ExtractOptions {
+        io:
+            IoOptions {
+                kind,
+                mechanisms,
+                strategy,
+                block_decode_workers,
+                fragment_cache_blocks,
+                ordering,
+                write_buffer,
+                read_buffer,
+                ..
+            },
+        policy:
+            PolicyOptions {
+                check_space,
+                overwrite,
+                salvage,
+                quarantine,
+                error_classifier,
+                strict_filter,
+                parsing,
+                allow_special_files,
+                escape,
+                metadata_errors,
+                extract_xattrs,
+                ..
+            },
+        durability:
+            DurabilityOptions {
+                fsync: durability,
+                resume,
+                atomic,
+                ..
+            },
+        observability:
+            ObservabilityOptions {
+                progress,
+                slow_entry_threshold,
+                sha256sums,
+                hash,
+                digests,
+                ..
+            },
+        shard_levels,
+        subtree,
+        recompress,
+        async_filter,
+        cancel,
+        shutdown,
+        dry_run,
+        ..
+    } = options;
+    let subtree = subtree.as_deref();
+    let slow_entry_threshold = slow_entry_threshold.unwrap_or(DEFAULT_SLOW_ENTRY_THRESHOLD);
 
     anyhow::ensure!(
-        squashfs_path.exists(),
-        "specified squashfs archive does not exist: '{}'",
-        squashfs_path.display(),
+        async_filter.is_none(),
+        "async filters are only supported by the async extractors"
     );
+    if filter.is_empty() {
+        return Ok(ExtractReport::default());
+    }
+
+    let archive = match input {
+        Input::Opened(archive) => archive.expanded()?.into_owned(),
+        Input::Source(source) => {
+            #[cfg(feature = "tar")]
+            if let Some(format) = tar_fallback::detect(&*source)? {
+                tar_options?;
+                return tar_fallback::extract(source, format, dest, &filter);
+            }
+            profile::timed(Stage::Open, || {
+                report::catch_panic(|| {
+                    Archive::open_with(source, kind, parsing, fragment_cache_blocks)
+                })
+            })?
+        }
+    };
+    let filesystem = &*archive.filesystem;
+    let xattrs = match extract_xattrs {
+        true => Some(archive.xattrs()?),
+        false => None,
+    };
 
-    let crates_filter = crates_filter.map(|filter| {
-        filter
-            .into_iter()
-            .filter_map(|krate| {
-                let index_path = Path::new("/index").join(&krate);
-                let salt_path = Path::new("/salts").join(&krate);
-
-                let paths_iter = index_path
-                    .ancestors()
-                    .chain(salt_path.ancestors())
-                    .map(|p| p.to_path_buf())
-                    .collect::<Vec<_>>();
-                Some(paths_iter)
-            })
-            .flatten()
-            .collect::<HashSet<_>>()
+    let nodes: Vec<&Node<_>> = profile::timed(Stage::Plan, || {
+        filesystem
+            .files()
+            .filter(|node| filter.matches(&node.fullpath))
+            .filter(|node| subtree.is_none_or(|subtree| node.fullpath.starts_with(subtree)))
+            .collect()
+    });
+    let unmatched_filter = filter::unmatched(&filter, filesystem.files(), strict_filter)?;
+    let nodes = parsing::supported_nodes(nodes, parsing, allow_special_files)?;
+    if dry_run {
+        return Ok(ExtractReport {
+            dry_run: Some(DryRun::plan(
+                dest,
+                &nodes,
+                shard_levels,
+                recompress,
+                subtree,
+            )),
+            unmatched_filter,
+            ..ExtractReport::default()
+        });
+    }
+    anyhow::ensure!(
+        resume.is_none() || !atomic,
+        "resuming atomic extractions is not supported"
+    );
+    let journal = match resume {
+        Some(path) => Some(Journal::open(&path, archive.fingerprint()?)?),
+        None => None,
+    };
+    let staging = atomic.then(|| Staging::new(dest)).transpose()?;
+    #[cfg(feature = "sqlite")]
+    let published = dest;
+    let dest = staging.as_ref().map_or(dest, Staging::path);
+    let dirs = conflict::dirs(dest, &nodes, shard_levels, recompress, subtree);
+    let skipped = conflict::prepare_dirs(overwrite, dirs)?;
+    let selected = nodes.len();
+    let mut nodes: Vec<_> = nodes
+        .into_iter()
+        .filter(|node| !skipped.iter().any(|dir| node.fullpath.starts_with(dir)))
+        .collect();
+    let sequential = ordering.sort(&mut nodes);
+    trace::filtered(filesystem.root.nodes.len(), selected, nodes.len());
+    let tally = Tally::default();
+    tally.skipped((selected - nodes.len()) as u64);
+    space::check(dest, &nodes, max_dest_bytes, check_space)?;
+    let executor = match sequential {
+        true => Executor::Sequential,
+        false => executor,
+    };
+    if let Some(progress) = &progress {
+        progress.plan(&nodes);
+    }
+    let shard_manifest =
+        shard_levels.map(|levels| ShardManifest::build(levels, recompress, &nodes));
+    let block_decoder = archive.block_decoder.as_ref().map(|decoder| {
+        decoder.with_workers(strategy.block_workers(
+            &nodes,
+            executor.concurrency(),
+            block_decode_workers,
+        ))
     });
+    let block_decoder = block_decoder.as_deref();
 
-    if crates_filter.as_ref().is_some_and(|f| f.is_empty()) {
-        return Ok(());
+    let file_digests = Mutex::new(Vec::new());
+    let mechanisms = Arc::new(Detected::new(mechanisms));
+    let node_options = NodeOptions {
+        quota: quota.as_ref(),
+        throttle: throttle.as_ref(),
+        shard_levels,
+        subtree,
+        overwrite,
+        recompress,
+        progress: progress.as_ref(),
+        cancel: cancel.as_ref(),
+        cleanup_partial: false,
+        metadata,
+        digests: digests.then_some(&file_digests),
+        mechanisms: &mechanisms,
+        journal: journal.as_ref(),
+        xattrs: xattrs.as_ref(),
+        escape,
+        write_buffer: write_buffer.unwrap_or(DEFAULT_WRITE_BUFFER),
+        read_buffer: read_buffer.unwrap_or(DEFAULT_READ_BUFFER),
+        durability,
+        tally: &tally,
+        counters: &archive.counters,
+    };
+    let unrecoverable = Mutex::new(Vec::new());
+    let extracted = Mutex::new(Vec::new());
+    let metadata_warnings = Mutex::new(Vec::new());
+    let (parent, in_flight) = (tracing::Span::current(), trace::InFlight::default());
+    // whether entries were left out because of a shutdown, rather than one arriving after the last
+    let interrupted = AtomicBool::new(false);
+    let stop = |entries: usize| {
+        let stop = shutdown
+            .as_ref()
+            .is_some_and(|shutdown| shutdown.load(atomic::Ordering::Relaxed));
+        if stop && entries > 0 {
+            interrupted.store(true, atomic::Ordering::Relaxed);
+            tally.skipped(entries as u64);
+        }
+        stop
+    };
+    let extract_entry = |&node: &&Node<SquashfsFileReader>| -> Result<()> {
+        CancelToken::check(cancel.as_ref())?;
+        if stop(1) {
+            return Ok(());
+        }
+        let span = trace::entry(&parent, node);
+        let _task = in_flight.start(&span);
+        let _span = span.entered();
+        let started = Instant::now();
+        let extract = || extract_node_blocking(dest, filesystem, block_decoder, node_options, node);
+        let mut attempts = 0;
+        // not counted as extraction time by the slow entry warning
+        let mut backed_off = Duration::ZERO;
+        let (res, class) = loop {
+            let res = match salvage {
+                true => report::catch_panic(extract),
+                false => extract(),
+            };
+            let classified = classify::classify(error_classifier.as_ref(), &res, node, attempts);
+            let Some(backoff) = classified.retry else {
+                break (res, classified.class);
+            };
+            tracing::warn!(path = %node.fullpath.display(), ?backoff, "retrying entry");
+            std::thread::sleep(backoff);
+            backed_off += backoff;
+            attempts += 1;
+        };
+        let res = match attempts {
+            0 => res,
+            _ => res.with_context(|| format!("gave up after {} attempts", attempts + 1)),
+        };
+        trace::entry_done(node, &res, started.elapsed(), attempts + 1);
+        let res = match res {
+            Err(e) if metadata_errors == MetadataErrorPolicy::Warn && MetadataError::caused(&e) => {
+                metadata_warnings
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(MetadataWarning::new(node.fullpath.clone(), &e));
+                tally.extracted(node);
+                Ok(())
+            }
+            res => res,
+        };
+        let elapsed = started.elapsed().saturating_sub(backed_off);
+        slow_entry::warn_if_slow(node, elapsed, slow_entry_threshold);
+        archive.counters.record_entry(node, &res);
+        if let Some(progress) = &progress {
+            progress.record(node);
+        }
+        match res {
+            Ok(()) if salvage => {
+                extracted
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(node.fullpath.clone());
+                Ok(())
+            }
+            Err(e) if class == Some(ErrorClass::Ignorable) => {
+                tracing::warn!(path = %node.fullpath.display(), "ignoring failed entry: {e:#}");
+                tally.skipped(1);
+                Ok(())
+            }
+            Err(e) if salvage && class != Some(ErrorClass::Fatal) => {
+                tally.skipped(1);
+                let dest_path = shard::dest_path(dest, node, shard_levels, recompress, subtree);
+                unrecoverable
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(Unrecoverable::new(
+                        node,
+                        &e,
+                        dest_path,
+                        quarantine.as_deref(),
+                    ));
+                Ok(())
+            }
+            res => res.map_err(|e| Error::entry(node, e).into()),
+        }
+    };
+    let (planned, links) = hardlink::plan(&nodes, mechanisms.hardlinks());
+    let (planned, symlinks) = confine::defer_symlinks(planned, sequential);
+    trace::started(nodes.len(), executor.concurrency());
+    executor.try_for_each(&planned, extract_entry)?;
+    let copies = match stop(links.len()) {
+        true => Vec::new(),
+        false => hardlink::link_blocking(dest, &links, node_options)?,
+    };
+    executor.try_for_each(&copies, extract_entry)?;
+    Executor::Sequential.try_for_each(&symlinks, extract_entry)?;
+    in_flight.finish();
+    let dirs = metadata.dirs(dest, &nodes, shard_levels, recompress, subtree);
+    let mut metadata_warnings = metadata_warnings
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner);
+    metadata_warnings.extend(metadata::finish_dirs(dirs, metadata_errors)?);
+    durability::sync_dirs(durability.dirs(dest, &nodes, shard_levels, recompress, subtree))?;
+    // a shutdown leaves a partial tree: no manifests, the journal kept for a resumed extraction
+    // to pick up from, and the staging directory of an atomic one discarded
+    let interrupted = interrupted.into_inner();
+    if interrupted {
+        tracing::info!(dest = %dest.display(), "extraction interrupted by shutdown");
+    }
+    let shard_manifest = shard_manifest.filter(|_| !interrupted);
+    let (recompress_manifest, sha256sums, digests) = match interrupted {
+        true => (None, false, false),
+        false => (recompress, sha256sums, digests),
+    };
+    if let Some(manifest) = shard_manifest {
+        std::fs::create_dir_all(dest)
+            .with_context(|| format!("create dir '{}'", dest.display()))?;
+        manifest.write(dest)?;
+    }
+    if let Some(recompress) = recompress_manifest {
+        RecompressManifest::build(dest, &nodes, recompress, shard_levels, subtree)?.write(dest)?;
+    }
+    if sha256sums {
+        let dest_paths = nodes
+            .iter()
+            .filter_map(|node| shard::dest_path(dest, node, shard_levels, recompress, subtree))
+            .collect();
+        sums::write_sums(dest, dest_paths, hash)?;
+    }
+    let digests = match digests {
+        true => {
+            let files = file_digests
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner);
+            Some(reapi::tree_digests(dest, files)?)
+        }
+        false => None,
+    };
+    if let Some(staging) = staging.filter(|_| !interrupted) {
+        staging.commit()?;
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(catalog) = catalog.filter(|_| !interrupted) {
+        let dest_paths = nodes
+            .iter()
+            .filter_map(|node| shard::dest_path(published, node, shard_levels, recompress, subtree))
+            .collect();
+        catalog::write_catalog(&catalog, &archive.source.name(), dest_paths, extracted_at)?;
     }
 
-    let squashfs_f = std::fs::File::open(squashfs_path)
-        .with_context(|| format!("open squashfs '{}'", squashfs_path.display()))?;
-    let squashfs_buf = std::io::BufReader::new(squashfs_f);
-    let squashfs = Squashfs::from_reader(squashfs_buf)
-        .with_context(|| format!("read squashfs '{}'", squashfs_path.display()))?;
+    let mut unrecoverable = unrecoverable
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner);
+    unrecoverable.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut extracted = extracted
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner);
+    extracted.sort_unstable();
+    if let Some(journal) = journal.filter(|_| unrecoverable.is_empty() && !interrupted) {
+        journal.finish()?;
+    }
+    Ok(ExtractReport {
+        summary: tally.summary(),
+        unrecoverable,
+        extracted,
+        metadata_warnings,
+        digests,
+        mechanisms: Some(mechanisms.used()),
+        interrupted,
+        unmatched_filter,
+        ..ExtractReport::default()
+    })
+}
 
-    let filesystem = squashfs
+/// The [`Filter`] selecting the given tpcii crates, or everything.
+pub(crate) fn tpcii_filter(crates_filter: Option<HashSet<String>>) -> Filter {
+    crates_filter.map_or(Filter::All, Filter::crates)
+}
+
+pub(crate) fn open_filesystem(
+    source: Arc<dyn SquashSource>,
+    kind: Option<Kind>,
+    parsing: Parsing,
+) -> Result<FilesystemReader<'static>> {
+    open_filesystem_counted(source, kind, parsing, &Arc::default())
+}
+
+/// [`open_filesystem`], counting the reads of the archive into `counters`.
+pub(crate) fn open_filesystem_counted(
+    source: Arc<dyn SquashSource>,
+    kind: Option<Kind>,
+    parsing: Parsing,
+    counters: &Arc<Counters>,
+) -> Result<FilesystemReader<'static>> {
+    let name = source.name();
+    let kind = format::resolve_kind(&*source, kind)?;
+    let squashfs_buf =
+        std::io::BufReader::new(Counting(SourceReader::new(source), Arc::clone(counters)));
+    let squashfs = Squashfs::from_reader_with_offset_and_kind(squashfs_buf, 0, kind)
+        .with_context(|| format!("read squashfs '{name}'"))?;
+    parsing::check_archive(&squashfs, &name, parsing)?;
+
+    squashfs
         .into_filesystem_reader()
-        .with_context(|| format!("convert squashfs to reader '{}'", squashfs_path.display()))?;
-
-    let nodes: Vec<&Node<_>> = filesystem
-        .files()
-        .filter(|node| {
-            crates_filter
-                .as_ref()
-                .map(|f| f.contains(&node.fullpath))
-                .unwrap_or(true)
-        })
-        .collect();
+        .with_context(|| format!("convert squashfs to reader '{name}'"))
+}
 
-    nodes
-        .into_par_iter()
-        .try_for_each(|node| extract_node_blocking(dest, &filesystem, node))
+/// [`open_filesystem`] for a local archive.
+pub(crate) fn open_filesystem_path(
+    squashfs_path: &Path,
+    kind: Option<Kind>,
+    parsing: Parsing,
+) -> Result<FilesystemReader<'static>> {
+    open_filesystem(Arc::new(FileSource::open(squashfs_path)?), kind, parsing)
 }
 
 #[inline]
 fn extract_node_blocking(
     root: impl AsRef<Path>,
     filesystem: &FilesystemReader<'_>,
+    block_decoder: Option<&BlockDecoder>,
+    options: NodeOptions<'_>,
     node: &Node<SquashfsFileReader>,
 ) -> anyhow::Result<()> {
-    let path = &node.fullpath;
-    let fullpath = path.strip_prefix(Component::RootDir).unwrap_or(path);
-    let dest_path = root.as_ref().join(fullpath);
+    let NodeOptions {
+        quota,
+        throttle,
+        shard_levels,
+        subtree,
+        overwrite,
+        recompress,
+        progress,
+        metadata,
+        digests,
+        journal,
+        mechanisms,
+        xattrs,
+        escape,
+        durability,
+        tally,
+        counters,
+        ..
+    } = options;
+    let root = root.as_ref();
+    let Some(dest_path) = shard::dest_path(root, node, shard_levels, recompress, subtree) else {
+        return Ok(());
+    };
+    confine::check_path(root, &dest_path, node)?;
+    if journal
+        .and_then(|journal| journal.done(node))
+        .is_some_and(|digest| resume::intact(&dest_path, digest))
+    {
+        tally.skipped(1);
+        return Ok(());
+    }
 
     std::fs::create_dir_all(
         dest_path
@@ -88,43 +811,175 @@ fn extract_node_blocking(
     )
     .with_context(|| format!("create dir to unpack '{}'", dest_path.display()))?;
 
+    let existing = match std::fs::symlink_metadata(&dest_path) {
+        Ok(existing) => Some(existing),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("stat '{}'", dest_path.display())),
+    };
+    match conflict::resolve(overwrite, node, &dest_path, existing.as_ref())? {
+        Conflict::None => {}
+        Conflict::Skip => {
+            tally.skipped(1);
+            return Ok(());
+        }
+        Conflict::Reuse => {
+            tally.reused();
+            return Ok(());
+        }
+        Conflict::Replace { dir: true } => std::fs::remove_dir_all(&dest_path)
+            .with_context(|| format!("remove dir '{}'", dest_path.display()))?,
+        Conflict::Replace { dir: false } => std::fs::remove_file(&dest_path)
+            .with_context(|| format!("remove '{}'", dest_path.display()))?,
+    }
+
     match &node.inner {
         InnerNode::File(file) => {
-            let fd = std::fs::File::create(&dest_path)
-                .with_context(|| format!("create file to unpack: '{}'", dest_path.display()))?;
-            let mut writer = std::io::BufWriter::with_capacity(file.basic.file_size as usize, &fd);
-            let file = filesystem.file(&file.basic);
-            let mut reader = file.reader();
+            let reservation = match quota {
+                Some(quota) => match quota.reserve(u64::from(file.basic.file_size))? {
+                    Some(reservation) => Some(reservation),
+                    None => {
+                        tracing::warn!(path = %dest_path.display(), "skipping file over quota");
+                        tally.skipped(1);
+                        return Ok(());
+                    }
+                },
+                None => None,
+            };
 
-            std::io::copy(&mut reader, &mut writer)
+            let new_file = mechanisms.create(&dest_path, true)?;
+            let fd = new_file.file();
+            let sparse = mechanisms.sparse() && recompress.is_none();
+            if recompress.is_none() && !(sparse && sparse::has_holes(&file.basic)) {
+                mechanisms.preallocate(fd, file.basic.file_size.into(), &dest_path)?;
+            }
+            let copied = match block_decoder {
+                Some(decoder) if options.verbatim(node) => decoder
+                    .copy_uncompressed(filesystem, &file.basic, mechanisms, fd, &dest_path)
+                    .with_context(|| format!("copy file into '{}'", dest_path.display()))?,
+                _ => false,
+            };
+            let mut hasher = (digests.is_some() || journal.is_some()).then(Hasher::default);
+            if !copied {
+                let mut writer = Encoder::new(
+                    std::io::BufWriter::with_capacity(
+                        options.write_buffer(file.basic.file_size),
+                        Hashing::new(
+                            Counting(
+                                Throttled::new(
+                                    Timed::new(Stage::Write, Sparse::new(fd, sparse)),
+                                    throttle,
+                                ),
+                                Arc::clone(counters),
+                            ),
+                            hasher.as_mut(),
+                        ),
+                    ),
+                    recompress,
+                )
+                .with_context(|| format!("set up recompression of '{}'", dest_path.display()))?;
+                let mut reporting = Reporting::new(&mut writer, EntryEvents::new(progress, node));
+                match block_decoder {
+                    Some(decoder) => decoder.copy(filesystem, &file.basic, &mut reporting),
+                    None => {
+                        let file = filesystem.file(&file.basic);
+                        let mut reader = Timed::new(Stage::Decompress, file.reader());
+                        std::io::copy(&mut reader, &mut reporting).map_err(Into::into)
+                    }
+                }
+                .and_then(|_| writer.finish().map_err(Into::into))
+                .and_then(|_| match sparse {
+                    // the file may end in a hole
+                    true => fd.set_len(file.basic.file_size.into()).map_err(Into::into),
+                    false => Ok(()),
+                })
                 .with_context(|| format!("extract file into '{}'", dest_path.display()))?;
-            std::fs::set_permissions(&dest_path, std::fs::Permissions::from_mode(0o644))
-                .with_context(|| format!("chmod 0o644 '{}'", dest_path.display()))?;
+            }
+            let digest = hasher.map(Hasher::finish);
+            if let (Some(digests), Some(digest)) = (digests, &digest) {
+                digests
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push((dest_path.clone(), digest.clone()));
+            }
+            let applied = metadata
+                .entry(node)
+                .apply_file(fd, &dest_path)
+                .and_then(|()| xattr::restore_entry_file(xattrs, node, fd, &dest_path));
+            if applied.is_ok() || metadata.tolerates_errors() {
+                durability.sync_file(fd, &dest_path)?;
+                if let Some(reservation) = reservation {
+                    let written = fd
+                        .metadata()
+                        .with_context(|| format!("stat '{}'", dest_path.display()))?;
+                    reservation.charge(&written);
+                }
+                new_file.publish()?;
+            }
+            applied?;
+            if let (Some(journal), Some(digest)) = (journal, digest) {
+                journal.record(node, digest)?;
+            }
         }
         InnerNode::Symlink(SquashfsSymlink { link }) => {
-            std::os::unix::fs::symlink(link, &dest_path)
+            if !platform::SYMLINKS {
+                tracing::debug!(path = %dest_path.display(), "skipping symlink");
+                tally.skipped(1);
+                return Ok(());
+            }
+            let link = &shard::link_target(filesystem, node, link, shard_levels, recompress);
+            confine::check_symlink(root, &dest_path, link, escape, node)?;
+            symlink(link, &dest_path)
                 .with_context(|| format!("symlink file into '{}'", dest_path.display()))?;
-            lchmod(&dest_path, &std::fs::Permissions::from_mode(0o644))
-                .with_context(|| format!("lchmod 0o644 '{}'", dest_path.display()))?;
+            metadata.entry(node).apply(&dest_path)?;
+            xattr::restore_entry(xattrs, node, &dest_path)?;
         }
         InnerNode::Dir(_) => {
             std::fs::create_dir_all(&dest_path)
                 .with_context(|| format!("create dir into '{}'", dest_path.display()))?;
-            std::fs::set_permissions(&dest_path, std::fs::Permissions::from_mode(0o755))
-                .with_context(|| format!("chmod 0o755 '{}'", dest_path.display()))?;
+            profile::timed(Stage::Chmod, || platform::set_mode(&dest_path, 0o755))
+                .with_context(|| format!("chmod 0o755 '{}'", dest_path.display()))
+                .map_err(MetadataError::from)?;
+            xattr::restore_entry(xattrs, node, &dest_path)?;
+        }
+        InnerNode::CharacterDevice(_)
+        | InnerNode::BlockDevice(_)
+        | InnerNode::NamedPipe
+        | InnerNode::Socket => {
+            let special = Special::of(node).expect("node is a special file");
+            if !special.create(&dest_path)? {
+                tally.skipped(1);
+                return Ok(());
+            }
+            metadata.entry(node).apply(&dest_path)?;
+            xattr::restore_entry(xattrs, node, &dest_path)?;
         }
-        InnerNode::CharacterDevice(_) => unimplemented!(),
-        InnerNode::BlockDevice(_) => unimplemented!(),
-        InnerNode::NamedPipe => unimplemented!(),
-        InnerNode::Socket => unimplemented!(),
     }
 
+    tally.extracted(node);
     Result::<(), anyhow::Error>::Ok(())
 }
 
-fn lchmod(symlink: impl AsRef<std::path::Path>, mode: &std::fs::Permissions) -> anyhow::Result<()> {
-    use nix::{fcntl, sys::stat};
-    use std::os::unix::fs::PermissionsExt;
+/// Create a symlink, accepting an identical one left behind by an interrupted extraction.
+pub(crate) fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    match platform::symlink(target, path) {
+        Err(e)
+            if e.kind() == std::io::ErrorKind::AlreadyExists
+                && std::fs::read_link(path).is_ok_and(|existing| existing == target) =>
+        {
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// Linux ignores the mode of symlinks and refuses to change it, which is tolerated.
+#[cfg(unix)]
+pub(crate) fn lchmod(
+    symlink: impl AsRef<std::path::Path>,
+    mode: &std::fs::Permissions,
+) -> anyhow::Result<()> {
+    use nix::{errno::Errno, sys::stat};
+    use std::os::{fd::AsRawFd, unix::fs::PermissionsExt};
 
     let path = symlink.as_ref();
     let mode = stat::Mode::from_bits_truncate(mode.mode());
@@ -142,14 +997,15 @@ fn lchmod(symlink: impl AsRef<std::path::Path>, mode: &std::fs::Permissions) ->
         .file_name()
         .with_context(|| format!("get filename of symlink '{}'", path.display()))?;
 
-    let dir_fd = fcntl::open(dir, fcntl::OFlag::empty(), stat::Mode::empty())
-        .with_context(|| format!("open dir '{}'", path.display()))?;
+    let dir = std::fs::File::open(dir).with_context(|| format!("open dir '{}'", dir.display()))?;
 
-    stat::fchmodat(
-        Some(dir_fd),
+    match stat::fchmodat(
+        Some(dir.as_raw_fd()),
         filename,
         mode,
         stat::FchmodatFlags::NoFollowSymlink,
-    )
-    .with_context(|| format!("fchmodat {:#o} of symlink '{}'", mode, path.display()))
+    ) {
+        Err(Errno::EOPNOTSUPP) => Ok(()),
+        res => res.with_context(|| format!("fchmodat {:#o} of symlink '{}'", mode, path.display())),
+    }
 }
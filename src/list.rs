@@ -0,0 +1,197 @@
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use backhand::{InnerNode, Node, SquashfsFileReader};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+
+use crate::{Archive, Error, ExtractOptions};
+
+/// An entry of an archive, as listed by [`list_blocking`] and [`list_async`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryInfo {
+    /// Absolute path in the archive, as matched by [`Filter`](crate::Filter).
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    /// File size, or length of the target of a symlink.
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Seconds since the Unix epoch.
+    pub mtime: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    CharacterDevice,
+    BlockDevice,
+    NamedPipe,
+    Socket,
+}
+
+impl EntryKind {
+    pub(crate) fn of(inner: &InnerNode<SquashfsFileReader>) -> Self {
+        match inner {
+            InnerNode::File(_) => Self::File,
+            InnerNode::Dir(_) => Self::Dir,
+            InnerNode::Symlink(_) => Self::Symlink,
+            InnerNode::CharacterDevice(_) => Self::CharacterDevice,
+            InnerNode::BlockDevice(_) => Self::BlockDevice,
+            InnerNode::NamedPipe => Self::NamedPipe,
+            InnerNode::Socket => Self::Socket,
+        }
+    }
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::File => "file",
+            Self::Dir => "directory",
+            Self::Symlink => "symlink",
+            Self::CharacterDevice => "character device",
+            Self::BlockDevice => "block device",
+            Self::NamedPipe => "named pipe",
+            Self::Socket => "socket",
+        })
+    }
+}
+
+/// An entry of an archive along with its contents, as yielded by
+/// [`Archive::entries_async`](crate::Archive::entries_async).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub info: EntryInfo,
+    /// Contents of a file, or target of a symlink. Empty for other kinds.
+    pub contents: Vec<u8>,
+}
+
+/// An entry of an archive borrowed from it, as visited by [`for_each_entry`], which unlike
+/// [`EntryInfo`] costs no allocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryRef<'a> {
+    /// Absolute path in the archive, as matched by [`Filter`](crate::Filter). Borrowed, unless
+    /// the archive was opened with [`compact_paths`](crate::IoOptions::compact_paths) and
+    /// the entry was looked up rather than visited.
+    pub path: Cow<'a, Path>,
+    pub kind: EntryKind,
+    /// File size, or length of the target of a symlink.
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Seconds since the Unix epoch.
+    pub mtime: u32,
+}
+
+impl<'a> EntryRef<'a> {
+    pub(crate) fn new(node: &'a Node<SquashfsFileReader>) -> Self {
+        Self::with_path(node, Cow::Borrowed(&node.fullpath))
+    }
+
+    /// The entry of `node`, at `path` rather than the path backhand recorded for it, which
+    /// [`compact_paths`](crate::IoOptions::compact_paths) drops.
+    pub(crate) fn with_path(node: &Node<SquashfsFileReader>, path: Cow<'a, Path>) -> Self {
+        let size = match &node.inner {
+            InnerNode::File(file) => file.basic.file_size.into(),
+            InnerNode::Symlink(symlink) => symlink.link.as_os_str().len() as u64,
+            _ => 0,
+        };
+        Self {
+            path,
+            kind: EntryKind::of(&node.inner),
+            size,
+            mode: u32::from(node.header.permissions & 0o7777),
+            uid: node.header.uid,
+            gid: node.header.gid,
+            mtime: node.header.mtime,
+        }
+    }
+
+    /// The last component of the path, or `/` for the root directory.
+    pub fn name(&self) -> &OsStr {
+        self.path.file_name().unwrap_or(self.path.as_os_str())
+    }
+
+    /// The path as a string, borrowed unless it is not valid UTF-8, in which case the invalid
+    /// bytes are escaped as `\xNN`.
+    pub fn path_str(&self) -> Cow<'_, str> {
+        let bytes = self.path.as_os_str().as_encoded_bytes();
+        if let Ok(path) = std::str::from_utf8(bytes) {
+            return Cow::Borrowed(path);
+        }
+        let mut escaped = String::with_capacity(bytes.len());
+        for chunk in bytes.utf8_chunks() {
+            escaped.push_str(chunk.valid());
+            for byte in chunk.invalid() {
+                escaped.push_str(&format!("\\x{byte:02x}"));
+            }
+        }
+        Cow::Owned(escaped)
+    }
+
+    /// An owned copy of the entry.
+    pub fn to_info(&self) -> EntryInfo {
+        EntryInfo {
+            path: self.path.clone().into_owned(),
+            kind: self.kind,
+            size: self.size,
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            mtime: self.mtime,
+        }
+    }
+}
+
+impl EntryInfo {
+    pub(crate) fn new(node: &Node<SquashfsFileReader>) -> Self {
+        EntryRef::new(node).to_info()
+    }
+}
+
+/// The entries of `squashfs`, read with the `kind`, `parsing` and `direct_io` of `options`,
+/// without extracting anything. Entries are copied out of the parsed archive as they are
+/// iterated, rather than all at once.
+pub fn list_blocking(
+    squashfs: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<impl Iterator<Item = EntryInfo>, Error> {
+    let archive = Archive::open(squashfs, options)?;
+    let len = archive.filesystem.root.nodes.len();
+    Ok((0..len).map(move |i| archive.entry_info(i)))
+}
+
+/// Call `visit` with each entry of `squashfs`, read with the `kind`, `parsing` and `direct_io` of
+/// `options`. See [`Archive::for_each_entry`] to list an archive repeatedly without parsing it
+/// again.
+pub fn for_each_entry(
+    squashfs: impl AsRef<Path>,
+    options: ExtractOptions,
+    visit: impl FnMut(&EntryRef<'_>),
+) -> Result<(), Error> {
+    Archive::open(squashfs, options)?.for_each_entry(visit);
+    Ok(())
+}
+
+/// Async flavor of [`list_blocking`].
+pub async fn list_async(
+    squashfs: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<impl Stream<Item = EntryInfo>, Error> {
+    let squashfs = squashfs.as_ref().to_path_buf();
+    let entries = tokio::task::spawn_blocking(move || list_blocking(squashfs, options))
+        .await
+        .context("spawn blocking listing task")??;
+    Ok(futures::stream::iter(entries))
+}
@@ -0,0 +1,636 @@
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(target_os = "linux")]
+use std::{
+    ffi::CString,
+    fs::FileTimes,
+    io::{Seek, SeekFrom},
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+};
+use std::{
+    ffi::OsString,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{Context, Result};
+#[cfg(unix)]
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::{
+    fcntl::{copy_file_range, fallocate, AtFlags, FallocateFlags},
+    libc,
+    unistd::linkat,
+};
+use serde::{Deserialize, Serialize};
+
+/// Alignment of the offsets and lengths cloned with `FICLONERANGE`, the block size of practically
+/// every reflink-capable filesystem.
+#[cfg(target_os = "linux")]
+const REFLINK_ALIGN: u64 = 4096;
+
+/// `struct file_clone_range` from `linux/fs.h`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
+#[cfg(target_os = "linux")]
+nix::ioctl_write_ptr!(ficlonerange, 0x94, 13, FileCloneRange);
+
+/// Filesystem mechanisms the extractors use where the destination supports them. Turning one off
+/// in [`IoOptions::mechanisms`](crate::IoOptions::mechanisms) keeps it from being used
+/// when detecting its support goes wrong; [`ExtractReport::mechanisms`](crate::ExtractReport::mechanisms)
+/// says which were used. Only `hardlinks` and `sparse` are used off Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Mechanisms {
+    /// Preallocate files to their size before writing them.
+    pub fallocate: bool,
+    /// Extract files sharing their data and metadata with an earlier file of the archive as
+    /// hardlinks to it, rather than as copies. Off by default: backhand does not expose inode
+    /// numbers, so hardlinks are told by files sharing their blocks, fragment, size, mode, owner
+    /// and mtime, which also links identical files that squashfs deduplicated but that were
+    /// distinct in the archived tree. Writing to one of them then changes the others.
+    pub hardlinks: bool,
+    /// Write files into an anonymous `O_TMPFILE` and link it into place once written, rather
+    /// than into a hidden temporary file renamed into place, so that an interrupted extraction
+    /// leaves nothing behind.
+    pub tmpfile: bool,
+    /// Clone files stored uncompressed in a local archive into place with `FICLONERANGE`, sharing
+    /// their extents with the archive, where the destination is on the same reflink-capable
+    /// filesystem and the data is block-aligned.
+    pub reflink: bool,
+    /// Copy files stored uncompressed in a local archive into place with `copy_file_range`,
+    /// rather than through userspace buffers.
+    pub copy_file_range: bool,
+    /// Leave the aligned blocks of zeros of files as holes rather than writing them, and do not
+    /// preallocate files the archive stores holes in. Turn it off for destinations that fill
+    /// holes anyway, where looking for zeros is wasted work.
+    pub sparse: bool,
+}
+
+impl Default for Mechanisms {
+    fn default() -> Self {
+        Self {
+            fallocate: true,
+            hardlinks: false,
+            tmpfile: true,
+            reflink: true,
+            copy_file_range: true,
+            sparse: true,
+        }
+    }
+}
+
+/// The [`Mechanisms`] still in use during one extraction, each turned off the first time the
+/// destination turns out not to support it.
+#[derive(Debug)]
+pub(crate) struct Detected {
+    fallocate: AtomicBool,
+    hardlinks: AtomicBool,
+    /// Shared with the `O_TMPFILE`s created, which turn it off if they cannot be linked into
+    /// place.
+    tmpfile: Arc<AtomicBool>,
+    reflink: AtomicBool,
+    copy_file_range: AtomicBool,
+    sparse: bool,
+}
+
+impl Detected {
+    pub(crate) fn new(enabled: Mechanisms) -> Self {
+        #[cfg(not(target_os = "linux"))]
+        let enabled = Mechanisms {
+            fallocate: false,
+            tmpfile: false,
+            reflink: false,
+            copy_file_range: false,
+            ..enabled
+        };
+        Self {
+            fallocate: AtomicBool::new(enabled.fallocate),
+            hardlinks: AtomicBool::new(enabled.hardlinks),
+            tmpfile: Arc::new(AtomicBool::new(enabled.tmpfile)),
+            reflink: AtomicBool::new(enabled.reflink),
+            copy_file_range: AtomicBool::new(enabled.copy_file_range),
+            sparse: enabled.sparse,
+        }
+    }
+
+    pub(crate) fn sparse(&self) -> bool {
+        self.sparse
+    }
+
+    pub(crate) fn hardlinks(&self) -> bool {
+        self.hardlinks.load(Ordering::Relaxed)
+    }
+
+    /// Whether hardlinking `link` to `original` succeeded, or it has to be copied instead because
+    /// `original` is missing or the destination does not support hardlinks.
+    pub(crate) fn linked(
+        &self,
+        res: std::io::Result<()>,
+        original: &Path,
+        link: &Path,
+    ) -> Result<bool> {
+        let Err(e) = res else {
+            return Ok(true);
+        };
+        // the original was not extracted
+        if e.kind() == std::io::ErrorKind::NotFound {
+            return Ok(false);
+        }
+        #[cfg(unix)]
+        let unsupported = match e.raw_os_error().map(Errno::from_raw) {
+            // the original has as many links as it can
+            Some(Errno::EMLINK) => return Ok(false),
+            errno => matches!(errno, Some(Errno::EXDEV | Errno::EPERM | Errno::EOPNOTSUPP)),
+        };
+        // errors do not tell unsupported hardlinks apart elsewhere
+        #[cfg(not(unix))]
+        let unsupported = true;
+        if !unsupported {
+            return Err(e).with_context(|| {
+                format!("hardlink '{}' to '{}'", link.display(), original.display())
+            });
+        }
+        if self.hardlinks.swap(false, Ordering::Relaxed) {
+            tracing::debug!(path = %link.display(), "hardlinks unsupported: {e}");
+        }
+        Ok(false)
+    }
+
+    /// Reserve `len` bytes for the file being written to `path`, which must be written in full.
+    pub(crate) fn preallocate(&self, fd: &File, len: u64, path: &Path) -> Result<()> {
+        if len == 0 || !self.fallocate.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let len = i64::try_from(len).unwrap_or(i64::MAX);
+            match fallocate(fd.as_raw_fd(), FallocateFlags::empty(), 0, len) {
+                Ok(()) => Ok(()),
+                // the destination is out of space, which writing would run into as well
+                Err(e @ (Errno::ENOSPC | Errno::EDQUOT)) => Err(std::io::Error::from(e))
+                    .with_context(|| format!("preallocate {len} bytes for '{}'", path.display())),
+                Err(e) => {
+                    if self.fallocate.swap(false, Ordering::Relaxed) {
+                        tracing::debug!(path = %path.display(), "fallocate unsupported: {e}");
+                    }
+                    Ok(())
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (fd, path);
+            Ok(())
+        }
+    }
+
+    /// Create the file to write to `path`, which only appears there once
+    /// [published](NewFile::publish). Unless published, a temporary file is removed when dropped
+    /// if `cleanup`.
+    pub(crate) fn create(&self, path: &Path, cleanup: bool) -> Result<NewFile> {
+        let dir = path
+            .parent()
+            .expect("path is guaranteed to contain a parent");
+        #[cfg(target_os = "linux")]
+        if self.tmpfile.load(Ordering::Relaxed) {
+            // readable, to be copied if it cannot be linked into place
+            let tmpfile = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_TMPFILE)
+                .mode(0o600)
+                .open(dir);
+            match tmpfile {
+                Ok(file) => {
+                    return Ok(NewFile {
+                        file,
+                        path: path.to_path_buf(),
+                        tmp: None,
+                        tmpfile: Some(Arc::clone(&self.tmpfile)),
+                        cleanup,
+                    })
+                }
+                // the kernel or filesystem does not support it
+                Err(e)
+                    if matches!(
+                        e.raw_os_error().map(Errno::from_raw),
+                        Some(Errno::EOPNOTSUPP | Errno::EISDIR | Errno::EINVAL)
+                    ) =>
+                {
+                    if self.tmpfile.swap(false, Ordering::Relaxed) {
+                        tracing::debug!(path = %path.display(), "O_TMPFILE unsupported: {e}");
+                    }
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("create file to unpack: '{}'", path.display()))
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = dir;
+        let tmp = tmp_path(path);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options
+            .open(&tmp)
+            .with_context(|| format!("create file to unpack: '{}'", tmp.display()))?;
+        Ok(NewFile {
+            file,
+            path: path.to_path_buf(),
+            tmp: Some(tmp),
+            tmpfile: None,
+            cleanup,
+        })
+    }
+
+    /// Copy the `extents` of `src`, each an offset and a length, one after the other into `dst`
+    /// without going through userspace or moving the file offset of `dst`. Returns `false` if
+    /// neither `FICLONERANGE` nor `copy_file_range` can be used between them, leaving what was
+    /// copied to be overwritten by writing the file normally.
+    pub(crate) fn copy_extents(
+        &self,
+        src: &File,
+        extents: &[(u64, u64)],
+        dst: &File,
+        path: &Path,
+    ) -> Result<bool> {
+        if !self.reflink.load(Ordering::Relaxed) && !self.copy_file_range.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            self.copy_extents_linux(src, extents, dst, path)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (src, extents, dst, path);
+            Ok(false)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn copy_extents_linux(
+        &self,
+        src: &File,
+        extents: &[(u64, u64)],
+        dst: &File,
+        path: &Path,
+    ) -> Result<bool> {
+        let mut written = 0;
+        for &(offset, len) in extents {
+            let cloned = self.clone_range(src, offset, len, dst, written, path);
+            let (mut src_offset, mut dst_offset) = (offset + cloned, written + cloned);
+            let end = offset + len;
+            while src_offset < end {
+                if !self.copy_file_range.load(Ordering::Relaxed) {
+                    return Ok(false);
+                }
+                let (mut off_in, mut off_out) = (src_offset as i64, dst_offset as i64);
+                let chunk = usize::try_from(end - src_offset).unwrap_or(usize::MAX);
+                match copy_file_range(src, Some(&mut off_in), dst, Some(&mut off_out), chunk) {
+                    Ok(0) => anyhow::bail!(
+                        "archive ends before the data of '{}' at offset {src_offset}",
+                        path.display()
+                    ),
+                    Ok(n) => {
+                        src_offset += n as u64;
+                        dst_offset += n as u64;
+                    }
+                    Err(Errno::EINTR) => {}
+                    // the kernel, the filesystems or this pair of files do not support it
+                    Err(
+                        e @ (Errno::ENOSYS
+                        | Errno::EXDEV
+                        | Errno::EOPNOTSUPP
+                        | Errno::EINVAL
+                        | Errno::EBADF),
+                    ) => {
+                        if self.copy_file_range.swap(false, Ordering::Relaxed) {
+                            tracing::debug!(
+                                path = %path.display(),
+                                "copy_file_range unsupported: {e}"
+                            );
+                        }
+                        return Ok(false);
+                    }
+                    Err(e) => {
+                        return Err(std::io::Error::from(e))
+                            .with_context(|| format!("copy_file_range into '{}'", path.display()))
+                    }
+                }
+            }
+            written = dst_offset;
+        }
+        Ok(true)
+    }
+
+    /// Clone the longest block-aligned prefix of the `len` bytes of `src` at `offset` to
+    /// `dst_offset` in `dst`, returning its length, which is 0 if none could be cloned.
+    #[cfg(target_os = "linux")]
+    fn clone_range(
+        &self,
+        src: &File,
+        offset: u64,
+        len: u64,
+        dst: &File,
+        dst_offset: u64,
+        path: &Path,
+    ) -> u64 {
+        let len = len - len % REFLINK_ALIGN;
+        if len == 0
+            || offset % REFLINK_ALIGN != 0
+            || dst_offset % REFLINK_ALIGN != 0
+            || !self.reflink.load(Ordering::Relaxed)
+        {
+            return 0;
+        }
+        let range = FileCloneRange {
+            src_fd: i64::from(src.as_raw_fd()),
+            src_offset: offset,
+            src_length: len,
+            dest_offset: dst_offset,
+        };
+        // SAFETY: FICLONERANGE takes a pointer to a `struct file_clone_range`, which `range` is
+        match unsafe { ficlonerange(dst.as_raw_fd(), &range) } {
+            Ok(_) => len,
+            // the filesystem has a larger block size than assumed
+            Err(Errno::EINVAL) => 0,
+            Err(e) => {
+                if self.reflink.swap(false, Ordering::Relaxed) {
+                    tracing::debug!(path = %path.display(), "reflinks unsupported: {e}");
+                }
+                0
+            }
+        }
+    }
+
+    pub(crate) fn used(&self) -> Mechanisms {
+        Mechanisms {
+            fallocate: self.fallocate.load(Ordering::Relaxed),
+            hardlinks: self.hardlinks(),
+            tmpfile: self.tmpfile.load(Ordering::Relaxed),
+            reflink: self.reflink.load(Ordering::Relaxed),
+            copy_file_range: self.copy_file_range.load(Ordering::Relaxed),
+            sparse: self.sparse,
+        }
+    }
+}
+
+/// A file being extracted, see [`Detected::create`].
+#[derive(Debug)]
+pub(crate) struct NewFile {
+    file: File,
+    path: PathBuf,
+    /// The hidden file it is written to, or `None` for an `O_TMPFILE`.
+    tmp: Option<PathBuf>,
+    /// For an `O_TMPFILE`, whether [`Detected`] still creates them.
+    tmpfile: Option<Arc<AtomicBool>>,
+    cleanup: bool,
+}
+
+impl NewFile {
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Link or rename the file into place, once its data and metadata were written.
+    pub(crate) fn publish(mut self) -> Result<()> {
+        let res = match self.tmp.take() {
+            #[cfg(not(target_os = "linux"))]
+            None => unreachable!("O_TMPFILE is only used on Linux"),
+            #[cfg(target_os = "linux")]
+            None => self.publish_tmpfile(),
+            Some(tmp) => std::fs::rename(&tmp, &self.path)
+                .inspect_err(|_| {
+                    self.tmp = Some(tmp);
+                })
+                .map_err(Into::into),
+        };
+        res.with_context(|| format!("move file into '{}'", self.path.display()))
+    }
+
+    /// Link the `O_TMPFILE` into place. If something was put at its path since its conflicts
+    /// were resolved, it is linked to a hidden path renamed over it instead; if it cannot be
+    /// linked at all, it is copied to a hidden file renamed into place, and files are no longer
+    /// created as `O_TMPFILE`s.
+    #[cfg(target_os = "linux")]
+    fn publish_tmpfile(&mut self) -> Result<()> {
+        let linkable = match link_tmpfile(&self.file, &self.path) {
+            Ok(()) => return Ok(()),
+            Err(Errno::EEXIST) => true,
+            Err(Errno::ENOENT | Errno::EPERM) => false,
+            Err(e) => return Err(e.into()),
+        };
+        let tmp = tmp_path(&self.path);
+        let published = match linkable {
+            true => link_tmpfile(&self.file, &tmp).map_err(Into::into),
+            false => copy_tmpfile(&self.file, &tmp),
+        }
+        .and_then(|()| std::fs::rename(&tmp, &self.path).map_err(Into::into));
+        match &published {
+            Ok(()) if !linkable => {
+                let tmpfile = self.tmpfile.as_ref().expect("file is an O_TMPFILE");
+                if tmpfile.swap(false, Ordering::Relaxed) {
+                    tracing::debug!(path = %self.path.display(), "cannot link O_TMPFILE into place");
+                }
+            }
+            Ok(()) => {}
+            Err(_) => {
+                let _ = std::fs::remove_file(&tmp);
+            }
+        }
+        published
+    }
+}
+
+/// Give the `O_TMPFILE` `file` the name `path`. Linking it with `AT_EMPTY_PATH` needs
+/// CAP_DAC_READ_SEARCH, following its link in /proc needs /proc mounted.
+#[cfg(target_os = "linux")]
+fn link_tmpfile(file: &File, path: &Path) -> nix::Result<()> {
+    let fd = file.as_raw_fd();
+    linkat(Some(fd), Path::new(""), None, path, AtFlags::AT_EMPTY_PATH).or_else(|e| match e {
+        Errno::EEXIST => Err(e),
+        _ => linkat(
+            None,
+            Path::new(&format!("/proc/self/fd/{fd}")),
+            None,
+            path,
+            AtFlags::AT_SYMLINK_FOLLOW,
+        ),
+    })
+}
+
+/// Copy the contents, owner, mode, times and extended attributes of `file` to a new file at
+/// `path`.
+#[cfg(target_os = "linux")]
+fn copy_tmpfile(file: &File, path: &Path) -> Result<()> {
+    let mut copy = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("create '{}'", path.display()))?;
+    let mut contents = file.try_clone()?;
+    contents.seek(SeekFrom::Start(0))?;
+    std::io::copy(&mut contents, &mut copy)
+        .with_context(|| format!("copy file into '{}'", path.display()))?;
+    let metadata = file.metadata()?;
+    let copied = copy.metadata()?;
+    if (metadata.uid(), metadata.gid()) != (copied.uid(), copied.gid()) {
+        std::os::unix::fs::fchown(&copy, Some(metadata.uid()), Some(metadata.gid()))
+            .with_context(|| format!("chown '{}'", path.display()))?;
+    }
+    copy_xattrs(file, &copy).with_context(|| format!("copy xattrs to '{}'", path.display()))?;
+    copy.set_permissions(metadata.permissions())
+        .with_context(|| format!("chmod '{}'", path.display()))?;
+    let times = FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+    copy.set_times(times)
+        .with_context(|| format!("set times of '{}'", path.display()))
+}
+
+#[cfg(target_os = "linux")]
+fn copy_xattrs(from: &File, to: &File) -> Result<()> {
+    let names = read_xattr(|buf, len| unsafe { libc::flistxattr(from.as_raw_fd(), buf, len) })?;
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let name = CString::new(name)?;
+        let value = read_xattr(|buf, len| unsafe {
+            libc::fgetxattr(from.as_raw_fd(), name.as_ptr(), buf.cast(), len)
+        })?;
+        // SAFETY: `name` is nul-terminated and `value` outlives the call.
+        let set = unsafe {
+            libc::fsetxattr(
+                to.as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if set != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+/// The value `read` puts into a buffer of the length it asks for when given an empty one, as
+/// `flistxattr` and `fgetxattr` do, growing the buffer if the value grew meanwhile. Filesystems
+/// without xattrs have none.
+#[cfg(target_os = "linux")]
+fn read_xattr(read: impl Fn(*mut libc::c_char, usize) -> libc::ssize_t) -> Result<Vec<u8>> {
+    loop {
+        let len = match read(std::ptr::null_mut(), 0) {
+            -1 => match Errno::last() {
+                Errno::ENOTSUP => return Ok(Vec::new()),
+                e => return Err(e.into()),
+            },
+            len => len as usize,
+        };
+        let mut buf = vec![0u8; len];
+        match read(buf.as_mut_ptr().cast(), len) {
+            -1 => match Errno::last() {
+                Errno::ERANGE => continue,
+                e => return Err(e.into()),
+            },
+            read => {
+                buf.truncate(read as usize);
+                return Ok(buf);
+            }
+        }
+    }
+}
+
+impl Drop for NewFile {
+    fn drop(&mut self) {
+        if let (Some(tmp), true) = (&self.tmp, self.cleanup) {
+            let _ = std::fs::remove_file(tmp);
+        }
+    }
+}
+
+/// A hidden path next to `path`, unique within this process.
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn publishes_over_files_put_in_place_meanwhile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        for tmpfile in [true, false] {
+            let detected = Detected::new(Mechanisms {
+                tmpfile,
+                ..Mechanisms::default()
+            });
+            let new_file = detected.create(&path, true).unwrap();
+            let mut file = new_file.file();
+            file.write_all(b"new").unwrap();
+            std::fs::write(&path, "old").unwrap();
+            new_file.publish().unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), b"new");
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn copies_tmpfiles_with_their_metadata() {
+        use std::{
+            os::unix::fs::PermissionsExt,
+            time::{Duration, UNIX_EPOCH},
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let detected = Detected::new(Mechanisms::default());
+        let new_file = detected.create(&dir.path().join("f"), true).unwrap();
+        if new_file.tmp.is_some() {
+            // the filesystem does not support O_TMPFILE
+            return;
+        }
+        let mut file = new_file.file();
+        file.write_all(b"contents").unwrap();
+        crate::platform::set_file_mode(file, 0o640).unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        file.set_times(FileTimes::new().set_modified(mtime))
+            .unwrap();
+
+        let copy = dir.path().join("copy");
+        copy_tmpfile(file, &copy).unwrap();
+        assert_eq!(std::fs::read(&copy).unwrap(), b"contents");
+        let metadata = std::fs::metadata(&copy).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(metadata.modified().unwrap(), mtime);
+    }
+}
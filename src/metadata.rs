@@ -0,0 +1,248 @@
+use std::{
+    fs::{File, FileTimes},
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use backhand::{InnerNode, Node, SquashfsFileReader};
+#[cfg(unix)]
+use nix::{
+    fcntl::AtFlags,
+    sys::{
+        stat::{utimensat, UtimensatFlags},
+        time::TimeSpec,
+    },
+    unistd::{fchownat, Gid, Uid},
+};
+
+use crate::{
+    platform,
+    profile::{self, Stage},
+    recompress::Recompress,
+    shard, ExtractOptions, IdMap, MetadataErrorPolicy, MetadataWarning, PermissionPolicy,
+};
+
+/// Failure to set the owner, mode or mtime of an entry, which [`MetadataErrorPolicy::Warn`]
+/// tolerates.
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+pub(crate) struct MetadataError(#[from] anyhow::Error);
+
+impl MetadataError {
+    pub(crate) fn caused(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| cause.is::<Self>())
+    }
+}
+
+/// How [`ExtractOptions`] wants the metadata of extracted entries set.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Metadata {
+    permissions: PermissionPolicy,
+    ownership: bool,
+    id_map: Option<IdMap>,
+    mtime: bool,
+    errors: MetadataErrorPolicy,
+}
+
+/// The metadata to give one extracted entry.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EntryMetadata {
+    mode: u32,
+    owner: Option<(u32, u32)>,
+    mtime: Option<u32>,
+    symlink: bool,
+}
+
+impl Metadata {
+    pub(crate) fn new(options: &ExtractOptions) -> Self {
+        Self {
+            permissions: options.policy.permissions,
+            ownership: options.policy.preserve_ownership,
+            id_map: options.policy.id_map,
+            mtime: options.policy.preserve_mtime,
+            errors: options.policy.metadata_errors,
+        }
+    }
+
+    /// Whether an entry is kept when setting its metadata fails.
+    pub(crate) fn tolerates_errors(&self) -> bool {
+        self.errors == MetadataErrorPolicy::Warn
+    }
+
+    pub(crate) fn entry(&self, node: &Node<SquashfsFileReader>) -> EntryMetadata {
+        let header = &node.header;
+        let dir = matches!(node.inner, InnerNode::Dir(_));
+        let mode = match self.permissions {
+            PermissionPolicy::Default if dir => 0o755,
+            PermissionPolicy::Default => 0o644,
+            PermissionPolicy::Preserve => u32::from(header.permissions) & 0o7777,
+            PermissionPolicy::Force { dir_mode, .. } if dir => dir_mode,
+            PermissionPolicy::Force { file_mode, .. } => file_mode,
+            PermissionPolicy::Nix if dir || header.permissions & 0o100 != 0 => 0o555,
+            PermissionPolicy::Nix => 0o444,
+        };
+        let nix = self.permissions == PermissionPolicy::Nix;
+        let owner = (header.uid, header.gid);
+        EntryMetadata {
+            mode,
+            owner: (self.ownership && !nix)
+                .then(|| self.id_map.map_or(owner, |id_map| id_map.owner(owner))),
+            mtime: match nix {
+                true => Some(1),
+                false => self.mtime.then_some(header.mtime),
+            },
+            symlink: matches!(node.inner, InnerNode::Symlink(_)),
+        }
+    }
+
+    /// The extracted directories among `nodes` with their path in the archive, destination and
+    /// metadata, deepest first, for
+    /// [`finish_dirs`].
+    ///
+    /// Directories keep the writable 0o755 they are created with until every entry has been
+    /// extracted, as a restrictive mode would prevent creating their entries and creating them
+    /// changes their mtime.
+    pub(crate) fn dirs(
+        &self,
+        dest: &Path,
+        nodes: &[&Node<SquashfsFileReader>],
+        shard_levels: Option<u8>,
+        recompress: Option<Recompress>,
+        subtree: Option<&Path>,
+    ) -> Vec<(PathBuf, PathBuf, EntryMetadata)> {
+        if self.permissions == PermissionPolicy::Default && !self.ownership && !self.mtime {
+            return Vec::new();
+        }
+        let mut dirs: Vec<_> = nodes
+            .iter()
+            .filter(|node| matches!(node.inner, InnerNode::Dir(_)))
+            .filter_map(|node| {
+                let dest_path = shard::dest_path(dest, node, shard_levels, recompress, subtree)?;
+                Some((node.fullpath.clone(), dest_path, self.entry(node)))
+            })
+            .collect();
+        dirs.sort_by_key(|(_, dest_path, _)| std::cmp::Reverse(dest_path.components().count()));
+        dirs
+    }
+}
+
+/// Apply the metadata of `dirs`, returning the failures `policy` tolerates.
+pub(crate) fn finish_dirs(
+    dirs: Vec<(PathBuf, PathBuf, EntryMetadata)>,
+    policy: MetadataErrorPolicy,
+) -> Result<Vec<MetadataWarning>> {
+    let mut warnings = Vec::new();
+    for (path, dest_path, metadata) in dirs {
+        match metadata.apply(&dest_path) {
+            Err(e) if policy == MetadataErrorPolicy::Warn => {
+                warnings.push(MetadataWarning::new(path, &e));
+            }
+            res => res?,
+        }
+    }
+    Ok(warnings)
+}
+
+impl EntryMetadata {
+    pub(crate) fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Whether an entry with the status `stat` already has this metadata. The mode of symlinks,
+    /// which Linux ignores, is not compared.
+    #[cfg(unix)]
+    pub(crate) fn matches(&self, stat: &std::fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        (self.symlink || stat.mode() & 0o7777 == self.mode)
+            && self
+                .owner
+                .is_none_or(|owner| (stat.uid(), stat.gid()) == owner)
+            && self
+                .mtime
+                .is_none_or(|mtime| stat.mtime() == i64::from(mtime))
+    }
+
+    /// Off Unix, only the read-only attribute and the mtime are compared.
+    #[cfg(not(unix))]
+    pub(crate) fn matches(&self, stat: &std::fs::Metadata) -> bool {
+        (platform::mode(stat) & 0o222 == 0) == (self.mode & 0o222 == 0)
+            && self
+                .mtime
+                .is_none_or(|mtime| platform::mtime(stat) == i64::from(mtime))
+    }
+
+    /// Set the owner, then the mode (which a change of owner may strip setuid bits from), then
+    /// the mtime of the entry at `path`.
+    #[cfg(unix)]
+    pub(crate) fn apply(&self, path: &Path) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        profile::timed(Stage::Chmod, || {
+            if let Some((uid, gid)) = self.owner {
+                let (uid, gid) = (Uid::from_raw(uid), Gid::from_raw(gid));
+                fchownat(
+                    None,
+                    path,
+                    Some(uid),
+                    Some(gid),
+                    AtFlags::AT_SYMLINK_NOFOLLOW,
+                )
+                .with_context(|| format!("chown {uid}:{gid} '{}'", path.display()))?;
+            }
+            let permissions = std::fs::Permissions::from_mode(self.mode);
+            match self.symlink {
+                true => crate::lchmod(path, &permissions)
+                    .with_context(|| format!("lchmod {:#o} '{}'", self.mode, path.display()))?,
+                false => std::fs::set_permissions(path, permissions)
+                    .with_context(|| format!("chmod {:#o} '{}'", self.mode, path.display()))?,
+            }
+            if let Some(mtime) = self.mtime {
+                let mtime = TimeSpec::new(mtime.into(), 0);
+                utimensat(
+                    None,
+                    path,
+                    &TimeSpec::UTIME_OMIT,
+                    &mtime,
+                    UtimensatFlags::NoFollowSymlink,
+                )
+                .with_context(|| format!("set mtime of '{}'", path.display()))?;
+            }
+            Ok(())
+        })
+        .map_err(|e| MetadataError(e).into())
+    }
+
+    /// Off Unix, only the read-only attribute of the mode is set on directories, which keep the
+    /// mtime extracting their entries gave them.
+    #[cfg(not(unix))]
+    pub(crate) fn apply(&self, path: &Path) -> Result<()> {
+        profile::timed(Stage::Chmod, || {
+            platform::set_mode(path, self.mode)
+                .with_context(|| format!("chmod {:#o} '{}'", self.mode, path.display()))
+        })
+        .map_err(|e| MetadataError(e).into())
+    }
+
+    /// Like [`apply`](Self::apply), through `file`, which is not at `path` yet. Ownership is only
+    /// set on Unix.
+    pub(crate) fn apply_file(&self, file: &File, path: &Path) -> Result<()> {
+        profile::timed(Stage::Chmod, || {
+            #[cfg(unix)]
+            if let Some((uid, gid)) = self.owner {
+                std::os::unix::fs::fchown(file, Some(uid), Some(gid))
+                    .with_context(|| format!("chown {uid}:{gid} '{}'", path.display()))?;
+            }
+            platform::set_file_mode(file, self.mode)
+                .with_context(|| format!("chmod {:#o} '{}'", self.mode, path.display()))?;
+            if let Some(mtime) = self.mtime {
+                let mtime = UNIX_EPOCH + Duration::from_secs(mtime.into());
+                file.set_times(FileTimes::new().set_modified(mtime))
+                    .with_context(|| format!("set mtime of '{}'", path.display()))?;
+            }
+            Ok(())
+        })
+        .map_err(|e| MetadataError(e).into())
+    }
+}
@@ -0,0 +1,55 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::Duration,
+};
+
+use crate::{profile::Stage, EntryKind};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static METRICS: RwLock<Option<Arc<dyn Metrics>>> = RwLock::new(None);
+
+/// Receives measurements of every extraction in this process, for embedders to export as counters
+/// and histograms. Every method does nothing by default, and is called from the threads doing the
+/// work, so it should be cheap.
+#[allow(unused_variables)]
+pub trait Metrics: Send + Sync {
+    /// `bytes` were read from an archive.
+    fn bytes_read(&self, bytes: u64) {}
+
+    /// `bytes` of file data were written to a destination.
+    fn bytes_written(&self, bytes: u64) {}
+
+    /// An entry of `kind` was extracted, or failed to if not `ok`.
+    fn entry(&self, kind: EntryKind, ok: bool) {}
+
+    /// A piece of work of `stage` took `elapsed`, e.g. decompressing a block or writing a buffer.
+    fn stage(&self, stage: Stage, elapsed: Duration) {}
+
+    /// An extraction finished, having extracted up to `tasks` entries at once.
+    fn concurrency_high_water(&self, tasks: usize) {}
+}
+
+/// Send the measurements of extractions in this process to `metrics`, or stop sending them if
+/// `None`.
+pub fn set_metrics(metrics: Option<Arc<dyn Metrics>>) {
+    let mut installed = METRICS.write().unwrap_or_else(PoisonError::into_inner);
+    INSTALLED.store(metrics.is_some(), Ordering::Relaxed);
+    *installed = metrics;
+}
+
+pub(crate) fn installed() -> bool {
+    INSTALLED.load(Ordering::Relaxed)
+}
+
+/// Call `f` with the installed [`Metrics`], if any.
+pub(crate) fn with(f: impl FnOnce(&dyn Metrics)) {
+    if !installed() {
+        return;
+    }
+    if let Some(metrics) = &*METRICS.read().unwrap_or_else(PoisonError::into_inner) {
+        f(&**metrics);
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    ffi::OsStr,
+    fmt,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use backhand::{BasicFile, InnerNode, Node, SquashfsFileReader};
+use fuser::{
+    BackgroundSession, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEntry, Request,
+};
+use nix::libc::{EINVAL, EIO, ENOENT, ENOTDIR};
+
+use crate::{Archive, Error};
+
+/// How long the kernel may cache entries and attributes, which never change.
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// An archive served read-only through FUSE, each entry's inode number being its index in the
+/// archive plus one, so that the root directory is [`fuser::FUSE_ROOT_ID`].
+struct ArchiveFs {
+    archive: Archive,
+    /// The index of the parent of each entry, the root being its own parent.
+    parents: Vec<usize>,
+    /// The indexes of the entries of each directory, sorted by name like the entries of the
+    /// archive.
+    children: Vec<Vec<usize>>,
+}
+
+impl ArchiveFs {
+    fn new(archive: &Archive) -> Result<Self> {
+        // entries are looked up by their full paths
+        let archive = archive.expanded()?.into_owned();
+        let nodes = &archive.filesystem.root.nodes;
+        let mut parents = vec![0; nodes.len()];
+        let mut children = vec![Vec::new(); nodes.len()];
+        for (i, node) in nodes.iter().enumerate().skip(1) {
+            let parent = node.fullpath.parent().unwrap_or(Path::new("/"));
+            let parent = nodes
+                .binary_search_by(|node| node.fullpath.as_path().cmp(parent))
+                .map_err(|_| anyhow::anyhow!("no parent of '{}'", node.fullpath.display()))?;
+            parents[i] = parent;
+            children[parent].push(i);
+        }
+        Ok(Self {
+            archive,
+            parents,
+            children,
+        })
+    }
+
+    fn nodes(&self) -> &[Node<SquashfsFileReader>] {
+        &self.archive.filesystem.root.nodes
+    }
+
+    /// The index of the entry of inode `ino`.
+    fn index(&self, ino: u64) -> Option<usize> {
+        let i = usize::try_from(ino.checked_sub(1)?).ok()?;
+        (i < self.nodes().len()).then_some(i)
+    }
+
+    fn attr(&self, i: usize) -> FileAttr {
+        let node = &self.nodes()[i];
+        let (size, rdev) = match &node.inner {
+            InnerNode::File(file) => (u64::from(file.basic.file_size), 0),
+            InnerNode::Symlink(symlink) => (symlink.link.as_os_str().len() as u64, 0),
+            InnerNode::CharacterDevice(device) => (0, device.device_number),
+            InnerNode::BlockDevice(device) => (0, device.device_number),
+            _ => (0, 0),
+        };
+        let mtime = UNIX_EPOCH + Duration::from_secs(node.header.mtime.into());
+        FileAttr {
+            ino: inode(i),
+            size,
+            blocks: size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: file_type(&node.inner),
+            perm: node.header.permissions & 0o7777,
+            nlink: match node.inner {
+                InnerNode::Dir(_) => 2,
+                _ => 1,
+            },
+            uid: node.header.uid,
+            gid: node.header.gid,
+            rdev,
+            blksize: self.archive.filesystem.block_size,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(parent) = self.index(parent) else {
+            return reply.error(ENOENT);
+        };
+        let nodes = self.nodes();
+        let found = self.children[parent]
+            .binary_search_by(|&i| nodes[i].fullpath.file_name().unwrap_or_default().cmp(name));
+        match found {
+            Ok(found) => reply.entry(&TTL, &self.attr(self.children[parent][found]), 0),
+            Err(_) => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.index(ino) {
+            Some(i) => reply.attr(&TTL, &self.attr(i)),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.index(ino).map(|i| &self.nodes()[i].inner) {
+            Some(InnerNode::Symlink(symlink)) => {
+                reply.data(symlink.link.as_os_str().as_encoded_bytes())
+            }
+            Some(_) => reply.error(EINVAL),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(i) = self.index(ino) else {
+            return reply.error(ENOENT);
+        };
+        let (InnerNode::File(file), Ok(offset)) = (&self.nodes()[i].inner, u64::try_from(offset))
+        else {
+            return reply.error(EINVAL);
+        };
+        match read_range(&self.archive, &file.basic, offset, size.into()) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                let path = self.nodes()[i].fullpath.display();
+                tracing::warn!(%path, "cannot read mounted file: {e:#}");
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(i) = self.index(ino) else {
+            return reply.error(ENOENT);
+        };
+        let nodes = self.nodes();
+        if !matches!(nodes[i].inner, InnerNode::Dir(_)) {
+            return reply.error(ENOTDIR);
+        }
+        let dots = [(i, "."), (self.parents[i], "..")]
+            .map(|(i, name)| (inode(i), FileType::Directory, OsStr::new(name)));
+        let children = self.children[i].iter().map(|&child| {
+            let node = &nodes[child];
+            let name = node.fullpath.file_name().unwrap_or_default();
+            (inode(child), file_type(&node.inner), name)
+        });
+        // the offset of an entry is that of the next one, to resume listing from
+        let skip = usize::try_from(offset).unwrap_or_default();
+        for (next, (ino, kind, name)) in dots.into_iter().chain(children).enumerate().skip(skip) {
+            if reply.add(ino, next as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+}
+
+fn inode(i: usize) -> u64 {
+    i as u64 + 1
+}
+
+fn file_type(inner: &InnerNode<SquashfsFileReader>) -> FileType {
+    match inner {
+        InnerNode::File(_) => FileType::RegularFile,
+        InnerNode::Dir(_) => FileType::Directory,
+        InnerNode::Symlink(_) => FileType::Symlink,
+        InnerNode::CharacterDevice(_) => FileType::CharDevice,
+        InnerNode::BlockDevice(_) => FileType::BlockDevice,
+        InnerNode::NamedPipe => FileType::NamedPipe,
+        InnerNode::Socket => FileType::Socket,
+    }
+}
+
+/// Up to `len` bytes of `file` from `offset`, decoding only the blocks holding them with the
+/// default [`Kind`](crate::compression::Kind), and the file up to them otherwise.
+fn read_range(archive: &Archive, file: &BasicFile, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let end = u64::from(file.file_size).min(offset.saturating_add(len));
+    if offset >= end {
+        return Ok(Vec::new());
+    }
+    let Some(decoder) = &archive.block_decoder else {
+        let mut reader = archive.filesystem.file(file).reader();
+        io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        let mut data = Vec::new();
+        reader.take(end - offset).read_to_end(&mut data)?;
+        return Ok(data);
+    };
+    let block_size = u64::from(archive.filesystem.block_size);
+    let blocks = file.block_sizes.len();
+    let first = usize::try_from(offset / block_size)?.min(blocks);
+    // the tail of the file is decoded along with its last blocks
+    let count = match end > blocks as u64 * block_size {
+        true => blocks - first,
+        false => usize::try_from((end - 1) / block_size)? + 1 - first,
+    };
+    let start = u64::from(file.blocks_start)
+        + (file.block_sizes[..first].iter())
+            .map(|block| u64::from(block.size()))
+            .sum::<u64>();
+    let mut data = Vec::new();
+    decoder.copy_chunk(&archive.filesystem, file, first, start, count, &mut data)?;
+    let skip = usize::try_from(offset - first as u64 * block_size)?;
+    data.truncate(skip + usize::try_from(end - offset)?);
+    data.drain(..skip);
+    archive.check_unchanged()?;
+    Ok(data)
+}
+
+fn options(archive: &Archive) -> [MountOption; 4] {
+    [
+        MountOption::RO,
+        MountOption::DefaultPermissions,
+        MountOption::FSName(archive.source.name()),
+        MountOption::Subtype(String::from("squashfs")),
+    ]
+}
+
+/// Serve `archive` read-only at `mountpoint` through FUSE until it is unmounted, e.g. with
+/// `fusermount -u`, so that its tree can be browsed with the usual tools without extracting it.
+/// The blocks of a file are only decoded when they are read.
+pub fn mount(archive: &Archive, mountpoint: impl AsRef<Path>) -> Result<(), Error> {
+    let mountpoint = mountpoint.as_ref();
+    let filesystem = ArchiveFs::new(archive)?;
+    fuser::mount2(filesystem, mountpoint, &options(archive))
+        .with_context(|| format!("mount '{}'", mountpoint.display()))?;
+    Ok(())
+}
+
+/// Like [`mount`], serving `archive` from a thread of its own until the returned [`Mount`] is
+/// dropped.
+pub fn spawn_mount(archive: &Archive, mountpoint: impl AsRef<Path>) -> Result<Mount, Error> {
+    let mountpoint = mountpoint.as_ref();
+    let filesystem = ArchiveFs::new(archive)?;
+    let session = fuser::spawn_mount2(filesystem, mountpoint, &options(archive))
+        .with_context(|| format!("mount '{}'", mountpoint.display()))?;
+    Ok(Mount {
+        mountpoint: mountpoint.to_path_buf(),
+        session,
+    })
+}
+
+/// An archive mounted by [`spawn_mount`], unmounted when dropped.
+pub struct Mount {
+    mountpoint: PathBuf,
+    session: BackgroundSession,
+}
+
+impl Mount {
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmount the archive, waiting for the thread serving it to finish.
+    pub fn unmount(self) {
+        self.session.join()
+    }
+}
+
+impl fmt::Debug for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mount")
+            .field("mountpoint", &self.mountpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Seek, SeekFrom},
+        os::unix::fs::PermissionsExt,
+    };
+
+    use super::*;
+    use crate::{
+        testing::{self, TestArchive},
+        ExtractOptions,
+    };
+
+    const BLOCK: usize = 128 << 10;
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn serves_the_tree_read_only() {
+        if !Path::new("/dev/fuse").exists() {
+            eprintln!("skipping: no /dev/fuse");
+            return;
+        }
+        let archive = TestArchive::new(vec![
+            testing::dir("a"),
+            testing::file("a/large", contents(3 * BLOCK + 1000)),
+            testing::file("a/small", "small"),
+            testing::symlink("link", "a/small"),
+        ]);
+        let opened = Archive::open(archive.path(), ExtractOptions::default()).unwrap();
+        let mountpoint = archive.scratch("mnt");
+        std::fs::create_dir(&mountpoint).unwrap();
+        let mounted = spawn_mount(&opened, &mountpoint).unwrap();
+
+        let mut names: Vec<_> = std::fs::read_dir(mountpoint.join("a"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["large", "small"]);
+        assert_eq!(
+            std::fs::read(mountpoint.join("a/large")).unwrap(),
+            contents(3 * BLOCK + 1000)
+        );
+        assert_eq!(std::fs::read(mountpoint.join("link")).unwrap(), b"small");
+        assert_eq!(
+            std::fs::read_link(mountpoint.join("link")).unwrap(),
+            Path::new("a/small")
+        );
+        let metadata = std::fs::metadata(mountpoint.join("a/small")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o644);
+
+        // a read straddling blocks only decodes those
+        let mut large = std::fs::File::open(mountpoint.join("a/large")).unwrap();
+        large.seek(SeekFrom::Start(BLOCK as u64 - 10)).unwrap();
+        let mut straddling = vec![0; BLOCK + 20];
+        large.read_exact(&mut straddling).unwrap();
+        assert!(straddling == contents(3 * BLOCK + 1000)[BLOCK - 10..2 * BLOCK + 10]);
+
+        assert!(std::fs::write(mountpoint.join("a/small"), "written").is_err());
+        assert!(std::fs::read(mountpoint.join("missing")).is_err());
+        mounted.unmount();
+        assert!(!mountpoint.join("a").exists());
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    io::{BufReader, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::platform;
+
+/// Alphabet of Nix's base-32 encoding, which omits `e`, `o`, `u` and `t`.
+const NIX32: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// The SHA-256 of the NAR serialization of the tree at `path`, in the `sha256:<base-32>` form
+/// printed by `nix-store --query --hash`.
+pub fn nar_hash(path: impl AsRef<Path>) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut nar = Nar(&mut hasher);
+    nar.str(b"nix-archive-1")?;
+    nar.node(path.as_ref())?;
+    Ok(format!("sha256:{}", nix32(&hasher.finalize())))
+}
+
+/// Writes the NAR serialization of a tree, in which every string is length-prefixed and padded
+/// to 8 bytes and directory entries are sorted by name.
+struct Nar<W>(W);
+
+impl<W: Write> Nar<W> {
+    fn str(&mut self, s: &[u8]) -> std::io::Result<()> {
+        self.0.write_all(&(s.len() as u64).to_le_bytes())?;
+        self.0.write_all(s)?;
+        self.pad(s.len() as u64)
+    }
+
+    fn pad(&mut self, len: u64) -> std::io::Result<()> {
+        let padding = (8 - len % 8) % 8;
+        self.0.write_all(&[0; 8][..padding as usize])
+    }
+
+    fn node(&mut self, path: &Path) -> Result<()> {
+        let metadata = std::fs::symlink_metadata(path)
+            .with_context(|| format!("stat '{}'", path.display()))?;
+        let file_type = metadata.file_type();
+        self.str(b"(")?;
+        self.str(b"type")?;
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(path)
+                .with_context(|| format!("read symlink '{}'", path.display()))?;
+            self.str(b"symlink")?;
+            self.str(b"target")?;
+            self.str(target.as_os_str().as_encoded_bytes())?;
+        } else if file_type.is_dir() {
+            self.str(b"directory")?;
+            let mut names = std::fs::read_dir(path)
+                .with_context(|| format!("read dir '{}'", path.display()))?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()
+                .with_context(|| format!("read dir '{}'", path.display()))?;
+            names.sort_unstable_by(|a, b| a.as_encoded_bytes().cmp(b.as_encoded_bytes()));
+            for name in names {
+                self.str(b"entry")?;
+                self.str(b"(")?;
+                self.str(b"name")?;
+                self.str(name.as_encoded_bytes())?;
+                self.str(b"node")?;
+                self.node(&path.join(name))?;
+                self.str(b")")?;
+            }
+        } else if file_type.is_file() {
+            self.str(b"regular")?;
+            if platform::mode(&metadata) & 0o100 != 0 {
+                self.str(b"executable")?;
+                self.str(b"")?;
+            }
+            self.str(b"contents")?;
+            let file =
+                std::fs::File::open(path).with_context(|| format!("open '{}'", path.display()))?;
+            self.0.write_all(&metadata.len().to_le_bytes())?;
+            let copied = std::io::copy(&mut BufReader::new(file), &mut self.0)
+                .with_context(|| format!("hash '{}'", path.display()))?;
+            anyhow::ensure!(
+                copied == metadata.len(),
+                "'{}' changed while being hashed",
+                path.display()
+            );
+            self.pad(copied)?;
+        } else {
+            anyhow::bail!(
+                "cannot serialize special file '{}' into a NAR",
+                path.display()
+            );
+        }
+        self.str(b")")?;
+        Ok(())
+    }
+}
+
+/// Nix's base-32 encoding, which reads the hash from its last byte.
+fn nix32(hash: &[u8]) -> String {
+    let len = (hash.len() * 8 - 1) / 5 + 1;
+    (0..len)
+        .rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let low = hash[i] >> j;
+            let high = hash.get(i + 1).map_or(0, |&b| u16::from(b) << (8 - j)) as u8;
+            char::from(NIX32[usize::from((low | high) & 0x1f)])
+        })
+        .collect()
+}
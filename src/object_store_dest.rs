@@ -0,0 +1,186 @@
+use std::{
+    io::{self, Write},
+    path::{Component, Path},
+    pin::pin,
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use object_store::{buffered::BufWriter, ObjectStore};
+use tokio::{io::AsyncWriteExt, runtime::Handle};
+
+use crate::{
+    async_file::EntryReader,
+    dest_fs::{DestFile, DestFs},
+    runtime, source, Archive, EntryInfo, EntryKind, Error, ExtractOptions, ExtractSummary, Filter,
+};
+
+/// A prefix of an [`ObjectStore`] (e.g. `s3://bucket/prefix`) that files are uploaded under, each
+/// as the object at its path. Object stores have no directories, modes nor symlinks: directories
+/// are implied by the objects under them, and modes and symlinks are left out.
+///
+/// Large files are uploaded in parts as they are decoded, small ones in a single request.
+#[derive(Debug, Clone)]
+pub struct ObjectStoreDest {
+    store: Arc<dyn ObjectStore>,
+    prefix: object_store::path::Path,
+    concurrency: usize,
+    /// Drives the uploads of blocking writes issued outside of a tokio runtime.
+    handle: Handle,
+}
+
+impl ObjectStoreDest {
+    /// Uploads at once unless set with [`concurrency`](Self::concurrency).
+    pub const DEFAULT_CONCURRENCY: usize = 16;
+
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: object_store::path::Path) -> Self {
+        let handle =
+            Handle::try_current().unwrap_or_else(|_| runtime::background().handle().clone());
+        Self {
+            store,
+            prefix,
+            concurrency: Self::DEFAULT_CONCURRENCY,
+            handle,
+        }
+    }
+
+    /// How many files [`unsquash_to_object_store`] uploads at once.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// The location of the entry at `path`, relative to the root of the archive.
+    fn location(&self, path: &Path) -> io::Result<object_store::path::Path> {
+        let mut location = self.prefix.clone();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => {
+                    let part = part.to_str().ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("'{}' is not valid UTF-8", path.display()),
+                        )
+                    })?;
+                    location = location.child(part);
+                }
+                Component::RootDir | Component::CurDir => {}
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("'{}' is not a plain path", path.display()),
+                    ))
+                }
+            }
+        }
+        Ok(location)
+    }
+
+    /// Upload the file of `info` from `reader`, or skip anything else.
+    async fn upload(&self, info: EntryInfo, mut reader: EntryReader) -> Result<EntryInfo> {
+        if info.kind != EntryKind::File {
+            return Ok(info);
+        }
+        let location = self.location(&info.path)?;
+        let mut writer = BufWriter::new(Arc::clone(&self.store), location.clone());
+        let uploaded = async {
+            tokio::io::copy(&mut reader, &mut writer).await?;
+            writer.shutdown().await
+        };
+        uploaded
+            .await
+            .with_context(|| format!("upload '{}' to '{location}'", info.path.display()))?;
+        Ok(info)
+    }
+}
+
+impl DestFs for ObjectStoreDest {
+    fn create_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn create_file(&self, path: &Path) -> io::Result<Box<dyn DestFile + '_>> {
+        let writer = BufWriter::new(Arc::clone(&self.store), self.location(path)?);
+        Ok(Box::new(ObjectStoreFile { dest: self, writer }))
+    }
+
+    fn symlink(&self, _target: &Path, path: &Path) -> io::Result<()> {
+        tracing::debug!(path = %path.display(), "leaving symlink out of object store");
+        Ok(())
+    }
+
+    fn set_permissions(&self, _path: &Path, _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An object being uploaded for [`unsquash_to_fs`](crate::unsquash_to_fs).
+struct ObjectStoreFile<'a> {
+    dest: &'a ObjectStoreDest,
+    writer: BufWriter,
+}
+
+impl Write for ObjectStoreFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        source::block_on(&self.dest.handle, self.writer.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // parts are uploaded as they fill up, and the rest once finished
+        Ok(())
+    }
+}
+
+impl DestFile for ObjectStoreFile<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        source::block_on(&self.dest.handle, self.writer.shutdown())
+    }
+}
+
+/// Upload the files of `squashfs` selected by `filter` under the prefix of `dest`, streaming
+/// them from the archive without writing anything to disk. The archive is read with the `kind`,
+/// `parsing` and `direct_io` of `options`.
+pub async fn unsquash_to_object_store(
+    squashfs: impl AsRef<Path>,
+    dest: &ObjectStoreDest,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractSummary, Error> {
+    let archive = Archive::open_async(squashfs, options).await?;
+    archive.extract_to_object_store(dest, filter).await
+}
+
+impl Archive {
+    /// Like [`unsquash_to_object_store`], uploading up to
+    /// [`concurrency`](ObjectStoreDest::concurrency) files at once. Must be called from within a
+    /// tokio runtime.
+    pub async fn extract_to_object_store(
+        &self,
+        dest: &ObjectStoreDest,
+        filter: Filter,
+    ) -> Result<ExtractSummary, Error> {
+        let started = Instant::now();
+        let mut uploads = pin!(self
+            .entries_stream(filter)
+            .map(|(info, reader)| dest.upload(info, reader))
+            .buffer_unordered(dest.concurrency));
+        let mut summary = ExtractSummary::default();
+        while let Some(uploaded) = uploads.next().await {
+            let info = uploaded?;
+            match info.kind {
+                EntryKind::File => {
+                    summary.files += 1;
+                    summary.bytes_written += info.size;
+                }
+                EntryKind::Dir => summary.dirs += 1,
+                _ => summary.skipped += 1,
+            }
+        }
+        self.check_unchanged()?;
+        summary.duration = started.elapsed();
+        Ok(summary)
+    }
+}
@@ -0,0 +1,550 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    path::{Component, Path, PathBuf},
+};
+#[cfg(unix)]
+use std::{
+    ffi::OsStr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use anyhow::{Context, Result};
+use backhand::{InnerNode, SquashfsSymlink};
+#[cfg(unix)]
+use nix::{
+    errno::Errno,
+    fcntl::{openat, AtFlags, OFlag},
+    sys::stat::{fchmod, fchmodat, fstatat, mkdirat, FchmodatFlags, Mode, SFlag},
+    unistd::symlinkat,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{digest, platform, Parsing};
+
+/// A single filesystem operation performed by extraction. Paths are relative to the destination.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    /// Create a directory and any missing parents.
+    Mkdir {
+        path: PathBuf,
+    },
+    /// Write the contents of the archive entry `source` to `path`.
+    Extract {
+        source: PathBuf,
+        path: PathBuf,
+        size: u64,
+    },
+    Symlink {
+        target: PathBuf,
+        path: PathBuf,
+    },
+    Chmod {
+        path: PathBuf,
+        mode: u32,
+    },
+}
+
+/// The operations an extraction would perform, in order, for review or later replay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpLog {
+    pub archive: PathBuf,
+    /// Hex-encoded SHA-256 of the archive the plan was made from.
+    pub archive_sha256: String,
+    pub dest: PathBuf,
+    pub ops: Vec<Op>,
+}
+
+impl OpLog {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("serialize op-log")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).context("deserialize op-log")
+    }
+
+    /// Render the op-log as a POSIX shell script that reproduces the extraction using
+    /// `unsquashfs -cat`. `ARCHIVE` and `DEST` can be overridden from the environment.
+    pub fn to_shell_script(&self) -> String {
+        let mut script = String::from("#!/bin/sh\nset -eu\n");
+        let _ = writeln!(script, "ARCHIVE=${{ARCHIVE:-{}}}", quote(&self.archive));
+        let _ = writeln!(script, "DEST=${{DEST:-{}}}", quote(&self.dest));
+        let _ = writeln!(
+            script,
+            "echo '{}  '\"$ARCHIVE\" | sha256sum -c - >/dev/null",
+            self.archive_sha256
+        );
+        for op in &self.ops {
+            let _ = match op {
+                Op::Mkdir { path } => writeln!(script, "mkdir -p {}", in_dest(path)),
+                Op::Extract { source, path, .. } => writeln!(
+                    script,
+                    "unsquashfs -cat \"$ARCHIVE\" {} > {}",
+                    quote(source),
+                    in_dest(path)
+                ),
+                Op::Symlink { target, path } => {
+                    writeln!(script, "ln -s {} {}", quote(target), in_dest(path))
+                }
+                Op::Chmod { path, mode } => writeln!(script, "chmod {mode:o} {}", in_dest(path)),
+            };
+        }
+        script
+    }
+}
+
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', r"'\''"))
+}
+
+fn in_dest(path: &Path) -> String {
+    if path.as_os_str().is_empty() {
+        "\"$DEST\"".to_owned()
+    } else {
+        format!("\"$DEST\"/{}", quote(path))
+    }
+}
+
+/// Compute the operations [`unsquash_tpcii_blocking`](crate::unsquash_tpcii_blocking) would
+/// perform, without touching the destination.
+pub fn plan_tpcii(
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    crates_filter: Option<HashSet<String>>,
+) -> Result<OpLog> {
+    let (squashfs_path, dest) = (squashfs.as_ref(), dest.as_ref());
+
+    anyhow::ensure!(
+        squashfs_path.exists(),
+        "specified squashfs archive does not exist: '{}'",
+        squashfs_path.display(),
+    );
+
+    let filter = crate::tpcii_filter(crates_filter);
+    let mut oplog = OpLog {
+        archive: squashfs_path.to_path_buf(),
+        archive_sha256: digest::sha256_file(squashfs_path)?,
+        dest: dest.to_path_buf(),
+        ops: Vec::new(),
+    };
+    if filter.is_empty() {
+        return Ok(oplog);
+    }
+
+    let filesystem = crate::open_filesystem_path(squashfs_path, None, Parsing::Strict)?;
+
+    let mut dirs = HashSet::new();
+    let nodes = filesystem
+        .files()
+        .filter(|node| filter.matches(&node.fullpath));
+    for node in nodes {
+        let path = &node.fullpath;
+        let fullpath = path.strip_prefix(Component::RootDir).unwrap_or(path);
+
+        if let Some(parent) = fullpath.parent() {
+            if dirs.insert(parent.to_path_buf()) {
+                oplog.ops.push(Op::Mkdir {
+                    path: parent.to_path_buf(),
+                });
+            }
+        }
+
+        match &node.inner {
+            InnerNode::File(file) => {
+                oplog.ops.push(Op::Extract {
+                    source: path.clone(),
+                    path: fullpath.to_path_buf(),
+                    size: u64::from(file.basic.file_size),
+                });
+                oplog.ops.push(Op::Chmod {
+                    path: fullpath.to_path_buf(),
+                    mode: 0o644,
+                });
+            }
+            InnerNode::Symlink(SquashfsSymlink { link }) => {
+                oplog.ops.push(Op::Symlink {
+                    target: link.clone(),
+                    path: fullpath.to_path_buf(),
+                });
+            }
+            InnerNode::Dir(_) => {
+                if dirs.insert(fullpath.to_path_buf()) {
+                    oplog.ops.push(Op::Mkdir {
+                        path: fullpath.to_path_buf(),
+                    });
+                }
+                oplog.ops.push(Op::Chmod {
+                    path: fullpath.to_path_buf(),
+                    mode: 0o755,
+                });
+            }
+            _ => anyhow::bail!("unsupported node type at '{}'", path.display()),
+        }
+    }
+
+    Ok(oplog)
+}
+
+/// Execute a previously planned [`OpLog`] exactly as recorded, reading entries from `squashfs`
+/// and writing under `dest`. Fails before touching `dest` if the archive's hash no longer matches
+/// the one recorded at planning time.
+pub fn apply_oplog(
+    oplog: &OpLog,
+    squashfs: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+) -> Result<()> {
+    let (squashfs_path, dest) = (squashfs.as_ref(), dest.as_ref());
+
+    let actual = digest::sha256_file(squashfs_path)?;
+    anyhow::ensure!(
+        actual == oplog.archive_sha256,
+        "archive '{}' changed since the op-log was planned: sha256 {actual}, expected {}",
+        squashfs_path.display(),
+        oplog.archive_sha256,
+    );
+
+    let filesystem = crate::open_filesystem_path(squashfs_path, None, Parsing::Strict)?;
+    let nodes: HashMap<&Path, _> = filesystem
+        .files()
+        .map(|node| (node.fullpath.as_path(), node))
+        .collect();
+    let dest = DestDir::open(dest)?;
+
+    for op in &oplog.ops {
+        match op {
+            Op::Mkdir { path } => dest.mkdir(path)?,
+            Op::Extract { source, path, size } => {
+                let node = nodes
+                    .get(source.as_path())
+                    .with_context(|| format!("'{}' is not in the archive", source.display()))?;
+                let InnerNode::File(file) = &node.inner else {
+                    anyhow::bail!("'{}' is not a file in the archive", source.display());
+                };
+                anyhow::ensure!(
+                    u64::from(file.basic.file_size) == *size,
+                    "'{}' is {} bytes in the archive, op-log expects {size}",
+                    source.display(),
+                    file.basic.file_size,
+                );
+
+                let (fd, dest_path) = dest.create_file(path)?;
+                let mut writer = std::io::BufWriter::new(fd);
+                let mut reader = filesystem.file(&file.basic).reader();
+                std::io::copy(&mut reader, &mut writer)
+                    .with_context(|| format!("extract file into '{}'", dest_path.display()))?;
+            }
+            Op::Symlink { target, path } => {
+                if !platform::SYMLINKS {
+                    tracing::debug!(path = %path.display(), "skipping symlink");
+                    continue;
+                }
+                dest.symlink(target, path)?;
+            }
+            Op::Chmod { path, mode } => dest.chmod(path, *mode)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// The destination an op-log is applied to. The paths of ops must stay under it, and are resolved
+/// one component at a time from it without following symlinks, so that a symlink created by an
+/// earlier op or left in the destination cannot send a later op outside of it.
+struct DestDir {
+    path: PathBuf,
+    #[cfg(unix)]
+    fd: OwnedFd,
+}
+
+impl DestDir {
+    fn open(path: &Path) -> Result<Self> {
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("create dir '{}'", path.display()))?;
+        #[cfg(unix)]
+        let fd = std::fs::File::open(path)
+            .with_context(|| format!("open dir '{}'", path.display()))?
+            .into();
+        Ok(Self {
+            path: path.to_path_buf(),
+            #[cfg(unix)]
+            fd,
+        })
+    }
+
+    /// `path` under the destination, failing unless it is relative and does not climb out.
+    fn join(&self, path: &Path) -> Result<PathBuf> {
+        anyhow::ensure!(
+            path.components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir)),
+            "op-log path '{}' is not under the destination",
+            path.display(),
+        );
+        Ok(self.path.join(path))
+    }
+
+    /// Create the directory at `path` and any missing parents.
+    fn mkdir(&self, path: &Path) -> Result<()> {
+        let dest_path = self.join(path)?;
+        #[cfg(unix)]
+        {
+            let _ = dest_path;
+            self.dir(path, true).map(drop)
+        }
+        #[cfg(not(unix))]
+        std::fs::create_dir_all(&dest_path)
+            .with_context(|| format!("create dir '{}'", dest_path.display()))
+    }
+
+    /// Create the file at `path`, which must not exist yet.
+    fn create_file(&self, path: &Path) -> Result<(std::fs::File, PathBuf)> {
+        let dest_path = self.join(path)?;
+        #[cfg(unix)]
+        let file = {
+            let (dir, name) = self.parent(path)?;
+            let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW;
+            open_at(&dir, name, flags, Mode::from_bits_truncate(0o666)).map(std::fs::File::from)
+        };
+        #[cfg(not(unix))]
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&dest_path);
+        let file = file.with_context(|| format!("create file '{}'", dest_path.display()))?;
+        Ok((file, dest_path))
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> Result<()> {
+        let dest_path = self.join(path)?;
+        #[cfg(unix)]
+        let res = {
+            let (dir, name) = self.parent(path)?;
+            symlinkat(target, Some(dir.as_raw_fd()), name).map_err(std::io::Error::from)
+        };
+        #[cfg(not(unix))]
+        let res = platform::symlink(target, &dest_path);
+        res.with_context(|| format!("symlink file into '{}'", dest_path.display()))
+    }
+
+    /// Give the file or directory at `path` the permission bits of `mode`, failing for a symlink.
+    fn chmod(&self, path: &Path, mode: u32) -> Result<()> {
+        let dest_path = self.join(path)?;
+        #[cfg(unix)]
+        let res = {
+            let mode = Mode::from_bits_truncate(mode as nix::libc::mode_t);
+            match path.file_name() {
+                // the destination itself
+                None => fchmod(self.dir(path, false)?.as_raw_fd(), mode),
+                Some(_) => {
+                    let (dir, name) = self.parent(path)?;
+                    let stat = fstatat(Some(dir.as_raw_fd()), name, AtFlags::AT_SYMLINK_NOFOLLOW)
+                        .with_context(|| format!("stat '{}'", dest_path.display()))?;
+                    anyhow::ensure!(
+                        SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFLNK,
+                        "'{}' is a symlink",
+                        dest_path.display(),
+                    );
+                    fchmodat(
+                        Some(dir.as_raw_fd()),
+                        name,
+                        mode,
+                        FchmodatFlags::FollowSymlink,
+                    )
+                }
+            }
+            .map_err(std::io::Error::from)
+        };
+        #[cfg(not(unix))]
+        let res = platform::set_mode(&dest_path, mode);
+        res.with_context(|| format!("chmod {mode:#o} '{}'", dest_path.display()))
+    }
+
+    /// Open the directory at `path` under the destination, creating it and its missing parents if
+    /// `create`.
+    #[cfg(unix)]
+    fn dir(&self, path: &Path, create: bool) -> Result<OwnedFd> {
+        let dest_path = self.path.join(path);
+        let mut dir = self
+            .fd
+            .try_clone()
+            .with_context(|| format!("duplicate fd of '{}'", self.path.display()))?;
+        for component in path.components() {
+            let Component::Normal(name) = component else {
+                continue;
+            };
+            if create {
+                match mkdirat(Some(dir.as_raw_fd()), name, Mode::from_bits_truncate(0o777)) {
+                    Ok(()) | Err(Errno::EEXIST) => {}
+                    Err(e) => {
+                        return Err(std::io::Error::from(e))
+                            .with_context(|| format!("create dir '{}'", dest_path.display()))
+                    }
+                }
+            }
+            let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW;
+            dir = open_at(&dir, name, flags, Mode::empty())
+                .with_context(|| format!("open dir '{}'", dest_path.display()))?;
+        }
+        Ok(dir)
+    }
+
+    /// The directory holding `path` under the destination, opened, and the name of `path` in it.
+    #[cfg(unix)]
+    fn parent<'p>(&self, path: &'p Path) -> Result<(OwnedFd, &'p OsStr)> {
+        let name = path
+            .file_name()
+            .with_context(|| format!("op-log path '{}' has no file name", path.display()))?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        Ok((self.dir(parent, false)?, name))
+    }
+}
+
+/// `openat` relative to `dir`, with `O_CLOEXEC`.
+#[cfg(unix)]
+fn open_at(dir: &OwnedFd, name: &OsStr, flags: OFlag, mode: Mode) -> std::io::Result<OwnedFd> {
+    let fd = openat(Some(dir.as_raw_fd()), name, flags | OFlag::O_CLOEXEC, mode)?;
+    // SAFETY: `openat` just opened this descriptor, which nothing else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{file, symlink, TestArchive};
+
+    /// The op-log planned for extracting `archive` to `dest`, with its ops replaced by `ops`.
+    fn with_ops(archive: &TestArchive, dest: &Path, ops: Vec<Op>) -> OpLog {
+        OpLog {
+            ops,
+            ..plan_tpcii(archive.path(), dest, None).unwrap()
+        }
+    }
+
+    fn extract(path: impl Into<PathBuf>) -> Op {
+        Op::Extract {
+            source: "/f".into(),
+            path: path.into(),
+            size: 1,
+        }
+    }
+
+    #[test]
+    fn applies_plan() {
+        let archive = TestArchive::new(vec![
+            file("index/se/rd/serde", "serde"),
+            symlink("index/link", "se/rd/serde"),
+        ]);
+        let dest = archive.scratch("dest");
+        let oplog = plan_tpcii(archive.path(), &dest, None).unwrap();
+        apply_oplog(&oplog, archive.path(), &dest).unwrap();
+        assert_eq!(
+            std::fs::read(dest.join("index/se/rd/serde")).unwrap(),
+            b"serde"
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::read_link(dest.join("index/link")).unwrap(),
+            Path::new("se/rd/serde")
+        );
+    }
+
+    #[test]
+    fn rejects_changed_archive() {
+        let (planned, changed) = (
+            TestArchive::new(vec![file("f", "x")]),
+            TestArchive::new(vec![file("f", "y")]),
+        );
+        let dest = planned.scratch("dest");
+        let oplog = plan_tpcii(planned.path(), &dest, None).unwrap();
+        assert!(apply_oplog(&oplog, changed.path(), &dest).is_err());
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn rejects_paths_outside_dest() {
+        let archive = TestArchive::new(vec![file("f", "x")]);
+        let (dest, outside) = (archive.scratch("dest"), archive.scratch("escaped"));
+        let paths = [
+            PathBuf::from("../escaped"),
+            PathBuf::from("a/../../escaped"),
+            outside.clone(),
+        ];
+        for path in paths {
+            let ops = [
+                Op::Mkdir { path: path.clone() },
+                extract(path.clone()),
+                Op::Symlink {
+                    target: "f".into(),
+                    path: path.clone(),
+                },
+                Op::Chmod {
+                    path: path.clone(),
+                    mode: 0o777,
+                },
+            ];
+            for op in ops {
+                let oplog = with_ops(&archive, &dest, vec![op]);
+                assert!(apply_oplog(&oplog, archive.path(), &dest).is_err());
+                assert!(std::fs::symlink_metadata(&outside).is_err());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn does_not_follow_symlinks() {
+        let archive = TestArchive::new(vec![file("f", "x")]);
+        let outside = archive.scratch("outside");
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(outside.join("f"), "original").unwrap();
+        let mode = platform::mode(&std::fs::metadata(outside.join("f")).unwrap());
+        let link_dir = || Op::Symlink {
+            target: outside.clone(),
+            path: "dir".into(),
+        };
+        let link_file = || Op::Symlink {
+            target: outside.join("f"),
+            path: "f".into(),
+        };
+        let cases = [
+            vec![link_dir(), extract("dir/f")],
+            vec![
+                link_dir(),
+                Op::Mkdir {
+                    path: "dir/sub".into(),
+                },
+            ],
+            vec![link_file(), extract("f")],
+            vec![
+                link_file(),
+                Op::Chmod {
+                    path: "f".into(),
+                    mode: 0o777,
+                },
+            ],
+        ];
+        for (i, ops) in cases.into_iter().enumerate() {
+            let dest = archive.scratch(&format!("dest{i}"));
+            let oplog = with_ops(&archive, &dest, ops);
+            assert!(apply_oplog(&oplog, archive.path(), &dest).is_err());
+        }
+        assert_eq!(std::fs::read(outside.join("f")).unwrap(), b"original");
+        let metadata = std::fs::metadata(outside.join("f")).unwrap();
+        assert_eq!(platform::mode(&metadata), mode);
+        assert!(!outside.join("sub").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn does_not_follow_symlinks_left_in_dest() {
+        let archive = TestArchive::new(vec![file("index/f", "x")]);
+        let (dest, outside) = (archive.scratch("dest"), archive.scratch("outside"));
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join("index")).unwrap();
+        let oplog = plan_tpcii(archive.path(), &dest, None).unwrap();
+        assert!(apply_oplog(&oplog, archive.path(), &dest).is_err());
+        assert!(!outside.join("f").exists());
+    }
+}
@@ -0,0 +1,846 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use backhand::{InnerNode, Node, SquashfsFileReader};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cancel::CancelToken,
+    classify::ErrorClassifier,
+    compression::Kind,
+    confine::EscapePolicy,
+    counters::Counters,
+    digest::HashAlgorithm,
+    durability::Durability,
+    filter::AsyncFilter,
+    mechanisms::{Detected, Mechanisms},
+    metadata::Metadata,
+    parsing::Parsing,
+    platform,
+    progress::{EntryEvents, Progress},
+    protect::ReadOnly,
+    reapi::Digest,
+    recompress::Recompress,
+    report::Tally,
+    resume::Journal,
+    runtime::Runtime,
+    throttle::Throttle,
+    xattr::Xattrs,
+    Error,
+};
+
+/// Default capacity of the buffer each file is written into the destination through; see
+/// [`IoOptions::write_buffer`].
+pub const DEFAULT_WRITE_BUFFER: usize = 1 << 20;
+
+/// Default length of the chunks the async extractors decode file data in; see
+/// [`IoOptions::read_buffer`].
+pub const DEFAULT_READ_BUFFER: usize = crate::async_file::CHUNK_LEN;
+
+/// Default duration after which extracting a single entry is logged as slow; see
+/// [`ObservabilityOptions::slow_entry_threshold`].
+pub const DEFAULT_SLOW_ENTRY_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// What to do when extraction would exceed [`PolicyOptions::max_dest_bytes`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPolicy {
+    /// Fail the extraction.
+    #[default]
+    Abort,
+    /// Skip files that no longer fit and keep extracting the rest.
+    Trim,
+}
+
+/// Which modes extracted entries get.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PermissionPolicy {
+    /// 0o644 for files and symlinks, 0o755 for directories.
+    #[default]
+    Default,
+    /// The modes recorded in the archive.
+    Preserve,
+    Force {
+        file_mode: u32,
+        dir_mode: u32,
+    },
+    /// Normalize entries the way the Nix store does: 0o555 for directories and executables,
+    /// 0o444 for other files, an mtime of 1 and no ownership, whatever
+    /// [`PolicyOptions::preserve_ownership`] and [`PolicyOptions::preserve_mtime`] say.
+    Nix,
+}
+
+/// The id given to archive ids outside of an [`IdRange`], like the overflow id of a user
+/// namespace.
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// Maps the uids and gids recorded in the archive to those given to entries, like the uid and gid
+/// maps of a user namespace, see [`PolicyOptions::id_map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdMap {
+    pub uids: IdRange,
+    pub gids: IdRange,
+}
+
+/// `count` ids from `inside` in the archive, given as the ids from `outside`. Other ids are given
+/// [`OVERFLOW_ID`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdRange {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+impl IdMap {
+    /// Shift the first `count` uids and gids by `offset`, e.g. `IdMap::shift(100_000, 65_536)`
+    /// for a container given the ids from 100000 in `/etc/subuid` and `/etc/subgid`.
+    pub fn shift(offset: u32, count: u32) -> Self {
+        let range = IdRange {
+            inside: 0,
+            outside: offset,
+            count,
+        };
+        Self {
+            uids: range,
+            gids: range,
+        }
+    }
+
+    pub(crate) fn owner(&self, (uid, gid): (u32, u32)) -> (u32, u32) {
+        (self.uids.map(uid), self.gids.map(gid))
+    }
+}
+
+impl IdRange {
+    pub fn map(&self, id: u32) -> u32 {
+        id.checked_sub(self.inside)
+            .filter(|&offset| offset < self.count)
+            .and_then(|offset| self.outside.checked_add(offset))
+            .unwrap_or(OVERFLOW_ID)
+    }
+}
+
+/// What to do when an entry is already at the destination path of an extracted entry.
+/// Directories are merged with existing directories under every policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverwritePolicy {
+    /// Replace it.
+    #[default]
+    Overwrite,
+    /// Fail the entry with [`Error::DestinationExists`].
+    Error,
+    /// Keep it and do not extract the entry, nor anything under it for a directory.
+    Skip,
+    /// Replace it if the entry's mtime in the archive is more recent than its own.
+    OverwriteIfNewer,
+    /// Keep a file whose size and mtime match the entry's, counting it in
+    /// [`ExtractSummary::reused`](crate::ExtractSummary::reused), and replace anything else. Meant
+    /// for refreshing a destination extracted with
+    /// [`preserve_mtime`](PolicyOptions::preserve_mtime).
+    SkipIdentical,
+}
+
+/// How the work of extracting files is spread over threads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Extract files in parallel, each decoded by the task extracting it with
+    /// [`block_decode_workers`](IoOptions::block_decode_workers) workers, 1 by default.
+    #[default]
+    PerFile,
+    /// Also decode the blocks of each large file in parallel, with as many workers as files
+    /// are extracted at once, so that a few huge files do not leave cores idle.
+    PerBlock,
+    /// [`PerBlock`](Self::PerBlock) if most of the bytes to extract are in files larger than
+    /// their share of the [`concurrency`](IoOptions::concurrency), which per-file tasks
+    /// would extract on a single core each, [`PerFile`](Self::PerFile) otherwise.
+    Auto,
+}
+
+impl Strategy {
+    /// How many workers decode the blocks of each of the `nodes` extracted `concurrency` at once,
+    /// given the [`block_decode_workers`](IoOptions::block_decode_workers) asked for.
+    pub(crate) fn block_workers(
+        self,
+        nodes: &[&Node<SquashfsFileReader>],
+        concurrency: usize,
+        block_decode_workers: Option<usize>,
+    ) -> usize {
+        let per_file = block_decode_workers.unwrap_or(1).max(1);
+        let per_block = match self {
+            Self::PerFile => false,
+            Self::PerBlock => true,
+            Self::Auto => dominated_by_large_files(nodes, concurrency),
+        };
+        let workers = match per_block {
+            true => concurrency.max(per_file),
+            false => per_file,
+        };
+        tracing::debug!(strategy = ?self, workers, "block decoding");
+        workers
+    }
+}
+
+/// The order entries are extracted in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ordering {
+    /// In parallel, in the order the archive lists them, each created as soon as it is extracted:
+    /// the order entries appear in the destination varies from one run to the next.
+    #[default]
+    Unordered,
+    /// One at a time, by depth and then by path, so that all the entries of a level appear
+    /// before the next level.
+    BreadthFirst,
+    /// One at a time, by path compared component by component, as `find | sort` lists them.
+    Lexicographic,
+}
+
+impl Ordering {
+    /// Sort `nodes` into this order, returning whether they must be extracted one at a time to
+    /// keep it. Either way, parents come before their children.
+    pub(crate) fn sort(self, nodes: &mut [&Node<SquashfsFileReader>]) -> bool {
+        match self {
+            Self::Unordered => return false,
+            Self::BreadthFirst => nodes.sort_by(|a, b| {
+                let depth = |node: &Node<_>| node.fullpath.components().count();
+                depth(a)
+                    .cmp(&depth(b))
+                    .then_with(|| a.fullpath.cmp(&b.fullpath))
+            }),
+            Self::Lexicographic => nodes.sort_by(|a, b| a.fullpath.cmp(&b.fullpath)),
+        }
+        true
+    }
+}
+
+fn dominated_by_large_files(nodes: &[&Node<SquashfsFileReader>], concurrency: usize) -> bool {
+    let sizes = nodes.iter().filter_map(|node| match &node.inner {
+        InnerNode::File(file) => Some(u64::from(file.basic.file_size)),
+        _ => None,
+    });
+    let total: u64 = sizes.clone().sum();
+    let share = total / concurrency.max(1) as u64;
+    let large: u64 = sizes.filter(|&size| size > share).sum();
+    large > total / 2
+}
+
+/// What to do when setting the owner, mode or mtime of an entry fails after its data was written,
+/// as happens on some CIFS and FUSE mounts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataErrorPolicy {
+    /// Fail the entry.
+    #[default]
+    Fail,
+    /// Keep the entry and list the failure in
+    /// [`ExtractReport::metadata_warnings`](crate::ExtractReport::metadata_warnings).
+    Warn,
+}
+
+/// Per-call extraction settings, grouped by what they tune. Outside of this crate, start from
+/// [`ExtractOptions::default`] or [`ExtractOptions::builder`] and set what differs, so that new
+/// options can be added without breaking callers.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ExtractOptions {
+    pub io: IoOptions,
+    pub policy: PolicyOptions,
+    pub durability: DurabilityOptions,
+    pub observability: ObservabilityOptions,
+    /// Spread files over this many levels of hash-prefix directories instead of mirroring the
+    /// archive layout, recording where each entry went in a [`ShardManifest`](crate::shard::ShardManifest).
+    pub shard_levels: Option<u8>,
+    /// Only extract the entries under this archive path, e.g. `/index/serde`, placing it directly
+    /// in the destination (as `serde`) rather than under its parent directories.
+    pub subtree: Option<PathBuf>,
+    /// Recompress every extracted file, appending the encoding's extension to its name and
+    /// recording the result in a [`RecompressManifest`](crate::recompress::RecompressManifest).
+    pub recompress: Option<Recompress>,
+    /// Consulted for every entry the [`Filter`](crate::Filter) selects, only extracting those it
+    /// accepts. Not supported by the blocking extractors, nor for tar archives.
+    pub async_filter: Option<AsyncFilter>,
+    pub cancel: Option<CancelToken>,
+    /// Stop extracting once set, e.g. from a SIGTERM handler: the entries being extracted are
+    /// finished and the others skipped, and the blocking extractors return what they extracted
+    /// with [`ExtractReport::interrupted`](crate::ExtractReport::interrupted) set rather than
+    /// failing as with `cancel`. Ignored by the async extractors.
+    pub shutdown: Option<Arc<AtomicBool>>,
+    /// Plan the extraction without writing anything, describing what would be extracted in
+    /// [`ExtractReport::dry_run`](crate::ExtractReport::dry_run).
+    pub dry_run: bool,
+}
+
+/// How the archive is read and the destination written, none of which changes what is
+/// extracted.
+#[derive(Debug, Default)]
+pub struct IoOptions {
+    /// Squashfs variant and decompressor to read the archive with. Defaults to little-endian v4.0
+    /// with the compiled-in decompressors.
+    pub kind: Option<Kind>,
+    /// Read the archive with `O_DIRECT`; see [`FileSource::open_direct`](crate::source::FileSource::open_direct).
+    pub direct_io: bool,
+    /// Map the archive into memory instead of reading it, which `direct_io` is then ignored for;
+    /// see [`MmapSource`](crate::source::MmapSource).
+    #[cfg(feature = "mmap")]
+    pub mmap: bool,
+    /// Serve the files [`Archive`](crate::Archive) reads from copies kept in this directory,
+    /// extracting them into it when they are missing. Copies are keyed by the
+    /// [fingerprint](crate::Archive::fingerprint) of the archive and their path in it. Ignored
+    /// when extracting.
+    pub read_cache: Option<PathBuf>,
+    /// When a file stored whole in a fragment is read into the `read_cache`, also cache the other
+    /// files stored whole in it, which are decompressed along with it. Only applies with the
+    /// default [`kind`](Self::kind).
+    pub read_siblings: bool,
+    /// Compute the [fingerprint](crate::Archive::fingerprint) of an [`Archive`](crate::Archive)
+    /// while it is opened, on other threads. Ignored when extracting.
+    pub fingerprint: bool,
+    /// Open an [`Archive`](crate::Archive) from the entries and tables saved in this sidecar
+    /// index instead of parsing them, when the index was saved from the same archive, as told by
+    /// its superblock and, unless it is the same file, the hash of its tables recorded in the
+    /// index. Otherwise the archive is parsed and the index replaced. Ignored when extracting and
+    /// with a non-default [`kind`](Self::kind).
+    pub index: Option<PathBuf>,
+    /// Keep the paths of the entries of an [`Archive`](crate::Archive) as the name of each entry
+    /// and the index of its directory, built when it is opened, instead of backhand's full path
+    /// per entry, which takes several times less memory for archives with many entries. Listing
+    /// and looking up entries and reading files use this table; extracting and the other
+    /// operations rebuild the full paths for their duration. Ignored when extracting.
+    pub compact_paths: bool,
+    pub mechanisms: Mechanisms,
+    /// Runs the blocking work of the async extractors, on tokio's blocking pool by default. Not
+    /// used by the blocking extractors.
+    pub runtime: Option<Arc<dyn Runtime>>,
+    /// Maximum number of entries extracted at once. The async extractors default to the available
+    /// parallelism, the blocking ones to the size of the global rayon pool.
+    pub concurrency: Option<usize>,
+    pub strategy: Strategy,
+    /// How many data blocks of a single file are read and decompressed concurrently. Values
+    /// above 1 let extraction of one large (e.g. zstd-compressed) file use more than one core:
+    /// files of more than this many blocks then go through a pipeline overlapping the reading,
+    /// decompression and writing of their blocks. Also applies to the files an
+    /// [`Archive`](crate::Archive) opened with it reads. Defaults to 1.
+    ///
+    /// Only archives opened with the default [`kind`](Self::kind) use this decoder, since a
+    /// custom decompressor cannot be invoked outside of backhand; the others go through
+    /// backhand's reader, which serializes all reads of the archive.
+    pub block_decode_workers: Option<usize>,
+    /// How many decompressed fragment blocks an archive keeps, so that the workers extracting
+    /// small files whose tails share a fragment decompress it once rather than once per file.
+    /// Each block takes up to the block size of the archive. 0 disables the cache. Defaults to
+    /// 16. Applies when the archive is opened, so an [`Archive`](crate::Archive) keeps the one
+    /// it was opened with.
+    pub fragment_cache_blocks: Option<usize>,
+    /// Extract entries one at a time in a fixed order, for tools watching the destination that
+    /// expect parents to appear before their children and the same order on every run.
+    pub ordering: Ordering,
+    /// Capacity of the buffer each file is written into the destination through, allocated per
+    /// file being extracted and no larger than the file. Defaults to [`DEFAULT_WRITE_BUFFER`].
+    pub write_buffer: Option<usize>,
+    /// Length of the chunks the async extractors decode file data in, each on a blocking task of
+    /// its own and written before the next is decoded, or with a custom [`kind`](Self::kind) or
+    /// [`recompress`](ExtractOptions::recompress), handed from a decoding thread to the writing
+    /// task a few chunks ahead. Defaults to [`DEFAULT_READ_BUFFER`].
+    pub read_buffer: Option<usize>,
+    /// Write file data into the destination at no more than this many bytes per second, shared
+    /// by every entry being extracted, to leave disk bandwidth to other processes.
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+// `Kind` is not `Clone`, but can be rebuilt from itself
+impl Clone for IoOptions {
+    fn clone(&self) -> Self {
+        Self {
+            kind: self.kind.as_ref().map(Kind::from_kind),
+            direct_io: self.direct_io,
+            #[cfg(feature = "mmap")]
+            mmap: self.mmap,
+            read_cache: self.read_cache.clone(),
+            read_siblings: self.read_siblings,
+            fingerprint: self.fingerprint,
+            index: self.index.clone(),
+            compact_paths: self.compact_paths,
+            mechanisms: self.mechanisms,
+            runtime: self.runtime.clone(),
+            concurrency: self.concurrency,
+            strategy: self.strategy,
+            block_decode_workers: self.block_decode_workers,
+            fragment_cache_blocks: self.fragment_cache_blocks,
+            ordering: self.ordering,
+            write_buffer: self.write_buffer,
+            read_buffer: self.read_buffer,
+            max_bytes_per_sec: self.max_bytes_per_sec,
+        }
+    }
+}
+
+/// Which entries are extracted and what they are given, and what is done about the ones that
+/// cannot be.
+#[derive(Debug, Clone, Default)]
+pub struct PolicyOptions {
+    /// Maximum number of bytes of file data to write into the destination. The size of a file is
+    /// reserved while it is written, then it is charged what it takes once written: its length,
+    /// or the blocks allocated to it when they are fewer, for files with holes.
+    pub max_dest_bytes: Option<u64>,
+    pub quota_policy: QuotaPolicy,
+    /// Before writing anything, check that the filesystem holding the destination has room for
+    /// the files selected and an inode for each entry, failing with how much is needed and free
+    /// otherwise. Without it, only destinations on tmpfs are checked, and only for space.
+    pub check_space: bool,
+    pub overwrite: OverwritePolicy,
+    /// Skip entries that fail to extract (e.g. because of corrupt blocks) and list them in the
+    /// [`ExtractReport`](crate::ExtractReport) instead of failing the whole extraction.
+    pub salvage: bool,
+    /// Move what was written of entries skipped in salvage mode into this directory, at their path
+    /// in the archive, instead of deleting it.
+    pub quarantine: Option<PathBuf>,
+    /// Decides which I/O errors fail the extraction, are retried, or are ignored, taking
+    /// precedence over `salvage`.
+    pub error_classifier: Option<ErrorClassifier>,
+    /// Fail with [`Error::UnmatchedFilter`](crate::Error::UnmatchedFilter) before extracting
+    /// anything if entries of the [`Filter`](crate::Filter) match nothing in the archive, rather
+    /// than listing them in
+    /// [`ExtractReport::unmatched_filter`](crate::ExtractReport::unmatched_filter).
+    pub strict_filter: bool,
+    pub parsing: Parsing,
+    pub escape: EscapePolicy,
+    /// Create device nodes, named pipes and sockets instead of skipping or rejecting them
+    /// according to [`Parsing`]. Device nodes that this process may not create are skipped.
+    pub allow_special_files: bool,
+    pub permissions: PermissionPolicy,
+    /// Give entries the uid and gid recorded in the archive, which usually needs privileges.
+    pub preserve_ownership: bool,
+    /// Map the uids and gids recorded in the archive before giving them to entries with
+    /// [`preserve_ownership`](Self::preserve_ownership), e.g. to extract an image for a user
+    /// namespace.
+    pub id_map: Option<IdMap>,
+    /// Give entries the mtime recorded in the archive.
+    pub preserve_mtime: bool,
+    /// Give entries the extended attributes recorded in the archive. Without privileges, the
+    /// `security` and `trusted` namespaces are skipped. Only supported with the default
+    /// [`kind`](IoOptions::kind).
+    pub extract_xattrs: bool,
+    pub metadata_errors: MetadataErrorPolicy,
+}
+
+/// What is left in the destination when an extraction fails, is interrupted or is followed by a
+/// power failure.
+#[derive(Debug, Clone, Default)]
+pub struct DurabilityOptions {
+    pub fsync: Durability,
+    /// Extract into a staging directory next to `dest`, renamed into place once every entry was
+    /// extracted, so that `dest` never holds a partial extraction. If `dest` exists, the
+    /// top-level entries extracted replace the ones it holds instead of being merged with them,
+    /// and [`overwrite`](PolicyOptions::overwrite) only applies within the staging directory.
+    /// Not supported for tar archives.
+    pub atomic: bool,
+    /// Journal the files extracted completely into this file, and skip the ones an interrupted
+    /// extraction of the same archive journaled there if they are still intact in the
+    /// destination. The journal is removed once every entry was extracted. Not supported with
+    /// [`atomic`](Self::atomic).
+    pub resume: Option<PathBuf>,
+    /// Remove the temporary files an async extraction was writing when it is cancelled or its
+    /// future is dropped, instead of leaving them behind. Files only appear at their destination
+    /// once written completely either way.
+    pub cleanup_partial: bool,
+    /// Make the destination read-only once the extraction succeeded.
+    pub read_only: Option<ReadOnly>,
+}
+
+/// How the extraction is followed, and what is reported and recorded about it.
+#[derive(Debug, Clone, Default)]
+pub struct ObservabilityOptions {
+    /// Updated as entries are extracted; see [`MultiProgress`](crate::progress::MultiProgress) to
+    /// follow several extractions at once.
+    pub progress: Option<Arc<Progress>>,
+    /// Count the reads of each file of an [`Archive`](crate::Archive) and the hits of its caches;
+    /// see [`Archive::access_stats`](crate::Archive::access_stats). Ignored when extracting.
+    pub access_stats: bool,
+    /// Log extracting a single entry taking at least this long as slow, along with its size and
+    /// how it is stored. Defaults to [`DEFAULT_SLOW_ENTRY_THRESHOLD`]; [`Duration::MAX`] disables
+    /// the warning.
+    pub slow_entry_threshold: Option<Duration>,
+    /// Time the stages of the extraction into
+    /// [`ExtractReport::profile`](crate::ExtractReport::profile).
+    pub profile: bool,
+    /// Write a [`SHA256SUMS`](crate::sums::SHA256SUMS) manifest of the extracted files, which
+    /// `sha256sum -c` can check, into the destination; [`SHA512SUMS`](crate::sums::SHA512SUMS)
+    /// with [`HashAlgorithm::Sha512`](crate::HashAlgorithm::Sha512), and
+    /// [`B3SUMS`](crate::sums::B3SUMS), which `b3sum -c` can check, with
+    /// [`HashAlgorithm::Blake3`](crate::HashAlgorithm::Blake3).
+    pub sha256sums: bool,
+    /// The hash of the `sha256sums` manifest, also used by [`verify`](crate::verify).
+    pub hash: HashAlgorithm,
+    /// Hash the extracted tree into [`ExtractReport::nar_hash`](crate::ExtractReport::nar_hash).
+    pub nar_hash: bool,
+    /// Hash files as they are written into [`ExtractReport::digests`](crate::ExtractReport::digests),
+    /// along with the digest of the tree, for registering it in a Bazel remote cache.
+    pub digests: bool,
+    /// Send an [`AuditRecord`](crate::audit::AuditRecord) of the extraction here once it finishes.
+    #[cfg(feature = "audit")]
+    pub audit: Option<crate::audit::AuditSink>,
+    /// SQLite database to record the path, size, mode and hash of every extracted entry in.
+    #[cfg(feature = "sqlite")]
+    pub catalog: Option<PathBuf>,
+}
+
+impl ExtractOptions {
+    /// Options set a group at a time, e.g.
+    /// `ExtractOptions::builder().policy(PolicyOptions { salvage: true, ..Default::default() })`.
+    pub fn builder() -> ExtractOptionsBuilder {
+        ExtractOptionsBuilder::default()
+    }
+}
+
+/// Builds [`ExtractOptions`] a group of options at a time, starting from the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptionsBuilder(ExtractOptions);
+
+impl ExtractOptionsBuilder {
+    pub fn io(mut self, io: IoOptions) -> Self {
+        self.0.io = io;
+        self
+    }
+
+    pub fn policy(mut self, policy: PolicyOptions) -> Self {
+        self.0.policy = policy;
+        self
+    }
+
+    pub fn durability(mut self, durability: DurabilityOptions) -> Self {
+        self.0.durability = durability;
+        self
+    }
+
+    pub fn observability(mut self, observability: ObservabilityOptions) -> Self {
+        self.0.observability = observability;
+        self
+    }
+
+    pub fn shard_levels(mut self, levels: u8) -> Self {
+        self.0.shard_levels = Some(levels);
+        self
+    }
+
+    pub fn subtree(mut self, subtree: impl AsRef<Path>) -> Self {
+        self.0.subtree = Some(subtree.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn recompress(mut self, recompress: Recompress) -> Self {
+        self.0.recompress = Some(recompress);
+        self
+    }
+
+    pub fn async_filter(mut self, filter: AsyncFilter) -> Self {
+        self.0.async_filter = Some(filter);
+        self
+    }
+
+    pub fn cancel(mut self, cancel: CancelToken) -> Self {
+        self.0.cancel = Some(cancel);
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown: Arc<AtomicBool>) -> Self {
+        self.0.shutdown = Some(shutdown);
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.0.dry_run = dry_run;
+        self
+    }
+
+    pub fn build(self) -> ExtractOptions {
+        self.0
+    }
+}
+
+/// The settings applying to the extraction of each entry.
+#[derive(Clone, Copy)]
+pub(crate) struct NodeOptions<'a> {
+    pub(crate) quota: Option<&'a Quota>,
+    pub(crate) throttle: Option<&'a Throttle>,
+    pub(crate) shard_levels: Option<u8>,
+    pub(crate) subtree: Option<&'a Path>,
+    pub(crate) overwrite: OverwritePolicy,
+    pub(crate) recompress: Option<Recompress>,
+    pub(crate) progress: Option<&'a Arc<Progress>>,
+    pub(crate) cancel: Option<&'a CancelToken>,
+    pub(crate) cleanup_partial: bool,
+    pub(crate) metadata: Metadata,
+    pub(crate) digests: Option<&'a Mutex<Vec<(PathBuf, Digest)>>>,
+    pub(crate) journal: Option<&'a Journal>,
+    pub(crate) mechanisms: &'a Arc<Detected>,
+    pub(crate) xattrs: Option<&'a Xattrs>,
+    pub(crate) escape: EscapePolicy,
+    pub(crate) write_buffer: usize,
+    pub(crate) read_buffer: usize,
+    pub(crate) durability: Durability,
+    pub(crate) tally: &'a Tally,
+    pub(crate) counters: &'a Arc<Counters>,
+}
+
+impl NodeOptions<'_> {
+    /// Whether the data of `node` goes into the destination exactly as decoded, without being
+    /// hashed, throttled or reported chunk by chunk on the way, so that data stored uncompressed
+    /// can be copied there straight from the archive.
+    pub(crate) fn verbatim(&self, node: &Node<SquashfsFileReader>) -> bool {
+        self.recompress.is_none()
+            && self.throttle.is_none()
+            && self.digests.is_none()
+            && self.journal.is_none()
+            && EntryEvents::new(self.progress, node).is_none()
+    }
+
+    /// Capacity of the buffer to write a file of `file_size` bytes through.
+    pub(crate) fn write_buffer(&self, file_size: u32) -> usize {
+        self.write_buffer.min(file_size as usize)
+    }
+}
+
+pub(crate) struct Quota {
+    max: u64,
+    policy: QuotaPolicy,
+    used: AtomicU64,
+}
+
+impl Quota {
+    pub(crate) fn new(options: &ExtractOptions) -> Option<Self> {
+        options.policy.max_dest_bytes.map(|max| Self {
+            max,
+            policy: options.policy.quota_policy,
+            used: AtomicU64::new(0),
+        })
+    }
+
+    /// Reserve room for a file of `size` bytes. Returns `None` if the file should be skipped.
+    pub(crate) fn reserve(&self, size: u64) -> Result<Option<Reservation<'_>>> {
+        let used = self.used.fetch_add(size, atomic::Ordering::Relaxed) + size;
+        if used <= self.max {
+            return Ok(Some(Reservation { quota: self, size }));
+        }
+
+        self.used.fetch_sub(size, atomic::Ordering::Relaxed);
+        match self.policy {
+            QuotaPolicy::Abort => Err(Error::QuotaExceeded {
+                max: self.max,
+                needed: used,
+            }
+            .into()),
+            QuotaPolicy::Trim => Ok(None),
+        }
+    }
+}
+
+/// Room held in a [`Quota`] for a file being written, released if it is dropped before the file
+/// was [`charged`](Self::charge).
+pub(crate) struct Reservation<'a> {
+    quota: &'a Quota,
+    size: u64,
+}
+
+impl Reservation<'_> {
+    /// Charge the quota the bytes the written file takes, per `metadata`, instead of its size.
+    pub(crate) fn charge(mut self, metadata: &std::fs::Metadata) {
+        let used = &self.quota.used;
+        used.fetch_add(platform::data_bytes(metadata), atomic::Ordering::Relaxed);
+        used.fetch_sub(std::mem::take(&mut self.size), atomic::Ordering::Relaxed);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.quota
+            .used
+            .fetch_sub(self.size, atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestArchive};
+
+    fn quota(max: u64) -> Quota {
+        let options = ExtractOptions {
+            policy: PolicyOptions {
+                max_dest_bytes: Some(max),
+                quota_policy: QuotaPolicy::Trim,
+                ..PolicyOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        Quota::new(&options).unwrap()
+    }
+
+    #[test]
+    fn releases_reservation_of_unwritten_file() {
+        let quota = quota(10);
+        drop(quota.reserve(8).unwrap().unwrap());
+        assert!(quota.reserve(8).unwrap().is_some());
+        assert!(quota.reserve(8).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn charges_holes_as_unwritten() {
+        let zeros = vec![0; 1 << 20];
+        let archive = TestArchive::new(vec![
+            testing::file("a", zeros.clone()),
+            testing::file("b", zeros),
+        ]);
+        let options = || ExtractOptions {
+            io: IoOptions {
+                mechanisms: Mechanisms {
+                    fallocate: false,
+                    ..Mechanisms::default()
+                },
+                ..IoOptions::default()
+            },
+            policy: PolicyOptions {
+                max_dest_bytes: Some(3 << 19),
+                quota_policy: QuotaPolicy::Trim,
+                ..PolicyOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        let blocking = archive.scratch("blocking");
+        crate::unsquash_blocking(archive.path(), &blocking, crate::Filter::All, options()).unwrap();
+        let nonblocking = archive.scratch("async");
+        crate::unsquash_async(archive.path(), &nonblocking, crate::Filter::All, options())
+            .await
+            .unwrap();
+        for dest in [blocking, nonblocking] {
+            for name in ["a", "b"] {
+                assert_eq!(std::fs::metadata(dest.join(name)).unwrap().len(), 1 << 20);
+            }
+        }
+    }
+
+    fn node(path: &str) -> Node<SquashfsFileReader> {
+        Node {
+            fullpath: PathBuf::from(path),
+            header: backhand::NodeHeader::new(0o755, 0, 0, 0),
+            inner: InnerNode::Dir(backhand::SquashfsDir::default()),
+        }
+    }
+
+    #[test]
+    fn sorts_nodes() {
+        let nodes = ["/a", "/a/b/c", "/a-b", "/a/b", "/b", "/a-b/c"].map(node);
+        let sorted = |ordering: Ordering| {
+            let mut sorted: Vec<_> = nodes.iter().collect();
+            let sequential = ordering.sort(&mut sorted);
+            let paths: Vec<_> = sorted
+                .iter()
+                .map(|node| node.fullpath.to_str().unwrap())
+                .collect();
+            (sequential, paths)
+        };
+        assert_eq!(
+            sorted(Ordering::Unordered),
+            (false, vec!["/a", "/a/b/c", "/a-b", "/a/b", "/b", "/a-b/c"])
+        );
+        assert_eq!(
+            sorted(Ordering::BreadthFirst),
+            (true, vec!["/a", "/a-b", "/b", "/a/b", "/a-b/c", "/a/b/c"])
+        );
+        // by component, so that `/a` is followed by what it holds rather than by `/a-b`
+        assert_eq!(
+            sorted(Ordering::Lexicographic),
+            (true, vec!["/a", "/a/b", "/a/b/c", "/a-b", "/a-b/c", "/b"])
+        );
+    }
+
+    /// Records the paths of the entries an extraction is done with, in the order it is.
+    fn recorder() -> (Arc<Progress>, Arc<Mutex<Vec<PathBuf>>>) {
+        let done = Arc::new(Mutex::new(Vec::new()));
+        let progress = Progress::with_hook({
+            let done = Arc::clone(&done);
+            move |event: &crate::progress::ExtractEvent<'_>| {
+                if event.done {
+                    done.lock().unwrap().push(event.path.to_path_buf());
+                }
+            }
+        });
+        (Arc::new(progress), done)
+    }
+
+    #[tokio::test]
+    async fn extracts_in_order() {
+        let archive = TestArchive::new(vec![
+            testing::file("b/c", "c"),
+            testing::file("a/d", "d"),
+            testing::symlink("a/e", "d"),
+            testing::file("a-b", "a-b"),
+            testing::file("a/f/g", "g"),
+        ]);
+        let orderings = [
+            (
+                Ordering::BreadthFirst,
+                [
+                    "/", "/a", "/a-b", "/b", "/a/d", "/a/e", "/a/f", "/b/c", "/a/f/g",
+                ],
+            ),
+            (
+                Ordering::Lexicographic,
+                [
+                    "/", "/a", "/a/d", "/a/e", "/a/f", "/a/f/g", "/a-b", "/b", "/b/c",
+                ],
+            ),
+        ];
+        for (ordering, expected) in orderings {
+            let expected = expected.map(PathBuf::from);
+            let options = |progress| ExtractOptions {
+                io: IoOptions {
+                    ordering,
+                    ..IoOptions::default()
+                },
+                observability: ObservabilityOptions {
+                    progress: Some(progress),
+                    ..ObservabilityOptions::default()
+                },
+                ..ExtractOptions::default()
+            };
+
+            let (progress, done) = recorder();
+            let blocking = archive.scratch(&format!("blocking-{ordering:?}"));
+            crate::unsquash_blocking(
+                archive.path(),
+                &blocking,
+                crate::Filter::All,
+                options(progress),
+            )
+            .unwrap();
+            assert_eq!(*done.lock().unwrap(), expected, "{ordering:?}");
+
+            let (progress, done) = recorder();
+            let nonblocking = archive.scratch(&format!("async-{ordering:?}"));
+            crate::unsquash_async(
+                archive.path(),
+                &nonblocking,
+                crate::Filter::All,
+                options(progress),
+            )
+            .await
+            .unwrap();
+            assert_eq!(*done.lock().unwrap(), expected, "async {ordering:?}");
+
+            for dest in [blocking, nonblocking] {
+                assert_eq!(std::fs::read(dest.join("a/f/g")).unwrap(), b"g");
+                assert_eq!(std::fs::read(dest.join("a/e")).unwrap(), b"d");
+            }
+        }
+    }
+}
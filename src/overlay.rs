@@ -0,0 +1,370 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::{ensure, Context, Result};
+use backhand::{InnerNode, Node, SquashfsFileReader};
+
+use crate::{
+    archive::Input, executor::Executor, platform, runtime, Archive, Error, ExtractOptions,
+    ExtractReport, Filter,
+};
+
+/// Prefix of the name of an OCI/aufs whiteout, hiding the entry of the rest of its name in the
+/// layers below.
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+/// Name of an OCI/aufs opaque marker, hiding everything in its directory in the layers below.
+const OPAQUE_MARKER: &[u8] = b".wh..wh..opq";
+
+/// The entries each layer provides to the union view.
+struct Plan {
+    layers: Vec<HashSet<PathBuf>>,
+    whiteouts: usize,
+}
+
+/// What an entry of a layer does to the union view.
+enum Role {
+    /// Hides the entry at this path, and everything under it, in the layers below.
+    Whiteout(PathBuf),
+    /// Hides everything under this directory in the layers below.
+    Opaque(PathBuf),
+    Entry,
+}
+
+impl Role {
+    fn of(node: &Node<SquashfsFileReader>) -> Self {
+        let path = &node.fullpath;
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Self::Entry;
+        };
+        let name = name.as_encoded_bytes();
+        if name == OPAQUE_MARKER {
+            return Self::Opaque(parent.to_path_buf());
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            return Self::Whiteout(parent.join(platform::os_str_from_bytes(hidden)));
+        }
+        // overlayfs whiteouts are character devices numbered 0/0
+        match &node.inner {
+            InnerNode::CharacterDevice(dev) if dev.device_number == 0 => {
+                Self::Whiteout(path.clone())
+            }
+            _ => Self::Entry,
+        }
+    }
+}
+
+/// Paths the layers above hide from the ones below.
+#[derive(Default)]
+struct Hidden {
+    /// Hidden along with everything under them.
+    subtrees: HashSet<PathBuf>,
+    /// Directories whose contents are hidden, but not the directories themselves.
+    contents: HashSet<PathBuf>,
+}
+
+impl Hidden {
+    fn hides(&self, path: &Path) -> bool {
+        self.subtrees.contains(path)
+            || path
+                .ancestors()
+                .skip(1)
+                .any(|dir| self.subtrees.contains(dir) || self.contents.contains(dir))
+    }
+
+    fn extend(&mut self, other: Self) {
+        self.subtrees.extend(other.subtrees);
+        self.contents.extend(other.contents);
+    }
+}
+
+/// Decide which layer provides each entry selected by `filter`, going from the topmost down.
+fn plan(layers: &[Archive], filter: &Filter) -> Plan {
+    let (mut hidden, mut taken) = (Hidden::default(), HashSet::new());
+    let mut provided = Vec::with_capacity(layers.len());
+    let mut whiteouts = 0;
+    for layer in layers.iter().rev() {
+        // what this layer hides only applies to the ones below
+        let mut hides = Hidden::default();
+        let mut paths = HashSet::new();
+        for node in layer.filesystem.files() {
+            let path = &node.fullpath;
+            match Role::of(node) {
+                Role::Whiteout(path) => {
+                    whiteouts += 1;
+                    hides.subtrees.insert(path);
+                }
+                Role::Opaque(dir) => {
+                    whiteouts += 1;
+                    hides.contents.insert(dir);
+                }
+                Role::Entry if hidden.hides(path) || taken.contains(path) => {}
+                Role::Entry => {
+                    // a file replacing a directory hides what the layers below hold under it
+                    if !matches!(node.inner, InnerNode::Dir(_)) {
+                        hides.subtrees.insert(path.clone());
+                    }
+                    taken.insert(path.clone());
+                    if filter.matches(path) {
+                        paths.insert(path.clone());
+                    }
+                }
+            }
+        }
+        hidden.extend(hides);
+        provided.push(paths);
+    }
+    provided.reverse();
+    Plan {
+        layers: provided,
+        whiteouts,
+    }
+}
+
+fn check_options(options: &ExtractOptions) -> Result<()> {
+    // these describe or stage a single extraction, not the union of several
+    ensure!(
+        !options.durability.atomic,
+        "atomic overlay extraction is not supported"
+    );
+    ensure!(
+        options.durability.resume.is_none(),
+        "resuming overlay extractions is not supported"
+    );
+    ensure!(
+        !options.observability.sha256sums && !options.observability.digests,
+        "manifests and digests of overlay extractions are not supported"
+    );
+    Ok(())
+}
+
+/// The options each layer is opened with: planning walks the full paths of its entries.
+fn open_options(options: &ExtractOptions) -> ExtractOptions {
+    let mut options = options.clone();
+    options.io.compact_paths = false;
+    options
+}
+
+/// The options each layer is extracted with: the tree is finished once all of them are.
+fn layer_options(options: &ExtractOptions) -> ExtractOptions {
+    let mut options = options.clone();
+    options.durability.read_only = None;
+    options.observability.nar_hash = false;
+    options.policy.strict_filter = false;
+    options
+}
+
+/// Extract the union view of `layers` into `dest`, as overlayfs would mount them: `layers` go
+/// from the bottom up, and an entry of a layer shadows the entries at the same path in the
+/// layers below.
+///
+/// OCI whiteouts (`.wh.<name>` and `.wh..wh..opq`) and overlayfs whiteouts (character devices
+/// numbered 0/0) hide the entries of the layers below instead of being extracted. Every entry is
+/// written once, from the layer it is visible in; `filter` selects among the union view.
+///
+/// `atomic`, `resume`, `sha256sums` and `digests` are not supported.
+pub fn unsquash_overlay_blocking(
+    layers: impl IntoIterator<Item = impl AsRef<Path>>,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    overlay_blocking(layers, dest.as_ref(), filter, options).map_err(Error::from)
+}
+
+fn overlay_blocking(
+    layers: impl IntoIterator<Item = impl AsRef<Path>>,
+    dest: &Path,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport> {
+    check_options(&options)?;
+    let started = Instant::now();
+    let layers = layers
+        .into_iter()
+        .map(|layer| Archive::open(layer, open_options(&options)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let plan = plan(&layers, &filter);
+    tracing::debug!(
+        layers = layers.len(),
+        whiteouts = plan.whiteouts,
+        "planned overlay"
+    );
+    let executor = options
+        .io
+        .concurrency
+        .map_or(Executor::Rayon, Executor::Threads);
+    let mut report = ExtractReport::default();
+    for (layer, paths) in layers.into_iter().zip(plan.layers) {
+        let layer_options = layer_options(&options);
+        let layer_report = crate::unsquash_blocking_on(
+            Input::Opened(layer),
+            dest,
+            Filter::Paths(paths),
+            layer_options,
+            executor,
+        )?;
+        report.merge(layer_report);
+        if report.interrupted {
+            break;
+        }
+    }
+    if !options.dry_run && !report.interrupted {
+        report.nar_hash = crate::finish_tree(
+            dest,
+            options.durability.read_only,
+            options.observability.nar_hash,
+        )?;
+    }
+    report.summary.duration = started.elapsed();
+    Ok(report)
+}
+
+/// Async flavor of [`unsquash_overlay_blocking`].
+pub async fn unsquash_overlay_async(
+    layers: impl IntoIterator<Item = impl AsRef<Path>>,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    runtime::compat(overlay_async(layers, dest, filter, options)).await
+}
+
+async fn overlay_async(
+    layers: impl IntoIterator<Item = impl AsRef<Path>>,
+    dest: impl AsRef<Path>,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<ExtractReport, Error> {
+    check_options(&options)?;
+    let (dest, started) = (dest.as_ref().to_path_buf(), Instant::now());
+    let mut opened = Vec::new();
+    for layer in layers {
+        opened.push(Archive::open_async(layer, open_options(&options)).await?);
+    }
+    let planned = {
+        let layers = opened.clone();
+        tokio::task::spawn_blocking(move || plan(&layers, &filter))
+    };
+    let plan = planned
+        .await
+        .context("spawn blocking overlay planning task")?;
+    tracing::debug!(
+        layers = opened.len(),
+        whiteouts = plan.whiteouts,
+        "planned overlay"
+    );
+    let mut report = ExtractReport::default();
+    for (layer, paths) in opened.into_iter().zip(plan.layers) {
+        let layer_report = crate::async_unsquash::unsquash_async_on(
+            Input::Opened(layer),
+            &dest,
+            Filter::Paths(paths),
+            layer_options(&options),
+        )
+        .await?;
+        report.merge(layer_report);
+    }
+    if !options.dry_run {
+        let (read_only, nar_hash) = (options.durability.read_only, options.observability.nar_hash);
+        let finish = {
+            let dest = dest.clone();
+            tokio::task::spawn_blocking(move || crate::finish_tree(&dest, read_only, nar_hash))
+        };
+        report.nar_hash = finish
+            .await
+            .context("spawn blocking tree finishing task")??;
+    }
+    report.summary.duration = started.elapsed();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestArchive};
+
+    fn layers() -> [TestArchive; 2] {
+        let lower = TestArchive::new(vec![
+            testing::file("etc/a", "lower"),
+            testing::file("etc/b", "lower"),
+            testing::file("var/cache/x", "lower"),
+            testing::file("keep", "lower"),
+        ]);
+        let upper = TestArchive::new(vec![
+            // an overlayfs whiteout
+            testing::char_device("etc/a", 0),
+            // an OCI whiteout
+            testing::file("etc/.wh.b", ""),
+            testing::file("var/cache/.wh..wh..opq", ""),
+            testing::file("var/cache/y", "upper"),
+            testing::file("keep", "upper"),
+        ]);
+        [lower, upper]
+    }
+
+    /// Every entry under `dir`, with the contents of files, sorted by path.
+    fn tree(dir: &Path) -> Vec<(PathBuf, Option<String>)> {
+        fn walk(root: &Path, dir: &Path, out: &mut Vec<(PathBuf, Option<String>)>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                let relative = path.strip_prefix(root).unwrap().to_path_buf();
+                if path.is_dir() {
+                    out.push((relative, None));
+                    walk(root, &path, out);
+                } else {
+                    out.push((relative, Some(std::fs::read_to_string(&path).unwrap())));
+                }
+            }
+        }
+        let mut entries = Vec::new();
+        walk(dir, dir, &mut entries);
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn extracts_union_view() {
+        let [lower, upper] = layers();
+        let dest = lower.scratch("dest");
+        let report = unsquash_overlay_blocking(
+            [lower.path(), upper.path()],
+            &dest,
+            Filter::All,
+            ExtractOptions::default(),
+        )
+        .unwrap();
+        assert!(!report.interrupted);
+        assert_eq!(
+            tree(&dest),
+            [
+                ("etc".into(), None),
+                ("keep".into(), Some("upper".into())),
+                ("var".into(), None),
+                ("var/cache".into(), None),
+                ("var/cache/y".into(), Some("upper".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn plans_whiteouts() {
+        let [lower, upper] = layers();
+        let devices = TestArchive::new(vec![
+            // not numbered 0/0, so an entry like any other
+            testing::char_device("etc/b", (1 << 8) | 3),
+        ]);
+        let layers = [lower.path(), upper.path(), devices.path()]
+            .map(|path| Archive::open(path, open_options(&ExtractOptions::default())).unwrap());
+        let plan = plan(&layers, &Filter::All);
+        assert_eq!(plan.whiteouts, 3);
+        let provided = |layer: usize, path: &str| plan.layers[layer].contains(Path::new(path));
+        assert!(!provided(0, "/etc/a") && !provided(1, "/etc/a"));
+        assert!(!provided(0, "/etc/b") && provided(2, "/etc/b"));
+        assert!(!provided(0, "/var/cache/x") && provided(1, "/var/cache/y"));
+        assert!(!provided(1, "/var/cache/.wh..wh..opq") && !provided(1, "/etc/.wh.b"));
+        assert!(provided(1, "/keep") && !provided(0, "/keep"));
+    }
+}
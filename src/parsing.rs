@@ -0,0 +1,90 @@
+use anyhow::Result;
+use backhand::{InnerNode, Node, Squashfs, SquashfsFileReader};
+use serde::{Deserialize, Serialize};
+
+use crate::{EntryKind, Error};
+
+/// How strictly to treat archives that deviate from the squashfs spec, as produced by some vendor
+/// forks of mksquashfs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Parsing {
+    /// Reject unknown superblock flags, unparseable compression options and entries that cannot
+    /// be extracted.
+    #[default]
+    Strict,
+    /// Ignore what can be ignored, with a warning, and skip entries that cannot be extracted.
+    Lenient,
+}
+
+/// Superblock flags defined by squashfs 4.0, including the uncompressed id table flag added by
+/// squashfs-tools 4.4.
+pub(crate) const KNOWN_FLAGS: u16 = 0x0fff;
+pub(crate) const COMPRESSOR_OPTIONS_PRESENT: u16 = 0x0400;
+
+/// Check the parts of the archive that backhand tolerates silently.
+pub(crate) fn check_archive(squashfs: &Squashfs, name: &str, parsing: Parsing) -> Result<()> {
+    let superblock = &squashfs.superblock;
+    let mut deviations = Vec::new();
+    if superblock.flags & !KNOWN_FLAGS != 0 {
+        deviations.push(format!(
+            "unknown superblock flags {:#06x}",
+            superblock.flags & !KNOWN_FLAGS
+        ));
+    }
+    if superblock.flags & COMPRESSOR_OPTIONS_PRESENT != 0 && squashfs.compression_options.is_none()
+    {
+        deviations.push("compression options are flagged but could not be parsed".to_owned());
+    }
+
+    match parsing {
+        Parsing::Strict => match deviations.is_empty() {
+            true => Ok(()),
+            false => anyhow::bail!(
+                "squashfs '{name}' deviates from the spec: {}",
+                deviations.join(", ")
+            ),
+        },
+        Parsing::Lenient => {
+            for deviation in deviations {
+                tracing::warn!(source = %name, "ignoring spec deviation: {deviation}");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Drop (lenient) or reject (strict) the entries that cannot be extracted, i.e. device nodes,
+/// named pipes and sockets unless `allow_special_files`.
+pub(crate) fn supported_nodes(
+    nodes: Vec<&Node<SquashfsFileReader>>,
+    parsing: Parsing,
+    allow_special_files: bool,
+) -> Result<Vec<&Node<SquashfsFileReader>>> {
+    if allow_special_files {
+        return Ok(nodes);
+    }
+    let mut supported = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let kind = match node.inner {
+            InnerNode::File(_) | InnerNode::Symlink(_) | InnerNode::Dir(_) => {
+                supported.push(node);
+                continue;
+            }
+            _ => EntryKind::of(&node.inner),
+        };
+        match parsing {
+            Parsing::Strict => {
+                return Err(Error::UnsupportedNode {
+                    path: node.fullpath.clone(),
+                    kind,
+                }
+                .into())
+            }
+            Parsing::Lenient => {
+                tracing::warn!(path = %node.fullpath.display(), "skipping {kind}")
+            }
+        }
+    }
+    Ok(supported)
+}
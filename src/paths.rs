@@ -0,0 +1,225 @@
+//! The paths of the entries of an archive opened with
+//! [`compact_paths`](crate::IoOptions::compact_paths): each entry records its name and the
+//! index of its directory, so that the prefix shared by the entries of a directory is stored once
+//! instead of once per entry.
+
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use backhand::{InnerNode, Node, SquashfsFileReader};
+
+use crate::platform;
+
+pub(crate) struct PathTable {
+    /// Index of the directory holding each entry; the root, which is first, is its own.
+    parents: Vec<u32>,
+    /// Where the name of each entry ends in `names`, which holds them back to back.
+    ends: Vec<u32>,
+    names: Vec<u8>,
+}
+
+impl PathTable {
+    /// The table of the paths of `nodes`, in the order backhand sorts them, or `None` if they
+    /// are too many or their names too long for it.
+    pub(crate) fn new(nodes: &[Node<SquashfsFileReader>]) -> Option<Self> {
+        let mut table = Self {
+            parents: Vec::with_capacity(nodes.len()),
+            ends: Vec::with_capacity(nodes.len()),
+            names: Vec::new(),
+        };
+        // the directories holding the last entry, innermost last
+        let mut ancestors: Vec<usize> = Vec::new();
+        for (i, node) in nodes.iter().enumerate() {
+            let parent = match node.fullpath.parent() {
+                None if i == 0 => 0,
+                None => return None,
+                Some(parent) => {
+                    while nodes[*ancestors.last()?].fullpath != parent {
+                        ancestors.pop();
+                    }
+                    *ancestors.last()?
+                }
+            };
+            let name = node.fullpath.file_name().unwrap_or_default();
+            table.names.extend_from_slice(name.as_encoded_bytes());
+            table.parents.push(u32::try_from(parent).ok()?);
+            table.ends.push(u32::try_from(table.names.len()).ok()?);
+            if matches!(node.inner, InnerNode::Dir(_)) {
+                ancestors.push(i);
+            }
+        }
+        table.names.shrink_to_fit();
+        Some(table)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.parents.len()
+    }
+
+    /// The name of entry `i`, empty for the root.
+    pub(crate) fn name(&self, i: usize) -> Cow<'_, OsStr> {
+        let start = match i {
+            0 => 0,
+            i => self.ends[i - 1] as usize,
+        };
+        platform::os_str_from_bytes(&self.names[start..self.ends[i] as usize])
+    }
+
+    /// The index of the directory holding entry `i`, `None` for the root.
+    pub(crate) fn parent(&self, i: usize) -> Option<usize> {
+        (i != 0).then(|| self.parents[i] as usize)
+    }
+
+    /// The absolute path of entry `i`.
+    pub(crate) fn path(&self, i: usize) -> PathBuf {
+        let mut path = PathBuf::new();
+        self.path_into(i, &mut path);
+        path
+    }
+
+    /// Like [`path`](Self::path), reusing the allocation of `path`.
+    pub(crate) fn path_into(&self, i: usize, path: &mut PathBuf) {
+        path.clear();
+        path.push("/");
+        self.push(i, path);
+    }
+
+    fn push(&self, i: usize, path: &mut PathBuf) {
+        if let Some(parent) = self.parent(i) {
+            self.push(parent, path);
+            path.push(self.name(i));
+        }
+    }
+
+    /// The index of the entry at the absolute `path`.
+    pub(crate) fn find(&self, path: &Path) -> Option<usize> {
+        let i = self.partition_point(path);
+        (i < self.len() && self.path(i) == path).then_some(i)
+    }
+
+    /// The index of the first entry whose path is not less than `path`.
+    pub(crate) fn partition_point(&self, path: &Path) -> usize {
+        let (mut low, mut high) = (0, self.len());
+        let mut mid_path = PathBuf::new();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            self.path_into(mid, &mut mid_path);
+            match mid_path.as_path().cmp(path) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Equal | Ordering::Greater => high = mid,
+            }
+        }
+        low
+    }
+
+    /// Bytes allocated for the table.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        (self.parents.capacity() + self.ends.capacity()) * size_of::<u32>() + self.names.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backhand::{NodeHeader, SquashfsDir, SquashfsSymlink};
+
+    use super::*;
+    use crate::{
+        testing::{self, TestArchive},
+        Archive, ExtractOptions, Filter, IoOptions,
+    };
+
+    fn node(path: &str, dir: bool) -> Node<SquashfsFileReader> {
+        Node {
+            fullpath: PathBuf::from(path),
+            header: NodeHeader::new(0o755, 0, 0, 0),
+            inner: match dir {
+                true => InnerNode::Dir(SquashfsDir::default()),
+                false => InnerNode::Symlink(SquashfsSymlink {
+                    link: PathBuf::from("target"),
+                }),
+            },
+        }
+    }
+
+    #[test]
+    fn rebuilds_paths() {
+        let nodes = [
+            node("/", true),
+            node("/a", true),
+            node("/a/b", true),
+            node("/a/b/c", false),
+            node("/a/d", false),
+            node("/e", false),
+        ];
+        let table = PathTable::new(&nodes).unwrap();
+        for (i, node) in nodes.iter().enumerate() {
+            assert_eq!(table.path(i), node.fullpath);
+            assert_eq!(table.find(&node.fullpath), Some(i));
+        }
+        assert_eq!(table.parent(3), Some(2));
+        assert_eq!(table.parent(5), Some(0));
+        assert_eq!(table.parent(0), None);
+        assert_eq!(&*table.name(4), OsStr::new("d"));
+        assert_eq!(table.find(Path::new("/a/c")), None);
+        assert_eq!(table.partition_point(Path::new("/a/c")), 4);
+    }
+
+    #[test]
+    fn rejects_entries_outside_directories() {
+        assert!(PathTable::new(&[node("/", true), node("/a/b", false)]).is_none());
+    }
+
+    #[test]
+    fn compact_archive_matches_full_paths() {
+        let archive = TestArchive::new(vec![
+            testing::dir("a"),
+            testing::file("a/b", "b"),
+            testing::dir("a/c"),
+            testing::file("a/c/d", "d"),
+            testing::symlink("a/e", "c/d"),
+            testing::file("ab", "ab"),
+        ]);
+        let full = Archive::open(archive.path(), ExtractOptions::default()).unwrap();
+        let compact = ExtractOptions {
+            io: IoOptions {
+                compact_paths: true,
+                ..IoOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        let compact = Archive::open(archive.path(), compact).unwrap();
+        assert_eq!(
+            compact.list().collect::<Vec<_>>(),
+            full.list().collect::<Vec<_>>()
+        );
+        let mut visited = Vec::new();
+        compact.for_each_entry(|entry| visited.push(entry.to_info()));
+        assert_eq!(visited, full.list().collect::<Vec<_>>());
+        assert_eq!(compact.find("a/c/d"), full.find("a/c/d"));
+        assert_eq!(compact.find("a/x"), None);
+        let under = |archive: &Archive| -> Vec<_> {
+            archive
+                .find_under("a")
+                .map(|entry| entry.to_info())
+                .collect()
+        };
+        assert_eq!(under(&compact), under(&full));
+        assert_eq!(under(&compact).len(), 5);
+        assert_eq!(compact.read_file_blocking("/a/c/d").unwrap(), b"d");
+
+        let dest = archive.scratch("dest");
+        compact
+            .extract_blocking(&dest, Filter::All, ExtractOptions::default())
+            .unwrap();
+        assert_eq!(std::fs::read(dest.join("a/c/d")).unwrap(), b"d");
+        assert_eq!(std::fs::read(dest.join("ab")).unwrap(), b"ab");
+        assert_eq!(
+            std::fs::read_link(dest.join("a/e")).unwrap(),
+            Path::new("c/d")
+        );
+    }
+}
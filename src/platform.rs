@@ -0,0 +1,159 @@
+//! What differs between extracting on Unix and elsewhere. Other platforms get regular files and
+//! directories, with their modes reduced to the read-only attribute; symlinks, special files,
+//! ownership and xattrs are skipped, and the Linux-specific
+//! [`Mechanisms`](crate::mechanisms::Mechanisms) are not used.
+
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fs::{File, Metadata},
+    io,
+    path::Path,
+};
+
+/// Whether symlinks are created rather than skipped.
+pub(crate) const SYMLINKS: bool = cfg!(unix);
+
+/// Give the file or directory at `path` the permission bits of `mode`.
+pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        std::fs::set_permissions(path, permissions)
+    }
+}
+
+/// Like [`set_mode`], for a file already open.
+pub(crate) fn set_file_mode(file: &File, mode: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = file.metadata()?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        file.set_permissions(permissions)
+    }
+}
+
+/// The permission bits of an entry, made up from its type and read-only attribute off Unix.
+pub(crate) fn mode(metadata: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        let mode = match metadata.is_dir() {
+            true => 0o755,
+            false => 0o644,
+        };
+        match metadata.permissions().readonly() {
+            true => mode & !0o222,
+            false => mode,
+        }
+    }
+}
+
+/// The bytes of data `metadata` tells a file takes: its length, or the blocks allocated to it
+/// when they are fewer, for files with holes.
+pub(crate) fn data_bytes(metadata: &Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.len().min(metadata.blocks() * 512)
+    }
+    #[cfg(not(unix))]
+    {
+        metadata.len()
+    }
+}
+
+/// The modification time of an entry, in seconds since the epoch.
+pub(crate) fn mtime(metadata: &Metadata) -> i64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.mtime()
+    }
+    #[cfg(not(unix))]
+    {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs() as i64)
+    }
+}
+
+/// Create a symlink at `path` pointing to `target`, which fails with
+/// [`Unsupported`](io::ErrorKind::Unsupported) unless [`SYMLINKS`].
+pub(crate) fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, path)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (target, path);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "symlinks are only supported on Unix",
+        ))
+    }
+}
+
+/// Read into `buf` from `offset` in `file`, which moves its cursor off Unix.
+pub(crate) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read(buf)
+    }
+}
+
+/// The name recorded in an archive as `bytes`, which is lossily decoded as UTF-8 off Unix.
+pub(crate) fn os_str_from_bytes(bytes: &[u8]) -> Cow<'_, OsStr> {
+    #[cfg(unix)]
+    {
+        Cow::Borrowed(std::os::unix::ffi::OsStrExt::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        match String::from_utf8_lossy(bytes) {
+            Cow::Borrowed(name) => Cow::Borrowed(OsStr::new(name)),
+            Cow::Owned(name) => Cow::Owned(name.into()),
+        }
+    }
+}
+
+/// Like [`os_str_from_bytes`], for an owned name.
+pub(crate) fn os_string_from_vec(bytes: Vec<u8>) -> OsString {
+    #[cfg(unix)]
+    {
+        std::os::unix::ffi::OsStringExt::from_vec(bytes)
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(&bytes).into_owned().into()
+    }
+}
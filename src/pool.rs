@@ -0,0 +1,341 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread::JoinHandle,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cancel::CancelToken,
+    executor::{Executor, FairShare},
+    pool_journal::{JobRecord, Journal},
+    progress::{Progress, ProgressSnapshot},
+    ExtractOptions, ExtractReport,
+};
+
+pub type JobId = u64;
+
+/// An extraction to run on an [`ExtractorPool`], with the arguments of
+/// [`unsquash_tpcii_blocking_with_options`](crate::unsquash_tpcii_blocking_with_options).
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub squashfs: PathBuf,
+    pub dest: PathBuf,
+    pub crates_filter: Option<HashSet<String>>,
+    pub options: ExtractOptions,
+    /// Jobs with a higher priority are started first; equal priorities run in submission order.
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "detail", rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running(ProgressSnapshot),
+    Succeeded(ExtractReport),
+    Failed(String),
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Succeeded(_) | Self::Failed(_) | Self::Cancelled)
+    }
+}
+
+/// Runs extraction jobs on a fixed number of threads, so that a service extracting many archives
+/// bounds how many it works on at once.
+///
+/// Running jobs share as many entry-level work slots as rayon has threads. Free slots go to the job
+/// holding the fewest, so a giant archive does not starve small ones running next to it.
+pub struct ExtractorPool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    share: FairShare,
+    journal_dir: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct State {
+    queue: BinaryHeap<(i32, Reverse<JobId>)>,
+    jobs: HashMap<JobId, Entry>,
+    next_id: JobId,
+    shutdown: bool,
+    journal: Option<Journal>,
+}
+
+struct Entry {
+    job: Option<Job>,
+    status: JobStatus,
+    cancel: CancelToken,
+    progress: Arc<Progress>,
+}
+
+impl ExtractorPool {
+    /// Start a pool running up to `workers` jobs concurrently. Each job still extracts its
+    /// entries on the rayon thread pool.
+    pub fn new(workers: usize) -> Self {
+        Self::start(workers, State::default())
+    }
+
+    /// Like [`new`](Self::new), but journaling jobs to `dir` so that a pool opened on the same
+    /// directory after a crash or restart knows the outcome of earlier jobs and runs again those
+    /// that had not finished. Jobs with a custom [`Kind`](crate::compression::Kind) are not
+    /// journaled.
+    ///
+    /// Resumed jobs extract from scratch over whatever the interrupted run left in their
+    /// destination. Paths are journaled relative to `dir`, so the journal can be moved to another
+    /// host with the archives and destinations it refers to, keeping their relative layout, and
+    /// resumed there. Jobs whose archive changed since they were submitted, or journaled with
+    /// options this build does not support, fail instead of being resumed.
+    pub fn with_journal(workers: usize, dir: impl AsRef<Path>) -> Result<Self> {
+        let (journal, recovered) = Journal::open(dir.as_ref())?;
+        let mut state = State {
+            journal: Some(journal),
+            ..Default::default()
+        };
+        for recovered in recovered {
+            let id = recovered.id;
+            state.next_id = state.next_id.max(id + 1);
+            let mut entry = Entry::new(recovered.job);
+            match recovered.status {
+                Some(status) => {
+                    entry.job = None;
+                    entry.status = status;
+                }
+                None => {
+                    tracing::info!(id, "resuming interrupted extraction job");
+                    state.queue.push((entry.priority(), Reverse(id)));
+                }
+            }
+            state.jobs.insert(id, entry);
+        }
+        Ok(Self::start(workers, state))
+    }
+
+    fn start(workers: usize, state: State) -> Self {
+        let shared = Arc::new(Shared {
+            journal_dir: state
+                .journal
+                .as_ref()
+                .map(|journal| journal.dir().to_path_buf()),
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            share: FairShare::new(rayon::current_num_threads()),
+        });
+        let workers = (0..workers.max(1))
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+                std::thread::Builder::new()
+                    .name(format!("extractor-pool-{i}"))
+                    .spawn(move || shared.work())
+                    .expect("spawn extractor pool worker")
+            })
+            .collect();
+        Self { shared, workers }
+    }
+
+    pub fn submit(&self, job: Job) -> JobId {
+        // hashing the archive for the journal is done without holding the lock
+        let record = self
+            .shared
+            .journal_dir
+            .as_deref()
+            .and_then(|dir| JobRecord::new(&job, dir));
+        let mut state = self.shared.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        if let (Some(journal), Some(record)) = (&mut state.journal, record) {
+            journal.submitted(id, record);
+        }
+        state.queue.push((job.priority, Reverse(id)));
+        state.jobs.insert(id, Entry::new(job));
+        self.shared.changed.notify_all();
+        id
+    }
+
+    /// Cancel a queued or running job. Returns `false` if the job is unknown or already finished.
+    pub fn cancel(&self, id: JobId) -> bool {
+        let mut state = self.shared.lock();
+        let state = &mut *state;
+        let Some(entry) = state.jobs.get_mut(&id) else {
+            return false;
+        };
+        match entry.status {
+            JobStatus::Queued => {
+                entry.job = None;
+                entry.status = JobStatus::Cancelled;
+                if let Some(journal) = &mut state.journal {
+                    journal.finished(id, &entry.status);
+                }
+                self.shared.changed.notify_all();
+            }
+            JobStatus::Running(_) => entry.cancel.cancel(),
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn status(&self, id: JobId) -> Option<JobStatus> {
+        self.shared.lock().jobs.get(&id).map(Entry::status)
+    }
+
+    /// Block until the job has finished, returning its final status.
+    pub fn wait(&self, id: JobId) -> Option<JobStatus> {
+        let mut state = self.shared.lock();
+        loop {
+            let status = state.jobs.get(&id)?.status();
+            if status.is_finished() {
+                return Some(status);
+            }
+            state = self
+                .shared
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Forget a finished job, returning its final status.
+    pub fn remove(&self, id: JobId) -> Option<JobStatus> {
+        let mut state = self.shared.lock();
+        let status = state.jobs.get(&id)?.status();
+        status.is_finished().then(|| {
+            state.jobs.remove(&id);
+            if let Some(journal) = &mut state.journal {
+                journal.removed(id);
+            }
+            status
+        })
+    }
+}
+
+/// Cancels queued and running jobs and waits for the workers to exit. With a journal, these jobs
+/// are resumed by the next pool.
+impl Drop for ExtractorPool {
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.lock();
+            state.shutdown = true;
+            for entry in state.jobs.values() {
+                entry.cancel.cancel();
+            }
+            self.shared.changed.notify_all();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Entry {
+    fn new(mut job: Job) -> Self {
+        let cancel = job
+            .options
+            .cancel
+            .get_or_insert_with(CancelToken::new)
+            .clone();
+        let progress = Arc::clone(job.options.observability.progress.get_or_insert_default());
+        Self {
+            job: Some(job),
+            status: JobStatus::Queued,
+            cancel,
+            progress,
+        }
+    }
+
+    fn priority(&self) -> i32 {
+        self.job.as_ref().map_or(0, |job| job.priority)
+    }
+
+    fn status(&self) -> JobStatus {
+        match self.status {
+            JobStatus::Running(_) => JobStatus::Running(self.progress.snapshot()),
+            ref status => status.clone(),
+        }
+    }
+}
+
+impl State {
+    /// Take the highest priority queued job and mark it running.
+    fn start_next(&mut self) -> Option<(JobId, Job)> {
+        while let Some((_, Reverse(id))) = self.queue.pop() {
+            // cancelled jobs stay in the queue without their job
+            let Some(entry) = self.jobs.get_mut(&id) else {
+                continue;
+            };
+            let Some(job) = entry.job.take() else {
+                continue;
+            };
+            entry.status = JobStatus::Running(ProgressSnapshot::default());
+            if let Some(journal) = &mut self.journal {
+                journal.started(id);
+            }
+            return Some((id, job));
+        }
+        None
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn work(&self) {
+        loop {
+            let (id, job) = {
+                let mut state = self.lock();
+                loop {
+                    if state.shutdown {
+                        return;
+                    }
+                    if let Some(next) = state.start_next() {
+                        break next;
+                    }
+                    state = self
+                        .changed
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            };
+
+            let cancel = job.options.cancel.clone();
+            let res = crate::open_source(&job.squashfs, &job.options).and_then(|source| {
+                crate::unsquash_blocking_on(
+                    crate::archive::Input::Source(Arc::new(source)),
+                    job.dest,
+                    crate::tpcii_filter(job.crates_filter),
+                    job.options,
+                    Executor::Fair(&self.share, id),
+                )
+            });
+            let status = match res {
+                _ if CancelToken::check(cancel.as_ref()).is_err() => JobStatus::Cancelled,
+                Ok(report) => JobStatus::Succeeded(report),
+                Err(e) => JobStatus::Failed(format!("{e:#}")),
+            };
+
+            let mut state = self.lock();
+            let state = &mut *state;
+            // jobs cancelled by dropping the pool are left unfinished in the journal
+            let interrupted = state.shutdown && status == JobStatus::Cancelled;
+            if let Some(journal) = state.journal.as_mut().filter(|_| !interrupted) {
+                journal.finished(id, &status);
+            }
+            if let Some(entry) = state.jobs.get_mut(&id) {
+                entry.status = status;
+            }
+            self.changed.notify_all();
+        }
+    }
+}
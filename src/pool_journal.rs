@@ -0,0 +1,507 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    digest,
+    mechanisms::Mechanisms,
+    pool::{Job, JobId, JobStatus},
+    protect::ReadOnly,
+    Durability, DurabilityOptions, EscapePolicy, ExtractOptions, HashAlgorithm, IdMap, IoOptions,
+    MetadataErrorPolicy, ObservabilityOptions, Ordering, OverwritePolicy, Parsing,
+    PermissionPolicy, PolicyOptions, QuotaPolicy, Strategy,
+};
+
+const JOURNAL: &str = "jobs.jsonl";
+
+/// Append-only record of [`ExtractorPool`](crate::pool::ExtractorPool) jobs, replayed on startup.
+///
+/// Paths are recorded relative to the journal directory, so that the journal can be moved to
+/// another host along with the archives and destinations of its jobs and resumed there.
+pub(crate) struct Journal {
+    file: File,
+    path: PathBuf,
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Record {
+    Submitted { id: JobId, job: JobRecord },
+    Started { id: JobId },
+    Finished { id: JobId, status: JobStatus },
+    Removed { id: JobId },
+}
+
+/// The parts of a [`Job`] that can be persisted.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct JobRecord {
+    squashfs: PathBuf,
+    dest: PathBuf,
+    /// SHA-256 of the archive when the job was submitted, so that a job resumed on another host
+    /// does not finish the extraction from a different archive.
+    #[serde(default)]
+    archive_hash: Option<String>,
+    /// SHA-256 of the rest of the record, so that a job resumed by a build that does not
+    /// understand all of its options fails instead of extracting differently.
+    #[serde(default)]
+    options_hash: Option<String>,
+    crates_filter: Option<BTreeSet<String>>,
+    priority: i32,
+    max_dest_bytes: Option<u64>,
+    quota_policy: QuotaPolicy,
+    #[serde(default)]
+    check_space: bool,
+    #[serde(default)]
+    max_bytes_per_sec: Option<u64>,
+    #[serde(default)]
+    overwrite: OverwritePolicy,
+    shard_levels: Option<u8>,
+    #[serde(default)]
+    subtree: Option<PathBuf>,
+    recompress: Option<crate::recompress::Recompress>,
+    salvage: bool,
+    #[serde(default)]
+    quarantine: Option<PathBuf>,
+    #[serde(default)]
+    strict_filter: bool,
+    parsing: Parsing,
+    direct_io: bool,
+    #[cfg(feature = "mmap")]
+    #[serde(default)]
+    mmap: bool,
+    #[serde(default)]
+    mechanisms: Mechanisms,
+    #[serde(default)]
+    cleanup_partial: bool,
+    #[serde(default)]
+    concurrency: Option<usize>,
+    #[serde(default)]
+    strategy: Strategy,
+    #[serde(default)]
+    block_decode_workers: Option<usize>,
+    #[serde(default)]
+    fragment_cache_blocks: Option<usize>,
+    #[serde(default)]
+    ordering: Ordering,
+    #[serde(default)]
+    durability: Durability,
+    #[serde(default)]
+    write_buffer: Option<usize>,
+    #[serde(default)]
+    read_buffer: Option<usize>,
+    #[serde(default)]
+    permissions: PermissionPolicy,
+    #[serde(default)]
+    preserve_ownership: bool,
+    #[serde(default)]
+    id_map: Option<IdMap>,
+    #[serde(default)]
+    preserve_mtime: bool,
+    #[serde(default)]
+    extract_xattrs: bool,
+    #[serde(default)]
+    metadata_errors: MetadataErrorPolicy,
+    #[serde(default)]
+    slow_entry_threshold: Option<Duration>,
+    #[serde(default)]
+    read_only: Option<ReadOnly>,
+    #[serde(default)]
+    allow_special_files: bool,
+    #[serde(default)]
+    escape: EscapePolicy,
+    #[serde(default)]
+    sha256sums: bool,
+    #[serde(default)]
+    hash: HashAlgorithm,
+    #[serde(default)]
+    nar_hash: bool,
+    #[serde(default)]
+    digests: bool,
+    #[serde(default)]
+    profile: bool,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    resume: Option<PathBuf>,
+    #[serde(default)]
+    atomic: bool,
+    #[cfg(feature = "audit")]
+    #[serde(default)]
+    audit: Option<crate::audit::AuditSink>,
+    #[cfg(feature = "sqlite")]
+    catalog: Option<PathBuf>,
+}
+
+/// A job found in the journal. Jobs without a final status were queued or running when the
+/// previous pool stopped.
+pub(crate) struct Recovered {
+    pub(crate) id: JobId,
+    pub(crate) job: Job,
+    pub(crate) status: Option<JobStatus>,
+}
+
+impl JobRecord {
+    /// The record of `job` for the journal in `dir`, hashing its archive. Returns `None` for jobs
+    /// that cannot be journaled.
+    pub(crate) fn new(job: &Job, dir: &Path) -> Option<Self> {
+        let options = &job.options;
+        if options.io.kind.is_some() {
+            tracing::warn!(
+                squashfs = %job.squashfs.display(),
+                "not journaling job with a custom kind, it will not be resumed after a restart"
+            );
+            return None;
+        }
+        if options.async_filter.is_some() {
+            tracing::warn!(
+                squashfs = %job.squashfs.display(),
+                "not journaling job with an async filter, it will not be resumed after a restart"
+            );
+            return None;
+        }
+        let archive_hash = match digest::sha256_file(&job.squashfs) {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                tracing::warn!("not recording the archive hash of journaled job: {e:#}");
+                None
+            }
+        };
+        let mut record = Self {
+            squashfs: relative(&job.squashfs, dir),
+            dest: relative(&job.dest, dir),
+            archive_hash,
+            options_hash: None,
+            crates_filter: job
+                .crates_filter
+                .as_ref()
+                .map(|crates| crates.iter().cloned().collect()),
+            priority: job.priority,
+            max_dest_bytes: options.policy.max_dest_bytes,
+            quota_policy: options.policy.quota_policy,
+            check_space: options.policy.check_space,
+            max_bytes_per_sec: options.io.max_bytes_per_sec,
+            overwrite: options.policy.overwrite,
+            shard_levels: options.shard_levels,
+            subtree: options.subtree.clone(),
+            recompress: options.recompress,
+            salvage: options.policy.salvage,
+            quarantine: options
+                .policy
+                .quarantine
+                .as_deref()
+                .map(|path| relative(path, dir)),
+            strict_filter: options.policy.strict_filter,
+            parsing: options.policy.parsing,
+            direct_io: options.io.direct_io,
+            #[cfg(feature = "mmap")]
+            mmap: options.io.mmap,
+            mechanisms: options.io.mechanisms,
+            cleanup_partial: options.durability.cleanup_partial,
+            concurrency: options.io.concurrency,
+            strategy: options.io.strategy,
+            block_decode_workers: options.io.block_decode_workers,
+            fragment_cache_blocks: options.io.fragment_cache_blocks,
+            ordering: options.io.ordering,
+            durability: options.durability.fsync,
+            write_buffer: options.io.write_buffer,
+            read_buffer: options.io.read_buffer,
+            permissions: options.policy.permissions,
+            preserve_ownership: options.policy.preserve_ownership,
+            id_map: options.policy.id_map,
+            preserve_mtime: options.policy.preserve_mtime,
+            extract_xattrs: options.policy.extract_xattrs,
+            metadata_errors: options.policy.metadata_errors,
+            slow_entry_threshold: options.observability.slow_entry_threshold,
+            read_only: options.durability.read_only,
+            allow_special_files: options.policy.allow_special_files,
+            escape: options.policy.escape,
+            sha256sums: options.observability.sha256sums,
+            hash: options.observability.hash,
+            nar_hash: options.observability.nar_hash,
+            digests: options.observability.digests,
+            profile: options.observability.profile,
+            dry_run: options.dry_run,
+            resume: options
+                .durability
+                .resume
+                .as_deref()
+                .map(|path| relative(path, dir)),
+            atomic: options.durability.atomic,
+            #[cfg(feature = "audit")]
+            audit: options.observability.audit,
+            #[cfg(feature = "sqlite")]
+            catalog: options
+                .observability
+                .catalog
+                .as_deref()
+                .map(|path| relative(path, dir)),
+        };
+        record.options_hash = record.options_hash().ok();
+        Some(record)
+    }
+
+    fn options_hash(&self) -> Result<String> {
+        let record = Self {
+            options_hash: None,
+            ..self.clone()
+        };
+        let json = serde_json::to_vec(&record).context("serialize job journal record")?;
+        Ok(digest::sha256_reader(json.as_slice())?)
+    }
+
+    /// Why the job cannot be resumed from this record, if it cannot.
+    fn check(&self, dir: &Path) -> Result<()> {
+        if let Some(expected) = &self.options_hash {
+            anyhow::ensure!(
+                *expected == self.options_hash()?,
+                "journaled with options this build does not support"
+            );
+        }
+        if let Some(expected) = &self.archive_hash {
+            let squashfs = resolve(dir, &self.squashfs);
+            anyhow::ensure!(
+                *expected == digest::sha256_file(&squashfs)?,
+                "archive '{}' changed since the job was submitted",
+                squashfs.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn into_job(self, dir: &Path) -> Job {
+        Job {
+            squashfs: resolve(dir, &self.squashfs),
+            dest: resolve(dir, &self.dest),
+            crates_filter: self
+                .crates_filter
+                .map(|crates| crates.into_iter().collect()),
+            priority: self.priority,
+            options: ExtractOptions {
+                io: IoOptions {
+                    max_bytes_per_sec: self.max_bytes_per_sec,
+                    direct_io: self.direct_io,
+                    #[cfg(feature = "mmap")]
+                    mmap: self.mmap,
+                    mechanisms: self.mechanisms,
+                    concurrency: self.concurrency,
+                    strategy: self.strategy,
+                    block_decode_workers: self.block_decode_workers,
+                    fragment_cache_blocks: self.fragment_cache_blocks,
+                    ordering: self.ordering,
+                    write_buffer: self.write_buffer,
+                    read_buffer: self.read_buffer,
+                    ..IoOptions::default()
+                },
+                policy: PolicyOptions {
+                    max_dest_bytes: self.max_dest_bytes,
+                    quota_policy: self.quota_policy,
+                    check_space: self.check_space,
+                    overwrite: self.overwrite,
+                    salvage: self.salvage,
+                    quarantine: self.quarantine.map(|path| resolve(dir, &path)),
+                    strict_filter: self.strict_filter,
+                    parsing: self.parsing,
+                    permissions: self.permissions,
+                    preserve_ownership: self.preserve_ownership,
+                    id_map: self.id_map,
+                    preserve_mtime: self.preserve_mtime,
+                    extract_xattrs: self.extract_xattrs,
+                    metadata_errors: self.metadata_errors,
+                    allow_special_files: self.allow_special_files,
+                    escape: self.escape,
+                    ..PolicyOptions::default()
+                },
+                durability: DurabilityOptions {
+                    cleanup_partial: self.cleanup_partial,
+                    fsync: self.durability,
+                    read_only: self.read_only,
+                    resume: self.resume.map(|path| resolve(dir, &path)),
+                    atomic: self.atomic,
+                    ..DurabilityOptions::default()
+                },
+                observability: ObservabilityOptions {
+                    slow_entry_threshold: self.slow_entry_threshold,
+                    sha256sums: self.sha256sums,
+                    hash: self.hash,
+                    nar_hash: self.nar_hash,
+                    digests: self.digests,
+                    profile: self.profile,
+                    #[cfg(feature = "audit")]
+                    audit: self.audit,
+                    #[cfg(feature = "sqlite")]
+                    catalog: self.catalog.map(|path| resolve(dir, &path)),
+                    ..ObservabilityOptions::default()
+                },
+                shard_levels: self.shard_levels,
+                subtree: self.subtree,
+                recompress: self.recompress,
+                dry_run: self.dry_run,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl Journal {
+    /// Replay the journal in `dir`, then rewrite it with only the jobs that are still known.
+    pub(crate) fn open(dir: &Path) -> Result<(Self, Vec<Recovered>)> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("create job journal dir '{}'", dir.display()))?;
+        let dir = std::path::absolute(dir)
+            .with_context(|| format!("resolve job journal dir '{}'", dir.display()))?;
+        let path = dir.join(JOURNAL);
+
+        let mut jobs: BTreeMap<JobId, (JobRecord, Option<JobStatus>)> = BTreeMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line =
+                        line.with_context(|| format!("read job journal '{}'", path.display()))?;
+                    // the last record may have been cut short by a crash
+                    let Ok(record) = serde_json::from_str(&line) else {
+                        tracing::warn!(journal = %path.display(), "skipping malformed record");
+                        continue;
+                    };
+                    match record {
+                        Record::Submitted { id, job } => {
+                            jobs.insert(id, (job, None));
+                        }
+                        Record::Started { .. } => {}
+                        Record::Finished { id, status } => {
+                            if let Some((_, slot)) = jobs.get_mut(&id) {
+                                *slot = Some(status);
+                            }
+                        }
+                        Record::Removed { id } => {
+                            jobs.remove(&id);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("open job journal '{}'", path.display()))
+            }
+        }
+        for (&id, (job, status)) in &mut jobs {
+            if status.is_some() {
+                continue;
+            }
+            if let Err(e) = job.check(&dir) {
+                tracing::warn!(id, "not resuming journaled job: {e:#}");
+                *status = Some(JobStatus::Failed(format!("{e:#}")));
+            }
+        }
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut compacted = Vec::new();
+        for (&id, (job, status)) in &jobs {
+            let job = job.clone();
+            append_record(&mut compacted, &Record::Submitted { id, job })?;
+            if let Some(status) = status.clone() {
+                append_record(&mut compacted, &Record::Finished { id, status })?;
+            }
+        }
+        std::fs::write(&tmp, compacted)
+            .with_context(|| format!("write job journal '{}'", tmp.display()))?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("replace job journal '{}'", path.display()))?;
+
+        let file = File::options()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("open job journal '{}'", path.display()))?;
+        let recovered = jobs
+            .into_iter()
+            .map(|(id, (job, status))| Recovered {
+                id,
+                job: job.into_job(&dir),
+                status,
+            })
+            .collect();
+        Ok((Self { file, path, dir }, recovered))
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub(crate) fn submitted(&mut self, id: JobId, job: JobRecord) {
+        self.append(&Record::Submitted { id, job });
+    }
+
+    pub(crate) fn started(&mut self, id: JobId) {
+        self.append(&Record::Started { id });
+    }
+
+    pub(crate) fn finished(&mut self, id: JobId, status: &JobStatus) {
+        let status = status.clone();
+        self.append(&Record::Finished { id, status });
+    }
+
+    pub(crate) fn removed(&mut self, id: JobId) {
+        self.append(&Record::Removed { id });
+    }
+
+    /// Journal failures are logged rather than failing jobs, which can still run without it.
+    fn append(&mut self, record: &Record) {
+        let mut line = Vec::new();
+        let res = append_record(&mut line, record)
+            .and_then(|()| {
+                self.file.write_all(&line)?;
+                self.file.sync_data()?;
+                Ok(())
+            })
+            .with_context(|| format!("append to job journal '{}'", self.path.display()));
+        if let Err(e) = res {
+            tracing::warn!("{e:#}");
+        }
+    }
+}
+
+fn append_record(out: &mut Vec<u8>, record: &Record) -> Result<()> {
+    serde_json::to_writer(&mut *out, record).context("serialize job journal record")?;
+    out.push(b'\n');
+    Ok(())
+}
+
+/// `path` relative to `dir`, both made absolute against the current directory.
+fn relative(path: &Path, dir: &Path) -> PathBuf {
+    let Ok(path) = std::path::absolute(path) else {
+        return path.to_path_buf();
+    };
+    let (mut path, mut dir) = (path.components().peekable(), dir.components().peekable());
+    while let (Some(a), Some(b)) = (path.peek(), dir.peek()) {
+        if a != b {
+            break;
+        }
+        path.next();
+        dir.next();
+    }
+    dir.map(|_| Component::ParentDir).chain(path).collect()
+}
+
+/// A path recorded relative to the journal in `dir`, with `..` resolved lexically.
+fn resolve(dir: &Path, path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in dir.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+    resolved
+}
@@ -0,0 +1,181 @@
+use std::{
+    collections::{HashMap, HashSet},
+    os::fd::AsFd,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError, Weak,
+    },
+    thread::JoinHandle,
+};
+
+use anyhow::Context;
+use nix::{
+    errno::Errno,
+    poll::{PollFd, PollFlags, PollTimeout},
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+};
+
+use crate::{Archive, ArchivePool, Error};
+
+/// How often the watcher thread checks whether it should stop, in milliseconds.
+const STOP_POLL_MS: u16 = 500;
+
+/// Sent by a [`PoolWatcher`] when an archive of the pool was replaced.
+#[derive(Debug, Clone, Copy)]
+pub enum PoolEvent<'a> {
+    /// The archive at `path` was opened again; handles given out before keep reading the one
+    /// they hold.
+    Reloaded { path: &'a Path },
+    /// The archive at `path` could not be opened again, and was closed to be opened on its next
+    /// access.
+    ReloadFailed { path: &'a Path, error: &'a Error },
+}
+
+/// Receives [`PoolEvent`]s, on the watcher thread.
+pub trait PoolEvents: Send + Sync {
+    fn on_event(&self, event: &PoolEvent<'_>);
+}
+
+impl<F: Fn(&PoolEvent<'_>) + Send + Sync> PoolEvents for F {
+    fn on_event(&self, event: &PoolEvent<'_>) {
+        self(event)
+    }
+}
+
+/// The directories watched for the archives of a pool.
+#[derive(Debug)]
+pub(crate) struct Watches {
+    inotify: Inotify,
+    /// The directories of the archives as they were given, which several may share.
+    dirs: Mutex<HashMap<WatchDescriptor, HashSet<PathBuf>>>,
+}
+
+impl Watches {
+    /// Watch the directory of `path` for files renamed over or written to it.
+    pub(crate) fn add(&self, path: &Path) {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let watched = match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        };
+        let flags = AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_CLOSE_WRITE;
+        match self.inotify.add_watch(watched, flags) {
+            Ok(wd) => {
+                self.lock().entry(wd).or_default().insert(dir.to_path_buf());
+            }
+            Err(e) => {
+                tracing::warn!(dir = %watched.display(), "cannot watch archive directory: {e}")
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<WatchDescriptor, HashSet<PathBuf>>> {
+        self.dirs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The paths of the files changed since the last call, waiting up to [`STOP_POLL_MS`] for
+    /// one.
+    fn changed(&self) -> nix::Result<Vec<PathBuf>> {
+        let mut fds = [PollFd::new(self.inotify.as_fd(), PollFlags::POLLIN)];
+        match nix::poll::poll(&mut fds, PollTimeout::from(STOP_POLL_MS)) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(Vec::new()),
+            ready => ready?,
+        };
+        let events = match self.inotify.read_events() {
+            Err(Errno::EAGAIN) => return Ok(Vec::new()),
+            events => events?,
+        };
+        let dirs = self.lock();
+        Ok(events
+            .into_iter()
+            .filter_map(|event| Some((dirs.get(&event.wd)?, event.name?)))
+            .flat_map(|(dirs, name)| dirs.iter().map(move |dir| dir.join(&name)))
+            .collect())
+    }
+}
+
+/// Watches the archives of an [`ArchivePool`], created by [`ArchivePool::watch`]. Dropping it
+/// stops watching.
+#[derive(Debug)]
+pub struct PoolWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for PoolWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl ArchivePool {
+    /// Watch the directories of the archives of the pool with inotify, opening an archive again
+    /// when a file is renamed over its path or written to it, and send `events` a [`PoolEvent`]
+    /// for it, e.g. for servers to invalidate what they cached from it. Only one watcher can be
+    /// started for a pool.
+    pub fn watch(
+        self: &Arc<Self>,
+        events: impl PoolEvents + 'static,
+    ) -> Result<PoolWatcher, Error> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .context("initialize inotify")?;
+        let watches = Arc::new(Watches {
+            inotify,
+            dirs: Mutex::default(),
+        });
+        if self.watches.set(Arc::clone(&watches)).is_err() {
+            return Err(anyhow::anyhow!("archive pool is already watched").into());
+        }
+        for path in self.lock().open.keys() {
+            watches.add(path);
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (pool, thread_stop) = (Arc::downgrade(self), Arc::clone(&stop));
+        let thread = std::thread::Builder::new()
+            .name("archive-pool-watcher".to_owned())
+            .spawn(move || watch(&pool, &watches, &thread_stop, &events))
+            .context("spawn archive pool watcher thread")?;
+        Ok(PoolWatcher {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+fn watch(pool: &Weak<ArchivePool>, watches: &Watches, stop: &AtomicBool, events: &dyn PoolEvents) {
+    while !stop.load(Ordering::Relaxed) {
+        let changed = match watches.changed() {
+            Ok(changed) => changed,
+            Err(e) => {
+                tracing::warn!("stopped watching archive pool: {e}");
+                return;
+            }
+        };
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        for path in changed {
+            if !pool.lock().open.contains_key(&path) {
+                continue;
+            }
+            match Archive::open(&path, pool.options.clone()) {
+                Ok(archive) => {
+                    pool.replace(&path, archive);
+                    events.on_event(&PoolEvent::Reloaded { path: &path });
+                }
+                Err(error) => {
+                    pool.remove(&path);
+                    events.on_event(&PoolEvent::ReloadFailed {
+                        path: &path,
+                        error: &error,
+                    });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::{collections::HashSet, sync::Arc, thread::JoinHandle};
+
+use anyhow::{Context, Result};
+use backhand::InnerNode;
+
+use crate::{parsing::Parsing, source::SquashSource};
+
+/// Warm the cache of `source` (e.g. the page cache for a [`FileSource`](crate::source::FileSource))
+/// for the data of the given tpcii crates on a background thread, so that extracting them soon
+/// after is not bound by storage latency. Join the returned handle to learn whether every hint
+/// was issued.
+pub fn prefetch_tpcii(
+    source: impl SquashSource + 'static,
+    crates: HashSet<String>,
+) -> JoinHandle<Result<()>> {
+    let source: Arc<dyn SquashSource> = Arc::new(source);
+    std::thread::spawn(move || {
+        let filter = crate::Filter::crates(crates);
+        let filesystem = crate::open_filesystem(Arc::clone(&source), None, Parsing::Lenient)?;
+        for node in filesystem
+            .files()
+            .filter(|node| filter.matches(&node.fullpath))
+        {
+            let InnerNode::File(file) = &node.inner else {
+                continue;
+            };
+            let file = &file.basic;
+            let len: u64 = file.block_sizes.iter().map(|b| u64::from(b.size())).sum();
+            if len > 0 {
+                source
+                    .prefetch(u64::from(file.blocks_start), len)
+                    .with_context(|| format!("prefetch '{}'", node.fullpath.display()))?;
+            }
+            let fragment = filesystem
+                .fragments
+                .as_ref()
+                .and_then(|fragments| fragments.get(file.frag_index as usize));
+            if let Some(fragment) = fragment {
+                source
+                    .prefetch(fragment.start, u64::from(fragment.size.size()))
+                    .with_context(|| {
+                        format!("prefetch fragment of '{}'", node.fullpath.display())
+                    })?;
+            }
+        }
+        Ok(())
+    })
+}
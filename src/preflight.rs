@@ -0,0 +1,204 @@
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use backhand::{BufReadSeek, Squashfs};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compression, format, parsing,
+    source::{FileSource, SourceReader, SquashSource},
+    FormatError, Parsing,
+};
+
+/// A problem found by [`preflight`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub check: Check,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Extraction may succeed but the result is probably not what was wanted.
+    Warning,
+    /// Extraction would fail.
+    Error,
+}
+
+/// The check that produced a [`Finding`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Readable,
+    Magic,
+    Superblock,
+    Compression,
+    Size,
+    Metadata,
+    /// The `/index` and `/salts` directories of a tpcii snapshot.
+    Layout,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PreflightLimits {
+    pub max_archive_bytes: Option<u64>,
+    pub max_inodes: Option<u32>,
+}
+
+impl Finding {
+    fn error(check: Check, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            check,
+            message: message.into(),
+        }
+    }
+
+    fn warning(check: Check, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            check,
+            message: message.into(),
+        }
+    }
+}
+
+/// Cheaply check that `archive` is a squashfs archive this crate can extract, without extracting
+/// anything. Returns no findings for a sound archive.
+pub fn preflight(archive: impl AsRef<Path>) -> Vec<Finding> {
+    match FileSource::open(archive) {
+        Ok(source) => preflight_source(source, &PreflightLimits::default()),
+        Err(e) => vec![Finding::error(Check::Readable, format!("{e:#}"))],
+    }
+}
+
+/// Like [`preflight`], for any [`SquashSource`] and with size limits.
+pub fn preflight_source(
+    source: impl SquashSource + 'static,
+    limits: &PreflightLimits,
+) -> Vec<Finding> {
+    let source: Arc<dyn SquashSource> = Arc::new(source);
+    let mut findings = Vec::new();
+
+    let size = match source.size() {
+        Ok(size) => size,
+        Err(e) => {
+            let message = format!("get size of '{}': {e}", source.name());
+            return vec![Finding::error(Check::Readable, message)];
+        }
+    };
+    if let Some(max) = limits.max_archive_bytes.filter(|&max| size > max) {
+        let message = format!("archive is {size} bytes, over the limit of {max}");
+        findings.push(Finding::error(Check::Size, message));
+    }
+
+    let kind = match format::resolve_kind(&*source, None) {
+        Ok(kind) => kind,
+        Err(e) => {
+            let check = match e.downcast_ref::<FormatError>() {
+                Some(_) => Check::Magic,
+                None => Check::Readable,
+            };
+            findings.push(Finding::error(check, format!("{e:#}")));
+            return findings;
+        }
+    };
+
+    let mut reader: Box<dyn BufReadSeek> =
+        Box::new(BufReader::new(SourceReader::new(Arc::clone(&source))));
+    let superblock = match Squashfs::superblock_and_compression_options(&mut reader, &kind) {
+        Ok((superblock, _)) => superblock,
+        Err(e) => {
+            let message = format!("parse superblock: {e}");
+            findings.push(Finding::error(Check::Superblock, message));
+            return findings;
+        }
+    };
+
+    let block_size = superblock.block_size;
+    if !block_size.is_power_of_two() || !(4096..=1 << 20).contains(&block_size) {
+        let message = format!("block size {block_size} is not a power of two from 4 KiB to 1 MiB");
+        findings.push(Finding::error(Check::Superblock, message));
+    } else if 1u32.checked_shl(superblock.block_log.into()) != Some(block_size) {
+        let message = format!(
+            "block log {} does not match block size {block_size}",
+            superblock.block_log
+        );
+        findings.push(Finding::error(Check::Superblock, message));
+    }
+    if superblock.bytes_used > size {
+        let message = format!(
+            "archive is truncated: the superblock says {} bytes are used, but it has {size}",
+            superblock.bytes_used
+        );
+        findings.push(Finding::error(Check::Size, message));
+    }
+    let tables = [
+        ("inode", superblock.inode_table),
+        ("directory", superblock.dir_table),
+        ("id", superblock.id_table),
+    ];
+    for (table, offset) in tables {
+        if offset >= superblock.bytes_used {
+            let message = format!("{table} table offset {offset} is past the end of the archive");
+            findings.push(Finding::error(Check::Superblock, message));
+        }
+    }
+    if superblock.inode_count == 0 {
+        findings.push(Finding::error(Check::Superblock, "archive has no inodes"));
+    }
+    if let Some(max) = limits
+        .max_inodes
+        .filter(|&max| superblock.inode_count > max)
+    {
+        let message = format!(
+            "archive has {} inodes, over the limit of {max}",
+            superblock.inode_count
+        );
+        findings.push(Finding::error(Check::Size, message));
+    }
+    let unknown_flags = superblock.flags & !parsing::KNOWN_FLAGS;
+    if unknown_flags != 0 {
+        let message = format!(
+            "unknown superblock flags {unknown_flags:#06x}, rejected in strict parsing mode"
+        );
+        findings.push(Finding::warning(Check::Superblock, message));
+    }
+    if !compression::compiled_decompressors().contains(&superblock.compressor) {
+        let message = format!(
+            "compressor {:?} is not compiled in; enable the matching cargo feature",
+            superblock.compressor
+        );
+        findings.push(Finding::error(Check::Compression, message));
+    }
+
+    if findings.iter().any(|f| f.severity == Severity::Error) {
+        return findings;
+    }
+
+    let open = || crate::open_filesystem(Arc::clone(&source), Some(kind), Parsing::Lenient);
+    let filesystem = match crate::report::catch_panic(open) {
+        Ok(filesystem) => filesystem,
+        Err(e) => {
+            findings.push(Finding::error(Check::Metadata, format!("{e:#}")));
+            return findings;
+        }
+    };
+    for top in ["/index", "/salts"] {
+        let top = PathBuf::from(top);
+        if !filesystem.files().any(|node| node.fullpath == top) {
+            let message = format!(
+                "no '{}' directory, this is not a tpcii snapshot",
+                top.display()
+            );
+            findings.push(Finding::warning(Check::Layout, message));
+        }
+    }
+
+    findings
+}
@@ -0,0 +1,12 @@
+//! The types and functions most uses of the crate need, to glob-import.
+
+pub use crate::{
+    extract::{
+        unsquash_async, unsquash_blocking, ExtractOptions, OverwritePolicy, PermissionPolicy,
+        Unsquasher,
+    },
+    filter::Filter,
+    list::{EntryInfo, EntryKind},
+    report::{ExtractReport, ExtractSummary},
+    Archive, ArchivePool, Error,
+};
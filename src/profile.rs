@@ -0,0 +1,334 @@
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    future::Future,
+    io::{Read, Write},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metrics;
+
+thread_local! {
+    /// The profiler of the extraction this thread works on, if it is profiled.
+    static CURRENT: RefCell<Option<Arc<Profiler>>> = const { RefCell::new(None) };
+}
+
+/// A phase of extraction that is timed with
+/// [`ObservabilityOptions::profile`](crate::ObservabilityOptions::profile), or while
+/// [`Metrics`](crate::metrics::Metrics) are installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Opening the archive and parsing its metadata.
+    Open,
+    /// Filtering the archive's nodes down to the ones to extract.
+    Plan,
+    /// Reading raw data blocks from the archive.
+    Read,
+    /// Decompressing data blocks. When backhand's own reader is used this includes the reads.
+    Decompress,
+    /// Writing file data to the destination.
+    Write,
+    /// Setting permissions on extracted entries.
+    Chmod,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::Open,
+        Stage::Plan,
+        Stage::Read,
+        Stage::Decompress,
+        Stage::Write,
+        Stage::Chmod,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Open => "open",
+            Stage::Plan => "plan",
+            Stage::Read => "read",
+            Stage::Decompress => "decompress",
+            Stage::Write => "write",
+            Stage::Chmod => "chmod",
+        }
+    }
+}
+
+/// Timing of a single [`Stage`], summed across all workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StageTiming {
+    pub stage: Stage,
+    pub total: Duration,
+    pub calls: u64,
+}
+
+/// Per-stage timings of an extraction, in
+/// [`ExtractReport::profile`](crate::ExtractReport::profile).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub stages: Vec<StageTiming>,
+}
+
+impl Profile {
+    /// Add the timings of `other` to these.
+    pub(crate) fn merge(&mut self, other: &Profile) {
+        for (timing, other) in self.stages.iter_mut().zip(&other.stages) {
+            timing.total += other.total;
+            timing.calls += other.calls;
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"stages\":[");
+        for (i, timing) in self.stages.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"stage\":\"{}\",\"total_ns\":{},\"calls\":{}}}",
+                timing.stage.name(),
+                timing.total.as_nanos(),
+                timing.calls,
+            );
+        }
+        out.push_str("]}");
+        out
+    }
+
+    /// Collapsed-stack lines (`extract;<stage> <microseconds>`), as consumed by flamegraph tools.
+    pub fn to_collapsed(&self) -> String {
+        self.stages
+            .iter()
+            .filter(|timing| !timing.total.is_zero())
+            .fold(String::new(), |mut out, timing| {
+                let _ = writeln!(
+                    out,
+                    "extract;{} {}",
+                    timing.stage.name(),
+                    timing.total.as_micros()
+                );
+                out
+            })
+    }
+}
+
+/// The timings of an extraction, added to by the threads and tasks working on it while they
+/// are [scoped](scoped) to it.
+#[derive(Default)]
+pub(crate) struct Profiler {
+    nanos: [AtomicU64; Stage::ALL.len()],
+    calls: [AtomicU64; Stage::ALL.len()],
+}
+
+impl Profiler {
+    /// A profiler for an extraction with
+    /// [`ObservabilityOptions::profile`](crate::ObservabilityOptions::profile) `enabled`.
+    pub(crate) fn new(enabled: bool) -> Option<Arc<Self>> {
+        enabled.then(Arc::default)
+    }
+
+    pub(crate) fn profile(&self) -> Profile {
+        let stages = Stage::ALL
+            .iter()
+            .enumerate()
+            .map(|(i, &stage)| StageTiming {
+                stage,
+                total: Duration::from_nanos(self.nanos[i].load(Ordering::Relaxed)),
+                calls: self.calls[i].load(Ordering::Relaxed),
+            })
+            .collect();
+        Profile { stages }
+    }
+
+    fn record(&self, stage: Stage, elapsed: Duration) {
+        let i = stage as usize;
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.nanos[i].fetch_add(nanos, Ordering::Relaxed);
+        self.calls[i].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The profiler of the extraction the calling thread works on, to [scope](scoped) the threads
+/// it hands work to.
+pub(crate) fn current() -> Option<Arc<Profiler>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Run `f` on behalf of the extraction profiled by `profiler`, if any.
+pub(crate) fn scoped<T>(profiler: Option<&Arc<Profiler>>, f: impl FnOnce() -> T) -> T {
+    /// Restores the profiler of the thread, even if `f` panics.
+    struct Restore(Option<Arc<Profiler>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+        }
+    }
+
+    if profiler.is_none() && CURRENT.with(|current| current.borrow().is_none()) {
+        return f();
+    }
+    let _restore = Restore(CURRENT.with(|current| current.replace(profiler.cloned())));
+    f()
+}
+
+/// A future polled on behalf of the extraction profiled by `profiler`, if any.
+pub(crate) struct Profiled<F> {
+    future: Pin<Box<F>>,
+    profiler: Option<Arc<Profiler>>,
+}
+
+impl<F: Future> Profiled<F> {
+    pub(crate) fn new(future: F, profiler: Option<Arc<Profiler>>) -> Self {
+        Self {
+            future: Box::pin(future),
+            profiler,
+        }
+    }
+}
+
+impl<F: Future> Future for Profiled<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        scoped(this.profiler.as_ref(), || this.future.as_mut().poll(cx))
+    }
+}
+
+/// Whether stages are timed, for the extraction being profiled or for the installed
+/// [`Metrics`](crate::metrics::Metrics).
+fn timing() -> bool {
+    metrics::installed() || CURRENT.with(|current| current.borrow().is_some())
+}
+
+pub(crate) fn record(stage: Stage, elapsed: Duration) {
+    metrics::with(|metrics| metrics.stage(stage, elapsed));
+    CURRENT.with(|current| {
+        if let Some(profiler) = &*current.borrow() {
+            profiler.record(stage, elapsed);
+        }
+    });
+}
+
+pub(crate) fn timed<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+    if !timing() {
+        return f();
+    }
+    let started = Instant::now();
+    let res = f();
+    record(stage, started.elapsed());
+    res
+}
+
+pub(crate) struct Timed<T> {
+    inner: T,
+    stage: Stage,
+}
+
+impl<T> Timed<T> {
+    pub(crate) fn new(stage: Stage, inner: T) -> Self {
+        Self { inner, stage }
+    }
+}
+
+impl<R: Read> Read for Timed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        timed(self.stage, || self.inner.read(buf))
+    }
+}
+
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for Timed<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let stage = self.stage;
+        timed(stage, || Pin::new(&mut self.inner).poll_write(cx, buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let stage = self.stage;
+        timed(stage, || Pin::new(&mut self.inner).poll_flush(cx))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<W: Write> Write for Timed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        timed(self.stage, || self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        timed(self.stage, || self.inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, TestArchive},
+        ExtractOptions, ObservabilityOptions,
+    };
+
+    fn calls(profile: &Profile, stage: Stage) -> u64 {
+        profile.stages[stage as usize].calls
+    }
+
+    #[tokio::test]
+    async fn profiles_each_extraction() {
+        let archive = TestArchive::new(vec![
+            testing::file("a/b", vec![1; 300 << 10]),
+            testing::file("c", "c"),
+        ]);
+        let options = |profile| ExtractOptions {
+            observability: ObservabilityOptions {
+                profile,
+                ..ObservabilityOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        let dest = archive.scratch("blocking");
+        let report =
+            crate::unsquash_tpcii_blocking_with_options(archive.path(), &dest, None, options(true))
+                .unwrap();
+        let profile = report.profile.unwrap();
+        assert_eq!(calls(&profile, Stage::Open), 1);
+        assert!(calls(&profile, Stage::Write) > 0);
+
+        let dest = archive.scratch("async");
+        let report =
+            crate::unsquash_tpcii_async_with_options(archive.path(), &dest, None, options(true))
+                .await
+                .unwrap();
+        let profile = report.profile.unwrap();
+        assert_eq!(calls(&profile, Stage::Open), 1);
+        assert!(calls(&profile, Stage::Write) > 0);
+
+        let dest = archive.scratch("unprofiled");
+        let report = crate::unsquash_tpcii_blocking_with_options(
+            archive.path(),
+            &dest,
+            None,
+            options(false),
+        )
+        .unwrap();
+        assert_eq!(report.profile, None);
+        assert!(current().is_none());
+    }
+}
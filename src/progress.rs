@@ -0,0 +1,271 @@
+use std::{
+    fmt,
+    io::Write,
+    iter::Sum,
+    ops::Add,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use backhand::{InnerNode, Node, SquashfsFileReader};
+use serde::{Deserialize, Serialize};
+
+/// Progress of one extraction, updated as entries complete. Pass it in
+/// [`ObservabilityOptions::progress`](crate::ObservabilityOptions::progress) and poll
+/// [`Progress::snapshot`], or create it [`with_hook`](Progress::with_hook) to be notified instead.
+#[derive(Default)]
+pub struct Progress {
+    entries_total: AtomicU64,
+    entries_done: AtomicU64,
+    bytes_total: AtomicU64,
+    bytes_done: AtomicU64,
+    hook: Option<Box<dyn ExtractProgress>>,
+}
+
+/// Receives [`ExtractEvent`]s from an extraction, on the threads doing the work.
+pub trait ExtractProgress: Send + Sync {
+    fn on_event(&self, event: &ExtractEvent<'_>);
+}
+
+impl<F: Fn(&ExtractEvent<'_>) + Send + Sync> ExtractProgress for F {
+    fn on_event(&self, event: &ExtractEvent<'_>) {
+        self(event)
+    }
+}
+
+/// Sent after each chunk of a file is written, and once for every entry when it is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtractEvent<'a> {
+    /// Path of the entry in the archive.
+    pub path: &'a Path,
+    /// Bytes of the entry's contents written so far, before any recompression.
+    pub bytes_written: u64,
+    pub total_bytes: u64,
+    /// Whether the entry has been extracted or given up on.
+    pub done: bool,
+}
+
+/// A point-in-time copy of a [`Progress`], or the sum of several.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProgressSnapshot {
+    /// Entries selected for extraction; zero until the archive has been read.
+    pub entries_total: u64,
+    /// Entries extracted or given up on.
+    pub entries_done: u64,
+    /// File data selected for extraction, in bytes.
+    pub bytes_total: u64,
+    pub bytes_done: u64,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_hook(hook: impl ExtractProgress + 'static) -> Self {
+        Self {
+            hook: Some(Box::new(hook)),
+            ..Default::default()
+        }
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            entries_total: self.entries_total.load(Ordering::Relaxed),
+            entries_done: self.entries_done.load(Ordering::Relaxed),
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn plan(&self, nodes: &[&Node<SquashfsFileReader>]) {
+        let bytes = nodes.iter().map(|node| file_size(node)).sum();
+        self.plan_entries(nodes.len() as u64, bytes);
+    }
+
+    pub(crate) fn plan_entries(&self, entries: u64, bytes: u64) {
+        self.entries_total.fetch_add(entries, Ordering::Relaxed);
+        self.bytes_total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record(&self, node: &Node<SquashfsFileReader>) {
+        self.record_entry(&node.fullpath, file_size(node));
+    }
+
+    pub(crate) fn record_entry(&self, path: &Path, size: u64) {
+        self.entries_done.fetch_add(1, Ordering::Relaxed);
+        self.bytes_done.fetch_add(size, Ordering::Relaxed);
+        self.chunk(path, size, size, true);
+    }
+
+    /// Send an event to the hook, if any.
+    pub(crate) fn chunk(&self, path: &Path, bytes_written: u64, total_bytes: u64, done: bool) {
+        if let Some(hook) = &self.hook {
+            hook.on_event(&ExtractEvent {
+                path,
+                bytes_written,
+                total_bytes,
+                done,
+            });
+        }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("snapshot", &self.snapshot())
+            .field("hook", &self.hook.as_ref().map(|_| ..))
+            .finish()
+    }
+}
+
+/// Sends an [`ExtractEvent`] for every chunk of a file entry written through it, if the
+/// [`Progress`] has a hook.
+pub(crate) struct Reporting<W> {
+    inner: W,
+    entry: Option<EntryEvents>,
+}
+
+/// Where to send the chunk events of one entry.
+pub(crate) struct EntryEvents {
+    progress: Arc<Progress>,
+    path: PathBuf,
+    written: u64,
+    total: u64,
+}
+
+impl EntryEvents {
+    /// `None` unless `progress` has a hook.
+    pub(crate) fn new(
+        progress: Option<&Arc<Progress>>,
+        node: &Node<SquashfsFileReader>,
+    ) -> Option<Self> {
+        progress
+            .filter(|progress| progress.hook.is_some())
+            .map(|progress| Self {
+                progress: Arc::clone(progress),
+                path: node.fullpath.clone(),
+                written: 0,
+                total: file_size(node),
+            })
+    }
+}
+
+impl<W> Reporting<W> {
+    pub(crate) fn new(inner: W, entry: Option<EntryEvents>) -> Self {
+        Self { inner, entry }
+    }
+
+    pub(crate) fn into_parts(self) -> (W, Option<EntryEvents>) {
+        (self.inner, self.entry)
+    }
+}
+
+impl<W: Write> Write for Reporting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(entry) = &mut self.entry {
+            entry.written += n as u64;
+            if let Some(hook) = &entry.progress.hook {
+                hook.on_event(&ExtractEvent {
+                    path: &entry.path,
+                    bytes_written: entry.written,
+                    total_bytes: entry.total,
+                    done: false,
+                });
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn file_size(node: &Node<SquashfsFileReader>) -> u64 {
+    match &node.inner {
+        InnerNode::File(file) => u64::from(file.basic.file_size),
+        _ => 0,
+    }
+}
+
+impl ProgressSnapshot {
+    /// Share of the selected bytes extracted so far, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        match self.bytes_total {
+            0 => match self.entries_total {
+                0 => 0.0,
+                total => self.entries_done as f64 / total as f64,
+            },
+            total => self.bytes_done as f64 / total as f64,
+        }
+    }
+}
+
+impl Add for ProgressSnapshot {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            entries_total: self.entries_total + other.entries_total,
+            entries_done: self.entries_done + other.entries_done,
+            bytes_total: self.bytes_total + other.bytes_total,
+            bytes_done: self.bytes_done + other.bytes_done,
+        }
+    }
+}
+
+impl Sum for ProgressSnapshot {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+/// Combined view over several concurrent extractions, e.g. the shards of one job.
+#[derive(Debug, Default)]
+pub struct MultiProgress {
+    parts: Mutex<Vec<Arc<Progress>>>,
+}
+
+impl MultiProgress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a new extraction; pass the returned [`Progress`] in its options.
+    pub fn add(&self) -> Arc<Progress> {
+        let progress = Arc::new(Progress::new());
+        self.parts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::clone(&progress));
+        progress
+    }
+
+    /// Number of extractions tracked.
+    pub fn len(&self) -> usize {
+        self.parts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The sum of every tracked extraction's progress.
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        self.parts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|progress| progress.snapshot())
+            .sum()
+    }
+}
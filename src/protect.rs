@@ -0,0 +1,147 @@
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::{fs::FileType, path::Path};
+
+use anyhow::{Context, Result};
+#[cfg(target_os = "linux")]
+use nix::{errno::Errno, libc};
+use serde::{Deserialize, Serialize};
+
+use crate::platform;
+
+/// `FS_IMMUTABLE_FL` from `linux/fs.h`.
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+// the kernel reads and writes an int despite the `long` in the request code
+#[cfg(target_os = "linux")]
+nix::ioctl_read_bad!(
+    fs_ioc_getflags,
+    nix::request_code_read!(b'f', 1, std::mem::size_of::<libc::c_long>()),
+    libc::c_int
+);
+#[cfg(target_os = "linux")]
+nix::ioctl_write_ptr_bad!(
+    fs_ioc_setflags,
+    nix::request_code_write!(b'f', 2, std::mem::size_of::<libc::c_long>()),
+    libc::c_int
+);
+
+/// How to protect an extracted tree from modification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadOnly {
+    /// Remove the write bits of every entry.
+    WriteProtect,
+    /// Set the immutable attribute on every entry, which needs `CAP_LINUX_IMMUTABLE`. Falls back
+    /// to [`ReadOnly::WriteProtect`] where the attribute cannot be set, and off Linux.
+    Immutable,
+}
+
+/// Make the tree at `path` read-only. Symlinks are left alone.
+pub fn protect(path: impl AsRef<Path>, read_only: ReadOnly) -> Result<()> {
+    let mut immutable = read_only == ReadOnly::Immutable;
+    walk(path.as_ref(), &mut |path, file_type| {
+        // opening device nodes to set their flags could have side effects
+        if immutable && (file_type.is_file() || file_type.is_dir()) {
+            match set_immutable(path, true) {
+                Ok(()) => return Ok(()),
+                Err(e) if not_permitted(e) => {
+                    tracing::warn!(
+                        path = %path.display(),
+                        "cannot set immutable attribute, removing write bits instead"
+                    );
+                    immutable = false;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("set immutable '{}'", path.display()))
+                }
+            }
+        }
+        set_writable(path, false)
+    })
+}
+
+/// Undo [`protect`] on the tree at `path`, e.g. before removing it.
+pub fn unprotect(path: impl AsRef<Path>) -> Result<()> {
+    walk(path.as_ref(), &mut |path, file_type| {
+        if !file_type.is_file() && !file_type.is_dir() {
+            return set_writable(path, true);
+        }
+        match set_immutable(path, false) {
+            Ok(()) => {}
+            Err(e) if not_permitted(e) => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("clear immutable '{}'", path.display()))
+            }
+        }
+        set_writable(path, true)
+    })
+}
+
+/// Call `f` on `path` and everything under it except symlinks, parents before their entries.
+fn walk(path: &Path, f: &mut impl FnMut(&Path, FileType) -> Result<()>) -> Result<()> {
+    let file_type = std::fs::symlink_metadata(path)
+        .with_context(|| format!("stat '{}'", path.display()))?
+        .file_type();
+    if file_type.is_symlink() {
+        return Ok(());
+    }
+    if file_type.is_dir() {
+        // entries are listed before the directory is protected, as that may prevent listing it
+        let entries = std::fs::read_dir(path)
+            .with_context(|| format!("read dir '{}'", path.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .with_context(|| format!("read dir '{}'", path.display()))?;
+        f(path, file_type)?;
+        return entries.iter().try_for_each(|entry| walk(entry, f));
+    }
+    f(path, file_type)
+}
+
+fn set_writable(path: &Path, writable: bool) -> Result<()> {
+    let metadata =
+        std::fs::symlink_metadata(path).with_context(|| format!("stat '{}'", path.display()))?;
+    let mode = platform::mode(&metadata);
+    let mode = match writable {
+        true => mode | 0o200,
+        false => mode & !0o222,
+    };
+    platform::set_mode(path, mode).with_context(|| format!("chmod {mode:#o} '{}'", path.display()))
+}
+
+/// Errors meaning the immutable attribute is not available for this entry, or to this process.
+#[cfg(target_os = "linux")]
+fn not_permitted(e: Errno) -> bool {
+    matches!(
+        e,
+        Errno::EPERM | Errno::EACCES | Errno::ENOTTY | Errno::EOPNOTSUPP | Errno::EINVAL
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn set_immutable(path: &Path, immutable: bool) -> nix::Result<()> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| Errno::from_raw(e.raw_os_error().unwrap_or(libc::EIO)))?;
+    let mut flags = 0;
+    // SAFETY: both ioctls take a pointer to an int, which `flags` is
+    unsafe { fs_ioc_getflags(file.as_raw_fd(), &mut flags) }?;
+    let flags = match immutable {
+        true => flags | FS_IMMUTABLE_FL,
+        false => flags & !FS_IMMUTABLE_FL,
+    };
+    unsafe { fs_ioc_setflags(file.as_raw_fd(), &flags) }?;
+    Ok(())
+}
+
+/// The immutable attribute is specific to Linux.
+#[cfg(not(target_os = "linux"))]
+fn not_permitted(_e: std::io::Error) -> bool {
+    true
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_immutable(_path: &Path, _immutable: bool) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,37 @@
+use std::{io::Write, path::Path};
+
+use tokio::io::AsyncWrite;
+
+use crate::{Archive, Error, ExtractOptions};
+
+/// The contents of the file at `path` in `squashfs`, read with the `kind`, `parsing` and
+/// `direct_io` of `options`.
+pub fn read_file_blocking(
+    squashfs: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<Vec<u8>, Error> {
+    Archive::open(squashfs, options)?.read_file_blocking(path)
+}
+
+/// Like [`read_file_blocking`], streaming the contents into `writer`. Returns the number of bytes
+/// written.
+pub fn read_file_to(
+    squashfs: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    writer: impl Write,
+    options: ExtractOptions,
+) -> Result<u64, Error> {
+    Archive::open(squashfs, options)?.read_file_to(path, writer)
+}
+
+/// Async flavor of [`read_file_to`].
+pub async fn read_file_async(
+    squashfs: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    options: ExtractOptions,
+) -> Result<u64, Error> {
+    let archive = Archive::open_async(squashfs, options).await?;
+    archive.read_file_async(path, writer).await
+}
@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use anyhow::{Context, Result};
+use backhand::{BasicFile, InnerNode};
+
+use crate::{cache::CacheStats, Archive};
+
+/// Copies of the files read from an archive, under a directory named after the fingerprint of the
+/// archive, at their path in it.
+#[derive(Debug)]
+pub(crate) struct ReadCache {
+    dir: PathBuf,
+    /// Also cache the files stored whole in the fragment of a file read.
+    siblings: bool,
+    /// Indexes of the nodes of the files stored whole in each fragment, by fragment index.
+    fragments: OnceLock<HashMap<u32, Vec<usize>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Tells apart the temporary files of concurrent misses.
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+impl ReadCache {
+    pub(crate) fn new(dir: PathBuf, siblings: bool) -> Self {
+        Self {
+            dir,
+            siblings,
+            fragments: OnceLock::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The copy of the regular file `file` at `path` in `archive`, extracted into the cache first
+    /// if it is missing.
+    pub(crate) fn file(&self, archive: &Archive, path: &Path, file: &BasicFile) -> Result<PathBuf> {
+        let cached = self.cached_path(archive, path)?;
+        if is_cached(&cached, file)? {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(cached);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        match (&archive.block_decoder, self.siblings && in_fragment(file)) {
+            (Some(decoder), true) => {
+                let fragment = decoder.fragment(&archive.filesystem, file.frag_index)?;
+                self.store(archive, &cached, |out| {
+                    out.write_all(slice(&fragment, file)?)?;
+                    Ok(())
+                })?;
+                self.store_siblings(archive, file.frag_index, &fragment)?;
+            }
+            _ => self.store(archive, &cached, |out| {
+                archive
+                    .copy(file, out)
+                    .with_context(|| format!("read '{}'", path.display()))?;
+                Ok(())
+            })?,
+        }
+        Ok(cached)
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Cache the files stored whole in the fragment at `frag_index`, decompressed as `fragment`,
+    /// that are not already.
+    fn store_siblings(&self, archive: &Archive, frag_index: u32, fragment: &[u8]) -> Result<()> {
+        let nodes = &archive.filesystem.root.nodes;
+        let fragments = self.fragments.get_or_init(|| {
+            let mut fragments: HashMap<u32, Vec<usize>> = HashMap::new();
+            for (i, node) in nodes.iter().enumerate() {
+                if let InnerNode::File(file) = &node.inner {
+                    if in_fragment(&file.basic) {
+                        fragments.entry(file.basic.frag_index).or_default().push(i);
+                    }
+                }
+            }
+            fragments
+        });
+        for &i in fragments.get(&frag_index).into_iter().flatten() {
+            let node = &nodes[i];
+            let InnerNode::File(file) = &node.inner else {
+                continue;
+            };
+            let cached = self.cached_path(archive, &archive.path_of(i))?;
+            if is_cached(&cached, &file.basic)? {
+                continue;
+            }
+            let data = slice(fragment, &file.basic)?;
+            self.store(archive, &cached, |out| Ok(out.write_all(data)?))?;
+        }
+        Ok(())
+    }
+
+    fn cached_path(&self, archive: &Archive, path: &Path) -> Result<PathBuf> {
+        let relative = Path::new("/").join(path);
+        let relative = relative.strip_prefix("/").unwrap_or(&relative);
+        Ok(self.dir.join(archive.fingerprint()?).join(relative))
+    }
+
+    /// Write `cached` with `fill` through a temporary file renamed into place.
+    fn store(
+        &self,
+        archive: &Archive,
+        cached: &Path,
+        fill: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+    ) -> Result<()> {
+        let parent = cached.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("create cache directory '{}'", parent.display()))?;
+        let name = cached.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = parent.join(format!(
+            ".{name}.{}.{}.tmp",
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let res = File::create(&tmp)
+            .with_context(|| format!("create '{}'", tmp.display()))
+            .and_then(|out| {
+                let mut out = BufWriter::new(out);
+                fill(&mut out).with_context(|| format!("write '{}'", tmp.display()))?;
+                out.flush()
+                    .with_context(|| format!("write '{}'", tmp.display()))?;
+                // do not cache what was read from a changed archive
+                Ok(archive.check_unchanged()?)
+            })
+            .and_then(|()| {
+                std::fs::rename(&tmp, cached).with_context(|| {
+                    format!("rename '{}' to '{}'", tmp.display(), cached.display())
+                })
+            });
+        if res.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        res
+    }
+}
+
+fn is_cached(cached: &Path, file: &BasicFile) -> Result<bool> {
+    match std::fs::metadata(cached) {
+        Ok(metadata) => Ok(metadata.is_file() && metadata.len() == u64::from(file.file_size)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("stat '{}'", cached.display())),
+    }
+}
+
+/// Whether `file` is stored whole in a fragment.
+fn in_fragment(file: &BasicFile) -> bool {
+    file.block_sizes.is_empty() && file.frag_index != u32::MAX
+}
+
+/// The contents of `file`, stored whole in the decompressed `fragment`.
+fn slice<'a>(fragment: &'a [u8], file: &BasicFile) -> Result<&'a [u8]> {
+    let start = file.block_offset as usize;
+    fragment
+        .get(start..start + file.file_size as usize)
+        .context("fragment is shorter than the file")
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::{digest, platform};
+
+/// A remote execution API `Digest`, as Bazel remote caches key blobs by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Digest {
+    /// Hex-encoded SHA-256.
+    pub hash: String,
+    pub size_bytes: u64,
+}
+
+/// Digests of an extracted tree, computed while it was written.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeDigests {
+    /// Digest of every extracted file, by path relative to the destination.
+    pub files: BTreeMap<PathBuf, Digest>,
+    /// Digest of the destination's `Directory` message, usable as an input root or as the root of
+    /// an output directory's `Tree`.
+    pub root: Option<Digest>,
+}
+
+/// Build the [`TreeDigests`] of `dest` from the digests of the `files` written into it. Files not
+/// among them, such as manifests, are hashed from disk, and entries other than files, directories
+/// and symlinks are left out.
+pub(crate) fn tree_digests(dest: &Path, files: Vec<(PathBuf, Digest)>) -> Result<TreeDigests> {
+    let files: BTreeMap<_, _> = files
+        .into_iter()
+        .map(|(path, digest)| {
+            let relative = path.strip_prefix(dest).unwrap_or(&path).to_path_buf();
+            (relative, digest)
+        })
+        .collect();
+    let root = match dest.is_dir() {
+        true => Some(directory(dest, Path::new(""), &files)?),
+        false => None,
+    };
+    Ok(TreeDigests { files, root })
+}
+
+/// The digest of the encoded `Directory` message for the directory at `relative` in `dest`.
+fn directory(dest: &Path, relative: &Path, files: &BTreeMap<PathBuf, Digest>) -> Result<Digest> {
+    let path = dest.join(relative);
+    let mut names = std::fs::read_dir(&path)
+        .with_context(|| format!("read dir '{}'", path.display()))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("read dir '{}'", path.display()))?;
+    names.sort_unstable_by(|a, b| a.as_encoded_bytes().cmp(b.as_encoded_bytes()));
+
+    let (mut file_nodes, mut dir_nodes, mut symlink_nodes) = (Vec::new(), Vec::new(), Vec::new());
+    for name in names {
+        let relative = relative.join(&name);
+        let path = dest.join(&relative);
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("stat '{}'", path.display()))?;
+        let file_type = metadata.file_type();
+        let mut node = Vec::new();
+        field(&mut node, 1, name.as_encoded_bytes());
+        if file_type.is_file() {
+            let digest = match files.get(&relative) {
+                Some(digest) => digest.clone(),
+                None => Digest {
+                    hash: digest::sha256_file(&path)?,
+                    size_bytes: metadata.len(),
+                },
+            };
+            field(&mut node, 2, &encode_digest(&digest));
+            if platform::mode(&metadata) & 0o100 != 0 {
+                node.extend_from_slice(&[4 << 3, 1]);
+            }
+            file_nodes.push(node);
+        } else if file_type.is_dir() {
+            let digest = directory(dest, &relative, files)?;
+            field(&mut node, 2, &encode_digest(&digest));
+            dir_nodes.push(node);
+        } else if file_type.is_symlink() {
+            let target = std::fs::read_link(&path)
+                .with_context(|| format!("read symlink '{}'", path.display()))?;
+            field(&mut node, 2, target.as_os_str().as_encoded_bytes());
+            symlink_nodes.push(node);
+        } else {
+            tracing::debug!(path = %path.display(), "leaving special file out of tree digest");
+        }
+    }
+
+    let mut message = Vec::new();
+    for (number, nodes) in [(1, file_nodes), (2, dir_nodes), (3, symlink_nodes)] {
+        for node in nodes {
+            field(&mut message, number, &node);
+        }
+    }
+    Ok(Digest {
+        hash: format!("{:x}", Sha256::digest(&message)),
+        size_bytes: message.len() as u64,
+    })
+}
+
+fn encode_digest(digest: &Digest) -> Vec<u8> {
+    let mut out = Vec::new();
+    field(&mut out, 1, digest.hash.as_bytes());
+    if digest.size_bytes != 0 {
+        out.push(2 << 3);
+        varint(&mut out, digest.size_bytes);
+    }
+    out
+}
+
+/// A length-delimited protobuf field.
+fn field(out: &mut Vec<u8>, number: u8, bytes: &[u8]) {
+    out.push(number << 3 | 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use backhand::{InnerNode, Node, SquashfsFileReader};
+use serde::{Deserialize, Serialize};
+
+/// Name of the manifest written at the root of a destination extracted with recompression.
+pub const RECOMPRESS_MANIFEST: &str = "recompressed.json";
+
+/// Encoding to recompress every extracted file with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recompress {
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Recompress {
+    /// File extension appended to recompressed files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            #[cfg(feature = "gzip")]
+            Self::Gzip => "gz",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zst",
+        }
+    }
+}
+
+/// `path` with the extension of `recompress` appended, if any.
+pub(crate) fn recompressed_path(path: PathBuf, recompress: Option<Recompress>) -> PathBuf {
+    match recompress {
+        Some(recompress) => {
+            let mut path = path.into_os_string();
+            path.push(".");
+            path.push(recompress.extension());
+            path.into()
+        }
+        None => path,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecompressedEntry {
+    /// Path of the recompressed file relative to the destination root.
+    pub path: PathBuf,
+    pub encoding: Recompress,
+    /// Size of the original contents.
+    pub size: u64,
+    pub compressed_size: u64,
+}
+
+/// Archive fullpath to the recompressed file holding its contents.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecompressManifest {
+    pub entries: BTreeMap<PathBuf, RecompressedEntry>,
+}
+
+impl RecompressManifest {
+    pub fn load(dest: impl AsRef<Path>) -> Result<Self> {
+        let path = dest.as_ref().join(RECOMPRESS_MANIFEST);
+        let json = std::fs::read_to_string(&path)
+            .with_context(|| format!("read recompression manifest '{}'", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("parse recompression manifest '{}'", path.display()))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("serialize recompression manifest")
+    }
+
+    pub(crate) fn write(&self, dest: &Path) -> Result<()> {
+        let path = dest.join(RECOMPRESS_MANIFEST);
+        std::fs::write(&path, self.to_json()?)
+            .with_context(|| format!("write recompression manifest '{}'", path.display()))
+    }
+
+    /// Build the manifest from the files written under `dest`; files that were not written
+    /// (e.g. trimmed by the quota) are left out.
+    pub(crate) fn build(
+        dest: &Path,
+        nodes: &[&Node<SquashfsFileReader>],
+        recompress: Recompress,
+        shard_levels: Option<u8>,
+        subtree: Option<&Path>,
+    ) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+        for node in nodes {
+            let InnerNode::File(file) = &node.inner else {
+                continue;
+            };
+            let Some(dest_path) =
+                crate::shard::dest_path(dest, node, shard_levels, Some(recompress), subtree)
+            else {
+                continue;
+            };
+            let compressed_size = match std::fs::metadata(&dest_path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("stat '{}'", dest_path.display())),
+            };
+            let entry = RecompressedEntry {
+                path: dest_path
+                    .strip_prefix(dest)
+                    .unwrap_or(&dest_path)
+                    .to_path_buf(),
+                encoding: recompress,
+                size: u64::from(file.basic.file_size),
+                compressed_size,
+            };
+            entries.insert(node.fullpath.clone(), entry);
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Writer that optionally compresses everything written through it.
+pub(crate) enum Encoder<W: Write> {
+    Plain(W),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn new(writer: W, recompress: Option<Recompress>) -> std::io::Result<Self> {
+        Ok(match recompress {
+            None => Self::Plain(writer),
+            #[cfg(feature = "gzip")]
+            Some(Recompress::Gzip) => Self::Gzip(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            )),
+            #[cfg(feature = "zstd")]
+            Some(Recompress::Zstd) => Self::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+
+    /// Write out any trailer and flush the underlying writer.
+    pub(crate) fn finish(self) -> std::io::Result<()> {
+        let mut writer = match self {
+            Self::Plain(writer) => writer,
+            #[cfg(feature = "gzip")]
+            Self::Gzip(encoder) => encoder.finish()?,
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        writer.flush()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            #[cfg(feature = "gzip")]
+            Self::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            #[cfg(feature = "gzip")]
+            Self::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
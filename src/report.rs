@@ -0,0 +1,328 @@
+use std::{
+    any::Any,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use backhand::{InnerNode, Node, SquashfsFileReader};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mechanisms::Mechanisms, profile::Profile, reapi::TreeDigests, shard, EntryKind, EntryRef,
+};
+
+/// Outcome of an extraction.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtractReport {
+    /// What was extracted, and how long it took.
+    #[serde(default)]
+    pub summary: ExtractSummary,
+    /// Entries skipped in salvage mode because they could not be extracted, sorted by path.
+    pub unrecoverable: Vec<Unrecoverable>,
+    /// Paths in the archive of the entries extracted in salvage mode, sorted, to tell what
+    /// survived next to `unrecoverable`. Empty otherwise.
+    #[serde(default)]
+    pub extracted: Vec<PathBuf>,
+    /// See [`nar_hash`](crate::nar::nar_hash).
+    #[serde(default)]
+    pub nar_hash: Option<String>,
+    /// See [`ObservabilityOptions::digests`](crate::ObservabilityOptions::digests).
+    #[serde(default)]
+    pub digests: Option<TreeDigests>,
+    /// See [`ObservabilityOptions::profile`](crate::ObservabilityOptions::profile).
+    pub profile: Option<Profile>,
+    /// Entries kept despite failing to get their metadata, see
+    /// [`MetadataErrorPolicy::Warn`](crate::MetadataErrorPolicy::Warn).
+    #[serde(default)]
+    pub metadata_warnings: Vec<MetadataWarning>,
+    /// The [`Mechanisms`] used, unless nothing was extracted.
+    #[serde(default)]
+    pub mechanisms: Option<Mechanisms>,
+    /// See [`ExtractOptions::dry_run`](crate::ExtractOptions::dry_run).
+    #[serde(default)]
+    pub dry_run: Option<DryRun>,
+    /// The extraction stopped early as [`ExtractOptions::shutdown`](crate::ExtractOptions::shutdown)
+    /// asked: the entries it did not get to are counted as skipped, and the manifests, hashes and
+    /// read-only protection of the tree are left out.
+    #[serde(default)]
+    pub interrupted: bool,
+    /// Paths, crate names, glob patterns or prefix of the [`Filter`](crate::Filter) that matched
+    /// nothing in the archive, sorted, to tell an entry missing from the archive from one
+    /// extracted; see [`PolicyOptions::strict_filter`](crate::PolicyOptions::strict_filter).
+    /// Not tracked for tar archives, nor for regex and predicate filters.
+    #[serde(default)]
+    pub unmatched_filter: Vec<String>,
+}
+
+impl ExtractReport {
+    /// Add what another extraction into the same destination did to this report. The tree
+    /// hashes and digests are left for the caller to compute over the whole destination.
+    pub(crate) fn merge(&mut self, other: ExtractReport) {
+        let (summary, other_summary) = (&mut self.summary, other.summary);
+        summary.files += other_summary.files;
+        summary.dirs += other_summary.dirs;
+        summary.symlinks += other_summary.symlinks;
+        summary.special_files += other_summary.special_files;
+        summary.bytes_written += other_summary.bytes_written;
+        summary.skipped += other_summary.skipped;
+        summary.reused += other_summary.reused;
+        summary.duration += other_summary.duration;
+        self.unrecoverable.extend(other.unrecoverable);
+        self.unrecoverable.sort_by(|a, b| a.path.cmp(&b.path));
+        self.extracted.extend(other.extracted);
+        self.extracted.sort();
+        self.metadata_warnings.extend(other.metadata_warnings);
+        self.unmatched_filter.extend(other.unmatched_filter);
+        self.unmatched_filter.sort_unstable();
+        self.unmatched_filter.dedup();
+        self.mechanisms = match (self.mechanisms, other.mechanisms) {
+            (Some(a), Some(b)) => Some(Mechanisms {
+                fallocate: a.fallocate || b.fallocate,
+                hardlinks: a.hardlinks || b.hardlinks,
+                tmpfile: a.tmpfile || b.tmpfile,
+                reflink: a.reflink || b.reflink,
+                copy_file_range: a.copy_file_range || b.copy_file_range,
+                sparse: a.sparse || b.sparse,
+            }),
+            (a, b) => a.or(b),
+        };
+        self.dry_run = match (self.dry_run.take(), other.dry_run) {
+            (Some(mut a), Some(b)) => {
+                a.entries.extend(b.entries);
+                a.total_bytes += b.total_bytes;
+                Some(a)
+            }
+            (a, b) => a.or(b),
+        };
+        self.profile = match (self.profile.take(), other.profile) {
+            (Some(mut a), Some(b)) => {
+                a.merge(&b);
+                Some(a)
+            }
+            (a, b) => a.or(b),
+        };
+        self.interrupted |= other.interrupted;
+    }
+}
+
+/// Counts of the entries an extraction wrote into its destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtractSummary {
+    /// Regular files, including those extracted as hardlinks.
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// Device nodes, named pipes and sockets.
+    pub special_files: u64,
+    /// Size of the files written, before any recompression.
+    pub bytes_written: u64,
+    /// Entries selected but not extracted: kept as they were at the destination, over the quota,
+    /// left intact by an interrupted extraction, or failed and skipped in salvage mode.
+    pub skipped: u64,
+    /// Files already at the destination and identical to the entry, kept as they were, see
+    /// [`OverwritePolicy::SkipIdentical`](crate::OverwritePolicy::SkipIdentical).
+    #[serde(default)]
+    pub reused: u64,
+    /// From the start of the extraction until its destination was finished.
+    pub duration: Duration,
+}
+
+/// The running counts of an [`ExtractSummary`], shared by the entries being extracted.
+#[derive(Debug, Default)]
+pub(crate) struct Tally {
+    files: AtomicU64,
+    dirs: AtomicU64,
+    symlinks: AtomicU64,
+    special_files: AtomicU64,
+    bytes_written: AtomicU64,
+    skipped: AtomicU64,
+    reused: AtomicU64,
+}
+
+impl Tally {
+    pub(crate) fn extracted(&self, node: &Node<SquashfsFileReader>) {
+        let counter = match &node.inner {
+            InnerNode::File(file) => {
+                let size = u64::from(file.basic.file_size);
+                self.bytes_written.fetch_add(size, Ordering::Relaxed);
+                &self.files
+            }
+            InnerNode::Dir(_) => &self.dirs,
+            InnerNode::Symlink(_) => &self.symlinks,
+            _ => &self.special_files,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn linked(&self) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn skipped(&self, entries: u64) {
+        self.skipped.fetch_add(entries, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reused(&self) {
+        self.reused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn summary(&self) -> ExtractSummary {
+        ExtractSummary {
+            files: self.files.load(Ordering::Relaxed),
+            dirs: self.dirs.load(Ordering::Relaxed),
+            symlinks: self.symlinks.load(Ordering::Relaxed),
+            special_files: self.special_files.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataWarning {
+    /// Path of the entry in the archive.
+    pub path: PathBuf,
+    pub error: String,
+}
+
+impl MetadataWarning {
+    pub(crate) fn new(path: PathBuf, error: &anyhow::Error) -> Self {
+        tracing::warn!(path = %path.display(), "failed to set metadata: {error:#}");
+        Self {
+            path,
+            error: format!("{error:#}"),
+        }
+    }
+}
+
+/// What a dry run would have extracted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DryRun {
+    pub entries: Vec<PlannedEntry>,
+    /// Size of the files in the archive, before any recompression.
+    pub total_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedEntry {
+    /// Path of the entry in the archive.
+    pub path: PathBuf,
+    /// Where the entry would be extracted to, if anywhere; sharded directories are not.
+    pub dest_path: Option<PathBuf>,
+    pub kind: EntryKind,
+    /// File size, or length of the target of a symlink.
+    pub size: u64,
+}
+
+impl DryRun {
+    pub(crate) fn plan(
+        dest: &Path,
+        nodes: &[&Node<SquashfsFileReader>],
+        shard_levels: Option<u8>,
+        recompress: Option<crate::recompress::Recompress>,
+        subtree: Option<&Path>,
+    ) -> Self {
+        let entries: Vec<_> = nodes
+            .iter()
+            .map(|node| {
+                let EntryRef {
+                    path, kind, size, ..
+                } = EntryRef::new(node);
+                PlannedEntry {
+                    dest_path: shard::dest_path(dest, node, shard_levels, recompress, subtree),
+                    path: path.into_owned(),
+                    kind,
+                    size,
+                }
+            })
+            .collect();
+        let total_bytes = entries
+            .iter()
+            .filter(|entry| entry.kind == EntryKind::File)
+            .map(|entry| entry.size)
+            .sum();
+        Self {
+            entries,
+            total_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unrecoverable {
+    /// Path of the entry in the archive.
+    pub path: PathBuf,
+    pub error: String,
+    /// Where the partial output was moved to, see
+    /// [`PolicyOptions::quarantine`](crate::PolicyOptions::quarantine).
+    #[serde(default)]
+    pub quarantined: Option<PathBuf>,
+}
+
+impl Unrecoverable {
+    /// Record a failed entry, moving what was written of it into `quarantine` if given, or
+    /// removing it otherwise.
+    pub(crate) fn new(
+        node: &Node<SquashfsFileReader>,
+        error: &anyhow::Error,
+        dest_path: Option<PathBuf>,
+        quarantine: Option<&Path>,
+    ) -> Self {
+        tracing::warn!(path = %node.fullpath.display(), "unrecoverable entry: {error:#}");
+        Self {
+            path: node.fullpath.clone(),
+            error: format!("{error:#}"),
+            quarantined: discard_partial(node, dest_path, quarantine),
+        }
+    }
+}
+
+/// Run `f`, turning a panic (e.g. from malformed metadata) into an error.
+pub(crate) fn catch_panic<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| Err(panic_error(panic)))
+}
+
+pub(crate) fn panic_error(panic: Box<dyn Any + Send>) -> anyhow::Error {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    anyhow::anyhow!("panicked: {message}")
+}
+
+/// Remove whatever part of a file entry was written before its extraction failed, or move it to
+/// the same path under `quarantine`, returning where it went.
+fn discard_partial(
+    node: &Node<SquashfsFileReader>,
+    dest_path: Option<PathBuf>,
+    quarantine: Option<&Path>,
+) -> Option<PathBuf> {
+    let (InnerNode::File(_), Some(dest_path)) = (&node.inner, dest_path) else {
+        return None;
+    };
+    if !dest_path.exists() {
+        return None;
+    }
+    if let Some(quarantine) = quarantine {
+        let target = quarantine.join(node.fullpath.strip_prefix("/").unwrap_or(&node.fullpath));
+        let res = target
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::rename(&dest_path, &target));
+        match res {
+            Ok(()) => return Some(target),
+            Err(e) => tracing::warn!(
+                path = %dest_path.display(),
+                "could not quarantine partial output, removing it: {e}"
+            ),
+        }
+    }
+    let _ = std::fs::remove_file(dest_path);
+    None
+}
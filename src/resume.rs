@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use anyhow::{Context, Result};
+use backhand::{Node, SquashfsFileReader};
+use serde::{Deserialize, Serialize};
+
+use crate::{digest, reapi::Digest};
+
+/// The first line of a journal, naming the archive whose extraction it records.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    fingerprint: String,
+}
+
+/// A line of a journal: a file of the archive written completely, with its metadata applied.
+#[derive(Serialize, Deserialize)]
+struct Done {
+    path: PathBuf,
+    #[serde(flatten)]
+    digest: Digest,
+}
+
+/// The journal of the files an extraction completed, in
+/// [`DurabilityOptions::resume`](crate::DurabilityOptions::resume).
+pub(crate) struct Journal {
+    path: PathBuf,
+    /// The files a previous extraction of the same archive completed.
+    done: HashMap<PathBuf, Digest>,
+    /// The files this extraction completed, for the hardlinks to them.
+    recorded: Mutex<HashMap<PathBuf, Digest>>,
+    file: Mutex<std::fs::File>,
+}
+
+impl Journal {
+    /// Open the journal at `path` for extracting the archive with `fingerprint`, keeping what it
+    /// records if it was written for that same archive.
+    pub(crate) fn open(path: &Path, fingerprint: String) -> Result<Self> {
+        let done = match std::fs::File::open(path) {
+            Ok(file) => read(std::io::BufReader::new(file), &fingerprint)
+                .with_context(|| format!("read resume journal '{}'", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(e).with_context(|| format!("open resume journal '{}'", path.display()))
+            }
+        };
+        let fresh = done.is_none();
+        let file = match fresh {
+            false => std::fs::OpenOptions::new().append(true).open(path),
+            true => std::fs::File::create(path),
+        }
+        .with_context(|| format!("open resume journal '{}'", path.display()))?;
+        let journal = Self {
+            path: path.to_path_buf(),
+            done: done.unwrap_or_default(),
+            recorded: Mutex::default(),
+            file: Mutex::new(file),
+        };
+        if fresh {
+            journal.append(&Header { fingerprint })?;
+        }
+        Ok(journal)
+    }
+
+    /// How a previous extraction of the archive wrote `node`, if it completed it.
+    pub(crate) fn done(&self, node: &Node<SquashfsFileReader>) -> Option<&Digest> {
+        self.done.get(&node.fullpath)
+    }
+
+    /// Record that `node` was written completely, as `digest`.
+    pub(crate) fn record(&self, node: &Node<SquashfsFileReader>, digest: Digest) -> Result<()> {
+        self.append(&Done {
+            path: node.fullpath.clone(),
+            digest: digest.clone(),
+        })?;
+        self.recorded
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(node.fullpath.clone(), digest);
+        Ok(())
+    }
+
+    /// Record that `link` was hardlinked to `target`, as the digest `target` was journaled with,
+    /// by this extraction or a previous one. Links to files that were not are left out, to be
+    /// linked again.
+    pub(crate) fn record_link(
+        &self,
+        link: &Node<SquashfsFileReader>,
+        target: &Node<SquashfsFileReader>,
+    ) -> Result<()> {
+        let recorded = self
+            .recorded
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&target.fullpath)
+            .cloned();
+        match recorded.or_else(|| self.done(target).cloned()) {
+            Some(digest) => self.record(link, digest),
+            None => Ok(()),
+        }
+    }
+
+    /// Remove the journal of an extraction that succeeded.
+    pub(crate) fn finish(self) -> Result<()> {
+        std::fs::remove_file(&self.path)
+            .with_context(|| format!("remove resume journal '{}'", self.path.display()))
+    }
+
+    fn append(&self, line: &impl Serialize) -> Result<()> {
+        let mut line = serde_json::to_vec(line).context("serialize resume journal line")?;
+        line.push(b'\n');
+        self.file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_all(&line)
+            .with_context(|| format!("write resume journal '{}'", self.path.display()))
+    }
+}
+
+/// The files recorded in a journal, or `None` if it was written for another archive. A line cut
+/// short by an interruption is ignored.
+fn read(reader: impl BufRead, fingerprint: &str) -> Result<Option<HashMap<PathBuf, Digest>>> {
+    let mut lines = reader.lines();
+    let Some(header) = lines.next().transpose()? else {
+        return Ok(None);
+    };
+    match serde_json::from_str::<Header>(&header) {
+        Ok(header) if header.fingerprint == fingerprint => {}
+        _ => return Ok(None),
+    }
+    let mut done = HashMap::new();
+    for line in lines {
+        if let Ok(Done { path, digest }) = serde_json::from_str(&line?) {
+            done.insert(path, digest);
+        }
+    }
+    Ok(Some(done))
+}
+
+/// Whether `dest_path` still holds the file written as `digest`.
+pub(crate) fn intact(dest_path: &Path, digest: &Digest) -> bool {
+    match std::fs::symlink_metadata(dest_path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() == digest.size_bytes => {}
+        _ => return false,
+    }
+    digest::sha256_file(dest_path).is_ok_and(|hash| hash == digest.hash)
+}
@@ -0,0 +1,115 @@
+//! Running the async extractors from applications that are not built on tokio.
+//!
+//! The async extractors run their blocking work (decoding, stat and metadata calls, manifests)
+//! through a [`Runtime`], [`Tokio`] unless [`IoOptions::runtime`](crate::IoOptions::runtime)
+//! says otherwise. What they need tokio itself for (the tasks entries are extracted on, file
+//! writes, retry backoffs) runs on the current tokio runtime, or when polled outside of one, on a
+//! runtime the crate starts on first use, so that their futures can be awaited from any executor.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result};
+use futures::{channel::oneshot, future::BoxFuture, FutureExt};
+
+use crate::profile;
+
+/// Runs blocking work for the async extractors.
+pub trait Runtime: fmt::Debug + Send + Sync + 'static {
+    /// Run `f` on a thread where it may block, resolving once it returned.
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()>;
+}
+
+/// Runs blocking work on the blocking pool of the current tokio runtime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tokio;
+
+impl Runtime for Tokio {
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()> {
+        // a panic drops the sender, which `unblock` reports
+        tokio::task::spawn_blocking(f).map(drop).boxed()
+    }
+}
+
+/// Runs blocking work on the blocking pool of async-std.
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStd;
+
+#[cfg(feature = "async-std")]
+impl Runtime for AsyncStd {
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()> {
+        async_std::task::spawn_blocking(f).boxed()
+    }
+}
+
+/// Runs blocking work on the thread pool of smol.
+#[cfg(feature = "smol")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Smol;
+
+#[cfg(feature = "smol")]
+impl Runtime for Smol {
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) -> BoxFuture<'static, ()> {
+        smol::unblock(f).boxed()
+    }
+}
+
+/// Run `f` through `runtime`, resolving to what it returns.
+pub(crate) async fn unblock<T: Send + 'static>(
+    runtime: &dyn Runtime,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T> {
+    let (tx, rx) = oneshot::channel();
+    let profiler = profile::current();
+    runtime
+        .spawn_blocking(Box::new(move || {
+            let _ = tx.send(profile::scoped(profiler.as_ref(), f));
+        }))
+        .await;
+    rx.await.context("blocking task panicked")
+}
+
+/// The runtime the futures of the async extractors enter when polled outside of a tokio one.
+pub(crate) fn background() -> &'static tokio::runtime::Runtime {
+    static BACKGROUND: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    BACKGROUND.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .thread_name("backhand-async")
+            .enable_all()
+            .build()
+            .expect("build background tokio runtime")
+    })
+}
+
+/// Poll `future` within a tokio runtime: the current one, or the background one.
+pub(crate) fn compat<F: Future>(future: F) -> Compat<F> {
+    let handle = match tokio::runtime::Handle::try_current() {
+        Ok(_) => None,
+        Err(_) => Some(background().handle().clone()),
+    };
+    Compat {
+        future: Box::pin(future),
+        handle,
+    }
+}
+
+pub(crate) struct Compat<F> {
+    future: Pin<Box<F>>,
+    handle: Option<tokio::runtime::Handle>,
+}
+
+impl<F: Future> Future for Compat<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let _entered = this.handle.as_ref().map(tokio::runtime::Handle::enter);
+        this.future.as_mut().poll(cx)
+    }
+}
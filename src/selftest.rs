@@ -0,0 +1,434 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Cursor,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use backhand::{compression::Compressor, FilesystemCompressor, FilesystemWriter, NodeHeader};
+
+use crate::{
+    compression, mechanisms::Mechanisms, platform, DurabilityOptions, Error, EscapePolicy,
+    ExtractOptions, IoOptions, PolicyOptions,
+};
+
+const ENTRIES: &[(&str, usize)] = &[
+    ("se/rd/serde", 300_000 + 1234),
+    ("3/a/abc", 42),
+    ("an/yh/anyhow", 0),
+];
+
+/// A symlink in the index, relative to it, and its target.
+const SYMLINK: (&str, &str) = ("se/rd/serde-latest", "serde");
+
+/// A symlink out of the destination, and its target.
+const ESCAPE: (&str, &str) = ("escape", "../outside");
+
+const MODES: [&str; 2] = ["blocking", "async"];
+
+/// Every file written through userspace buffers, as the extractions of the other
+/// [`Mechanisms`] are compared against.
+const NO_MECHANISMS: Mechanisms = Mechanisms {
+    fallocate: false,
+    hardlinks: false,
+    tmpfile: false,
+    reflink: false,
+    copy_file_range: false,
+    sparse: false,
+};
+
+/// Build a small tpcii-style archive in a temporary directory, extract it through the supported
+/// extraction paths, and verify the output. Useful to validate that a deployment's kernel and
+/// filesystem behave as this crate expects.
+pub fn selftest() -> Result<()> {
+    let root = std::env::temp_dir().join(format!(
+        "backhand-async-selftest-{}-{}",
+        std::process::id(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    ));
+    std::fs::create_dir_all(&root)
+        .with_context(|| format!("create selftest dir '{}'", root.display()))?;
+
+    let res = run(&root);
+    let cleanup = std::fs::remove_dir_all(&root)
+        .with_context(|| format!("remove selftest dir '{}'", root.display()));
+    res.and(cleanup)
+}
+
+fn run(root: &Path) -> Result<()> {
+    let archive = root.join("reference.squashfs");
+    write_reference_archive(&archive, reference_compressor())?;
+
+    let filters: [Option<HashSet<String>>; 2] =
+        [None, Some(HashSet::from(["se/rd/serde".to_owned()]))];
+    for workers in [1, 4] {
+        let options = || ExtractOptions {
+            io: IoOptions {
+                block_decode_workers: Some(workers),
+                ..IoOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        for (i, filter) in filters.iter().enumerate() {
+            for mode in MODES {
+                let dest = root.join(format!("{mode}-workers{workers}-filter{i}"));
+                extract(mode, &archive, &dest, filter.clone(), options())
+                    .and_then(|()| verify(&dest, filter.as_ref()))
+                    .with_context(|| {
+                        format!("{mode} extraction, {workers} block workers, filter {filter:?}")
+                    })?;
+            }
+        }
+    }
+
+    // stored uncompressed, for files to be cloned or copied in the kernel out of it
+    let stored = root.join("stored.squashfs");
+    write_reference_archive(&stored, Compressor::None)?;
+    for archive in [&archive, &stored] {
+        check_variants(root, archive)?;
+    }
+    check_confinement(root)
+}
+
+/// Extract `archive` with each mechanism and policy changing how entries are written, comparing
+/// the result against an extraction using none of them.
+fn check_variants(root: &Path, archive: &Path) -> Result<()> {
+    let name = archive.file_stem().unwrap_or_default().to_string_lossy();
+    let reference = root.join(format!("{name}-reference"));
+    let plain = || ExtractOptions {
+        io: IoOptions {
+            mechanisms: NO_MECHANISMS,
+            ..IoOptions::default()
+        },
+        ..ExtractOptions::default()
+    };
+    extract("blocking", archive, &reference, None, plain())
+        .and_then(|()| verify(&reference, None))
+        .with_context(|| format!("reference extraction of '{}'", archive.display()))?;
+
+    let mechanism = |mechanisms| ExtractOptions {
+        io: IoOptions {
+            mechanisms,
+            ..IoOptions::default()
+        },
+        ..ExtractOptions::default()
+    };
+    let variants = [
+        (
+            "reflink",
+            mechanism(Mechanisms {
+                reflink: true,
+                ..NO_MECHANISMS
+            }),
+        ),
+        (
+            "copy_file_range",
+            mechanism(Mechanisms {
+                copy_file_range: true,
+                ..NO_MECHANISMS
+            }),
+        ),
+        (
+            "tmpfile",
+            mechanism(Mechanisms {
+                tmpfile: true,
+                ..NO_MECHANISMS
+            }),
+        ),
+        (
+            "hardlinks",
+            mechanism(Mechanisms {
+                hardlinks: true,
+                ..NO_MECHANISMS
+            }),
+        ),
+        ("defaults", ExtractOptions::default()),
+        (
+            "atomic",
+            ExtractOptions {
+                durability: DurabilityOptions {
+                    atomic: true,
+                    ..DurabilityOptions::default()
+                },
+                ..plain()
+            },
+        ),
+        (
+            "strict",
+            ExtractOptions {
+                policy: PolicyOptions {
+                    escape: EscapePolicy::Strict,
+                    ..PolicyOptions::default()
+                },
+                ..plain()
+            },
+        ),
+    ];
+    for (variant, options) in variants {
+        for mode in MODES {
+            let dest = root.join(format!("{name}-{mode}-{variant}"));
+            extract(mode, archive, &dest, None, options.clone())
+                .and_then(|()| compare(&reference, &dest))
+                .with_context(|| {
+                    format!(
+                        "{mode} extraction of '{}' with {variant}",
+                        archive.display()
+                    )
+                })?;
+        }
+    }
+    Ok(())
+}
+
+/// Extract an archive holding a symlink out of the destination, which [`EscapePolicy::Strict`]
+/// must refuse and the default policy extract as it is.
+fn check_confinement(root: &Path) -> Result<()> {
+    // symlinks are skipped off Unix
+    if !platform::SYMLINKS {
+        return Ok(());
+    }
+    let archive = root.join("escape.squashfs");
+    let mut writer = FilesystemWriter::default();
+    writer.set_compressor(FilesystemCompressor::new(Compressor::None, None)?);
+    let (link, target) = ESCAPE;
+    writer.push_symlink(target, link, NodeHeader::new(0o777, 0, 0, 0))?;
+    let out = std::fs::File::create(&archive)
+        .with_context(|| format!("create archive '{}'", archive.display()))?;
+    writer
+        .write(out)
+        .with_context(|| format!("write archive '{}'", archive.display()))?;
+
+    for mode in MODES {
+        let dest = root.join(format!("escape-{mode}-strict"));
+        let options = ExtractOptions {
+            policy: PolicyOptions {
+                escape: EscapePolicy::Strict,
+                ..PolicyOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        let res = extract(mode, &archive, &dest, None, options);
+        let refused = res.as_ref().is_err_and(|e| {
+            e.chain()
+                .any(|e| matches!(e.downcast_ref::<Error>(), Some(Error::Escape { .. })))
+        });
+        anyhow::ensure!(
+            refused,
+            "{mode} extraction of a symlink out of the destination was not refused: {res:?}"
+        );
+
+        let dest = root.join(format!("escape-{mode}-allowed"));
+        extract(mode, &archive, &dest, None, ExtractOptions::default())
+            .with_context(|| format!("{mode} extraction allowing symlinks out of it"))?;
+        let path = dest.join(link);
+        let found = std::fs::read_link(&path)
+            .with_context(|| format!("read symlink '{}'", path.display()))?;
+        anyhow::ensure!(
+            found == Path::new(target),
+            "'{}' points to '{}', expected '{target}'",
+            path.display(),
+            found.display()
+        );
+    }
+    Ok(())
+}
+
+fn extract(
+    mode: &str,
+    archive: &Path,
+    dest: &Path,
+    crates_filter: Option<HashSet<String>>,
+    options: ExtractOptions,
+) -> Result<()> {
+    match mode {
+        "blocking" => {
+            crate::unsquash_tpcii_blocking_with_options(archive, dest, crates_filter, options)
+                .map(drop)
+                .map_err(Into::into)
+        }
+        _ => unsquash_async(archive, dest, crates_filter, options),
+    }
+}
+
+/// Run the async extractor on a runtime of its own, so that this works from any context.
+fn unsquash_async(
+    archive: &Path,
+    dest: &Path,
+    crates_filter: Option<HashSet<String>>,
+    options: ExtractOptions,
+) -> Result<()> {
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .context("build selftest runtime")?
+                    .block_on(crate::unsquash_tpcii_async_with_options(
+                        archive,
+                        dest,
+                        crates_filter,
+                        options,
+                    ))
+                    .map(drop)
+                    .map_err(Into::into)
+            })
+            .join()
+            .unwrap_or_else(|panic| Err(crate::report::panic_error(panic)))
+    })
+}
+
+fn contents(name: &str, len: usize) -> Vec<u8> {
+    name.bytes().cycle().take(len).collect()
+}
+
+/// The first compressor this build can decompress.
+fn reference_compressor() -> Compressor {
+    [Compressor::Zstd, Compressor::Gzip, Compressor::Xz]
+        .into_iter()
+        .find(|c| compression::compiled_decompressors().contains(c))
+        .unwrap_or(Compressor::None)
+}
+
+fn write_reference_archive(path: &Path, compressor: Compressor) -> Result<()> {
+    let mut writer = FilesystemWriter::default();
+    writer.set_compressor(FilesystemCompressor::new(compressor, None)?);
+    let dir = NodeHeader::new(0o755, 0, 0, 0);
+    let file = NodeHeader::new(0o600, 0, 0, 0);
+    for (name, len) in ENTRIES {
+        for top in ["index", "salts"] {
+            let entry = Path::new(top).join(name);
+            let parent = entry.parent().expect("entries are nested");
+            writer.push_dir_all(parent, dir)?;
+            writer.push_file(Cursor::new(contents(name, *len)), &entry, file)?;
+        }
+    }
+    let (link, target) = SYMLINK;
+    writer.push_symlink(target, Path::new("index").join(link), file)?;
+
+    let out = std::fs::File::create(path)
+        .with_context(|| format!("create reference archive '{}'", path.display()))?;
+    writer
+        .write(out)
+        .with_context(|| format!("write reference archive '{}'", path.display()))?;
+    Ok(())
+}
+
+fn verify(dest: &Path, filter: Option<&HashSet<String>>) -> Result<()> {
+    for (name, len) in ENTRIES {
+        let expected = filter.is_none_or(|f| f.contains(*name));
+        for top in ["index", "salts"] {
+            let path = dest.join(top).join(name);
+            if !expected {
+                anyhow::ensure!(
+                    !path.exists(),
+                    "'{}' was extracted despite the filter",
+                    path.display()
+                );
+                continue;
+            }
+
+            let data =
+                std::fs::read(&path).with_context(|| format!("read '{}'", path.display()))?;
+            anyhow::ensure!(
+                data == contents(name, *len),
+                "'{}' has unexpected contents",
+                path.display()
+            );
+            let mode = platform::mode(&std::fs::metadata(&path)?);
+            anyhow::ensure!(
+                mode == 0o644,
+                "'{}' has mode {mode:#o}, expected 0o644",
+                path.display()
+            );
+        }
+    }
+
+    let link = dest.join("index").join(SYMLINK.0);
+    match filter {
+        Some(_) => anyhow::ensure!(
+            !link.is_symlink(),
+            "'{}' was extracted despite the filter",
+            link.display()
+        ),
+        // symlinks are skipped off Unix
+        None if !platform::SYMLINKS => {}
+        None => {
+            let target = std::fs::read_link(&link)
+                .with_context(|| format!("read symlink '{}'", link.display()))?;
+            anyhow::ensure!(
+                target == Path::new(SYMLINK.1),
+                "'{}' points to '{}', expected '{}'",
+                link.display(),
+                target.display(),
+                SYMLINK.1
+            );
+        }
+    }
+
+    let dir = dest.join("index").join("se");
+    let mode = platform::mode(&std::fs::metadata(&dir)?);
+    anyhow::ensure!(
+        mode == 0o755,
+        "'{}' has mode {mode:#o}, expected 0o755",
+        dir.display()
+    );
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TreeEntry {
+    Dir(u32),
+    File(u32, Vec<u8>),
+    Symlink(PathBuf),
+}
+
+/// Every entry under `root`, by its path relative to it.
+fn tree(root: &Path) -> Result<BTreeMap<PathBuf, TreeEntry>> {
+    let mut entries = BTreeMap::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let read = std::fs::read_dir(&dir).with_context(|| format!("read '{}'", dir.display()))?;
+        for entry in read {
+            let path = entry?.path();
+            let metadata = std::fs::symlink_metadata(&path)
+                .with_context(|| format!("stat '{}'", path.display()))?;
+            let entry = if metadata.is_dir() {
+                pending.push(path.clone());
+                TreeEntry::Dir(platform::mode(&metadata))
+            } else if metadata.is_symlink() {
+                TreeEntry::Symlink(std::fs::read_link(&path)?)
+            } else {
+                let data =
+                    std::fs::read(&path).with_context(|| format!("read '{}'", path.display()))?;
+                TreeEntry::File(platform::mode(&metadata), data)
+            };
+            let relative = path.strip_prefix(root).expect("listed under root");
+            entries.insert(relative.to_path_buf(), entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Check that `dest` holds the same entries as `reference`.
+fn compare(reference: &Path, dest: &Path) -> Result<()> {
+    let expected = tree(reference)?;
+    let found = tree(dest)?;
+    for (path, entry) in &expected {
+        match found.get(path) {
+            None => anyhow::bail!("'{}' is missing", dest.join(path).display()),
+            Some(found) if found != entry => {
+                anyhow::bail!("'{}' differs from the reference", dest.join(path).display())
+            }
+            Some(_) => {}
+        }
+    }
+    if let Some(path) = found.keys().find(|path| !expected.contains_key(*path)) {
+        anyhow::bail!("'{}' was not expected", dest.join(path).display());
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use backhand::{FilesystemReader, InnerNode, Node, SquashfsFileReader};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::recompress::{recompressed_path, Recompress};
+
+/// Name of the manifest written at the root of a sharded destination.
+pub const SHARD_MANIFEST: &str = "shards.json";
+
+/// Mapping from archive paths to their location in a sharded destination.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardManifest {
+    pub levels: u8,
+    /// Archive fullpath to the path relative to the destination root.
+    pub entries: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl ShardManifest {
+    pub fn load(dest: impl AsRef<Path>) -> Result<Self> {
+        let path = dest.as_ref().join(SHARD_MANIFEST);
+        let json = std::fs::read_to_string(&path)
+            .with_context(|| format!("read shard manifest '{}'", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("parse shard manifest '{}'", path.display()))
+    }
+
+    /// Where the archive entry `fullpath` lives under the destination root, if it was extracted.
+    pub fn resolve(&self, fullpath: impl AsRef<Path>) -> Option<&Path> {
+        self.entries.get(fullpath.as_ref()).map(PathBuf::as_path)
+    }
+
+    pub(crate) fn build(
+        levels: u8,
+        recompress: Option<Recompress>,
+        nodes: &[&Node<SquashfsFileReader>],
+    ) -> Self {
+        let entries = nodes
+            .iter()
+            .filter_map(|node| {
+                let path = dest_path(Path::new(""), node, Some(levels), recompress, None)?;
+                Some((node.fullpath.clone(), path))
+            })
+            .collect();
+        Self { levels, entries }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("serialize shard manifest")
+    }
+
+    pub(crate) fn write(&self, dest: &Path) -> Result<()> {
+        let path = dest.join(SHARD_MANIFEST);
+        std::fs::write(&path, self.to_json()?)
+            .with_context(|| format!("write shard manifest '{}'", path.display()))
+    }
+}
+
+/// Sharded location, relative to the destination root, of the archive entry `fullpath`: `levels`
+/// directories named after successive bytes of the SHA-256 of the path, then a file named after
+/// the full hash and the original file name so distinct entries never collide.
+pub fn shard_path(fullpath: impl AsRef<Path>, levels: u8) -> PathBuf {
+    let fullpath = fullpath.as_ref();
+    let hash = format!(
+        "{:x}",
+        Sha256::digest(fullpath.as_os_str().as_encoded_bytes())
+    );
+
+    let mut path: PathBuf = (0..usize::from(levels).min(hash.len() / 2))
+        .map(|level| &hash[level * 2..level * 2 + 2])
+        .collect();
+    let name = fullpath
+        .file_name()
+        .map(|name| format!("{hash}-{}", name.to_string_lossy()))
+        .unwrap_or(hash);
+    path.push(name);
+    path
+}
+
+/// Where `node` is extracted to under `root`, or `None` for directories of a sharded layout, which
+/// only exist implicitly as parents of the shard directories, and for entries outside of
+/// `subtree`. Recompressed files get the extension of their encoding.
+pub(crate) fn dest_path(
+    root: &Path,
+    node: &Node<SquashfsFileReader>,
+    levels: Option<u8>,
+    recompress: Option<Recompress>,
+    subtree: Option<&Path>,
+) -> Option<PathBuf> {
+    let path = &node.fullpath;
+    // the subtree is re-based onto the root, keeping its own name
+    let base = match subtree {
+        Some(subtree) if !path.starts_with(subtree) => return None,
+        Some(subtree) => subtree.parent().unwrap_or(subtree),
+        None => Path::new(Component::RootDir.as_os_str()),
+    };
+    let dest_path = match levels {
+        Some(_) if matches!(node.inner, InnerNode::Dir(_)) => return None,
+        Some(levels) => root.join(shard_path(path, levels)),
+        None => root.join(path.strip_prefix(base).unwrap_or(path)),
+    };
+    match node.inner {
+        InnerNode::File(_) => Some(recompressed_path(dest_path, recompress)),
+        _ => Some(dest_path),
+    }
+}
+
+/// The target to give the symlink `node` pointing to `link` when extracted with `levels`: the
+/// path from its shard to the shard of the entry `link` resolves to in `filesystem`. Targets
+/// outside of the archive, missing from it or naming a directory, which a sharded layout has no
+/// counterpart of, are kept as they are.
+pub(crate) fn link_target<'a>(
+    filesystem: &FilesystemReader<'_>,
+    node: &Node<SquashfsFileReader>,
+    link: &'a Path,
+    levels: Option<u8>,
+    recompress: Option<Recompress>,
+) -> Cow<'a, Path> {
+    let Some(levels) = levels else {
+        return Cow::Borrowed(link);
+    };
+    let target = resolve(&node.fullpath, link).and_then(|target| {
+        let nodes = &filesystem.root.nodes;
+        let i = nodes
+            .binary_search_by(|node| node.fullpath.as_path().cmp(&target))
+            .ok()?;
+        dest_path(Path::new(""), &nodes[i], Some(levels), recompress, None)
+    });
+    let Some(target) = target else {
+        tracing::debug!(
+            path = %node.fullpath.display(),
+            link = %link.display(),
+            "keeping symlink target outside of the shards",
+        );
+        return Cow::Borrowed(link);
+    };
+    // the symlink is in a shard as deep as those of every other entry
+    let depth = shard_path(&node.fullpath, levels).components().count() - 1;
+    let mut relative: PathBuf = std::iter::repeat_n(Component::ParentDir, depth).collect();
+    relative.push(target);
+    Cow::Owned(relative)
+}
+
+/// The absolute path in the archive `link` points to from the entry `fullpath`, or `None` if it
+/// leaves the archive.
+fn resolve(fullpath: &Path, link: &Path) -> Option<PathBuf> {
+    let mut resolved = PathBuf::from(Component::RootDir.as_os_str());
+    let base = match link.is_absolute() {
+        true => Path::new(""),
+        false => fullpath.parent()?,
+    };
+    for component in base.components().chain(link.components()) {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    Some(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, TestArchive},
+        ExtractOptions,
+    };
+
+    #[test]
+    fn resolves_links() {
+        let resolve = |fullpath: &str, link: &str| resolve(Path::new(fullpath), Path::new(link));
+        assert_eq!(resolve("/a/b", "c"), Some(PathBuf::from("/a/c")));
+        assert_eq!(resolve("/a/b", "../c/./d"), Some(PathBuf::from("/c/d")));
+        assert_eq!(resolve("/a/b", "/c"), Some(PathBuf::from("/c")));
+        assert_eq!(resolve("/a/b", "../../c"), None);
+    }
+
+    #[test]
+    fn links_between_shards() {
+        let archive = TestArchive::new(vec![
+            testing::dir("a"),
+            testing::file("a/file", "contents"),
+            testing::dir("b"),
+            testing::symlink("b/relative", "../a/file"),
+            testing::symlink("b/absolute", "/a/file"),
+            testing::symlink("b/dir", "../a"),
+            testing::symlink("b/outside", "../../etc/passwd"),
+        ]);
+        let dest = archive.scratch("dest");
+        let options = ExtractOptions {
+            shard_levels: Some(2),
+            ..ExtractOptions::default()
+        };
+        crate::unsquash_tpcii_blocking_with_options(archive.path(), &dest, None, options).unwrap();
+        let manifest = ShardManifest::load(&dest).unwrap();
+        let shard = |path: &str| dest.join(manifest.resolve(path).unwrap());
+        for link in ["/b/relative", "/b/absolute"] {
+            assert_eq!(std::fs::read(shard(link)).unwrap(), b"contents", "{link}");
+        }
+        assert_eq!(
+            std::fs::read_link(shard("/b/relative")).unwrap(),
+            Path::new("../..").join(shard_path("/a/file", 2))
+        );
+        assert_eq!(
+            std::fs::read_link(shard("/b/dir")).unwrap(),
+            Path::new("../a")
+        );
+        assert_eq!(
+            std::fs::read_link(shard("/b/outside")).unwrap(),
+            Path::new("../../etc/passwd")
+        );
+    }
+
+    fn assert_rebased(dest: &Path) {
+        assert_eq!(std::fs::read(dest.join("serde/a")).unwrap(), b"a");
+        assert_eq!(std::fs::read(dest.join("serde/b/c")).unwrap(), b"c");
+        assert_eq!(
+            std::fs::read_link(dest.join("serde/link")).unwrap(),
+            Path::new("b/c")
+        );
+        assert!(!dest.join("index").exists());
+        assert!(!dest.join("other").exists());
+        assert!(!dest.join("serde-derive").exists());
+    }
+
+    fn subtree_archive() -> TestArchive {
+        TestArchive::new(vec![
+            testing::file("index/serde/a", "a"),
+            testing::file("index/serde/b/c", "c"),
+            testing::symlink("index/serde/link", "b/c"),
+            testing::file("index/serde-derive/d", "d"),
+            testing::file("index/other", "other"),
+        ])
+    }
+
+    fn subtree_options() -> ExtractOptions {
+        ExtractOptions {
+            subtree: Some(PathBuf::from("/index/serde")),
+            ..ExtractOptions::default()
+        }
+    }
+
+    #[test]
+    fn rebases_subtrees() {
+        let archive = subtree_archive();
+        let dest = archive.scratch("dest");
+        crate::unsquash_blocking(archive.path(), &dest, Filter::All, subtree_options()).unwrap();
+        assert_rebased(&dest);
+    }
+
+    #[tokio::test]
+    async fn rebases_subtrees_async() {
+        let archive = subtree_archive();
+        let dest = archive.scratch("dest");
+        crate::unsquash_async(archive.path(), &dest, Filter::All, subtree_options())
+            .await
+            .unwrap();
+        assert_rebased(&dest);
+    }
+}
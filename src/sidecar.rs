@@ -0,0 +1,651 @@
+//! Sidecar indexes holding the parsed entries and tables of an archive, so that opening it again
+//! does not parse its inode and directory tables.
+//!
+//! backhand only builds a [`FilesystemReader`] by parsing an archive, so an archive is opened
+//! from an index by parsing an [`Image`] of it whose superblock points at the tables of an empty
+//! root directory, stored past its end, then setting the entries and tables of the index on the
+//! result; see [`assemble`], which also rebuilds archives opened with
+//! [`compact_paths`](crate::IoOptions::compact_paths). Blocks are read from the archive
+//! itself.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use backhand::{
+    BasicFile, BufReadSeek, DataSize, FilesystemReader, Fragment, Id, InnerNode, Node, NodeHeader,
+    Squashfs, SquashfsBlockDevice, SquashfsCharacterDevice, SquashfsDir, SquashfsFileReader,
+    SquashfsSymlink, SuperBlock,
+};
+use sha2::{Digest as _, Sha256};
+
+use crate::{
+    compression::{CompressionOptions, Kind},
+    counters::{Counters, Counting},
+    mechanisms,
+    parsing::{COMPRESSOR_OPTIONS_PRESENT, KNOWN_FLAGS},
+    platform,
+    source::{self, SourceReader, SquashSource, IDENTITY_LEN},
+};
+
+const MAGIC: &[u8; 8] = b"bhaidx\0\x02";
+/// Size of the squashfs 4.0 superblock.
+const SUPERBLOCK_LEN: usize = 96;
+/// Length of the pieces the tables of the archive are hashed in.
+const PIECE_LEN: u64 = 4 << 20;
+/// The flag of [`DataSize`] marking a block stored uncompressed, the bit above the largest size.
+const UNCOMPRESSED: u32 = 1 << 24;
+/// The flag of the header of a metadata block stored uncompressed.
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+const NOT_SET: u64 = u64::MAX;
+
+/// What an index was saved from, read from its start.
+struct Origin<'a> {
+    /// The superblock of the archive, as stored in it.
+    superblock: &'a [u8],
+    /// The [identity](source::identity) of the file of the archive, zeroed without one.
+    identity: &'a [u8],
+    /// The SHA-256 of the tables of the archive, computed when the index was saved.
+    tables: &'a [u8],
+}
+
+impl Origin<'_> {
+    /// Whether the index was saved from `source`, whose superblock is `superblock`: the
+    /// superblocks must be the same, then either the file or the tables, which are only hashed
+    /// again when the file is another one, such as a copy.
+    fn matches(&self, source: &dyn SquashSource, superblock: &SuperBlock) -> Result<bool> {
+        let mut current = [0; SUPERBLOCK_LEN];
+        source::read_exact_at(source, &mut current, 0)
+            .with_context(|| format!("read superblock of '{}'", source.name()))?;
+        if self.superblock != current {
+            return Ok(false);
+        }
+        if source::identity(source)?.is_some_and(|identity| self.identity == identity) {
+            return Ok(true);
+        }
+        Ok(self.tables == tables_digest(source, superblock)?)
+    }
+}
+
+/// The SHA-256 of the tables of the archive read by `source`, whose superblock is `superblock`,
+/// i.e. of everything parsing it reads past the superblock.
+fn tables_digest(source: &dyn SquashSource, superblock: &SuperBlock) -> Result<[u8; 32]> {
+    let name = source.name();
+    let mut offset = superblock.inode_table;
+    anyhow::ensure!(
+        offset <= superblock.bytes_used,
+        "inode table of '{name}' starts past its end"
+    );
+    let mut hasher = Sha256::new();
+    let mut piece = Vec::new();
+    while offset < superblock.bytes_used {
+        piece.resize(PIECE_LEN.min(superblock.bytes_used - offset) as usize, 0);
+        source::read_exact_at(source, &mut piece, offset)
+            .with_context(|| format!("read '{name}' at {offset}"))?;
+        hasher.update(&piece);
+        offset += piece.len() as u64;
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// The superblock of `source` as `kind`, with its compression options.
+fn superblock(
+    source: &Arc<dyn SquashSource>,
+    kind: &Kind,
+) -> Result<(SuperBlock, Option<CompressionOptions>)> {
+    let mut reader: Box<dyn BufReadSeek> =
+        Box::new(BufReader::new(SourceReader::new(Arc::clone(source))));
+    Squashfs::superblock_and_compression_options(&mut reader, kind)
+        .with_context(|| format!("parse superblock of '{}'", source.name()))
+}
+
+/// Save the entries and tables of `filesystem`, parsed from `source` as `kind`, to the index at
+/// `path`, replacing it atomically.
+pub(crate) fn save(
+    source: &Arc<dyn SquashSource>,
+    kind: &Kind,
+    filesystem: &FilesystemReader<'_>,
+    path: &Path,
+) -> Result<()> {
+    let name = source.name();
+    let (parsed, _) = superblock(source, kind)?;
+    let mut superblock = [0; SUPERBLOCK_LEN];
+    source::read_exact_at(&**source, &mut superblock, 0)
+        .with_context(|| format!("read superblock of '{name}'"))?;
+    let identity = source::identity(&**source)
+        .with_context(|| format!("stat '{name}'"))?
+        .unwrap_or([0; IDENTITY_LEN]);
+    let tables = tables_digest(&**source, &parsed)?;
+    let tmp = mechanisms::tmp_path(path);
+    let written = File::create(&tmp)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| {
+            let mut out = BufWriter::new(file);
+            out.write_all(MAGIC)?;
+            out.write_all(&superblock)?;
+            out.write_all(&identity)?;
+            out.write_all(&tables)?;
+            encode(filesystem, &mut out)?;
+            out.into_inner().map_err(io::IntoInnerError::into_error)?;
+            Ok(())
+        })
+        .and_then(|()| std::fs::rename(&tmp, path).map_err(Into::into));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    written.with_context(|| format!("write index '{}'", path.display()))
+}
+
+/// Open `source` as `kind` from the index at `path`, or `None` if there is none or it was saved
+/// from another archive. Archives deviating from the spec are not opened from an index, for
+/// [`Parsing`](crate::Parsing) to apply to them. Reads of the archive are counted into `counters`.
+pub(crate) fn load(
+    source: Arc<dyn SquashSource>,
+    kind: Kind,
+    path: &Path,
+    counters: &Arc<Counters>,
+) -> Result<Option<FilesystemReader<'static>>> {
+    let index = match std::fs::read(path) {
+        Ok(index) => index,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("read index '{}'", path.display())),
+    };
+    let (superblock, compression_options) = superblock(&source, &kind)?;
+    if superblock.flags & !KNOWN_FLAGS != 0
+        || (superblock.flags & COMPRESSOR_OPTIONS_PRESENT != 0 && compression_options.is_none())
+    {
+        return Ok(None);
+    }
+    let mut decoder = Decoder(&index);
+    if decoder.take(MAGIC.len())? != MAGIC {
+        return Ok(None);
+    }
+    let origin = Origin {
+        superblock: decoder.take(SUPERBLOCK_LEN)?,
+        identity: decoder.take(IDENTITY_LEN)?,
+        tables: decoder.take(32)?,
+    };
+    if !origin.matches(&*source, &superblock)? {
+        return Ok(None);
+    }
+    let (id_table, fragments, nodes) =
+        decode(&mut decoder).with_context(|| format!("decode index '{}'", path.display()))?;
+    assemble(source, kind, counters, id_table, fragments, nodes).map(Some)
+}
+
+/// A [`FilesystemReader`] reading `source` as `kind`, with the given entries and tables rather
+/// than ones parsed from it, counting its reads into `counters`.
+pub(crate) fn assemble(
+    source: Arc<dyn SquashSource>,
+    kind: Kind,
+    counters: &Arc<Counters>,
+    id_table: Vec<Id>,
+    fragments: Option<Vec<Fragment>>,
+    nodes: Vec<Node<SquashfsFileReader>>,
+) -> Result<FilesystemReader<'static>> {
+    let (superblock, compression_options) = superblock(&source, &kind)?;
+    let name = source.name();
+    let image =
+        Image::new(source, counters, &superblock).with_context(|| format!("stat '{name}'"))?;
+    let mut filesystem = Squashfs::from_reader(BufReader::new(image))
+        .and_then(Squashfs::into_filesystem_reader)
+        .with_context(|| format!("parse image of '{name}'"))?;
+    filesystem.kind = kind;
+    filesystem.compression_options = compression_options;
+    filesystem.id_table = id_table;
+    filesystem.fragments = fragments;
+    filesystem.root.nodes = nodes;
+    Ok(filesystem)
+}
+
+/// An archive as parsed when it is opened from an index: its superblock is replaced by one with
+/// its block size, compressor and modification time, pointing at the tables of an empty root
+/// directory, stored uncompressed past its end. Everything else is read from the archive, in the
+/// default [`Kind`] whatever the kind of the archive, which only its tables tell.
+struct Image {
+    superblock: [u8; SUPERBLOCK_LEN],
+    tables: Vec<u8>,
+    /// Where the tables start: the end of the archive.
+    tables_at: u64,
+    archive: Counting<SourceReader>,
+    pos: u64,
+}
+
+impl Image {
+    fn new(
+        source: Arc<dyn SquashSource>,
+        counters: &Arc<Counters>,
+        archive: &SuperBlock,
+    ) -> io::Result<Self> {
+        let tables_at = source.size()?;
+        let block = |data: &[u8]| {
+            let header = data.len() as u16 | METADATA_UNCOMPRESSED;
+            [&header.to_le_bytes()[..], data].concat()
+        };
+        // a basic directory inode numbered 1 and owned by id 0, listing nothing: its size counts
+        // the implicit . and .. entries only
+        let root = [
+            &1u16.to_le_bytes()[..],
+            &0o755u16.to_le_bytes(),
+            &[0; 8],
+            &1u32.to_le_bytes(),
+            &0u32.to_le_bytes(),
+            &2u32.to_le_bytes(),
+            &3u16.to_le_bytes(),
+            &0u16.to_le_bytes(),
+            &2u32.to_le_bytes(),
+        ]
+        .concat();
+        let mut tables = block(&root);
+        // the directory table is empty, ending where the id table starts
+        let dir_table = tables_at + tables.len() as u64;
+        tables.extend(block(&0u32.to_le_bytes()));
+        let id_table = tables_at + tables.len() as u64;
+        tables.extend(dir_table.to_le_bytes());
+
+        let mut superblock = [0; SUPERBLOCK_LEN];
+        let mut put =
+            |at: usize, field: &[u8]| superblock[at..at + field.len()].copy_from_slice(field);
+        put(0, b"hsqs");
+        put(4, &1u32.to_le_bytes());
+        put(8, &archive.mod_time.to_le_bytes());
+        put(12, &archive.block_size.to_le_bytes());
+        put(20, &(archive.compressor as u16).to_le_bytes());
+        put(22, &archive.block_log.to_le_bytes());
+        put(26, &1u16.to_le_bytes());
+        put(28, &4u16.to_le_bytes());
+        put(40, &(tables_at + tables.len() as u64).to_le_bytes());
+        put(48, &id_table.to_le_bytes());
+        put(56, &NOT_SET.to_le_bytes());
+        put(64, &tables_at.to_le_bytes());
+        put(72, &dir_table.to_le_bytes());
+        put(80, &NOT_SET.to_le_bytes());
+        put(88, &NOT_SET.to_le_bytes());
+        Ok(Self {
+            superblock,
+            tables,
+            tables_at,
+            archive: Counting(SourceReader::new(source), Arc::clone(counters)),
+            pos: 0,
+        })
+    }
+}
+
+impl Read for Image {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.pos {
+            pos if pos < SUPERBLOCK_LEN as u64 => {
+                let superblock = &self.superblock[pos as usize..];
+                let n = buf.len().min(superblock.len());
+                buf[..n].copy_from_slice(&superblock[..n]);
+                n
+            }
+            pos if pos >= self.tables_at => {
+                let start = usize::try_from(pos - self.tables_at)
+                    .unwrap_or(usize::MAX)
+                    .min(self.tables.len());
+                let tables = &self.tables[start..];
+                let n = buf.len().min(tables.len());
+                buf[..n].copy_from_slice(&tables[..n]);
+                n
+            }
+            pos => {
+                let len = buf.len().min((self.tables_at - pos) as usize);
+                self.archive.seek(SeekFrom::Start(pos))?;
+                self.archive.read(&mut buf[..len])?
+            }
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Image {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (self.pos, delta),
+            SeekFrom::End(delta) => (self.tables_at + self.tables.len() as u64, delta),
+        };
+        self.pos = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+fn encode(filesystem: &FilesystemReader<'_>, out: &mut impl Write) -> io::Result<()> {
+    let len = |len: usize| u32::try_from(len).map_err(|_| io::ErrorKind::InvalidInput);
+    let bytes = |out: &mut dyn Write, bytes: &[u8]| {
+        out.write_all(&len(bytes.len())?.to_le_bytes())?;
+        out.write_all(bytes)
+    };
+    out.write_all(&len(filesystem.id_table.len())?.to_le_bytes())?;
+    for id in &filesystem.id_table {
+        out.write_all(&id.num.to_le_bytes())?;
+    }
+    match &filesystem.fragments {
+        Some(fragments) => {
+            out.write_all(&len(fragments.len())?.to_le_bytes())?;
+            for fragment in fragments {
+                out.write_all(&fragment.start.to_le_bytes())?;
+                out.write_all(&data_size(fragment.size).to_le_bytes())?;
+                out.write_all(&fragment.unused.to_le_bytes())?;
+            }
+        }
+        None => out.write_all(&u32::MAX.to_le_bytes())?,
+    }
+
+    let nodes = &filesystem.root.nodes;
+    out.write_all(&(nodes.len() as u64).to_le_bytes())?;
+    let mut previous: &[u8] = &[];
+    for node in nodes {
+        // nodes are sorted by path, so consecutive paths share long prefixes
+        let path = node.fullpath.as_os_str().as_encoded_bytes();
+        let shared = path
+            .iter()
+            .zip(previous)
+            .take_while(|(a, b)| a == b)
+            .count();
+        out.write_all(&len(shared)?.to_le_bytes())?;
+        bytes(out, &path[shared..])?;
+        previous = path;
+
+        let header = &node.header;
+        out.write_all(&header.permissions.to_le_bytes())?;
+        out.write_all(&header.uid.to_le_bytes())?;
+        out.write_all(&header.gid.to_le_bytes())?;
+        out.write_all(&header.mtime.to_le_bytes())?;
+        match &node.inner {
+            InnerNode::File(SquashfsFileReader { basic }) => {
+                out.write_all(&[0])?;
+                for field in [
+                    basic.blocks_start,
+                    basic.frag_index,
+                    basic.block_offset,
+                    basic.file_size,
+                    len(basic.block_sizes.len())?,
+                ] {
+                    out.write_all(&field.to_le_bytes())?;
+                }
+                for size in &basic.block_sizes {
+                    out.write_all(&data_size(*size).to_le_bytes())?;
+                }
+            }
+            InnerNode::Symlink(SquashfsSymlink { link }) => {
+                out.write_all(&[1])?;
+                bytes(out, link.as_os_str().as_encoded_bytes())?;
+            }
+            InnerNode::Dir(_) => out.write_all(&[2])?,
+            InnerNode::CharacterDevice(SquashfsCharacterDevice { device_number }) => {
+                out.write_all(&[3])?;
+                out.write_all(&device_number.to_le_bytes())?;
+            }
+            InnerNode::BlockDevice(SquashfsBlockDevice { device_number }) => {
+                out.write_all(&[4])?;
+                out.write_all(&device_number.to_le_bytes())?;
+            }
+            InnerNode::NamedPipe => out.write_all(&[5])?,
+            InnerNode::Socket => out.write_all(&[6])?,
+        }
+    }
+    Ok(())
+}
+
+type Decoded = (
+    Vec<Id>,
+    Option<Vec<Fragment>>,
+    Vec<Node<SquashfsFileReader>>,
+);
+
+fn decode(decoder: &mut Decoder<'_>) -> Result<Decoded> {
+    let ids = decoder.u32()?;
+    let id_table = (0..ids)
+        .map(|_| decoder.u32().map(Id::new))
+        .collect::<Result<_>>()?;
+    let fragments = match decoder.u32()? {
+        u32::MAX => None,
+        fragments => Some(
+            (0..fragments)
+                .map(|_| {
+                    let start = decoder.u64()?;
+                    let size = decoder.data_size()?;
+                    Ok(Fragment::new(start, size, decoder.u32()?))
+                })
+                .collect::<Result<_>>()?,
+        ),
+    };
+
+    let len = decoder.u64()?;
+    let mut nodes = Vec::new();
+    let mut path = Vec::new();
+    for _ in 0..len {
+        let shared = decoder.u32()? as usize;
+        anyhow::ensure!(
+            shared <= path.len(),
+            "path shares more than the previous one"
+        );
+        path.truncate(shared);
+        let suffix = decoder.bytes()?;
+        path.extend_from_slice(suffix);
+        let fullpath = PathBuf::from(platform::os_string_from_vec(path.clone()));
+        let header = NodeHeader::new(
+            decoder.u16()?,
+            decoder.u32()?,
+            decoder.u32()?,
+            decoder.u32()?,
+        );
+        let inner = match decoder.take(1)?[0] {
+            0 => {
+                let (blocks_start, frag_index, block_offset, file_size) = (
+                    decoder.u32()?,
+                    decoder.u32()?,
+                    decoder.u32()?,
+                    decoder.u32()?,
+                );
+                let blocks = decoder.u32()?;
+                let block_sizes = (0..blocks)
+                    .map(|_| decoder.data_size())
+                    .collect::<Result<_>>()?;
+                InnerNode::File(SquashfsFileReader {
+                    basic: BasicFile {
+                        blocks_start,
+                        frag_index,
+                        block_offset,
+                        file_size,
+                        block_sizes,
+                    },
+                })
+            }
+            1 => InnerNode::Symlink(SquashfsSymlink {
+                link: platform::os_string_from_vec(decoder.bytes()?.to_vec()).into(),
+            }),
+            2 => InnerNode::Dir(SquashfsDir::default()),
+            3 => InnerNode::CharacterDevice(SquashfsCharacterDevice {
+                device_number: decoder.u32()?,
+            }),
+            4 => InnerNode::BlockDevice(SquashfsBlockDevice {
+                device_number: decoder.u32()?,
+            }),
+            5 => InnerNode::NamedPipe,
+            6 => InnerNode::Socket,
+            kind => anyhow::bail!("unknown entry kind {kind}"),
+        };
+        nodes.push(Node {
+            fullpath,
+            header,
+            inner,
+        });
+    }
+    anyhow::ensure!(decoder.0.is_empty(), "trailing bytes");
+    anyhow::ensure!(
+        nodes
+            .first()
+            .is_some_and(|root| root.fullpath == Path::new("/")),
+        "no root directory"
+    );
+    Ok((id_table, fragments, nodes))
+}
+
+fn data_size(size: DataSize) -> u32 {
+    match size.uncompressed() {
+        true => size.size() | UNCOMPRESSED,
+        false => size.size(),
+    }
+}
+
+/// Reads the fields of an index in order.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        anyhow::ensure!(len <= self.0.len(), "truncated");
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+
+    fn data_size(&mut self) -> Result<DataSize> {
+        let size = self.u32()?;
+        anyhow::ensure!(
+            size & !(UNCOMPRESSED | (UNCOMPRESSED - 1)) == 0,
+            "bad block size"
+        );
+        Ok(DataSize::new(
+            size & !UNCOMPRESSED,
+            size & UNCOMPRESSED != 0,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, TestArchive},
+        Archive, ExtractOptions, IoOptions,
+    };
+
+    fn open(archive: &TestArchive, index: &Path) -> Archive {
+        let options = ExtractOptions {
+            io: IoOptions {
+                index: Some(index.to_path_buf()),
+                ..IoOptions::default()
+            },
+            ..Default::default()
+        };
+        Archive::open(archive.path(), options).unwrap()
+    }
+
+    #[test]
+    fn opens_from_index() {
+        let large: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+        let archive = TestArchive::new(vec![
+            testing::file("a/f", "small"),
+            testing::file("a/large", large.clone()),
+            testing::symlink("a/l", "f"),
+            testing::dir("empty"),
+        ]);
+        let index = archive.scratch("index");
+        let parsed = open(&archive, &index);
+        assert!(index.exists());
+        let indexed = open(&archive, &index);
+
+        let paths = |archive: &Archive| archive.list().map(|entry| entry.path).collect::<Vec<_>>();
+        assert_eq!(paths(&indexed), paths(&parsed));
+        assert_eq!(indexed.read_file_blocking("/a/f").unwrap(), b"small");
+        assert_eq!(indexed.read_file_blocking("/a/large").unwrap(), large);
+        let dest = archive.scratch("dest");
+        indexed
+            .extract_blocking(&dest, crate::Filter::All, ExtractOptions::default())
+            .unwrap();
+        assert_eq!(std::fs::read(dest.join("a/large")).unwrap(), large);
+        assert_eq!(
+            std::fs::read_link(dest.join("a/l")).unwrap(),
+            Path::new("f")
+        );
+    }
+
+    #[test]
+    fn ignores_index_of_other_archive() {
+        let (first, second) = (
+            TestArchive::new(vec![testing::file("f", "first")]),
+            TestArchive::new(vec![testing::file("g", "second")]),
+        );
+        let index = first.scratch("index");
+        open(&first, &index);
+        let archive = open(&second, &index);
+        assert_eq!(archive.read_file_blocking("/g").unwrap(), b"second");
+        // and the index is replaced with one of the archive it was used for
+        let source = crate::source::FileSource::open(second.path()).unwrap();
+        let kind = crate::format::resolve_kind(&source, None).unwrap();
+        assert!(load(Arc::new(source), kind, &index, &Arc::default())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn hashes_tables_of_other_files_only() {
+        let archive = TestArchive::new(vec![testing::file("f", "contents")]);
+        let index = archive.scratch("index");
+        open(&archive, &index);
+        // the hash of the tables is only checked for another file than the index was saved from
+        let mut bytes = std::fs::read(&index).unwrap();
+        let tables = MAGIC.len() + SUPERBLOCK_LEN + IDENTITY_LEN;
+        bytes[tables] ^= 1;
+        std::fs::write(&index, &bytes).unwrap();
+        let copy = archive.scratch("copy.squashfs");
+        std::fs::copy(archive.path(), &copy).unwrap();
+        let load_from = |path: &Path| {
+            let source = crate::source::FileSource::open(path).unwrap();
+            let kind = crate::format::resolve_kind(&source, None).unwrap();
+            load(Arc::new(source), kind, &index, &Arc::default()).unwrap()
+        };
+        assert!(load_from(archive.path()).is_some());
+        assert!(load_from(&copy).is_none());
+        bytes[tables] ^= 1;
+        std::fs::write(&index, &bytes).unwrap();
+        let filesystem = load_from(&copy).unwrap();
+        assert_eq!(filesystem.root.nodes[1].fullpath, Path::new("/f"));
+    }
+
+    #[test]
+    fn rejects_corrupt_index() {
+        let archive = TestArchive::new(vec![testing::file("f", "contents")]);
+        let index = archive.scratch("index");
+        open(&archive, &index);
+        let mut bytes = std::fs::read(&index).unwrap();
+        bytes.truncate(bytes.len() - 3);
+        std::fs::write(&index, bytes).unwrap();
+        let source = crate::source::FileSource::open(archive.path()).unwrap();
+        let kind = crate::format::resolve_kind(&source, None).unwrap();
+        assert!(load(Arc::new(source), kind, &index, &Arc::default()).is_err());
+        // opening falls back to parsing the archive
+        let archive = open(&archive, &index);
+        assert_eq!(archive.read_file_blocking("/f").unwrap(), b"contents");
+    }
+}
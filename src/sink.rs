@@ -0,0 +1,17 @@
+//! Writing the contents of archives somewhere else than a directory.
+
+#[cfg(feature = "object-store")]
+pub use crate::object_store_dest::{unsquash_to_object_store, ObjectStoreDest};
+#[cfg(feature = "tar")]
+pub use crate::to_tar::{to_tar_async, to_tar_blocking};
+pub use crate::{
+    cpio::unsquash_tpcii_to_cpio,
+    dest_fs::{unsquash_to_fs, DestFile, DestFs, MemoryEntry, MemoryFs, OsFs},
+    erofs::unsquash_tpcii_to_erofs,
+    read::{read_file_async, read_file_blocking, read_file_to},
+    squash::{
+        squash_async, squash_blocking, squash_entries_async, squash_entries_blocking,
+        SquashOptions, SquashReport,
+    },
+    transcode::transcode,
+};
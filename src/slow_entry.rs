@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use backhand::{InnerNode, Node, SquashfsFileReader};
+
+/// Log extracting `node` as slow if it took `elapsed`, no shorter than `threshold`; see
+/// [`slow_entry_threshold`](crate::ObservabilityOptions::slow_entry_threshold).
+pub(crate) fn warn_if_slow(
+    node: &Node<SquashfsFileReader>,
+    elapsed: Duration,
+    threshold: Duration,
+) {
+    if elapsed < threshold {
+        return;
+    }
+
+    let (size, blocks, fragment) = match &node.inner {
+        InnerNode::File(file) => (
+            u64::from(file.basic.file_size),
+            file.basic.block_sizes.len(),
+            file.basic.frag_index != u32::MAX,
+        ),
+        _ => (0, 0, false),
+    };
+
+    tracing::warn!(
+        path = %node.fullpath.display(),
+        size,
+        blocks,
+        fragment,
+        elapsed_ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+        threshold_ms = u64::try_from(threshold.as_millis()).unwrap_or(u64::MAX),
+        "slow squashfs entry extraction",
+    );
+}
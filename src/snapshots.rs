@@ -0,0 +1,105 @@
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use backhand::{BufReadSeek, Squashfs};
+
+use crate::{
+    format,
+    source::{FileSource, SourceReader, SquashSource},
+    ExtractOptions, ExtractReport, Filter, FormatError,
+};
+
+/// A squashfs archive in a directory of snapshots, dated by the build time in its superblock.
+/// Archives built with a pinned mkfs time (e.g. from `SOURCE_DATE_EPOCH`) all carry that date.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub created: SystemTime,
+}
+
+/// The squashfs archives directly in `dir`, oldest first. Other files are skipped.
+pub fn list_snapshots(dir: impl AsRef<Path>) -> Result<Vec<Snapshot>> {
+    let dir = dir.as_ref();
+    let mut snapshots = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("read snapshot dir '{}'", dir.display()))?;
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("read snapshot dir '{}'", dir.display()))?
+            .path();
+        if !path.is_file() {
+            continue;
+        }
+        match created(&path) {
+            Ok(created) => snapshots.push(Snapshot { path, created }),
+            Err(e) if e.is::<FormatError>() => {
+                tracing::debug!(path = %path.display(), "skipping non-squashfs file: {e}");
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    snapshots.sort_by(|a, b| (a.created, &a.path).cmp(&(b.created, &b.path)));
+    Ok(snapshots)
+}
+
+/// The newest snapshot in `dir` built at or before `as_of`.
+pub fn snapshot_as_of(dir: impl AsRef<Path>, as_of: SystemTime) -> Result<Snapshot> {
+    let dir = dir.as_ref();
+    list_snapshots(dir)?
+        .into_iter()
+        .rev()
+        .find(|snapshot| snapshot.created <= as_of)
+        .with_context(|| {
+            let secs = as_of
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            format!("no snapshot in '{}' as of {secs}", dir.display())
+        })
+}
+
+/// Extract the entries selected by `filter` from the snapshot in `dir` that was current at
+/// `as_of`, to reproduce the index as it was then.
+pub fn extract_as_of(
+    dir: impl AsRef<Path>,
+    as_of: SystemTime,
+    filter: Filter,
+    dest: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<(Snapshot, ExtractReport)> {
+    let snapshot = snapshot_as_of(dir, as_of)?;
+    tracing::info!(snapshot = %snapshot.path.display(), "extracting snapshot");
+    let report = crate::unsquash_blocking(&snapshot.path, dest, filter, options)?;
+    Ok((snapshot, report))
+}
+
+/// Async flavor of [`extract_as_of`].
+pub async fn extract_as_of_async(
+    dir: impl AsRef<Path>,
+    as_of: SystemTime,
+    filter: Filter,
+    dest: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<(Snapshot, ExtractReport)> {
+    let dir = dir.as_ref().to_path_buf();
+    let snapshot = tokio::task::spawn_blocking(move || snapshot_as_of(dir, as_of))
+        .await
+        .context("spawn blocking snapshot listing task")??;
+    tracing::info!(snapshot = %snapshot.path.display(), "extracting snapshot");
+    let report = crate::unsquash_async(&snapshot.path, dest, filter, options).await?;
+    Ok((snapshot, report))
+}
+
+fn created(path: &Path) -> Result<SystemTime> {
+    let source: Arc<dyn SquashSource> = Arc::new(FileSource::open(path)?);
+    let kind = format::resolve_kind(&*source, None)?;
+    let mut reader: Box<dyn BufReadSeek> = Box::new(BufReader::new(SourceReader::new(source)));
+    let (superblock, _) = Squashfs::superblock_and_compression_options(&mut reader, &kind)
+        .with_context(|| format!("parse superblock of '{}'", path.display()))?;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(superblock.mod_time.into()))
+}
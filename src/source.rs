@@ -0,0 +1,1056 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    future::Future,
+    io::{self, IoSliceMut, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt},
+    runtime::{Handle, RuntimeFlavor},
+};
+
+use crate::{cache::CacheStats, platform};
+
+/// Random-access storage holding a squashfs archive.
+pub trait SquashSource: Send + Sync {
+    /// Read up to `buf.len()` bytes at `offset`, returning how many were read (0 at the end).
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Scatter a read at `offset` across `bufs`. Like [`read_at`](Self::read_at), this may read
+    /// less than requested; the default reads into the first non-empty buffer only.
+    fn read_vectored_at(&self, bufs: &mut [IoSliceMut<'_>], offset: u64) -> io::Result<usize> {
+        match bufs.iter_mut().find(|buf| !buf.is_empty()) {
+            Some(buf) => self.read_at(buf, offset),
+            None => Ok(0),
+        }
+    }
+
+    fn size(&self) -> io::Result<u64>;
+
+    /// Hint that the `len` bytes at `offset` will be read soon. Sources that can warm a cache for
+    /// them should start doing so without waiting for it; the default does nothing.
+    fn prefetch(&self, offset: u64, len: u64) -> io::Result<()> {
+        let _ = (offset, len);
+        Ok(())
+    }
+
+    /// Bytes of the archive the source holds in memory to serve later reads; the default holds
+    /// none.
+    fn cached_bytes(&self) -> u64 {
+        0
+    }
+
+    /// Drop what the source holds in memory to serve later reads.
+    fn trim(&self) {}
+
+    /// Reads served from what the source holds in memory, and the others; none by default.
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+
+    /// Whether the archive was modified since the source was opened, making what was read from
+    /// it unreliable. Sources that cannot tell report `false`, the default.
+    fn changed(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// The local file holding the archive, which extractors copy data stored uncompressed from
+    /// without going through userspace. Sources that are not a plain local file return `None`,
+    /// the default.
+    fn file(&self) -> Option<&File> {
+        None
+    }
+
+    /// Name of the source used in errors and logs.
+    fn name(&self) -> String {
+        String::from("<source>")
+    }
+}
+
+/// Asynchronous flavor of [`SquashSource`], for remote storage. Extractors take a
+/// [`SquashSource`], so wrap these in [`BlockOn`].
+pub trait AsyncSquashSource: Send + Sync {
+    fn read_at(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    fn size(&self) -> impl Future<Output = io::Result<u64>> + Send;
+
+    fn name(&self) -> String {
+        String::from("<source>")
+    }
+}
+
+impl<S: SquashSource + ?Sized> SquashSource for Arc<S> {
+    fn prefetch(&self, offset: u64, len: u64) -> io::Result<()> {
+        (**self).prefetch(offset, len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn read_vectored_at(&self, bufs: &mut [IoSliceMut<'_>], offset: u64) -> io::Result<usize> {
+        (**self).read_vectored_at(bufs, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn cached_bytes(&self) -> u64 {
+        (**self).cached_bytes()
+    }
+
+    fn trim(&self) {
+        (**self).trim()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        (**self).cache_stats()
+    }
+
+    fn changed(&self) -> io::Result<bool> {
+        (**self).changed()
+    }
+
+    fn file(&self) -> Option<&File> {
+        (**self).file()
+    }
+
+    fn name(&self) -> String {
+        (**self).name()
+    }
+}
+
+impl<S: SquashSource + ?Sized> SquashSource for Box<S> {
+    fn prefetch(&self, offset: u64, len: u64) -> io::Result<()> {
+        (**self).prefetch(offset, len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn read_vectored_at(&self, bufs: &mut [IoSliceMut<'_>], offset: u64) -> io::Result<usize> {
+        (**self).read_vectored_at(bufs, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn cached_bytes(&self) -> u64 {
+        (**self).cached_bytes()
+    }
+
+    fn trim(&self) {
+        (**self).trim()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        (**self).cache_stats()
+    }
+
+    fn changed(&self) -> io::Result<bool> {
+        (**self).changed()
+    }
+
+    fn file(&self) -> Option<&File> {
+        (**self).file()
+    }
+
+    fn name(&self) -> String {
+        (**self).name()
+    }
+}
+
+impl SquashSource for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        platform::read_at(self, buf, offset)
+    }
+
+    fn read_vectored_at(&self, bufs: &mut [IoSliceMut<'_>], offset: u64) -> io::Result<usize> {
+        preadv(self, bufs, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.metadata().map(|metadata| metadata.len())
+    }
+
+    fn prefetch(&self, offset: u64, len: u64) -> io::Result<()> {
+        fadvise_willneed(self, offset, len)
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn fadvise_willneed(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+    use std::os::fd::AsRawFd;
+
+    let out_of_range = |_| io::Error::new(io::ErrorKind::InvalidInput, "range out of bounds");
+    let offset = i64::try_from(offset).map_err(out_of_range)?;
+    let len = i64::try_from(len).map_err(out_of_range)?;
+    Ok(posix_fadvise(
+        file.as_raw_fd(),
+        offset,
+        len,
+        PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+    )?)
+}
+
+/// Prefetching is only a hint, which is not given elsewhere.
+#[cfg(not(target_os = "linux"))]
+fn fadvise_willneed(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn preadv(file: &File, bufs: &mut [IoSliceMut<'_>], offset: u64) -> io::Result<usize> {
+    let offset = i64::try_from(offset)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset out of range"))?;
+    Ok(nix::sys::uio::preadv(file, bufs, offset)?)
+}
+
+/// Reads into the first non-empty buffer only, like [`SquashSource::read_vectored_at`].
+#[cfg(not(target_os = "linux"))]
+fn preadv(file: &File, bufs: &mut [IoSliceMut<'_>], offset: u64) -> io::Result<usize> {
+    match bufs.iter_mut().find(|buf| !buf.is_empty()) {
+        Some(buf) => platform::read_at(file, buf, offset),
+        None => Ok(0),
+    }
+}
+
+/// Alignment of offsets, lengths and buffers for `O_DIRECT` reads. Covers the logical block size of
+/// practically every device.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// Read `bufs` at `offset` from a file opened with `O_DIRECT`, through an aligned bounce buffer.
+fn pread_direct(file: &File, bufs: &mut [IoSliceMut<'_>], offset: u64) -> io::Result<usize> {
+    let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+    if len == 0 {
+        return Ok(0);
+    }
+    let start = offset - offset % DIRECT_IO_ALIGN as u64;
+    let skip = (offset - start) as usize;
+    let span = (skip + len).next_multiple_of(DIRECT_IO_ALIGN);
+    let mut bounce = vec![0; span + DIRECT_IO_ALIGN];
+    let pad = bounce.as_ptr().align_offset(DIRECT_IO_ALIGN);
+    let aligned = &mut bounce[pad..pad + span];
+    let n = platform::read_at(file, aligned, start)?;
+
+    let mut data = aligned[..n].get(skip..).unwrap_or_default();
+    let mut read = 0;
+    for buf in bufs {
+        let k = buf.len().min(data.len());
+        buf[..k].copy_from_slice(&data[..k]);
+        data = &data[k..];
+        read += k;
+    }
+    Ok(read)
+}
+
+/// What identifies the contents of a local file: it is modified in place if any of it changes.
+/// Replacing the file by renaming another over it leaves the open one as it was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Identity {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl Identity {
+    #[cfg(unix)]
+    fn of(file: &File) -> io::Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = file.metadata()?;
+        Ok(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.size(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        })
+    }
+
+    /// Without inode numbers nor ctimes, only the size and mtime tell changes apart.
+    #[cfg(not(unix))]
+    fn of(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            dev: 0,
+            ino: 0,
+            size: metadata.len(),
+            mtime: (mtime.as_secs() as i64, i64::from(mtime.subsec_nanos())),
+            ctime: (0, 0),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; IDENTITY_LEN] {
+        let fields = [
+            self.dev,
+            self.ino,
+            self.size,
+            self.mtime.0 as u64,
+            self.mtime.1 as u64,
+            self.ctime.0 as u64,
+            self.ctime.1 as u64,
+        ];
+        let mut bytes = [0; IDENTITY_LEN];
+        for (bytes, field) in bytes.chunks_exact_mut(8).zip(fields) {
+            bytes.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Length of the [`identity`] of a source.
+pub(crate) const IDENTITY_LEN: usize = 56;
+
+/// What identifies the contents of the local file `source` reads, if it reads one: it differs
+/// once the file is modified in place, and for a copy of it.
+pub(crate) fn identity(source: &dyn SquashSource) -> io::Result<Option<[u8; IDENTITY_LEN]>> {
+    source
+        .file()
+        .map(|file| Identity::of(file).map(Identity::to_bytes))
+        .transpose()
+}
+
+/// A local file, read with `pread`/`preadv`.
+pub struct FileSource {
+    file: File,
+    path: PathBuf,
+    direct: bool,
+    identity: Identity,
+}
+
+impl FileSource {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("open squashfs '{}'", path.display()))?;
+        Ok(Self {
+            identity: Identity::of(&file)
+                .with_context(|| format!("stat squashfs '{}'", path.display()))?,
+            file,
+            path: path.to_path_buf(),
+            direct: false,
+        })
+    }
+
+    /// Open `path` with `O_DIRECT`, bypassing the page cache. Meant for archives much larger than
+    /// RAM that are read once, where caching them would only evict everything else on the host.
+    /// Every read costs at least one aligned block, so this is slower for archives that fit in
+    /// the page cache.
+    ///
+    /// `O_DIRECT` is specific to Linux; elsewhere, `path` is opened like with [`open`](Self::open).
+    #[cfg(target_os = "linux")]
+    pub fn open_direct(path: impl AsRef<Path>) -> Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::fcntl::OFlag::O_DIRECT.bits())
+            .open(path)
+            .with_context(|| format!("open squashfs '{}' with O_DIRECT", path.display()))?;
+        Ok(Self {
+            identity: Identity::of(&file)
+                .with_context(|| format!("stat squashfs '{}'", path.display()))?,
+            file,
+            path: path.to_path_buf(),
+            direct: true,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open_direct(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl SquashSource for FileSource {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match self.direct {
+            true => pread_direct(&self.file, &mut [IoSliceMut::new(buf)], offset),
+            false => platform::read_at(&self.file, buf, offset),
+        }
+    }
+
+    fn read_vectored_at(&self, bufs: &mut [IoSliceMut<'_>], offset: u64) -> io::Result<usize> {
+        match self.direct {
+            true => pread_direct(&self.file, bufs, offset),
+            false => preadv(&self.file, bufs, offset),
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.file.metadata().map(|metadata| metadata.len())
+    }
+
+    /// No-op with `O_DIRECT`, which bypasses the page cache.
+    fn prefetch(&self, offset: u64, len: u64) -> io::Result<()> {
+        match self.direct {
+            true => Ok(()),
+            false => fadvise_willneed(&self.file, offset, len),
+        }
+    }
+
+    fn changed(&self) -> io::Result<bool> {
+        Ok(Identity::of(&self.file)? != self.identity)
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+/// Local reads are fast enough to perform inline.
+impl AsyncSquashSource for FileSource {
+    async fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        SquashSource::read_at(self, buf, offset)
+    }
+
+    async fn size(&self) -> io::Result<u64> {
+        SquashSource::size(self)
+    }
+
+    fn name(&self) -> String {
+        SquashSource::name(self)
+    }
+}
+
+/// An archive held in memory.
+pub struct Buffer<T>(pub T);
+
+impl<T: AsRef<[u8]> + Send + Sync> SquashSource for Buffer<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let bytes = self.0.as_ref();
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(bytes.len());
+        let len = buf.len().min(bytes.len() - start);
+        buf[..len].copy_from_slice(&bytes[start..start + len]);
+        Ok(len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.as_ref().len() as u64)
+    }
+
+    fn name(&self) -> String {
+        String::from("<buffer>")
+    }
+}
+
+impl<T: AsRef<[u8]> + Send + Sync> AsyncSquashSource for Buffer<T> {
+    async fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        SquashSource::read_at(self, buf, offset)
+    }
+
+    async fn size(&self) -> io::Result<u64> {
+        SquashSource::size(self)
+    }
+
+    fn name(&self) -> String {
+        SquashSource::name(self)
+    }
+}
+
+/// A seekable reader, such as a network-backed one. Reads are serialized, as each seeks it.
+pub struct ReaderSource<R> {
+    reader: Mutex<R>,
+    size: u64,
+}
+
+impl<R: Read + Seek + Send> ReaderSource<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let size = reader
+            .seek(SeekFrom::End(0))
+            .context("get size of reader")?;
+        Ok(Self {
+            reader: Mutex::new(reader),
+            size,
+        })
+    }
+}
+
+impl<R: Read + Seek + Send> SquashSource for ReaderSource<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap_or_else(PoisonError::into_inner);
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read(buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn name(&self) -> String {
+        String::from("<reader>")
+    }
+}
+
+/// Async flavor of [`ReaderSource`]; wrap it in [`BlockOn`] to extract from it.
+pub struct AsyncReaderSource<R> {
+    reader: tokio::sync::Mutex<R>,
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin + Send> AsyncReaderSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: tokio::sync::Mutex::new(reader),
+        }
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin + Send> AsyncSquashSource for AsyncReaderSource<R> {
+    async fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut reader = self.reader.lock().await;
+        reader.seek(SeekFrom::Start(offset)).await?;
+        reader.read(buf).await
+    }
+
+    async fn size(&self) -> io::Result<u64> {
+        self.reader.lock().await.seek(SeekFrom::End(0)).await
+    }
+
+    fn name(&self) -> String {
+        String::from("<reader>")
+    }
+}
+
+/// A memory-mapped local file.
+///
+/// Touching a mapped page past the end of a file that was truncated raises `SIGBUS`, so each read
+/// first checks that the file still covers the mapping and fails otherwise. A truncation racing
+/// the copy that follows is not caught: only map archives that are replaced rather than modified
+/// in place.
+#[cfg(feature = "mmap")]
+pub struct MmapSource {
+    map: memmap2::Mmap,
+    file: File,
+    path: PathBuf,
+    identity: Identity,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapSource {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("open squashfs '{}'", path.display()))?;
+        // SAFETY: the mapping is only read from while the file still covers it; see the type docs
+        // for what is left to the caller.
+        let map = unsafe { memmap2::Mmap::map(&file) }
+            .with_context(|| format!("mmap squashfs '{}'", path.display()))?;
+        Ok(Self {
+            map,
+            identity: Identity::of(&file)
+                .with_context(|| format!("stat squashfs '{}'", path.display()))?,
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl SquashSource for MmapSource {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if self.file.metadata()?.len() < self.map.len() as u64 {
+            return Err(io::Error::other(format!(
+                "'{}' was truncated while mapped",
+                self.path.display()
+            )));
+        }
+        SquashSource::read_at(&Buffer(&self.map[..]), buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.map.len() as u64)
+    }
+
+    fn prefetch(&self, offset: u64, len: u64) -> io::Result<()> {
+        let offset = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(self.map.len());
+        let len = usize::try_from(len)
+            .unwrap_or(usize::MAX)
+            .min(self.map.len() - offset);
+        self.map
+            .advise_range(memmap2::Advice::WillNeed, offset, len)
+    }
+
+    fn changed(&self) -> io::Result<bool> {
+        Ok(Identity::of(&self.file)? != self.identity)
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+/// An archive served over HTTP(S) by a server that supports range requests.
+#[cfg(feature = "http")]
+pub struct HttpSource {
+    agent: ureq::Agent,
+    url: String,
+    size: u64,
+}
+
+#[cfg(feature = "http")]
+impl HttpSource {
+    pub fn open(url: impl Into<String>) -> Result<Self> {
+        let url = url.into();
+        let agent = ureq::Agent::new();
+        let response = agent
+            .head(&url)
+            .call()
+            .with_context(|| format!("HEAD '{url}'"))?;
+        let size = response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .with_context(|| format!("'{url}' did not report its Content-Length"))?;
+        Ok(Self { agent, url, size })
+    }
+}
+
+#[cfg(feature = "http")]
+impl SquashSource for HttpSource {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if buf.is_empty() || offset >= self.size {
+            return Ok(0);
+        }
+        let end = (offset + buf.len() as u64).min(self.size) - 1;
+        let response = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={offset}-{end}"))
+            .call()
+            .map_err(io::Error::other)?;
+        if response.status() != 206 {
+            return Err(io::Error::other(format!(
+                "'{}' ignored the range request (status {})",
+                self.url,
+                response.status()
+            )));
+        }
+        let len = (end - offset + 1) as usize;
+        response.into_reader().read_exact(&mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn name(&self) -> String {
+        self.url.clone()
+    }
+}
+
+/// Chunk length of the [`ChunkCache`] of [`Archive::open_http`](crate::Archive::open_http).
+pub const DEFAULT_CHUNK_LEN: u64 = 1 << 20;
+
+/// Wraps a remote source such as an `HttpSource`, fetching it in aligned chunks of `chunk_len`
+/// and keeping up to `max_bytes` of them, least recently used evicted first, so that the
+/// metadata and blocks an extraction reads piecemeal cost one request per chunk.
+pub struct ChunkCache<S> {
+    source: S,
+    chunk_len: u64,
+    max_chunks: usize,
+    chunks: Mutex<Chunks>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Chunks {
+    /// Chunk index to its contents and last use tick.
+    cached: HashMap<u64, (Arc<[u8]>, u64)>,
+    /// Last use tick to chunk index, oldest first.
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl<S: SquashSource> ChunkCache<S> {
+    pub fn new(source: S, chunk_len: u64, max_bytes: u64) -> Self {
+        let chunk_len = chunk_len.max(1);
+        Self {
+            source,
+            chunk_len,
+            max_chunks: usize::try_from(max_bytes / chunk_len).unwrap_or(usize::MAX),
+            chunks: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn chunk(&self, index: u64) -> io::Result<Arc<[u8]>> {
+        {
+            let mut chunks = self.chunks.lock().unwrap_or_else(PoisonError::into_inner);
+            chunks.tick += 1;
+            let tick = chunks.tick;
+            if let Some((chunk, last_used)) = chunks.cached.get_mut(&index) {
+                let (chunk, last_used) = (Arc::clone(chunk), std::mem::replace(last_used, tick));
+                chunks.lru.remove(&last_used);
+                chunks.lru.insert(tick, index);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(chunk);
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // fetched without holding the lock, at the cost of concurrent readers of the same chunk
+        // fetching it each
+        let start = index * self.chunk_len;
+        let len = self
+            .chunk_len
+            .min(self.source.size()?.saturating_sub(start));
+        let mut chunk = vec![0; len as usize];
+        read_exact_at(&self.source, &mut chunk, start)?;
+        let chunk: Arc<[u8]> = chunk.into();
+        if self.max_chunks > 0 {
+            let mut chunks = self.chunks.lock().unwrap_or_else(PoisonError::into_inner);
+            while chunks.cached.len() >= self.max_chunks {
+                let Some((_, oldest)) = chunks.lru.pop_first() else {
+                    break;
+                };
+                chunks.cached.remove(&oldest);
+            }
+            chunks.tick += 1;
+            let tick = chunks.tick;
+            if let Some((_, last_used)) = chunks.cached.insert(index, (Arc::clone(&chunk), tick)) {
+                chunks.lru.remove(&last_used);
+            }
+            chunks.lru.insert(tick, index);
+        }
+        Ok(chunk)
+    }
+}
+
+impl<S: SquashSource> SquashSource for ChunkCache<S> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if buf.is_empty() || offset >= self.size()? {
+            return Ok(0);
+        }
+        let chunk = self.chunk(offset / self.chunk_len)?;
+        let start = (offset % self.chunk_len) as usize;
+        let len = buf.len().min(chunk.len() - start);
+        buf[..len].copy_from_slice(&chunk[start..start + len]);
+        Ok(len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.source.size()
+    }
+
+    fn cached_bytes(&self) -> u64 {
+        let chunks = self.chunks.lock().unwrap_or_else(PoisonError::into_inner);
+        chunks
+            .cached
+            .values()
+            .map(|(chunk, _)| chunk.len() as u64)
+            .sum()
+    }
+
+    fn trim(&self) {
+        *self.chunks.lock().unwrap_or_else(PoisonError::into_inner) = Chunks::default();
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn changed(&self) -> io::Result<bool> {
+        self.source.changed()
+    }
+
+    fn name(&self) -> String {
+        self.source.name()
+    }
+}
+
+/// An object in an [`ObjectStore`](object_store::ObjectStore) (S3, GCS, Azure, ...).
+#[cfg(feature = "object-store")]
+pub struct ObjectStoreSource {
+    store: Arc<dyn object_store::ObjectStore>,
+    location: object_store::path::Path,
+}
+
+#[cfg(feature = "object-store")]
+impl ObjectStoreSource {
+    pub fn new(
+        store: Arc<dyn object_store::ObjectStore>,
+        location: object_store::path::Path,
+    ) -> Self {
+        Self { store, location }
+    }
+}
+
+#[cfg(feature = "object-store")]
+impl AsyncSquashSource for ObjectStoreSource {
+    async fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let size = AsyncSquashSource::size(self).await?;
+        if buf.is_empty() || offset >= size {
+            return Ok(0);
+        }
+        let end = (offset + buf.len() as u64).min(size);
+        let bytes = self
+            .store
+            .get_range(&self.location, offset as usize..end as usize)
+            .await
+            .map_err(io::Error::other)?;
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    async fn size(&self) -> io::Result<u64> {
+        let meta = self
+            .store
+            .head(&self.location)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(meta.size as u64)
+    }
+
+    fn name(&self) -> String {
+        format!("{}/{}", self.store, self.location)
+    }
+}
+
+/// Adapts an [`AsyncSquashSource`] to a [`SquashSource`] by blocking on its reads, so it can be
+/// handed to the extractors. Within a tokio runtime this requires the multi-threaded flavor.
+pub struct BlockOn<S> {
+    source: S,
+    handle: Handle,
+    size: u64,
+}
+
+impl<S: AsyncSquashSource> BlockOn<S> {
+    /// Must be called from within a tokio runtime, which is used to drive reads issued outside
+    /// of it (e.g. from extraction worker threads).
+    pub async fn new(source: S) -> Result<Self> {
+        let size = source
+            .size()
+            .await
+            .with_context(|| format!("get size of '{}'", source.name()))?;
+        Ok(Self {
+            source,
+            handle: Handle::current(),
+            size,
+        })
+    }
+}
+
+/// Run `f` to completion from synchronous code: on the current runtime if called from within one,
+/// which must be multi-threaded, or on `handle` otherwise.
+pub(crate) fn block_on<T>(
+    handle: &Handle,
+    f: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match Handle::try_current() {
+        Ok(current) if current.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| current.block_on(f))
+        }
+        Ok(_) => Err(io::Error::other(
+            "blocking on async I/O needs a multi-threaded tokio runtime",
+        )),
+        Err(_) => handle.block_on(f),
+    }
+}
+
+impl<S: AsyncSquashSource> SquashSource for BlockOn<S> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        block_on(&self.handle, self.source.read_at(buf, offset))
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn name(&self) -> String {
+        self.source.name()
+    }
+}
+
+/// Fail with [`Error::ArchiveChanged`](crate::Error::ArchiveChanged) if `source` changed since it
+/// was opened.
+pub(crate) fn check_unchanged(source: &dyn SquashSource) -> Result<()> {
+    let name = source.name();
+    match source
+        .changed()
+        .with_context(|| format!("check whether '{name}' changed"))?
+    {
+        true => Err(crate::Error::ArchiveChanged(name).into()),
+        false => Ok(()),
+    }
+}
+
+/// Fill `buf` from `source` at `offset`.
+pub(crate) fn read_exact_at(
+    source: &dyn SquashSource,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        match source.read_at(buf, offset) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ))
+            }
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Fill every buffer in `bufs`, in order, from `source` starting at `offset`.
+pub(crate) fn read_exact_vectored_at(
+    source: &dyn SquashSource,
+    mut bufs: &mut [IoSliceMut<'_>],
+    mut offset: u64,
+) -> io::Result<()> {
+    loop {
+        IoSliceMut::advance_slices(&mut bufs, 0);
+        if bufs.is_empty() {
+            return Ok(());
+        }
+        match source.read_vectored_at(bufs, offset) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ))
+            }
+            Ok(n) => {
+                IoSliceMut::advance_slices(&mut bufs, n);
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sequential [`Read`] + [`Seek`] view of a source, as backhand expects.
+pub(crate) struct SourceReader {
+    source: Arc<dyn SquashSource>,
+    pos: u64,
+}
+
+impl SourceReader {
+    pub(crate) fn new(source: Arc<dyn SquashSource>) -> Self {
+        Self { source, pos: 0 }
+    }
+}
+
+impl Read for SourceReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.source.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SourceReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (self.pos, delta),
+            SeekFrom::End(delta) => (self.source.size()?, delta),
+        };
+        self.pos = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(all(test, feature = "mmap"))]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestArchive};
+
+    #[test]
+    fn maps_what_is_read() {
+        let archive = TestArchive::new(vec![
+            testing::dir("data"),
+            testing::file("data/small", "small"),
+            testing::file("data/large", vec![7; 300 * 1024]),
+        ]);
+        let (file, map) = (
+            FileSource::open(archive.path()).unwrap(),
+            MmapSource::open(archive.path()).unwrap(),
+        );
+        let size = SquashSource::size(&file).unwrap();
+        assert_eq!(SquashSource::size(&map).unwrap(), size);
+        for (offset, len) in [(0, 96), (1000, 4096), (size - 10, 100), (size, 1)] {
+            let (mut read, mut mapped) = (vec![0; len], vec![0; len]);
+            let n = SquashSource::read_at(&file, &mut read, offset).unwrap();
+            assert_eq!(SquashSource::read_at(&map, &mut mapped, offset).unwrap(), n);
+            assert_eq!(read[..n], mapped[..n], "at {offset}");
+        }
+    }
+
+    #[test]
+    fn fails_reads_of_truncated_maps() {
+        let archive = TestArchive::new(vec![testing::file("file", vec![7; 64 * 1024])]);
+        let map = MmapSource::open(archive.path()).unwrap();
+        File::options()
+            .write(true)
+            .open(archive.path())
+            .unwrap()
+            .set_len(4096)
+            .unwrap();
+        let error = SquashSource::read_at(&map, &mut [0; 16], 8192).unwrap_err();
+        assert!(
+            error.to_string().contains("truncated while mapped"),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,92 @@
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use std::path::Path;
+
+use anyhow::Result;
+use backhand::{InnerNode, Node, SquashfsFileReader};
+
+/// Nearest existing ancestor of `dest`, which is what will hold the extracted tree.
+fn existing_ancestor(dest: &Path) -> &Path {
+    dest.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or(Path::new("."))
+}
+
+fn gib(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / f64::from(1 << 30))
+}
+
+#[cfg(target_os = "linux")]
+fn is_tmpfs(mount: &Path) -> Result<bool> {
+    use anyhow::Context;
+    use nix::sys::statfs::{statfs, TMPFS_MAGIC};
+
+    let stat = statfs(mount).with_context(|| format!("statfs '{}'", mount.display()))?;
+    Ok(stat.filesystem_type() == TMPFS_MAGIC)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_tmpfs(_mount: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// Fail early if the entries to extract cannot fit in the filesystem holding `dest`, rather than
+/// with `ENOSPC` halfway through. With [`check_space`](crate::PolicyOptions::check_space), both
+/// the space and the inodes of any filesystem are checked; otherwise only the space of tmpfs,
+/// since running out of it is the most common way large extractions fail.
+#[cfg(unix)]
+pub(crate) fn check(
+    dest: &Path,
+    nodes: &[&Node<SquashfsFileReader>],
+    max_dest_bytes: Option<u64>,
+    check_space: bool,
+) -> Result<()> {
+    use anyhow::Context;
+    use nix::sys::statvfs::statvfs;
+
+    let mount = existing_ancestor(dest);
+    if !check_space && !is_tmpfs(mount)? {
+        return Ok(());
+    }
+    let stat = statvfs(mount).with_context(|| format!("statvfs '{}'", mount.display()))?;
+
+    let block_size = (stat.fragment_size() as u64).max(1);
+    let needed: u64 = nodes
+        .iter()
+        .filter_map(|node| match &node.inner {
+            InnerNode::File(file) => Some(u64::from(file.basic.file_size).div_ceil(block_size)),
+            _ => None,
+        })
+        .sum::<u64>()
+        * block_size;
+    let needed = max_dest_bytes.map_or(needed, |max| needed.min(max));
+    let available = stat.blocks_available() as u64 * block_size;
+    anyhow::ensure!(
+        needed <= available,
+        "not enough space on '{}': need {}, have {} of {}",
+        mount.display(),
+        gib(needed),
+        gib(available),
+        gib(stat.blocks() as u64 * block_size),
+    );
+
+    // filesystems allocating inodes on demand, e.g. btrfs, report having none
+    let inodes = nodes.len() as u64;
+    let free = stat.files_available() as u64;
+    anyhow::ensure!(
+        !check_space || stat.files() == 0 || inodes <= free,
+        "not enough inodes on '{}': need {inodes}, have {free}",
+        mount.display(),
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn check(
+    _dest: &Path,
+    _nodes: &[&Node<SquashfsFileReader>],
+    _max_dest_bytes: Option<u64>,
+    _check_space: bool,
+) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use std::{
+    io::{Seek, SeekFrom, Write},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use backhand::BasicFile;
+use tokio::io::{AsyncSeek, AsyncWrite};
+
+/// Size of the holes left in files, the block size of practically every filesystem.
+const HOLE_ALIGN: usize = 4096;
+
+/// Whether the archive stores some blocks of `file` as holes, which preallocating it would fill.
+pub(crate) fn has_holes(file: &BasicFile) -> bool {
+    file.block_sizes.iter().any(|block| block.size() == 0)
+}
+
+/// Seeks over the aligned blocks of zeros written through it instead of writing them, leaving
+/// holes in the file, with [`Mechanisms::sparse`](crate::mechanisms::Mechanisms::sparse). A file
+/// ending in a hole must be extended to its size with `set_len` once written.
+pub(crate) struct Sparse<W> {
+    inner: W,
+    enabled: bool,
+    /// Offset in the file that the next write of `inner` lands at.
+    offset: u64,
+    /// Zeros skipped at `offset`, to seek over before the next write.
+    hole: u64,
+    /// Whether seeking over `hole` was started but has not completed.
+    seeking: bool,
+}
+
+impl<W> Sparse<W> {
+    pub(crate) fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            offset: 0,
+            hole: 0,
+            seeking: false,
+        }
+    }
+}
+
+/// Split `buf`, written at `offset`, into the length of the data before its first run of aligned
+/// blocks of zeros, and the length of that run.
+fn split(buf: &[u8], offset: u64) -> (usize, usize) {
+    let align = HOLE_ALIGN as u64;
+    let mut start = ((align - offset % align) % align) as usize;
+    let is_zero = |at: usize| {
+        buf.get(at..at + HOLE_ALIGN)
+            .is_some_and(|block| block.iter().all(|&b| b == 0))
+    };
+    while start + HOLE_ALIGN <= buf.len() {
+        if is_zero(start) {
+            let mut end = start + HOLE_ALIGN;
+            while is_zero(end) {
+                end += HOLE_ALIGN;
+            }
+            return (start, end - start);
+        }
+        start += HOLE_ALIGN;
+    }
+    (buf.len(), 0)
+}
+
+impl<W: Write + Seek> Write for Sparse<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.enabled {
+            return self.inner.write(buf);
+        }
+        let (data, zeros) = split(buf, self.offset + self.hole);
+        if data == 0 && zeros > 0 {
+            self.hole += zeros as u64;
+            return Ok(zeros);
+        }
+        if self.hole > 0 {
+            self.inner.seek(SeekFrom::Current(self.hole as i64))?;
+            self.offset += std::mem::take(&mut self.hole);
+        }
+        let n = self.inner.write(&buf[..data])?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: AsyncWrite + AsyncSeek + Unpin> AsyncWrite for Sparse<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        loop {
+            if this.seeking {
+                ready!(Pin::new(&mut this.inner).poll_complete(cx))?;
+                this.seeking = false;
+                this.offset += std::mem::take(&mut this.hole);
+            }
+            let (data, zeros) = split(buf, this.offset + this.hole);
+            if data == 0 && zeros > 0 {
+                this.hole += zeros as u64;
+                return Poll::Ready(Ok(zeros));
+            }
+            if this.hole == 0 {
+                let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..data]))?;
+                this.offset += n as u64;
+                return Poll::Ready(Ok(n));
+            }
+            // a seek cannot be started while a write is in flight
+            ready!(Pin::new(&mut this.inner).poll_complete(cx))?;
+            Pin::new(&mut this.inner).start_seek(SeekFrom::Current(this.hole as i64))?;
+            this.seeking = true;
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
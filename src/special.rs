@@ -0,0 +1,78 @@
+use std::path::Path;
+
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::Result;
+use backhand::{InnerNode, Node, SquashfsFileReader};
+#[cfg(unix)]
+use nix::{
+    errno::Errno,
+    sys::stat::{makedev, mknod, Mode, SFlag},
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    CharDevice,
+    BlockDevice,
+    NamedPipe,
+    Socket,
+}
+
+/// A device node, named pipe or socket to create.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Special {
+    kind: Kind,
+    device_number: u32,
+}
+
+impl Special {
+    pub(crate) fn of(node: &Node<SquashfsFileReader>) -> Option<Self> {
+        let (kind, device_number) = match &node.inner {
+            InnerNode::CharacterDevice(dev) => (Kind::CharDevice, dev.device_number),
+            InnerNode::BlockDevice(dev) => (Kind::BlockDevice, dev.device_number),
+            InnerNode::NamedPipe => (Kind::NamedPipe, 0),
+            InnerNode::Socket => (Kind::Socket, 0),
+            InnerNode::File(_) | InnerNode::Symlink(_) | InnerNode::Dir(_) => return None,
+        };
+        Some(Self {
+            kind,
+            device_number,
+        })
+    }
+
+    /// Create the entry at `path`, replacing one left behind by an interrupted extraction.
+    /// Returns `false`, after a warning, if this process is not allowed to create it.
+    #[cfg(unix)]
+    pub(crate) fn create(self, path: &Path) -> Result<bool> {
+        // squashfs stores device numbers in the kernel's `new_encode_dev` layout.
+        let dev = self.device_number;
+        let (major, minor) = ((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00));
+        let kind = match self.kind {
+            Kind::CharDevice => SFlag::S_IFCHR,
+            Kind::BlockDevice => SFlag::S_IFBLK,
+            Kind::NamedPipe => SFlag::S_IFIFO,
+            Kind::Socket => SFlag::S_IFSOCK,
+        };
+        let _ = std::fs::remove_file(path);
+        match mknod(
+            path,
+            kind,
+            Mode::from_bits_truncate(0o644),
+            makedev(major.into(), minor.into()),
+        ) {
+            Ok(()) => Ok(true),
+            Err(Errno::EPERM) => {
+                tracing::warn!(path = %path.display(), "not permitted to create device node, skipping");
+                Ok(false)
+            }
+            Err(e) => Err(e).with_context(|| format!("mknod '{}'", path.display())),
+        }
+    }
+
+    /// Special files only exist on Unix, so they are always skipped elsewhere.
+    #[cfg(not(unix))]
+    pub(crate) fn create(self, path: &Path) -> Result<bool> {
+        tracing::warn!(path = %path.display(), kind = ?self.kind, "special files are only supported on Unix, skipping");
+        Ok(false)
+    }
+}
@@ -0,0 +1,592 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{Context, Result};
+use backhand::{FilesystemWriter, NodeHeader, DEFAULT_BLOCK_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compression::FilesystemCompressor, platform, progress::Progress, Archive, EntryKind, Error,
+    Filter,
+};
+
+/// How [`squash_blocking`] and its flavors build an archive.
+#[derive(Debug, Clone)]
+pub struct SquashOptions {
+    /// backhand's default is xz.
+    pub compressor: FilesystemCompressor,
+    /// A power of two from 4 KiB to 1 MiB.
+    pub block_size: u32,
+    /// Modification time of the archive and of the entries added without one, in seconds since
+    /// the epoch. The current time if `None`.
+    pub mtime: Option<u32>,
+    /// Updated as entries are written into the archive.
+    pub progress: Option<Arc<Progress>>,
+}
+
+impl Default for SquashOptions {
+    fn default() -> Self {
+        Self {
+            compressor: FilesystemCompressor::default(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            mtime: None,
+            progress: None,
+        }
+    }
+}
+
+/// The outcome of building an archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SquashReport {
+    /// Entries written, not counting the root directory.
+    pub entries: u64,
+    /// Contents of the files written, before compression.
+    pub bytes: u64,
+    /// Size of the archive.
+    pub archive_bytes: u64,
+}
+
+/// Build a squashfs archive at `dst` from the directory tree at `src`, keeping the modes,
+/// ownership and mtimes of its entries. Only the entries `filter` selects by their path in the
+/// archive (e.g. `/index/se/rd/serde`) are added; the parent directories of selected entries are
+/// added as needed, but keep their modes only if they are selected themselves. Hard links are
+/// stored as separate files. The archive is written next to `dst` and renamed over it once
+/// complete.
+pub fn squash_blocking(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    filter: Filter,
+    options: SquashOptions,
+) -> Result<SquashReport, Error> {
+    let src = src.as_ref();
+    let mtime = archive_mtime(&options);
+    let mut entries = Vec::new();
+    walk(src, Path::new("/"), &filter, &mut entries)
+        .with_context(|| format!("read directory tree '{}'", src.display()))?;
+    let root = std::fs::metadata(src).with_context(|| format!("stat '{}'", src.display()))?;
+    let (uid, gid) = owner(&root);
+    let root = NodeHeader::new(platform::mode(&root) as u16, uid, gid, mtime);
+    let writer = new_writer(root, &options, mtime)?;
+    Ok(write(writer, entries, None, dst.as_ref(), &options, mtime)?)
+}
+
+/// Async flavor of [`squash_blocking`], building the archive on tokio's blocking thread pool.
+pub async fn squash_async(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    filter: Filter,
+    options: SquashOptions,
+) -> Result<SquashReport, Error> {
+    let (src, dst) = (src.as_ref().to_path_buf(), dst.as_ref().to_path_buf());
+    tokio::task::spawn_blocking(move || squash_blocking(src, dst, filter, options))
+        .await
+        .context("spawn blocking squash task")?
+}
+
+/// Like [`squash_blocking`], building the archive from files given as their path in the archive
+/// and a reader over their contents, read while the archive is written. Files get mode `0o644`,
+/// owner root and the archive's mtime, and directories mode `0o755`.
+pub fn squash_entries_blocking<R: Read>(
+    entries: impl IntoIterator<Item = (PathBuf, R)>,
+    dst: impl AsRef<Path>,
+    filter: Filter,
+    options: SquashOptions,
+) -> Result<SquashReport, Error> {
+    let mtime = archive_mtime(&options);
+    let header = NodeHeader::new(0o644, 0, 0, mtime);
+    let entries = entries
+        .into_iter()
+        .map(|(path, reader)| (Path::new("/").join(path), reader))
+        .filter(|(path, _)| filter.matches(path))
+        .map(|(path, reader)| Planned {
+            path,
+            header,
+            contents: Contents::File {
+                reader: Box::new(reader),
+                size: None,
+            },
+        })
+        .collect();
+    let writer = new_writer(NodeHeader::new(0o755, 0, 0, mtime), &options, mtime)?;
+    Ok(write(writer, entries, None, dst.as_ref(), &options, mtime)?)
+}
+
+/// Async flavor of [`squash_entries_blocking`], reading the entries and building the archive on
+/// tokio's blocking thread pool.
+pub async fn squash_entries_async<I, R>(
+    entries: I,
+    dst: impl AsRef<Path>,
+    filter: Filter,
+    options: SquashOptions,
+) -> Result<SquashReport, Error>
+where
+    I: IntoIterator<Item = (PathBuf, R)> + Send + 'static,
+    R: Read + 'static,
+{
+    let dst = dst.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || squash_entries_blocking(entries, dst, filter, options))
+        .await
+        .context("spawn blocking squash task")?
+}
+
+/// An entry to add, at `path` in the archive.
+struct Planned<'a> {
+    path: PathBuf,
+    header: NodeHeader,
+    contents: Contents<'a>,
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+enum Contents<'a> {
+    Dir,
+    /// `size` is known for files read from disk.
+    File {
+        reader: Box<dyn Read + 'a>,
+        size: Option<u64>,
+    },
+    Symlink(PathBuf),
+    CharDevice(u32),
+    BlockDevice(u32),
+    Fifo,
+    Socket,
+}
+
+fn archive_mtime(options: &SquashOptions) -> u32 {
+    options.mtime.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as u32)
+    })
+}
+
+/// Add the tree at `src` to `archive`, writing the result to `dst`; see [`Archive::append`].
+pub(crate) fn append(
+    archive: &Archive,
+    src: &Path,
+    dst: &Path,
+    options: &SquashOptions,
+) -> Result<SquashReport> {
+    let mtime = archive_mtime(options);
+    let mut entries = Vec::new();
+    walk(src, Path::new("/"), &Filter::All, &mut entries)
+        .with_context(|| format!("read directory tree '{}'", src.display()))?;
+    let mut writer = FilesystemWriter::from_fs_reader(&archive.filesystem)
+        .with_context(|| format!("read squashfs '{}' for appending", archive.source.name()))?;
+    writer.set_time(mtime);
+    write(writer, entries, Some(archive), dst, options, mtime)
+}
+
+/// Plan the entries under `dir`, at `path` in the archive, in sorted order.
+fn walk(dir: &Path, path: &Path, filter: &Filter, entries: &mut Vec<Planned<'_>>) -> Result<()> {
+    let mut children = std::fs::read_dir(dir)
+        .with_context(|| format!("read directory '{}'", dir.display()))?
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("read directory '{}'", dir.display()))?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let (src, path) = (child.path(), path.join(child.file_name()));
+        let metadata =
+            std::fs::symlink_metadata(&src).with_context(|| format!("stat '{}'", src.display()))?;
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            if filter.matches(&path) {
+                entries.push(Planned {
+                    path: path.clone(),
+                    header: header(&metadata),
+                    contents: Contents::Dir,
+                });
+            }
+            walk(&src, &path, filter, entries)?;
+            continue;
+        }
+        if !filter.matches(&path) {
+            continue;
+        }
+        let contents = if file_type.is_file() {
+            Contents::File {
+                size: Some(metadata.len()),
+                reader: Box::new(LazyFile {
+                    path: src,
+                    file: None,
+                }),
+            }
+        } else if file_type.is_symlink() {
+            let target = std::fs::read_link(&src)
+                .with_context(|| format!("read link '{}'", src.display()))?;
+            Contents::Symlink(target)
+        } else {
+            special(&metadata)
+        };
+        entries.push(Planned {
+            path,
+            header: header(&metadata),
+            contents,
+        });
+    }
+    Ok(())
+}
+
+fn header(metadata: &std::fs::Metadata) -> NodeHeader {
+    let (uid, gid) = owner(metadata);
+    NodeHeader::new(
+        platform::mode(metadata) as u16,
+        uid,
+        gid,
+        platform::mtime(metadata).clamp(0, i64::from(u32::MAX)) as u32,
+    )
+}
+
+/// The uid and gid of an entry, which is owned by root off Unix.
+fn owner(metadata: &std::fs::Metadata) -> (u32, u32) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        (metadata.uid(), metadata.gid())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        (0, 0)
+    }
+}
+
+/// The contents of a device node, named pipe or socket.
+#[cfg(unix)]
+fn special(metadata: &std::fs::Metadata) -> Contents<'static> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let file_type = metadata.file_type();
+    if file_type.is_char_device() {
+        Contents::CharDevice(device_number(metadata.rdev()))
+    } else if file_type.is_block_device() {
+        Contents::BlockDevice(device_number(metadata.rdev()))
+    } else if file_type.is_fifo() {
+        Contents::Fifo
+    } else {
+        Contents::Socket
+    }
+}
+
+/// There are no special files off Unix; anything else is taken for an empty file.
+#[cfg(not(unix))]
+fn special(_metadata: &std::fs::Metadata) -> Contents<'static> {
+    Contents::File {
+        reader: Box::new(io::empty()),
+        size: Some(0),
+    }
+}
+
+/// `rdev` in the kernel's `new_encode_dev` layout squashfs stores device numbers in.
+#[cfg(unix)]
+fn device_number(rdev: u64) -> u32 {
+    let (major, minor) = (nix::sys::stat::major(rdev), nix::sys::stat::minor(rdev));
+    ((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as u32
+}
+
+/// A writer with the compressor, block size and mtime of `options`.
+fn new_writer<'c>(
+    root: NodeHeader,
+    options: &SquashOptions,
+    mtime: u32,
+) -> Result<FilesystemWriter<'static, 'static, 'c>> {
+    let block_size = options.block_size;
+    anyhow::ensure!(
+        block_size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size),
+        "invalid squashfs block size {block_size}"
+    );
+    let mut writer = FilesystemWriter::default();
+    writer.set_compressor(options.compressor);
+    writer.set_block_size(block_size);
+    writer.set_time(mtime);
+    writer.set_root_mode(root.permissions);
+    writer.set_root_uid(root.uid);
+    writer.set_root_gid(root.gid);
+    Ok(writer)
+}
+
+/// Add `entries` to `writer` and write it to `dst`. Entries already in `base`, which `writer`
+/// was built from, replace its files and are merged into its directories.
+fn write<'c>(
+    mut writer: FilesystemWriter<'_, '_, 'c>,
+    entries: Vec<Planned<'c>>,
+    base: Option<&Archive>,
+    dst: &Path,
+    options: &SquashOptions,
+    mtime: u32,
+) -> Result<SquashReport> {
+    if let Some(progress) = &options.progress {
+        let bytes = entries
+            .iter()
+            .map(|entry| match entry.contents {
+                Contents::File { size, .. } => size.unwrap_or(0),
+                _ => 0,
+            })
+            .sum();
+        progress.plan_entries(entries.len() as u64, bytes);
+    }
+    let mut report = SquashReport {
+        entries: entries.len() as u64,
+        ..Default::default()
+    };
+    let bytes = Arc::new(AtomicU64::new(0));
+    let parents = NodeHeader::new(0o755, 0, 0, mtime);
+    for Planned {
+        path,
+        header,
+        contents,
+    } in entries
+    {
+        if let Some(parent) = path.parent().filter(|parent| *parent != Path::new("/")) {
+            writer
+                .push_dir_all(parent, parents)
+                .with_context(|| format!("add directory '{}'", parent.display()))?;
+        }
+        // entries other than files are done as soon as they are added
+        if let (Some(progress), false) =
+            (&options.progress, matches!(contents, Contents::File { .. }))
+        {
+            progress.record_entry(&path, 0);
+        }
+        let existing = base
+            .and_then(|base| base.find(&path))
+            .map(|entry| entry.kind);
+        // only files can be replaced and directories merged
+        let replaces = match contents {
+            Contents::Dir => Some(EntryKind::Dir),
+            Contents::File { .. } => Some(EntryKind::File),
+            _ => None,
+        };
+        anyhow::ensure!(
+            existing.is_none() || existing == replaces,
+            "cannot replace '{}' with an entry of another kind",
+            path.display()
+        );
+        let pushed = match contents {
+            Contents::Dir if existing.is_some() => Ok(()),
+            Contents::File { reader, size } => {
+                let reader = Counting {
+                    reader,
+                    path: path.clone(),
+                    read: 0,
+                    size: size.unwrap_or(0),
+                    done: false,
+                    bytes: Arc::clone(&bytes),
+                    progress: options.progress.clone(),
+                };
+                match existing {
+                    Some(_) => writer.replace_file(&path, reader),
+                    None => writer.push_file(reader, &path, header),
+                }
+            }
+            Contents::Dir => writer.push_dir(&path, header),
+            Contents::Symlink(target) => writer.push_symlink(target, &path, header),
+            Contents::CharDevice(device) => writer.push_char_device(device, &path, header),
+            Contents::BlockDevice(device) => writer.push_block_device(device, &path, header),
+            Contents::Fifo => writer.push_fifo(&path, header),
+            Contents::Socket => writer.push_socket(&path, header),
+        };
+        pushed.with_context(|| format!("add '{}'", path.display()))?;
+    }
+
+    let mut tmp = dst.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    let res = File::create(tmp)
+        .with_context(|| format!("create '{}'", tmp.display()))
+        .and_then(|file| {
+            writer
+                .write(io::BufWriter::new(file))
+                .with_context(|| format!("write squashfs '{}'", tmp.display()))
+        })
+        .and_then(|(_, archive_bytes)| {
+            std::fs::rename(tmp, dst)
+                .with_context(|| format!("rename '{}' to '{}'", tmp.display(), dst.display()))?;
+            Ok(archive_bytes)
+        });
+    match res {
+        Ok(archive_bytes) => report.archive_bytes = archive_bytes,
+        Err(e) => {
+            let _ = std::fs::remove_file(tmp);
+            return Err(e);
+        }
+    }
+    report.bytes = bytes.load(Ordering::Relaxed);
+    Ok(report)
+}
+
+/// A file on disk, opened on its first read and closed at its end, so that building an archive
+/// does not hold every file open.
+struct LazyFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Read for LazyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(File::open(&self.path).map_err(|e| {
+                io::Error::new(e.kind(), format!("open '{}': {e}", self.path.display()))
+            })?),
+        };
+        let n = file.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.file = None;
+        }
+        Ok(n)
+    }
+}
+
+/// Counts the contents of a file as the writer reads them, reporting them to the progress.
+struct Counting<'a> {
+    reader: Box<dyn Read + 'a>,
+    path: PathBuf,
+    read: u64,
+    size: u64,
+    done: bool,
+    bytes: Arc<AtomicU64>,
+    progress: Option<Arc<Progress>>,
+}
+
+impl Read for Counting<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.read += n as u64;
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(progress) = &self.progress {
+            match n {
+                0 if !buf.is_empty() && !self.done => {
+                    self.done = true;
+                    progress.record_entry(&self.path, self.read);
+                }
+                0 => {}
+                _ => progress.chunk(&self.path, self.read, self.size.max(self.read), false),
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::ExtractOptions;
+
+    fn set_mode(path: &Path, mode: u32) {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn dir(root: &Path, path: &str, mode: u32) {
+        std::fs::create_dir_all(root.join(path)).unwrap();
+        set_mode(&root.join(path), mode);
+    }
+
+    fn file(root: &Path, path: &str, contents: impl AsRef<[u8]>, mode: u32) {
+        std::fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+        std::fs::write(root.join(path), contents).unwrap();
+        set_mode(&root.join(path), mode);
+    }
+
+    type Listed = (PathBuf, EntryKind, u64, u32);
+
+    /// The path, kind, size and permissions of every entry of `archive`.
+    fn listing(archive: &Archive) -> Vec<Listed> {
+        archive
+            .list()
+            .map(|entry| (entry.path, entry.kind, entry.size, entry.mode & 0o7777))
+            .collect()
+    }
+
+    fn entry(path: &str, kind: EntryKind, size: usize, mode: u32) -> Listed {
+        (PathBuf::from(path), kind, size as u64, mode)
+    }
+
+    #[test]
+    fn appends_to_built_archives() {
+        let root = tempfile::tempdir().unwrap();
+        let (src, added, dst) = (
+            root.path().join("src"),
+            root.path().join("added"),
+            root.path().join("archive.squashfs"),
+        );
+        let large: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        dir(&src, "", 0o755);
+        dir(&src, "index", 0o750);
+        dir(&src, "index/se/rd", 0o755);
+        file(&src, "index/se/rd/serde", "serde 1", 0o640);
+        file(&src, "large", &large, 0o600);
+        std::os::unix::fs::symlink("se/rd/serde", src.join("index/serde")).unwrap();
+        let options = || SquashOptions {
+            mtime: Some(1_000_000),
+            ..Default::default()
+        };
+        let report = squash_blocking(&src, &dst, Filter::All, options()).unwrap();
+        assert_eq!(report.entries, 6);
+        assert_eq!(report.bytes, 7 + large.len() as u64);
+
+        let archive = Archive::open(&dst, ExtractOptions::default()).unwrap();
+        let mut built = vec![
+            entry("/", EntryKind::Dir, 0, 0o755),
+            entry("/index", EntryKind::Dir, 0, 0o750),
+            entry("/index/se", EntryKind::Dir, 0, 0o755),
+            entry("/index/se/rd", EntryKind::Dir, 0, 0o755),
+            entry("/index/se/rd/serde", EntryKind::File, 7, 0o640),
+            entry("/index/serde", EntryKind::Symlink, 11, 0o777),
+            entry("/large", EntryKind::File, large.len(), 0o600),
+        ];
+        assert_eq!(listing(&archive), built);
+        assert_eq!(
+            archive.read_file_blocking("/index/se/rd/serde").unwrap(),
+            b"serde 1"
+        );
+        assert_eq!(archive.read_file_blocking("/large").unwrap(), large);
+
+        file(&added, "index/se/rd/serde", "serde 1.0.1", 0o644);
+        file(&added, "index/an/yh/anyhow", "anyhow", 0o644);
+        archive.append(&added, &dst, options()).unwrap();
+        drop(archive);
+
+        let appended = Archive::open(&dst, ExtractOptions::default()).unwrap();
+        let listed = listing(&appended);
+        // the replaced file takes the mode of the new one, the rest is kept as it was
+        built[4] = entry("/index/se/rd/serde", EntryKind::File, 11, 0o644);
+        for entry in &built[4..] {
+            assert!(listed.contains(entry), "{entry:?} not in {listed:?}");
+        }
+        let paths: Vec<_> = listed.iter().map(|(path, ..)| path.as_path()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/",
+                "/index",
+                "/index/an",
+                "/index/an/yh",
+                "/index/an/yh/anyhow",
+                "/index/se",
+                "/index/se/rd",
+                "/index/se/rd/serde",
+                "/index/serde",
+                "/large",
+            ]
+            .map(Path::new)
+        );
+        assert_eq!(
+            appended.read_file_blocking("/index/se/rd/serde").unwrap(),
+            b"serde 1.0.1"
+        );
+        assert_eq!(
+            appended.read_file_blocking("/index/an/yh/anyhow").unwrap(),
+            b"anyhow"
+        );
+        assert_eq!(appended.read_file_blocking("/large").unwrap(), large);
+    }
+}
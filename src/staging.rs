@@ -0,0 +1,211 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, Result};
+#[cfg(target_os = "linux")]
+use nix::fcntl::{renameat2, RenameFlags};
+
+/// A directory next to the destination of an [atomic](crate::DurabilityOptions::atomic) extraction,
+/// extracted into and then renamed into place. It is removed if dropped before being
+/// [committed](Self::commit).
+#[derive(Debug)]
+pub(crate) struct Staging {
+    dir: Option<PathBuf>,
+    dest: PathBuf,
+}
+
+impl Staging {
+    pub(crate) fn new(dest: &Path) -> Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+
+        let name = dest
+            .file_name()
+            .with_context(|| format!("no file name in '{}'", dest.display()))?;
+        let mut staging_name = OsString::from(".");
+        staging_name.push(name);
+        staging_name.push(format!(
+            ".staging-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let dir = parent(dest).join(staging_name);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("create staging dir '{}'", dir.display()))?;
+        Ok(Self {
+            dir: Some(dir),
+            dest: dest.to_path_buf(),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        self.dir
+            .as_deref()
+            .expect("staging dir is only taken when committing")
+    }
+
+    /// Rename the staging directory to the destination, or each of its entries into the
+    /// destination if it exists, exchanging them with the entries they replace.
+    pub(crate) fn commit(mut self) -> Result<()> {
+        let dir = self
+            .dir
+            .take()
+            .expect("staging dir is only taken when committing");
+        let res = self.publish(&dir);
+        // the staging dir is empty, or holds what was replaced
+        if dir.exists() {
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+        res?;
+        // directories cannot be opened to be synced off Unix
+        #[cfg(unix)]
+        for dir in [parent(&self.dest), self.dest.as_path()] {
+            std::fs::File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("fsync dir '{}'", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    fn publish(&self, dir: &Path) -> Result<()> {
+        let dest = &self.dest;
+        match std::fs::symlink_metadata(dest) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return std::fs::rename(dir, dest)
+                    .with_context(|| format!("rename '{}' to '{}'", dir.display(), dest.display()))
+            }
+            Err(e) => return Err(e).with_context(|| format!("stat '{}'", dest.display())),
+            Ok(metadata) => anyhow::ensure!(
+                metadata.is_dir(),
+                "destination '{}' is not a directory",
+                dest.display()
+            ),
+        }
+
+        let entries =
+            std::fs::read_dir(dir).with_context(|| format!("read dir '{}'", dir.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("read dir '{}'", dir.display()))?;
+            let (from, to) = (entry.path(), dest.join(entry.file_name()));
+            exchange(&from, &to)
+                .with_context(|| format!("rename '{}' to '{}'", from.display(), to.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Rename `from` to `to`, moving what `to` held to `from`.
+#[cfg(target_os = "linux")]
+fn exchange(from: &Path, to: &Path) -> Result<()> {
+    let flags = match to.symlink_metadata() {
+        Ok(_) => RenameFlags::RENAME_EXCHANGE,
+        Err(_) => RenameFlags::empty(),
+    };
+    Ok(renameat2(None, from, None, to, flags)?)
+}
+
+/// Off Linux, entries cannot be exchanged atomically, so `to` is missing while what it held is
+/// moved next to `from`.
+#[cfg(not(target_os = "linux"))]
+fn exchange(from: &Path, to: &Path) -> Result<()> {
+    if to.symlink_metadata().is_ok() {
+        let mut aside = from.as_os_str().to_owned();
+        aside.push(".replaced");
+        std::fs::rename(to, aside)?;
+    }
+    Ok(std::fs::rename(from, to)?)
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, TestArchive},
+        DurabilityOptions, EscapePolicy, ExtractOptions, Filter, PolicyOptions,
+    };
+
+    fn names(dir: &Path) -> Vec<OsString> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn commits_into_place() {
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("dest");
+
+        let staging = Staging::new(&dest).unwrap();
+        std::fs::write(staging.path().join("a"), "first").unwrap();
+        std::fs::write(staging.path().join("b"), "first").unwrap();
+        staging.commit().unwrap();
+        assert_eq!(names(root.path()), ["dest"]);
+
+        // into an existing destination, each entry replaces its namesake
+        let staging = Staging::new(&dest).unwrap();
+        std::fs::write(staging.path().join("a"), "second").unwrap();
+        std::fs::write(staging.path().join("c"), "second").unwrap();
+        staging.commit().unwrap();
+        assert_eq!(names(root.path()), ["dest"]);
+        assert_eq!(names(&dest), ["a", "b", "c"]);
+        assert_eq!(std::fs::read(dest.join("a")).unwrap(), b"second");
+        assert_eq!(std::fs::read(dest.join("b")).unwrap(), b"first");
+    }
+
+    #[test]
+    fn removes_uncommitted_staging() {
+        let root = tempfile::tempdir().unwrap();
+        let staging = Staging::new(&root.path().join("dest")).unwrap();
+        std::fs::write(staging.path().join("a"), "a").unwrap();
+        drop(staging);
+        assert!(names(root.path()).is_empty());
+    }
+
+    #[test]
+    fn leaves_nothing_behind_failed_extractions() {
+        let archive = TestArchive::new(vec![
+            testing::file("a/file", "contents"),
+            testing::symlink("a/outside", "../../etc/passwd"),
+        ]);
+        let options = || ExtractOptions {
+            policy: PolicyOptions {
+                escape: EscapePolicy::Strict,
+                ..PolicyOptions::default()
+            },
+            durability: DurabilityOptions {
+                atomic: true,
+                ..DurabilityOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        let dest = archive.scratch("dest");
+        crate::unsquash_blocking(archive.path(), &dest, Filter::All, options()).unwrap_err();
+        assert!(!dest.exists());
+        assert_eq!(names(dest.parent().unwrap()), ["archive.squashfs"]);
+
+        let filter = Filter::Paths(["/a/file".into()].into());
+        crate::unsquash_blocking(archive.path(), &dest, filter, options()).unwrap();
+        assert_eq!(std::fs::read(dest.join("a/file")).unwrap(), b"contents");
+        assert_eq!(names(dest.parent().unwrap()), ["archive.squashfs", "dest"]);
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::HashAlgorithm;
+
+/// Name of the `sha256sum -c` compatible manifest written into the destination.
+pub const SHA256SUMS: &str = "SHA256SUMS";
+/// Name of the `sha512sum -c` compatible manifest written into the destination.
+pub const SHA512SUMS: &str = "SHA512SUMS";
+/// Name of the `b3sum -c` compatible manifest written into the destination.
+pub const B3SUMS: &str = "B3SUMS";
+
+/// Write the hash of every regular file among `dest_paths` into the
+/// [`sums_file`](HashAlgorithm::sums_file) of `algorithm` in `dest`, with paths relative to
+/// `dest`. Files that were not written (e.g. trimmed by the quota) are skipped.
+pub(crate) fn write_sums(
+    dest: &Path,
+    dest_paths: Vec<PathBuf>,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    use rayon::prelude::*;
+
+    let mut lines = dest_paths
+        .into_par_iter()
+        .map(|path| {
+            match std::fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_file() => {}
+                Ok(_) => return Ok(None),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e).with_context(|| format!("stat '{}'", path.display())),
+            }
+            let hash = algorithm.hash_file(&path)?;
+            let relative = path.strip_prefix(dest).unwrap_or(&path).to_path_buf();
+            Ok(Some((relative, hash)))
+        })
+        .filter_map(Result::transpose)
+        .collect::<Result<Vec<_>>>()?;
+    lines.sort_unstable();
+
+    let mut out = String::new();
+    for (path, hash) in lines {
+        out.push_str(&sum_line(&path, &hash));
+    }
+    let path = dest.join(algorithm.sums_file());
+    std::fs::write(&path, out).with_context(|| format!("write '{}'", path.display()))
+}
+
+/// A line as `sha256sum` prints it, escaping names containing a backslash or newline the way it
+/// does.
+fn sum_line(path: &Path, hash: &str) -> String {
+    let name = path.to_string_lossy();
+    match name.contains(['\\', '\n']) {
+        true => {
+            let name = name.replace('\\', "\\\\").replace('\n', "\\n");
+            format!("\\{hash}  {name}\n")
+        }
+        false => format!("{hash}  {name}\n"),
+    }
+}
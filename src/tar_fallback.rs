@@ -0,0 +1,194 @@
+use std::{
+    io::Read,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    format::{self, Format},
+    mechanisms::Mechanisms,
+    platform,
+    source::{SourceReader, SquashSource},
+    ExtractOptions, ExtractReport, Filter,
+};
+
+/// The format of `source` if it is a tarball this fallback can extract.
+pub(crate) fn detect(source: &dyn SquashSource) -> Result<Option<Format>> {
+    let format = format::sniff(&format::read_header(source)?);
+    Ok(match format {
+        Format::Tar => Some(format),
+        #[cfg(feature = "gzip")]
+        Format::Gzip => Some(format),
+        #[cfg(feature = "zstd")]
+        Format::Zstd => Some(format),
+        _ => None,
+    })
+}
+
+/// Fail if any of `options` changing what is extracted, where, or what is reported is set: this
+/// fallback honors none of them. Options only tuning how fast squashfs archives are opened and
+/// extracted are ignored.
+pub(crate) fn check_options(options: &ExtractOptions) -> Result<()> {
+    let set = [
+        (options.policy.max_dest_bytes.is_some(), "max_dest_bytes"),
+        (options.policy.check_space, "check_space"),
+        (options.io.max_bytes_per_sec.is_some(), "max_bytes_per_sec"),
+        (options.policy.overwrite != Default::default(), "overwrite"),
+        (options.shard_levels.is_some(), "shard_levels"),
+        (options.subtree.is_some(), "subtree"),
+        (options.recompress.is_some(), "recompress"),
+        (options.policy.salvage, "salvage"),
+        (options.policy.quarantine.is_some(), "quarantine"),
+        (
+            options.policy.error_classifier.is_some(),
+            "error_classifier",
+        ),
+        (options.async_filter.is_some(), "async_filter"),
+        (options.policy.strict_filter, "strict_filter"),
+        (options.policy.escape != Default::default(), "escape"),
+        (options.policy.allow_special_files, "allow_special_files"),
+        (options.io.mechanisms != Mechanisms::default(), "mechanisms"),
+        (options.observability.progress.is_some(), "progress"),
+        (options.cancel.is_some(), "cancel"),
+        (options.shutdown.is_some(), "shutdown"),
+        (options.io.ordering != Default::default(), "ordering"),
+        (options.durability.fsync != Default::default(), "durability"),
+        (
+            options.policy.permissions != Default::default(),
+            "permissions",
+        ),
+        (options.policy.preserve_ownership, "preserve_ownership"),
+        (options.policy.id_map.is_some(), "id_map"),
+        (options.policy.preserve_mtime, "preserve_mtime"),
+        (options.policy.extract_xattrs, "extract_xattrs"),
+        (
+            options.policy.metadata_errors != Default::default(),
+            "metadata_errors",
+        ),
+        (options.durability.read_only.is_some(), "read_only"),
+        (options.observability.sha256sums, "sha256sums"),
+        (options.observability.nar_hash, "nar_hash"),
+        (options.observability.digests, "digests"),
+        (options.observability.profile, "profile"),
+        (options.dry_run, "dry_run"),
+        (options.durability.resume.is_some(), "resume"),
+        (options.durability.atomic, "atomic"),
+        #[cfg(feature = "sqlite")]
+        (options.observability.catalog.is_some(), "catalog"),
+    ];
+    let unsupported: Vec<_> = set
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect();
+    anyhow::ensure!(
+        unsupported.is_empty(),
+        "options not supported for tar archives: {}",
+        unsupported.join(", ")
+    );
+    Ok(())
+}
+
+/// Extract a tarball into `dest`, honoring `filter` and the modes the squashfs extractors set.
+pub(crate) fn extract(
+    source: Arc<dyn SquashSource>,
+    format: Format,
+    dest: &Path,
+    filter: &Filter,
+) -> Result<ExtractReport> {
+    let name = source.name();
+    tracing::warn!(source = %name, "not a squashfs archive, extracting it as {format}");
+    let file = std::io::BufReader::new(SourceReader::new(source));
+    let reader: Box<dyn Read> = match format {
+        #[cfg(feature = "gzip")]
+        Format::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+        #[cfg(feature = "zstd")]
+        Format::Zstd => Box::new(
+            zstd::Decoder::with_buffer(file)
+                .with_context(|| format!("open zstd stream '{name}'"))?,
+        ),
+        _ => Box::new(file),
+    };
+
+    std::fs::create_dir_all(dest).with_context(|| format!("create dir '{}'", dest.display()))?;
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive
+        .entries()
+        .with_context(|| format!("read tar archive '{name}'"))?;
+    for entry in entries {
+        let mut entry = entry.with_context(|| format!("read tar entry in '{name}'"))?;
+        let entry_path = entry.path().context("read tar entry path")?.into_owned();
+        let fullpath: PathBuf = Path::new("/")
+            .components()
+            .chain(entry_path.components().filter(|c| *c != Component::CurDir))
+            .collect();
+        if !filter.matches(&fullpath) {
+            continue;
+        }
+
+        let mode = match entry.header().entry_type() {
+            tar::EntryType::Regular => Some(0o644),
+            tar::EntryType::Directory => Some(0o755),
+            tar::EntryType::Symlink => None,
+            other => {
+                tracing::warn!(path = %fullpath.display(), "skipping tar entry of type {other:?}");
+                continue;
+            }
+        };
+        let unpacked = entry.unpack_in(dest).with_context(|| {
+            format!("unpack '{}' into '{}'", fullpath.display(), dest.display())
+        })?;
+        if let Some(mode) = mode.filter(|_| unpacked) {
+            let dest_path = dest.join(fullpath.strip_prefix("/").unwrap_or(&fullpath));
+            platform::set_mode(&dest_path, mode)
+                .with_context(|| format!("chmod {mode:#o} '{}'", dest_path.display()))?;
+        }
+    }
+
+    Ok(ExtractReport::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IoOptions, PolicyOptions};
+
+    #[test]
+    fn rejects_options_it_ignores() {
+        let dir = tempfile::tempdir().unwrap();
+        let tarball = dir.path().join("archive.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&tarball).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o600);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "file", &b"data"[..])
+            .unwrap();
+        builder.into_inner().unwrap();
+
+        let dest = dir.path().join("dest");
+        let options = ExtractOptions {
+            policy: PolicyOptions {
+                max_dest_bytes: Some(1),
+                preserve_mtime: true,
+                ..PolicyOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        let err = crate::unsquash_blocking(&tarball, &dest, Filter::All, options).unwrap_err();
+        let err = format!("{err:#}");
+        assert!(err.contains("max_dest_bytes, preserve_mtime"), "{err}");
+
+        let options = ExtractOptions {
+            io: IoOptions {
+                concurrency: Some(2),
+                ..IoOptions::default()
+            },
+            ..ExtractOptions::default()
+        };
+        crate::unsquash_blocking(&tarball, &dest, Filter::All, options).unwrap();
+        assert_eq!(std::fs::read(dest.join("file")).unwrap(), b"data");
+    }
+}
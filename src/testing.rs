@@ -0,0 +1,95 @@
+//! Archives built by the tests, in temporary directories along with the destinations they are
+//! extracted to.
+
+use std::{
+    fs::File,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use backhand::{FilesystemWriter, NodeHeader};
+use tempfile::TempDir;
+
+/// An entry of a [`TestArchive`], at its path in the archive without the leading `/`.
+pub(crate) enum Entry {
+    Dir(&'static str),
+    File(&'static str, Vec<u8>),
+    Symlink(&'static str, &'static str),
+    /// A character device, by its device number in the kernel's `new_encode_dev` layout.
+    CharDevice(&'static str, u32),
+}
+
+pub(crate) fn dir(path: &'static str) -> Entry {
+    Entry::Dir(path)
+}
+
+pub(crate) fn file(path: &'static str, contents: impl Into<Vec<u8>>) -> Entry {
+    Entry::File(path, contents.into())
+}
+
+pub(crate) fn symlink(path: &'static str, target: &'static str) -> Entry {
+    Entry::Symlink(path, target)
+}
+
+pub(crate) fn char_device(path: &'static str, device_number: u32) -> Entry {
+    Entry::CharDevice(path, device_number)
+}
+
+/// A squashfs archive of the given entries, with their parent directories added as needed,
+/// written into a temporary directory removed when it is dropped.
+pub(crate) struct TestArchive {
+    dir: TempDir,
+    path: PathBuf,
+}
+
+impl TestArchive {
+    pub(crate) fn new(entries: Vec<Entry>) -> Self {
+        let dir = tempfile::tempdir().expect("create temporary directory");
+        let path = dir.path().join("archive.squashfs");
+        let (dir_header, file_header) = (
+            NodeHeader::new(0o755, 0, 0, 0),
+            NodeHeader::new(0o644, 0, 0, 0),
+        );
+        let mut writer = FilesystemWriter::default();
+        writer.set_root_mode(0o755);
+        let push_parent = |writer: &mut FilesystemWriter, path: &str| {
+            let parent = Path::new("/").join(path);
+            let parent = parent.parent().expect("entry path has a parent");
+            if parent != Path::new("/") {
+                writer
+                    .push_dir_all(parent, dir_header)
+                    .expect("add parent dirs");
+            }
+        };
+        for entry in entries {
+            match entry {
+                Entry::Dir(path) => writer.push_dir_all(path, dir_header),
+                Entry::File(path, contents) => {
+                    push_parent(&mut writer, path);
+                    writer.push_file(Cursor::new(contents), path, file_header)
+                }
+                Entry::Symlink(path, target) => {
+                    push_parent(&mut writer, path);
+                    writer.push_symlink(target, path, NodeHeader::new(0o777, 0, 0, 0))
+                }
+                Entry::CharDevice(path, device_number) => {
+                    push_parent(&mut writer, path);
+                    writer.push_char_device(device_number, path, file_header)
+                }
+            }
+            .expect("add entry");
+        }
+        let out = File::create(&path).expect("create archive");
+        writer.write(out).expect("write archive");
+        Self { dir, path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A path next to the archive, e.g. for a destination, which does not exist yet.
+    pub(crate) fn scratch(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    future::Future,
+    io::Write,
+    pin::Pin,
+    sync::{Mutex, PoisonError},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{io::AsyncWrite, time::Sleep};
+
+/// A token bucket capping the bytes written by every entry of an extraction, in
+/// [`IoOptions::max_bytes_per_sec`](crate::IoOptions::max_bytes_per_sec). Up to a
+/// second's worth of bytes can be written in a burst.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that can be written without waiting, negative when writers owe some.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `n` bytes out of the bucket, returning how long to wait before writing more.
+    fn take(&self, n: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * self.bytes_per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec) - n as f64;
+        bucket.refilled = now;
+        match bucket.tokens < 0.0 {
+            true => Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Waits after writes through it for as long as its [`Throttle`], if any, requires.
+pub(crate) struct Throttled<'a, W> {
+    inner: W,
+    throttle: Option<&'a Throttle>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<'a, W> Throttled<'a, W> {
+    pub(crate) fn new(inner: W, throttle: Option<&'a Throttle>) -> Self {
+        Self {
+            inner,
+            throttle,
+            delay: None,
+        }
+    }
+
+    /// Wait for the delay owed for previous writes.
+    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<W: Write> Write for Throttled<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(throttle) = self.throttle {
+            std::thread::sleep(throttle.take(n));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttled<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_delay(cx));
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(throttle)) = (&res, this.throttle) {
+            let wait = throttle.take(*n);
+            if !wait.is_zero() {
+                this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_delay(cx));
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    io::{self, BufWriter, Write},
+    path::{Component, Path},
+};
+
+use anyhow::{Context, Result};
+use backhand::InnerNode;
+use tar::{EntryType, Header};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    async_file::{Sender, CHUNKS_AHEAD, CHUNK_LEN},
+    Archive, Error, ExtractOptions, Filter,
+};
+
+/// Stream the entries of the archive at `squashfs` selected by `filter` into a tar archive
+/// written to `out`, with their modes, ownership, mtimes and symlink targets, without writing
+/// anything to disk. Sockets, which tar cannot hold, are left out. The archive is read with the
+/// `kind`, `parsing` and `direct_io` of `options`.
+pub fn to_tar_blocking(
+    squashfs: impl AsRef<Path>,
+    out: impl Write,
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<(), Error> {
+    Archive::open(squashfs, options)?.to_tar_blocking(out, &filter)
+}
+
+/// Async flavor of [`to_tar_blocking`].
+pub async fn to_tar_async(
+    squashfs: impl AsRef<Path>,
+    out: &mut (impl AsyncWrite + Unpin),
+    filter: Filter,
+    options: ExtractOptions,
+) -> Result<(), Error> {
+    let archive = Archive::open_async(squashfs, options).await?;
+    archive.to_tar_async(out, filter).await
+}
+
+impl Archive {
+    /// Like [`to_tar_blocking`].
+    pub fn to_tar_blocking(&self, out: impl Write, filter: &Filter) -> Result<(), Error> {
+        Ok(write(&self.expanded()?, out, filter)?)
+    }
+
+    /// Like [`to_tar_async`]. The tar archive is built on tokio's blocking thread pool, a bounded
+    /// number of chunks ahead of `out`.
+    pub async fn to_tar_async(
+        &self,
+        out: &mut (impl AsyncWrite + Unpin),
+        filter: Filter,
+    ) -> Result<(), Error> {
+        let (tx, mut chunks) = mpsc::channel(CHUNKS_AHEAD);
+        let archive = self.expanded()?.into_owned();
+        let builder = tokio::task::spawn_blocking(move || {
+            write(
+                &archive,
+                BufWriter::with_capacity(CHUNK_LEN, Sender(&tx)),
+                &filter,
+            )
+        });
+        while let Some(chunk) = chunks.recv().await {
+            let written = match chunk {
+                Ok(chunk) => out.write_all(&chunk).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                // stops the builder at its next chunk
+                drop(chunks);
+                let _ = builder.await;
+                return Err(anyhow::Error::new(e).context("write tar archive").into());
+            }
+        }
+        builder.await.context("spawn blocking tar task")??;
+        out.flush().await.context("flush tar archive")?;
+        Ok(())
+    }
+}
+
+fn write(archive: &Archive, out: impl Write, filter: &Filter) -> Result<()> {
+    let filesystem = &archive.filesystem;
+    let mut builder = tar::Builder::new(out);
+    let nodes = filesystem
+        .files()
+        .filter(|node| filter.matches(&node.fullpath));
+    for node in nodes {
+        let path = node
+            .fullpath
+            .strip_prefix(Component::RootDir)
+            .unwrap_or(&node.fullpath);
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        let mut header = Header::new_gnu();
+        header.set_mode(u32::from(node.header.permissions & 0o7777));
+        header.set_uid(node.header.uid.into());
+        header.set_gid(node.header.gid.into());
+        header.set_mtime(node.header.mtime.into());
+        header.set_size(0);
+        let appended = match &node.inner {
+            InnerNode::File(file) => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(file.basic.file_size.into());
+                let reader = filesystem.file(&file.basic).reader();
+                builder.append_data(&mut header, path, reader)
+            }
+            InnerNode::Symlink(symlink) => {
+                header.set_entry_type(EntryType::Symlink);
+                builder.append_link(&mut header, path, &symlink.link)
+            }
+            InnerNode::Dir(_) => {
+                header.set_entry_type(EntryType::Directory);
+                builder.append_data(&mut header, path, io::empty())
+            }
+            InnerNode::CharacterDevice(dev) => {
+                header.set_entry_type(EntryType::Char);
+                device(&mut header, dev.device_number)?;
+                builder.append_data(&mut header, path, io::empty())
+            }
+            InnerNode::BlockDevice(dev) => {
+                header.set_entry_type(EntryType::Block);
+                device(&mut header, dev.device_number)?;
+                builder.append_data(&mut header, path, io::empty())
+            }
+            InnerNode::NamedPipe => {
+                header.set_entry_type(EntryType::Fifo);
+                builder.append_data(&mut header, path, io::empty())
+            }
+            InnerNode::Socket => {
+                tracing::debug!(path = %node.fullpath.display(), "leaving socket out of tar archive");
+                continue;
+            }
+        };
+        appended.with_context(|| format!("add '{}' to tar archive", node.fullpath.display()))?;
+    }
+    let mut out = builder.into_inner().context("finish tar archive")?;
+    out.flush().context("flush tar archive")
+}
+
+/// Set the device number, stored by squashfs in the kernel's `new_encode_dev` layout.
+fn device(header: &mut Header, rdev: u32) -> io::Result<()> {
+    header.set_device_major((rdev & 0xfff00) >> 8)?;
+    header.set_device_minor((rdev & 0xff) | ((rdev >> 12) & 0xfff00))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io::Read, path::PathBuf};
+
+    use super::*;
+    use crate::testing::{self, TestArchive};
+
+    /// The type, mode, contents and symlink target of each entry of the tar archive `tar`.
+    fn entries(tar: &[u8]) -> BTreeMap<PathBuf, (EntryType, u32, Vec<u8>, Option<PathBuf>)> {
+        let mut archive = tar::Archive::new(tar);
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().into_owned();
+                let header = entry.header();
+                let (kind, mode) = (header.entry_type(), header.mode().unwrap());
+                let target = entry.link_name().unwrap().map(|target| target.into_owned());
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                (path, (kind, mode, contents, target))
+            })
+            .collect()
+    }
+
+    fn archive() -> TestArchive {
+        TestArchive::new(vec![
+            testing::file("a/b", "hello"),
+            testing::file("big", vec![7; 300_000]),
+            testing::dir("empty"),
+            testing::symlink("a/link", "b"),
+        ])
+    }
+
+    #[test]
+    fn writes_readable_tar() {
+        let archive = archive();
+        let mut tar = Vec::new();
+        to_tar_blocking(
+            archive.path(),
+            &mut tar,
+            Filter::All,
+            ExtractOptions::default(),
+        )
+        .unwrap();
+        let entries = entries(&tar);
+        let dir = (EntryType::Directory, 0o755, Vec::new(), None);
+        assert_eq!(
+            entries,
+            BTreeMap::from([
+                ("a".into(), dir.clone()),
+                (
+                    "a/b".into(),
+                    (EntryType::Regular, 0o644, b"hello".to_vec(), None)
+                ),
+                (
+                    "a/link".into(),
+                    (EntryType::Symlink, 0o777, Vec::new(), Some("b".into()))
+                ),
+                (
+                    "big".into(),
+                    (EntryType::Regular, 0o644, vec![7; 300_000], None)
+                ),
+                ("empty".into(), dir),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_selected_entries_async() {
+        let archive = archive();
+        let mut tar = Vec::new();
+        to_tar_async(
+            archive.path(),
+            &mut tar,
+            Filter::Prefix("/a".into()),
+            ExtractOptions::default(),
+        )
+        .await
+        .unwrap();
+        let paths: Vec<_> = entries(&tar).into_keys().collect();
+        assert_eq!(paths, [PathBuf::from("a"), "a/b".into(), "a/link".into()]);
+    }
+}
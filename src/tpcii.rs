@@ -0,0 +1,292 @@
+//! Extracting the index and salts of tpcii archives, selected by crate name, and looking crates
+//! up without extracting anything.
+
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+};
+
+pub use crate::{
+    async_unsquash::{
+        unsquash_tpcii_async, unsquash_tpcii_async_from_source, unsquash_tpcii_async_with_kind,
+        unsquash_tpcii_async_with_options,
+    },
+    cpio::unsquash_tpcii_to_cpio,
+    erofs::unsquash_tpcii_to_erofs,
+    prefetch::prefetch_tpcii,
+    unsquash_tpcii_blocking, unsquash_tpcii_blocking_from_source,
+    unsquash_tpcii_blocking_with_kind, unsquash_tpcii_blocking_with_options,
+};
+use crate::{shard, Archive, EntryInfo, Error, ExtractOptions, Filter};
+
+/// Where [`extract_crate_blocking`] extracted the files of a crate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrateArtifacts {
+    /// The index entry of the crate, `index/<name>` under the destination by default.
+    pub index: PathBuf,
+    /// The salt of the crate, if the archive has one.
+    pub salt: Option<PathBuf>,
+}
+
+/// Extract the index entry and salt of the crate `name`, its path under `/index` such as
+/// `se/rd/serde`, from the tpcii archive `squashfs` into `dest`, returning where they were
+/// extracted. Fails with [`Error::CrateNotFound`] if the
+/// archive has no index entry for the crate, rather than extracting nothing.
+pub fn extract_crate_blocking(
+    squashfs: impl AsRef<Path>,
+    name: &str,
+    dest: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<CrateArtifacts, Error> {
+    Archive::open(squashfs, options.clone())?.extract_crate_blocking(name, dest, options)
+}
+
+/// Async flavor of [`extract_crate_blocking`].
+pub async fn extract_crate_async(
+    squashfs: impl AsRef<Path>,
+    name: &str,
+    dest: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<CrateArtifacts, Error> {
+    let archive = Archive::open_async(squashfs, options.clone()).await?;
+    archive.extract_crate_async(name, dest, options).await
+}
+
+impl Archive {
+    /// Like [`extract_crate_blocking`], for an archive already opened.
+    pub fn extract_crate_blocking(
+        &self,
+        name: &str,
+        dest: impl AsRef<Path>,
+        options: ExtractOptions,
+    ) -> Result<CrateArtifacts, Error> {
+        let dest = dest.as_ref();
+        let artifacts = self.crate_artifacts(name, dest, &options)?;
+        self.extract_blocking(dest, crate_filter(name), options)?;
+        Ok(artifacts)
+    }
+
+    /// Async flavor of [`extract_crate_blocking`](Self::extract_crate_blocking).
+    pub async fn extract_crate_async(
+        &self,
+        name: &str,
+        dest: impl AsRef<Path>,
+        options: ExtractOptions,
+    ) -> Result<CrateArtifacts, Error> {
+        let dest = dest.as_ref();
+        let artifacts = self.crate_artifacts(name, dest, &options)?;
+        self.extract_async(dest, crate_filter(name), options)
+            .await?;
+        Ok(artifacts)
+    }
+
+    /// Where extracting the crate `name` into `dest` with `options` puts its files.
+    fn crate_artifacts(
+        &self,
+        name: &str,
+        dest: &Path,
+        options: &ExtractOptions,
+    ) -> Result<CrateArtifacts, Error> {
+        let archive = self.expanded()?;
+        let dest_path = |dir: &str| {
+            let node = archive.node(&Path::new(dir).join(name))?;
+            let subtree = options.subtree.as_deref();
+            shard::dest_path(
+                dest,
+                node,
+                options.shard_levels,
+                options.recompress,
+                subtree,
+            )
+        };
+        let index = match is_crate_name(name) {
+            true => dest_path("/index"),
+            false => None,
+        };
+        let index = index.ok_or_else(|| Error::CrateNotFound(name.to_owned()))?;
+        Ok(CrateArtifacts {
+            index,
+            salt: dest_path("/salts"),
+        })
+    }
+}
+
+fn crate_filter(name: &str) -> Filter {
+    crate::tpcii_filter(Some(HashSet::from([name.to_owned()])))
+}
+
+/// Whether `name` is a relative path of plain components, as crate names, their paths under
+/// `/index`, are.
+fn is_crate_name(name: &str) -> bool {
+    let mut components = Path::new(name).components().peekable();
+    components.peek().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Whether the tpcii `archive` has an index entry or a salt for the crate `name`, looked up in
+/// its directory tables.
+pub fn contains_crate(archive: &Archive, name: &str) -> bool {
+    crate_metadata(archive, name).is_some()
+}
+
+/// The index entry of the crate `name` in the tpcii `archive`, or its salt if it has no index
+/// entry. Names that are not a relative path of plain components are never found.
+pub fn crate_metadata(archive: &Archive, name: &str) -> Option<EntryInfo> {
+    if !is_crate_name(name) {
+        return None;
+    }
+    ["/index", "/salts"]
+        .into_iter()
+        .find_map(|dir| archive.find(Path::new(dir).join(name)))
+        .map(|entry| entry.to_info())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestArchive};
+
+    fn archive() -> TestArchive {
+        TestArchive::new(vec![
+            testing::file("index/se/rd/serde", "serde index"),
+            testing::file("salts/se/rd/serde", "serde salt"),
+            testing::file("index/se/rd/serde_json", "serde_json index"),
+            testing::file("salts/se/rd/serde_json", "serde_json salt"),
+            testing::file("index/an/yh/anyhow", "anyhow index"),
+            testing::file("salts/3/l/log", "log salt"),
+        ])
+    }
+
+    /// The files under `dir`, relative to it and sorted.
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        fn walk(root: &Path, dir: &Path, out: &mut Vec<PathBuf>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                match path.is_dir() {
+                    true => walk(root, &path, out),
+                    false => out.push(path.strip_prefix(root).unwrap().to_path_buf()),
+                }
+            }
+        }
+        let mut files = Vec::new();
+        walk(dir, dir, &mut files);
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn extracts_one_crate() {
+        let archive = archive();
+        let dest = archive.scratch("dest");
+        let artifacts = extract_crate_blocking(
+            archive.path(),
+            "se/rd/serde",
+            &dest,
+            ExtractOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            artifacts,
+            CrateArtifacts {
+                index: dest.join("index/se/rd/serde"),
+                salt: Some(dest.join("salts/se/rd/serde")),
+            }
+        );
+        assert_eq!(std::fs::read(&artifacts.index).unwrap(), b"serde index");
+        assert_eq!(
+            std::fs::read(artifacts.salt.unwrap()).unwrap(),
+            b"serde salt"
+        );
+        // not the crates next to it, nor those whose names it is a prefix of
+        assert_eq!(
+            files(&dest),
+            [
+                PathBuf::from("index/se/rd/serde"),
+                PathBuf::from("salts/se/rd/serde")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn extracts_crates_without_salt() {
+        let archive = archive();
+        let dest = archive.scratch("dest");
+        let artifacts = extract_crate_async(
+            archive.path(),
+            "an/yh/anyhow",
+            &dest,
+            ExtractOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            artifacts,
+            CrateArtifacts {
+                index: dest.join("index/an/yh/anyhow"),
+                salt: None,
+            }
+        );
+        assert_eq!(std::fs::read(&artifacts.index).unwrap(), b"anyhow index");
+        assert_eq!(files(&dest), [PathBuf::from("index/an/yh/anyhow")]);
+    }
+
+    #[test]
+    fn places_artifacts_under_the_subtree() {
+        let archive = archive();
+        let dest = archive.scratch("dest");
+        let options = ExtractOptions {
+            subtree: Some("/index".into()),
+            ..ExtractOptions::default()
+        };
+        let artifacts =
+            extract_crate_blocking(archive.path(), "se/rd/serde", &dest, options).unwrap();
+        assert_eq!(artifacts.index, dest.join("index/se/rd/serde"));
+        // the salt is outside of the subtree, so not extracted
+        assert_eq!(artifacts.salt, None);
+        assert_eq!(files(&dest), [PathBuf::from("index/se/rd/serde")]);
+    }
+
+    #[test]
+    fn fails_on_missing_crates_without_extracting() {
+        let archive = archive();
+        let opened = Archive::open(archive.path(), ExtractOptions::default()).unwrap();
+        let dest = archive.scratch("dest");
+        // a salt without an index entry is not enough to extract
+        for name in [
+            "missing",
+            "se/rd/serd",
+            "serde",
+            "../index/se/rd/serde",
+            "/se/rd/serde",
+            "",
+            "3/l/log",
+        ] {
+            let err = opened
+                .extract_crate_blocking(name, &dest, ExtractOptions::default())
+                .unwrap_err();
+            assert!(
+                matches!(&err, Error::CrateNotFound(found) if found == name),
+                "{name}: {err}"
+            );
+        }
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn looks_crates_up() {
+        let archive = archive();
+        let opened = Archive::open(archive.path(), ExtractOptions::default()).unwrap();
+        assert!(contains_crate(&opened, "se/rd/serde"));
+        assert!(!contains_crate(&opened, "serde"));
+        assert!(!contains_crate(&opened, "../index/se/rd/serde"));
+        assert_eq!(
+            crate_metadata(&opened, "an/yh/anyhow").map(|entry| entry.path),
+            Some(PathBuf::from("/index/an/yh/anyhow"))
+        );
+        // found by its salt
+        assert_eq!(
+            crate_metadata(&opened, "3/l/log").map(|entry| entry.path),
+            Some(PathBuf::from("/salts/3/l/log"))
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use backhand::{InnerNode, Node, SquashfsFileReader};
+use tracing::Span;
+
+use crate::{metrics, EntryKind};
+
+// Spans and events of an extraction, only emitted with the `trace` feature. The warnings the crate
+// always logs are not affected.
+const ENABLED: bool = cfg!(feature = "trace");
+
+/// Span of opening the archive read from `source`.
+pub(crate) fn open(source: &str) -> Span {
+    match ENABLED {
+        true => tracing::info_span!("open_archive", source),
+        false => Span::none(),
+    }
+}
+
+/// Record how many inodes the opened archive has and how long parsing it took.
+pub(crate) fn opened(inodes: usize, elapsed: Duration) {
+    if ENABLED {
+        tracing::debug!(inodes, elapsed_ms = millis(elapsed), "opened archive");
+    }
+}
+
+/// Span of extracting into `dest`.
+pub(crate) fn extract(dest: &Path) -> Span {
+    match ENABLED {
+        true => tracing::info_span!("extract", dest = %dest.display()),
+        false => Span::none(),
+    }
+}
+
+/// Record how many of the `total` entries of the archive the filter selected, and how many of
+/// those are left to extract.
+pub(crate) fn filtered(total: usize, selected: usize, extracting: usize) {
+    if ENABLED {
+        tracing::debug!(total, selected, extracting, "filtered entries");
+    }
+}
+
+/// Record that `entries` are about to be extracted, up to `concurrency` at once.
+pub(crate) fn started(entries: usize, concurrency: usize) {
+    if ENABLED {
+        tracing::debug!(entries, concurrency, "extracting entries");
+    }
+}
+
+/// Counts the entries being extracted at once, and the most that were.
+#[derive(Debug, Default)]
+pub(crate) struct InFlight {
+    running: AtomicUsize,
+    high_water: AtomicUsize,
+}
+
+/// Counted by [`InFlight`] until dropped.
+pub(crate) struct Task<'a>(&'a InFlight);
+
+impl InFlight {
+    /// Start extracting the entry of `span`, recording how many are being extracted now.
+    pub(crate) fn start(&self, span: &Span) -> Task<'_> {
+        let in_flight = self.running.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water.fetch_max(in_flight, Ordering::Relaxed);
+        span.record("in_flight", in_flight);
+        Task(self)
+    }
+
+    /// Report the most entries that were extracted at once.
+    pub(crate) fn finish(&self) {
+        let high_water = self.high_water.load(Ordering::Relaxed);
+        metrics::with(|metrics| metrics.concurrency_high_water(high_water));
+        if ENABLED {
+            tracing::debug!(high_water, "extracted entries");
+        }
+    }
+}
+
+impl Drop for Task<'_> {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Span of extracting `node`, within `parent` since the entry may run on another thread.
+pub(crate) fn entry(parent: &Span, node: &Node<SquashfsFileReader>) -> Span {
+    match ENABLED {
+        true => tracing::debug_span!(
+            parent: parent,
+            "extract_entry",
+            path = %node.fullpath.display(),
+            kind = %EntryKind::of(&node.inner),
+            in_flight = tracing::field::Empty,
+        ),
+        false => Span::none(),
+    }
+}
+
+/// Record the outcome of extracting `node`, with the bytes it holds.
+pub(crate) fn entry_done(
+    node: &Node<SquashfsFileReader>,
+    res: &anyhow::Result<()>,
+    elapsed: Duration,
+    attempts: u32,
+) {
+    if !ENABLED {
+        return;
+    }
+    let bytes = match &node.inner {
+        InnerNode::File(file) => u64::from(file.basic.file_size),
+        _ => 0,
+    };
+    let elapsed_ms = millis(elapsed);
+    match res {
+        Ok(()) => tracing::debug!(bytes, elapsed_ms, attempts, "extracted entry"),
+        Err(e) => tracing::debug!(
+            bytes,
+            elapsed_ms,
+            attempts,
+            "failed to extract entry: {e:#}"
+        ),
+    }
+}
+
+fn millis(elapsed: Duration) -> u64 {
+    u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+}
@@ -0,0 +1,49 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use backhand::FilesystemWriter;
+
+use crate::{compression::FilesystemCompressor, Parsing};
+
+/// Rewrite the archive at `src` into `dst` using `compressor`, streaming every entry straight from
+/// the source without an intermediate directory. The block size, ownership, modes and mtimes of
+/// the source are kept.
+///
+/// Note that backhand's writer compresses blocks one at a time, so the block decoding workers do
+/// not apply to the compression side.
+pub fn transcode(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    compressor: FilesystemCompressor,
+) -> Result<()> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    anyhow::ensure!(
+        src.exists(),
+        "specified squashfs archive does not exist: '{}'",
+        src.display(),
+    );
+
+    let filesystem = crate::open_filesystem_path(src, None, Parsing::Strict)?;
+    let mut writer = FilesystemWriter::from_fs_reader(&filesystem)
+        .with_context(|| format!("read squashfs '{}' for transcoding", src.display()))?;
+    writer.set_compressor(compressor);
+
+    let mut tmp = dst.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    let res = std::fs::File::create(tmp)
+        .with_context(|| format!("create '{}'", tmp.display()))
+        .and_then(|file| {
+            writer
+                .write(std::io::BufWriter::new(file))
+                .with_context(|| format!("write squashfs '{}'", tmp.display()))
+        })
+        .and_then(|_| {
+            std::fs::rename(tmp, dst)
+                .with_context(|| format!("rename '{}' to '{}'", tmp.display(), dst.display()))
+        });
+    if res.is_err() {
+        let _ = std::fs::remove_file(tmp);
+    }
+    res
+}
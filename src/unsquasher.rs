@@ -0,0 +1,382 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::{
+    cancel::CancelToken, classify::ErrorClassifier, compression::Kind, confine::EscapePolicy,
+    filter::AsyncFilter, mechanisms::Mechanisms, progress::Progress, protect::ReadOnly,
+    recompress::Recompress, runtime::Runtime, source::SquashSource, Durability, Error,
+    ExtractOptions, ExtractReport, Filter, HashAlgorithm, IdMap, MetadataErrorPolicy, Ordering,
+    OverwritePolicy, Parsing, PermissionPolicy, QuotaPolicy, Strategy,
+};
+
+/// Builder for an extraction, collecting the archive, destination, [`Filter`] and
+/// [`ExtractOptions`] in one place.
+#[derive(Debug, Clone)]
+pub struct Unsquasher {
+    archive: Archive,
+    dest: PathBuf,
+    filter: Filter,
+    options: ExtractOptions,
+}
+
+#[derive(Clone)]
+enum Archive {
+    Path(PathBuf),
+    Source(Arc<dyn SquashSource>),
+    Opened(crate::Archive),
+}
+
+impl std::fmt::Debug for Archive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            Self::Source(source) => f.debug_tuple("Source").field(&source.name()).finish(),
+            Self::Opened(archive) => f.debug_tuple("Opened").field(archive).finish(),
+        }
+    }
+}
+
+impl Unsquasher {
+    /// Extract the squashfs archive at `squashfs` into `dest`.
+    pub fn new(squashfs: impl AsRef<Path>, dest: impl AsRef<Path>) -> Self {
+        Self::with_archive(Archive::Path(squashfs.as_ref().to_path_buf()), dest)
+    }
+
+    /// Extract the archive read from `source` into `dest`.
+    pub fn from_source(source: impl SquashSource + 'static, dest: impl AsRef<Path>) -> Self {
+        Self::with_archive(Archive::Source(Arc::new(source)), dest)
+    }
+
+    /// Extract from an [`Archive`](crate::Archive) opened already, whose `kind`, `parsing` and
+    /// `direct_io` were fixed when opening it.
+    pub fn from_archive(archive: crate::Archive, dest: impl AsRef<Path>) -> Self {
+        Self::with_archive(Archive::Opened(archive), dest)
+    }
+
+    fn with_archive(archive: Archive, dest: impl AsRef<Path>) -> Self {
+        Self {
+            archive,
+            dest: dest.as_ref().to_path_buf(),
+            filter: Filter::All,
+            options: ExtractOptions::default(),
+        }
+    }
+
+    pub fn filter(mut self, filter: impl Into<Filter>) -> Self {
+        self.filter = filter.into();
+        self
+    }
+
+    /// Only extract the index entries of these crates, as the `crates_filter` of
+    /// [`unsquash_tpcii_blocking`](crate::unsquash_tpcii_blocking) does.
+    pub fn crates(mut self, crates: HashSet<String>) -> Self {
+        self.filter = crate::tpcii_filter(Some(crates));
+        self
+    }
+
+    /// Replace all options at once.
+    pub fn options(mut self, options: ExtractOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn kind(mut self, kind: Kind) -> Self {
+        self.options.io.kind = Some(kind);
+        self
+    }
+
+    pub fn max_dest_bytes(mut self, max_dest_bytes: u64, policy: QuotaPolicy) -> Self {
+        self.options.policy.max_dest_bytes = Some(max_dest_bytes);
+        self.options.policy.quota_policy = policy;
+        self
+    }
+
+    pub fn check_space(mut self, check_space: bool) -> Self {
+        self.options.policy.check_space = check_space;
+        self
+    }
+
+    pub fn max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+        self.options.io.max_bytes_per_sec = Some(max_bytes_per_sec);
+        self
+    }
+
+    pub fn overwrite(mut self, overwrite: OverwritePolicy) -> Self {
+        self.options.policy.overwrite = overwrite;
+        self
+    }
+
+    pub fn shard_levels(mut self, levels: u8) -> Self {
+        self.options.shard_levels = Some(levels);
+        self
+    }
+
+    pub fn subtree(mut self, subtree: impl AsRef<Path>) -> Self {
+        self.options.subtree = Some(subtree.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn recompress(mut self, recompress: Recompress) -> Self {
+        self.options.recompress = Some(recompress);
+        self
+    }
+
+    pub fn salvage(mut self, salvage: bool) -> Self {
+        self.options.policy.salvage = salvage;
+        self
+    }
+
+    pub fn quarantine(mut self, quarantine: impl AsRef<Path>) -> Self {
+        self.options.policy.quarantine = Some(quarantine.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn error_classifier(mut self, error_classifier: ErrorClassifier) -> Self {
+        self.options.policy.error_classifier = Some(error_classifier);
+        self
+    }
+
+    pub fn strict_filter(mut self, strict_filter: bool) -> Self {
+        self.options.policy.strict_filter = strict_filter;
+        self
+    }
+
+    pub fn async_filter(mut self, async_filter: AsyncFilter) -> Self {
+        self.options.async_filter = Some(async_filter);
+        self
+    }
+
+    pub fn runtime(mut self, runtime: impl Runtime) -> Self {
+        self.options.io.runtime = Some(Arc::new(runtime));
+        self
+    }
+
+    pub fn parsing(mut self, parsing: Parsing) -> Self {
+        self.options.policy.parsing = parsing;
+        self
+    }
+
+    /// Ignored for archives given with [`from_source`](Self::from_source).
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.options.io.direct_io = direct_io;
+        self
+    }
+
+    /// Ignored for archives given with [`from_source`](Self::from_source).
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.options.io.mmap = mmap;
+        self
+    }
+
+    pub fn mechanisms(mut self, mechanisms: Mechanisms) -> Self {
+        self.options.io.mechanisms = mechanisms;
+        self
+    }
+
+    pub fn progress(mut self, progress: Arc<Progress>) -> Self {
+        self.options.observability.progress = Some(progress);
+        self
+    }
+
+    pub fn cancel(mut self, cancel: CancelToken) -> Self {
+        self.options.cancel = Some(cancel);
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown: Arc<AtomicBool>) -> Self {
+        self.options.shutdown = Some(shutdown);
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.options.io.concurrency = Some(concurrency);
+        self
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.options.io.strategy = strategy;
+        self
+    }
+
+    pub fn block_decode_workers(mut self, workers: usize) -> Self {
+        self.options.io.block_decode_workers = Some(workers);
+        self
+    }
+
+    pub fn fragment_cache_blocks(mut self, blocks: usize) -> Self {
+        self.options.io.fragment_cache_blocks = Some(blocks);
+        self
+    }
+
+    pub fn ordering(mut self, ordering: Ordering) -> Self {
+        self.options.io.ordering = ordering;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.options.durability.fsync = durability;
+        self
+    }
+
+    pub fn write_buffer(mut self, write_buffer: usize) -> Self {
+        self.options.io.write_buffer = Some(write_buffer);
+        self
+    }
+
+    pub fn read_buffer(mut self, read_buffer: usize) -> Self {
+        self.options.io.read_buffer = Some(read_buffer);
+        self
+    }
+
+    pub fn permissions(mut self, permissions: PermissionPolicy) -> Self {
+        self.options.policy.permissions = permissions;
+        self
+    }
+
+    /// Shorthand for [`PermissionPolicy::Preserve`] with the archive's ownership and mtimes.
+    pub fn preserve_permissions(self) -> Self {
+        self.permissions(PermissionPolicy::Preserve)
+            .preserve_ownership(true)
+            .preserve_mtime(true)
+    }
+
+    pub fn preserve_ownership(mut self, preserve_ownership: bool) -> Self {
+        self.options.policy.preserve_ownership = preserve_ownership;
+        self
+    }
+
+    pub fn id_map(mut self, id_map: IdMap) -> Self {
+        self.options.policy.id_map = Some(id_map);
+        self
+    }
+
+    pub fn preserve_mtime(mut self, preserve_mtime: bool) -> Self {
+        self.options.policy.preserve_mtime = preserve_mtime;
+        self
+    }
+
+    pub fn extract_xattrs(mut self, extract_xattrs: bool) -> Self {
+        self.options.policy.extract_xattrs = extract_xattrs;
+        self
+    }
+
+    pub fn metadata_errors(mut self, metadata_errors: MetadataErrorPolicy) -> Self {
+        self.options.policy.metadata_errors = metadata_errors;
+        self
+    }
+
+    pub fn slow_entry_threshold(mut self, threshold: Duration) -> Self {
+        self.options.observability.slow_entry_threshold = Some(threshold);
+        self
+    }
+
+    pub fn escape(mut self, escape: EscapePolicy) -> Self {
+        self.options.policy.escape = escape;
+        self
+    }
+
+    pub fn allow_special_files(mut self, allow_special_files: bool) -> Self {
+        self.options.policy.allow_special_files = allow_special_files;
+        self
+    }
+
+    pub fn sha256sums(mut self, sha256sums: bool) -> Self {
+        self.options.observability.sha256sums = sha256sums;
+        self
+    }
+
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+        self.options.observability.hash = hash;
+        self
+    }
+
+    pub fn nar_hash(mut self, nar_hash: bool) -> Self {
+        self.options.observability.nar_hash = nar_hash;
+        self
+    }
+
+    pub fn digests(mut self, digests: bool) -> Self {
+        self.options.observability.digests = digests;
+        self
+    }
+
+    pub fn profile(mut self, profile: bool) -> Self {
+        self.options.observability.profile = profile;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    pub fn resume(mut self, journal: impl AsRef<Path>) -> Self {
+        self.options.durability.resume = Some(journal.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn atomic(mut self, atomic: bool) -> Self {
+        self.options.durability.atomic = atomic;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: ReadOnly) -> Self {
+        self.options.durability.read_only = Some(read_only);
+        self
+    }
+
+    pub fn cleanup_partial(mut self, cleanup_partial: bool) -> Self {
+        self.options.durability.cleanup_partial = cleanup_partial;
+        self
+    }
+
+    #[cfg(feature = "audit")]
+    pub fn audit(mut self, sink: crate::audit::AuditSink) -> Self {
+        self.options.observability.audit = Some(sink);
+        self
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn catalog(mut self, catalog: impl AsRef<Path>) -> Self {
+        self.options.observability.catalog = Some(catalog.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn run_blocking(self) -> Result<ExtractReport, Error> {
+        match self.archive {
+            Archive::Path(path) => {
+                crate::unsquash_blocking(path, self.dest, self.filter, self.options)
+            }
+            Archive::Source(source) => {
+                crate::unsquash_blocking_from_source(source, self.dest, self.filter, self.options)
+            }
+            Archive::Opened(archive) => {
+                archive.extract_blocking(self.dest, self.filter, self.options)
+            }
+        }
+    }
+
+    pub async fn run_async(self) -> Result<ExtractReport, Error> {
+        match self.archive {
+            Archive::Path(path) => {
+                crate::unsquash_async(path, self.dest, self.filter, self.options).await
+            }
+            Archive::Source(source) => {
+                crate::unsquash_async_from_source(source, self.dest, self.filter, self.options)
+                    .await
+            }
+            Archive::Opened(archive) => {
+                archive
+                    .extract_async(self.dest, self.filter, self.options)
+                    .await
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Writing extracted file contents through io_uring, for the async extractors.
+//!
+//! tokio's [`File`](tokio::fs::File) performs every write as a blocking call on tokio's blocking
+//! thread pool, so extracting many small files costs a thread handoff and a syscall per write.
+//! With the `io-uring` feature the writes are instead handed to a thread running a tokio-uring
+//! runtime, which submits the writes of every file being extracted to a single ring. Files are
+//! still created and published by [`Mechanisms`](crate::mechanisms::Mechanisms), which need
+//! `O_TMPFILE` and `linkat` for crash consistency; only their contents go through the ring.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncSeek, AsyncWrite},
+    sync::{mpsc, oneshot},
+};
+use tokio_uring::buf::IoBuf;
+
+/// The outcome of a write, and its buffer to reuse for the next one.
+type Written = (io::Result<usize>, Vec<u8>);
+
+enum Job {
+    Open {
+        id: u64,
+        file: std::fs::File,
+    },
+    Write {
+        id: u64,
+        buf: Vec<u8>,
+        offset: u64,
+        done: oneshot::Sender<Written>,
+    },
+    Close {
+        id: u64,
+    },
+}
+
+/// The thread submitting writes to the ring.
+pub(crate) struct Ring {
+    jobs: mpsc::UnboundedSender<Job>,
+    next_id: AtomicU64,
+}
+
+impl Ring {
+    /// The ring shared by every extraction of the process, started on first use, or `None` if the
+    /// kernel does not support io_uring, in which case the extractors write with `tokio::fs`.
+    pub(crate) fn get() -> Option<&'static Ring> {
+        static RING: OnceLock<Option<Ring>> = OnceLock::new();
+        RING.get_or_init(Ring::start).as_ref()
+    }
+
+    fn start() -> Option<Ring> {
+        let (jobs, rx) = mpsc::unbounded_channel();
+        let (started_tx, started) = std::sync::mpsc::channel();
+        let spawned = std::thread::Builder::new()
+            .name("backhand-async-uring".into())
+            .spawn(move || {
+                let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        let _ = started_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = started_tx.send(Ok(()));
+                runtime.block_on(run(rx));
+            });
+        if let Err(e) = spawned {
+            tracing::warn!("cannot spawn io_uring thread, writing with tokio::fs: {e}");
+            return None;
+        }
+        match started.recv() {
+            Ok(Ok(())) => Some(Ring {
+                jobs,
+                next_id: AtomicU64::new(0),
+            }),
+            Ok(Err(e)) => {
+                tracing::warn!("cannot set up io_uring, writing with tokio::fs: {e}");
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Hand `file` to the ring, to be written through the returned [`UringFile`] from its start.
+    pub(crate) fn open(&'static self, file: std::fs::File) -> io::Result<UringFile> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stat = file.try_clone()?;
+        self.send(Job::Open { id, file })?;
+        Ok(UringFile {
+            ring: self,
+            id,
+            stat,
+            offset: 0,
+            seek_from_end: None,
+            pending: None,
+            buf: Vec::new(),
+        })
+    }
+
+    fn send(&self, job: Job) -> io::Result<()> {
+        self.jobs
+            .send(job)
+            .map_err(|_| io::Error::other("io_uring thread exited"))
+    }
+}
+
+/// Run the jobs handed to the ring thread, each write as its own task so that the writes of
+/// different files are in flight together.
+async fn run(mut jobs: mpsc::UnboundedReceiver<Job>) {
+    let mut files = HashMap::new();
+    while let Some(job) = jobs.recv().await {
+        match job {
+            Job::Open { id, file } => {
+                files.insert(id, Rc::new(tokio_uring::fs::File::from_std(file)));
+            }
+            Job::Write {
+                id,
+                buf,
+                offset,
+                done,
+            } => match files.get(&id) {
+                Some(file) => {
+                    let file = Rc::clone(file);
+                    tokio_uring::spawn(async move {
+                        let _ = done.send(write_all_at(&file, buf, offset).await);
+                    });
+                }
+                None => {
+                    let _ = done.send((Err(io::Error::other("write to closed file")), buf));
+                }
+            },
+            // closed once its writes in flight are done
+            Job::Close { id } => drop(files.remove(&id)),
+        }
+    }
+}
+
+async fn write_all_at(file: &tokio_uring::fs::File, mut buf: Vec<u8>, offset: u64) -> Written {
+    let mut written = 0;
+    while written < buf.len() {
+        let (res, slice) = file
+            .write_at(buf.slice(written..), offset + written as u64)
+            .await;
+        buf = slice.into_inner();
+        match res {
+            Ok(0) => return (Err(io::ErrorKind::WriteZero.into()), buf),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return (Err(e), buf),
+        }
+    }
+    (Ok(written), buf)
+}
+
+/// [`AsyncWrite`] into a file through the [`Ring`]. Like [`tokio::fs::File`], a write is
+/// accepted as soon as it is submitted, with at most one in flight; its error is returned by the
+/// next write, seek or flush.
+pub(crate) struct UringFile {
+    ring: &'static Ring,
+    id: u64,
+    /// The file, to get its size from once the write in flight is done.
+    stat: std::fs::File,
+    offset: u64,
+    /// The offset from the end of the file of a seek not completed yet.
+    seek_from_end: Option<i64>,
+    pending: Option<oneshot::Receiver<Written>>,
+    buf: Vec<u8>,
+}
+
+impl UringFile {
+    /// Wait for the write in flight, if any.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(pending) = &mut self.pending else {
+            return Poll::Ready(Ok(()));
+        };
+        let written = ready!(Pin::new(pending).poll(cx));
+        self.pending = None;
+        match written {
+            Ok((res, buf)) => {
+                self.buf = buf;
+                Poll::Ready(res.map(drop))
+            }
+            Err(_) => Poll::Ready(Err(io::Error::other("io_uring thread exited"))),
+        }
+    }
+}
+
+impl AsyncWrite for UringFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_pending(cx))?;
+        let mut buf = std::mem::take(&mut this.buf);
+        buf.clear();
+        buf.extend_from_slice(data);
+        let (done, pending) = oneshot::channel();
+        this.ring.send(Job::Write {
+            id: this.id,
+            buf,
+            offset: this.offset,
+            done,
+        })?;
+        this.offset += data.len() as u64;
+        this.pending = Some(pending);
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_pending(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_pending(cx)
+    }
+}
+
+/// Seeking only moves the offset of the next write, as writes carry their offset. Seeking from
+/// the end of the file waits for the write in flight to know its size.
+impl AsyncSeek for UringFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let offset = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => {
+                self.seek_from_end = Some(delta);
+                return Ok(());
+            }
+        };
+        self.offset = offset.ok_or_else(before_start)?;
+        self.seek_from_end = None;
+        Ok(())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        ready!(self.poll_pending(cx))?;
+        if let Some(delta) = self.seek_from_end.take() {
+            let len = self.stat.metadata()?.len();
+            self.offset = len.checked_add_signed(delta).ok_or_else(before_start)?;
+        }
+        Poll::Ready(Ok(self.offset))
+    }
+}
+
+fn before_start() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file")
+}
+
+impl Drop for UringFile {
+    fn drop(&mut self) {
+        let _ = self.ring.send(Job::Close { id: self.id });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        testing::{self, TestArchive},
+        ExtractOptions, Filter,
+    };
+
+    #[tokio::test]
+    async fn writes_at_offsets() {
+        let Some(ring) = Ring::get() else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        let mut file = ring.open(std::fs::File::create(&path).unwrap()).unwrap();
+        file.write_all(b"head").await.unwrap();
+        file.seek(SeekFrom::Current(4)).await.unwrap();
+        file.write_all(b"tail").await.unwrap();
+        assert_eq!(file.seek(SeekFrom::End(-2)).await.unwrap(), 10);
+        file.write_all(b"IL").await.unwrap();
+        assert_eq!(file.seek(SeekFrom::End(2)).await.unwrap(), 14);
+        file.write_all(b"!").await.unwrap();
+        assert!(file.seek(SeekFrom::End(-16)).await.is_err());
+        file.flush().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"head\0\0\0\0taIL\0\0!");
+    }
+
+    #[tokio::test]
+    async fn extracts_files() {
+        let Some(ring) = Ring::get() else {
+            return;
+        };
+        let contents: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let archive = TestArchive::new(vec![
+            testing::file("a/small", "small"),
+            testing::file("a/large", contents.clone()),
+            testing::file("empty", ""),
+        ]);
+        let dest = archive.scratch("dest");
+        let opened = ring.next_id.load(Ordering::Relaxed);
+        crate::unsquash_async(
+            archive.path(),
+            &dest,
+            Filter::All,
+            ExtractOptions::default(),
+        )
+        .await
+        .unwrap();
+        // the files with contents were written through the ring, which other tests may use
+        // meanwhile
+        assert!(ring.next_id.load(Ordering::Relaxed) - opened >= 2);
+        assert_eq!(std::fs::read(dest.join("a/small")).unwrap(), b"small");
+        assert_eq!(std::fs::read(dest.join("a/large")).unwrap(), contents);
+        assert!(std::fs::read(dest.join("empty")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    confine, platform, protect::ReadOnly, recompress, shard, verify::Manifest, Error,
+    ExtractOptions, PermissionPolicy,
+};
+
+/// The outcome of [`validate_dest_blocking`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidationReport {
+    /// Entries of the destination looked at, including the destination itself.
+    pub checked: u64,
+    /// Sorted by path.
+    pub violations: Vec<Violation>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violation {
+    /// Path relative to the destination.
+    pub path: PathBuf,
+    pub kind: ViolationKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "violation", rename_all = "snake_case")]
+pub enum ViolationKind {
+    /// The mode is not one the [`PermissionPolicy`] gives, with the write bits removed if the
+    /// tree is [write-protected](ReadOnly::WriteProtect).
+    Mode { expected: Vec<u32>, actual: u32 },
+    /// Not owned like the destination, when ownership is not preserved.
+    Owner {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// An mtime other than the 1 [`PermissionPolicy::Nix`] gives.
+    Mtime { actual: i64 },
+    /// A symlink whose target is absolute or climbs out of the destination, possibly through
+    /// other symlinks.
+    SymlinkEscapes { target: PathBuf },
+    /// A device node, named pipe or socket, without
+    /// [`allow_special_files`](crate::PolicyOptions::allow_special_files).
+    SpecialFile,
+    /// A regular file missing from the sums manifest.
+    Stray,
+    /// The sums manifest asked for by [`sha256sums`](crate::ObservabilityOptions::sha256sums) is
+    /// missing.
+    MissingManifest,
+}
+
+/// Check that the tree extracted into `dest` conforms to the policies of `options`, without the
+/// archive it was extracted from: modes per [`PermissionPolicy`] (unless
+/// [`Preserve`](PermissionPolicy::Preserve)) and
+/// [`read_only`](crate::DurabilityOptions::read_only), ownership matching `dest` unless preserved,
+/// the mtimes of [`PermissionPolicy::Nix`], symlinks staying inside `dest`, special files, and,
+/// with [`sha256sums`](crate::ObservabilityOptions::sha256sums), regular files not listed in the
+/// manifest. For periodic compliance sweeps of published trees.
+pub fn validate_dest_blocking(
+    dest: impl AsRef<Path>,
+    options: &ExtractOptions,
+) -> Result<ValidationReport, Error> {
+    Ok(validate(dest.as_ref(), options)?)
+}
+
+/// Async flavor of [`validate_dest_blocking`].
+pub async fn validate_dest_async(
+    dest: impl AsRef<Path>,
+    options: ExtractOptions,
+) -> Result<ValidationReport, Error> {
+    let dest = dest.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || validate_dest_blocking(dest, &options))
+        .await
+        .context("spawn blocking validation task")?
+}
+
+fn validate(dest: &Path, options: &ExtractOptions) -> Result<ValidationReport> {
+    let root =
+        std::fs::symlink_metadata(dest).with_context(|| format!("stat '{}'", dest.display()))?;
+    let sums_file = options.observability.hash.sums_file();
+    let listed = match options.observability.sha256sums {
+        true => match std::fs::read_to_string(dest.join(sums_file)) {
+            Ok(sums) => match Manifest::from_sums(&sums)? {
+                Manifest::Hashes(hashes) => Some(hashes),
+                Manifest::Archive => None,
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("read '{sums_file}'")),
+        },
+        false => None,
+    };
+    let bookkeeping = [
+        sums_file,
+        shard::SHARD_MANIFEST,
+        recompress::RECOMPRESS_MANIFEST,
+    ];
+
+    let mut entries = vec![(PathBuf::new(), dest.to_path_buf())];
+    walk(dest, dest, &mut entries)?;
+    let mut report = ValidationReport::default();
+    for (relative, path) in entries {
+        if bookkeeping.iter().any(|name| relative == Path::new(name)) {
+            continue;
+        }
+        report.checked += 1;
+        let stat = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("stat '{}'", path.display()))?;
+        for kind in check(
+            dest,
+            &relative,
+            &path,
+            &stat,
+            &root,
+            options,
+            listed.as_ref(),
+        )? {
+            report.violations.push(Violation {
+                path: relative.clone(),
+                kind,
+            });
+        }
+    }
+    if options.observability.sha256sums && listed.is_none() {
+        report.violations.push(Violation {
+            path: PathBuf::from(sums_file),
+            kind: ViolationKind::MissingManifest,
+        });
+    }
+    report.violations.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(report)
+}
+
+fn check(
+    dest: &Path,
+    relative: &Path,
+    path: &Path,
+    stat: &std::fs::Metadata,
+    root: &std::fs::Metadata,
+    options: &ExtractOptions,
+    listed: Option<&BTreeMap<PathBuf, String>>,
+) -> Result<Vec<ViolationKind>> {
+    let mut violations = Vec::new();
+    let file_type = stat.file_type();
+    let special = !file_type.is_file() && !file_type.is_dir() && !file_type.is_symlink();
+    if special && !options.policy.allow_special_files {
+        violations.push(ViolationKind::SpecialFile);
+    }
+    if file_type.is_symlink() {
+        let target =
+            std::fs::read_link(path).with_context(|| format!("read link '{}'", path.display()))?;
+        let outside = confine::resolves_outside(dest, relative, &target)
+            .with_context(|| format!("resolve symlink '{}'", path.display()))?;
+        if outside {
+            violations.push(ViolationKind::SymlinkEscapes { target });
+        }
+    } else if cfg!(unix) {
+        // off Unix, modes are reduced to the read-only attribute, which tells little
+        let expected = modes(options, file_type.is_dir());
+        let actual = platform::mode(stat);
+        if !expected.is_empty() && !expected.contains(&actual) {
+            violations.push(ViolationKind::Mode { expected, actual });
+        }
+    }
+
+    let nix = options.policy.permissions == PermissionPolicy::Nix;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let (expected, actual) = ((root.uid(), root.gid()), (stat.uid(), stat.gid()));
+        if (nix || !options.policy.preserve_ownership) && actual != expected {
+            violations.push(ViolationKind::Owner { expected, actual });
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = root;
+    // the destination itself is not an entry of the archive
+    let mtime = platform::mtime(stat);
+    if nix && !relative.as_os_str().is_empty() && mtime != 1 {
+        violations.push(ViolationKind::Mtime { actual: mtime });
+    }
+    if let Some(listed) = listed {
+        if file_type.is_file() && !listed.contains_key(relative) {
+            violations.push(ViolationKind::Stray);
+        }
+    }
+    Ok(violations)
+}
+
+/// The modes the permission policy of `options` allows, or none to allow any.
+fn modes(options: &ExtractOptions, dir: bool) -> Vec<u32> {
+    let modes = match options.policy.permissions {
+        PermissionPolicy::Default if dir => vec![0o755],
+        PermissionPolicy::Default => vec![0o644],
+        PermissionPolicy::Preserve => return Vec::new(),
+        PermissionPolicy::Force { dir_mode, .. } if dir => vec![dir_mode],
+        PermissionPolicy::Force { file_mode, .. } => vec![file_mode],
+        PermissionPolicy::Nix if dir => vec![0o555],
+        PermissionPolicy::Nix => vec![0o444, 0o555],
+    };
+    match options.durability.read_only {
+        Some(ReadOnly::WriteProtect) => modes.into_iter().map(|mode| mode & !0o222).collect(),
+        _ => modes,
+    }
+}
+
+/// Every entry under `path`, by its path relative to `root`, without following symlinks.
+fn walk(root: &Path, path: &Path, out: &mut Vec<(PathBuf, PathBuf)>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(path).with_context(|| format!("read dir '{}'", path.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("read dir '{}'", path.display()))?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+        let is_dir = entry
+            .file_type()
+            .with_context(|| format!("stat '{}'", path.display()))?
+            .is_dir();
+        out.push((relative, path.clone()));
+        if is_dir {
+            walk(root, &path, out)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{digest::AnyHasher, shard, Archive, Error, ExtractOptions, HashAlgorithm};
+use anyhow::{Context, Result};
+use backhand::{InnerNode, Node, SquashfsFileReader};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// What extracted files are expected to hash to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Manifest {
+    /// The hashes of the files of the archive, computed by streaming them out of it.
+    Archive,
+    /// Hex-encoded hashes by path relative to the destination, e.g. as parsed by
+    /// [`from_sums`](Self::from_sums).
+    Hashes(BTreeMap<PathBuf, String>),
+}
+
+impl Manifest {
+    /// Parse the output of `sha256sum`, `sha512sum` or `b3sum`, such as the
+    /// [`SHA256SUMS`](crate::sums::SHA256SUMS) manifest written by extractions.
+    pub fn from_sums(sums: &str) -> Result<Self, Error> {
+        let mut hashes = BTreeMap::new();
+        for (n, line) in sums.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let (escaped, line) = match line.strip_prefix('\\') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (hash, name) = line
+                .split_once("  ")
+                .or_else(|| line.split_once(" *"))
+                .with_context(|| format!("malformed line {}", n + 1))?;
+            let name = match escaped {
+                true => unescape(name),
+                false => name.to_owned(),
+            };
+            hashes.insert(PathBuf::from(name), hash.to_ascii_lowercase());
+        }
+        Ok(Self::Hashes(hashes))
+    }
+}
+
+/// Undo the escaping `sha256sum` applies to names containing a backslash or newline.
+fn unescape(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                out.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                out.push('\\');
+                chars.next();
+            }
+            (c, _) => out.push(c),
+        }
+    }
+    out
+}
+
+/// The outcome of [`verify_blocking`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    /// Number of files matching their expected hash.
+    pub verified: u64,
+    /// Sorted by path.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mismatch {
+    /// Path relative to the destination.
+    pub path: PathBuf,
+    pub kind: MismatchKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mismatch", rename_all = "snake_case")]
+pub enum MismatchKind {
+    Missing,
+    /// Something other than a regular file is at the path.
+    NotAFile,
+    /// Hex-encoded, with the [`hash`](crate::ObservabilityOptions::hash) of the options.
+    Hash {
+        expected: String,
+        actual: String,
+    },
+}
+
+/// Hash the files extracted into `dest` with the [`hash`](crate::ObservabilityOptions::hash) of
+/// `options` and compare them against `manifest`. With [`Manifest::Archive`], `archive` is read
+/// with the `kind`, `parsing` and `direct_io` of `options`, whose `shard_levels` tell where its
+/// files were extracted; it is not read otherwise.
+pub fn verify_blocking(
+    archive: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    manifest: Manifest,
+    options: ExtractOptions,
+) -> Result<VerifyReport, Error> {
+    let (dest, algorithm) = (dest.as_ref(), options.observability.hash);
+    let checked = match manifest {
+        Manifest::Hashes(hashes) => hashes
+            .into_par_iter()
+            .map(|(path, hash)| check(dest, path, algorithm, || Ok(hash)))
+            .collect::<Result<Vec<_>>>()?,
+        Manifest::Archive => {
+            if options.recompress.is_some() {
+                return Err(anyhow::anyhow!(
+                    "cannot verify recompressed destination '{}'",
+                    dest.display()
+                )
+                .into());
+            }
+            let (shard_levels, subtree) = (options.shard_levels, options.subtree.clone());
+            let subtree = subtree.as_deref();
+            // the files are matched by their full paths
+            let mut options = options;
+            options.io.compact_paths = false;
+            let archive = Archive::open(archive, options)?;
+            let files: Vec<_> = archive
+                .filesystem
+                .files()
+                .filter(|node| matches!(node.inner, InnerNode::File(_)))
+                .filter_map(|node| {
+                    let path = shard::dest_path(Path::new(""), node, shard_levels, None, subtree)?;
+                    Some((path, node))
+                })
+                .collect();
+            files
+                .into_par_iter()
+                .map(|(path, node)| {
+                    check(dest, path, algorithm, || {
+                        archive_hash(&archive, node, algorithm)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?
+        }
+    };
+
+    let mut report = VerifyReport::default();
+    for mismatch in checked {
+        match mismatch {
+            Some(mismatch) => report.mismatches.push(mismatch),
+            None => report.verified += 1,
+        }
+    }
+    report.mismatches.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(report)
+}
+
+/// Async flavor of [`verify_blocking`].
+pub async fn verify_async(
+    archive: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    manifest: Manifest,
+    options: ExtractOptions,
+) -> Result<VerifyReport, Error> {
+    let (archive, dest) = (archive.as_ref().to_path_buf(), dest.as_ref().to_path_buf());
+    tokio::task::spawn_blocking(move || verify_blocking(archive, dest, manifest, options))
+        .await
+        .context("spawn blocking verification task")?
+}
+
+/// Compare the file at `path` under `dest` against the hash `expected` computes, which is only
+/// called if it is a regular file.
+fn check(
+    dest: &Path,
+    path: PathBuf,
+    algorithm: HashAlgorithm,
+    expected: impl FnOnce() -> Result<String>,
+) -> Result<Option<Mismatch>> {
+    let dest_path = dest.join(&path);
+    let kind = match std::fs::symlink_metadata(&dest_path) {
+        Ok(metadata) if metadata.is_file() => {
+            let actual = algorithm.hash_file(&dest_path)?;
+            let expected = expected()?;
+            match actual == expected {
+                true => return Ok(None),
+                false => MismatchKind::Hash { expected, actual },
+            }
+        }
+        Ok(_) => MismatchKind::NotAFile,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => MismatchKind::Missing,
+        Err(e) => return Err(e).with_context(|| format!("stat '{}'", dest_path.display())),
+    };
+    Ok(Some(Mismatch { path, kind }))
+}
+
+fn archive_hash(
+    archive: &Archive,
+    node: &Node<SquashfsFileReader>,
+    algorithm: HashAlgorithm,
+) -> Result<String> {
+    let InnerNode::File(file) = &node.inner else {
+        anyhow::bail!("'{}' is not a file", node.fullpath.display());
+    };
+    let mut hasher = AnyHasher::new(algorithm);
+    archive
+        .copy(&file.basic, &mut hasher)
+        .with_context(|| format!("read '{}'", node.fullpath.display()))?;
+    Ok(hasher.finish())
+}
@@ -0,0 +1,665 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use backhand::{
+    compression::{CompressionAction, Compressor, DefaultCompressor},
+    Node, SquashfsFileReader,
+};
+#[cfg(target_os = "linux")]
+use nix::{errno::Errno, libc};
+
+#[cfg(target_os = "linux")]
+use crate::metadata::MetadataError;
+use crate::{
+    platform,
+    source::{self, SquashSource},
+};
+
+const NOT_SET: u64 = u64::MAX;
+/// The inode of an entry without extended attributes.
+const NO_XATTRS: u32 = u32::MAX;
+const METADATA_BLOCK_LEN: u64 = 8192;
+/// Set in the type of an xattr whose value is stored once, elsewhere in the table.
+const XATTR_VALUE_OOL: u16 = 0x100;
+/// Longest value Linux lets an xattr have; longer ones in an archive are corrupt.
+const XATTR_SIZE_MAX: u32 = 1 << 16;
+/// Most entries a directory header of the directory table may announce.
+const DIR_HEADER_MAX_ENTRIES: u32 = 256;
+
+/// One extended attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Xattr {
+    name: CString,
+    value: Vec<u8>,
+}
+
+/// The extended attributes of the entries of an archive, by path in the archive.
+///
+/// Backhand neither parses the xattr table nor keeps which entries refer to it, so the inode,
+/// directory and xattr tables are read here. Only the default [`Kind`](crate::compression::Kind)
+/// is supported.
+#[derive(Debug, Default)]
+pub(crate) struct Xattrs {
+    entries: HashMap<PathBuf, Arc<[Xattr]>>,
+}
+
+impl Xattrs {
+    /// Read the xattrs of `source`. Without privileges, the `security` and `trusted`
+    /// namespaces, which cannot be set, are left out.
+    pub(crate) fn read(source: &dyn SquashSource, compressor: Compressor) -> Result<Self> {
+        let mut superblock = [0; 96];
+        source::read_exact_at(source, &mut superblock, 0).context("read superblock")?;
+        let (root, xattr_table) = (u64_at(&superblock, 32)?, u64_at(&superblock, 56)?);
+        if xattr_table == NOT_SET {
+            return Ok(Self::default());
+        }
+        // the bytes used and the starts of the id, xattr, inode, directory, fragment and export
+        // tables, each table ending where the next one starts
+        let bounds = (40..96)
+            .step_by(8)
+            .map(|at| u64_at(&superblock, at))
+            .collect::<Result<Vec<_>>>()?;
+        let end = |start: u64| {
+            let next = bounds.iter().copied().filter(|&bound| bound > start);
+            next.filter(|&bound| bound != NOT_SET)
+                .min()
+                .unwrap_or(start)
+        };
+
+        let mut header = [0; 16];
+        source::read_exact_at(source, &mut header, xattr_table).context("read xattr id table")?;
+        let kv_start = u64_at(&header, 0)?;
+        let (inodes, dirs) = (u64_at(&superblock, 64)?, u64_at(&superblock, 72)?);
+        let mut tables = Tables {
+            inodes: Metadata::new(source, compressor, inodes, end(inodes)),
+            dirs: Metadata::new(source, compressor, dirs, end(dirs)),
+            ids: Metadata::new(source, compressor, 0, xattr_table),
+            kv: Metadata::new(source, compressor, kv_start, end(kv_start)),
+            lookup: xattr_table + 16,
+        };
+
+        #[cfg(unix)]
+        let privileged = nix::unistd::geteuid().is_root();
+        #[cfg(not(unix))]
+        let privileged = false;
+        let mut sets: HashMap<u32, Arc<[Xattr]>> = HashMap::new();
+        let mut entries = HashMap::new();
+        let mut dirs = HashSet::new();
+        let mut pending = vec![(PathBuf::from("/"), root)];
+        while let Some((path, reference)) = pending.pop() {
+            let inode = tables.inode(reference)?;
+            if let Some(dir) = inode.dir {
+                anyhow::ensure!(
+                    dirs.insert(reference),
+                    "directory '{}' listed twice",
+                    path.display()
+                );
+                for (name, child) in tables.dir(dir)? {
+                    pending.push((path.join(platform::os_str_from_bytes(&name)), child));
+                }
+            }
+            if inode.xattr == NO_XATTRS {
+                continue;
+            }
+            let set = match sets.get(&inode.xattr) {
+                Some(set) => Arc::clone(set),
+                None => {
+                    let mut set = tables.xattr_set(source, inode.xattr)?;
+                    set.retain(|xattr| {
+                        let name = xattr.name.as_bytes();
+                        privileged
+                            || !(name.starts_with(b"security.") || name.starts_with(b"trusted."))
+                    });
+                    let set: Arc<[Xattr]> = set.into();
+                    sets.insert(inode.xattr, Arc::clone(&set));
+                    set
+                }
+            };
+            if !set.is_empty() {
+                entries.insert(path, set);
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    pub(crate) fn get(&self, path: &Path) -> Option<Arc<[Xattr]>> {
+        self.entries.get(path).cloned()
+    }
+}
+
+/// Set the xattrs `node` has in `xattrs` on the entry extracted to `path`.
+pub(crate) fn restore_entry(
+    xattrs: Option<&Xattrs>,
+    node: &Node<SquashfsFileReader>,
+    path: &Path,
+) -> Result<()> {
+    match xattrs.and_then(|xattrs| xattrs.get(&node.fullpath)) {
+        Some(xattrs) => restore(&xattrs, path),
+        None => Ok(()),
+    }
+}
+
+/// Set `xattrs` on the entry at `path`, without following it if it is a symlink.
+#[cfg(target_os = "linux")]
+pub(crate) fn restore(xattrs: &[Xattr], path: &Path) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("nul byte in '{}'", path.display()))?;
+    set_each(xattrs, path, |xattr| {
+        // SAFETY: the path and name are nul-terminated and the value is valid for its length
+        unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                xattr.name.as_ptr(),
+                xattr.value.as_ptr().cast(),
+                xattr.value.len(),
+                0,
+            )
+        }
+    })
+}
+
+/// Like [`restore_entry`], through `file`, which is not at `path` yet.
+pub(crate) fn restore_entry_file(
+    xattrs: Option<&Xattrs>,
+    node: &Node<SquashfsFileReader>,
+    file: &File,
+    path: &Path,
+) -> Result<()> {
+    match xattrs.and_then(|xattrs| xattrs.get(&node.fullpath)) {
+        Some(xattrs) => restore_file(&xattrs, file, path),
+        None => Ok(()),
+    }
+}
+
+/// Xattrs are only restored on Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn restore(_xattrs: &[Xattr], _path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Like [`restore`], through `file`, which is not at `path` yet.
+#[cfg(target_os = "linux")]
+pub(crate) fn restore_file(xattrs: &[Xattr], file: &File, path: &Path) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    set_each(xattrs, path, |xattr| {
+        // SAFETY: the name is nul-terminated and the value is valid for its length
+        unsafe {
+            libc::fsetxattr(
+                file.as_raw_fd(),
+                xattr.name.as_ptr(),
+                xattr.value.as_ptr().cast(),
+                xattr.value.len(),
+                0,
+            )
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn restore_file(_xattrs: &[Xattr], _file: &File, _path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_each(xattrs: &[Xattr], path: &Path, set: impl Fn(&Xattr) -> libc::c_int) -> Result<()> {
+    for xattr in xattrs {
+        Errno::result(set(xattr))
+            .with_context(|| {
+                format!(
+                    "set xattr '{}' of '{}'",
+                    xattr.name.to_string_lossy(),
+                    path.display()
+                )
+            })
+            .map_err(MetadataError::from)?;
+    }
+    Ok(())
+}
+
+struct Tables<'a> {
+    inodes: Metadata<'a>,
+    dirs: Metadata<'a>,
+    /// Blocks of the xattr id table, found by absolute offset.
+    ids: Metadata<'a>,
+    /// The xattr key/value pairs.
+    kv: Metadata<'a>,
+    /// Where the offsets of the xattr id table blocks are.
+    lookup: u64,
+}
+
+/// What is needed from an inode.
+struct Inode {
+    /// Where its entries start in the directory table, and how many bytes they take, if it is a
+    /// directory.
+    dir: Option<(Position, u32)>,
+    xattr: u32,
+}
+
+impl Tables<'_> {
+    fn inode(&mut self, inode: u64) -> Result<Inode> {
+        let mut at = Position::of(inode);
+        let header = self.inodes.take(&mut at, 16)?;
+        let kind = u16_at(&header, 0)?;
+        let mut inode = Inode {
+            dir: None,
+            xattr: NO_XATTRS,
+        };
+        match kind {
+            // basic directory
+            1 => {
+                let dir = self.inodes.take(&mut at, 16)?;
+                let position = Position {
+                    block: u32_at(&dir, 0)?.into(),
+                    offset: u16_at(&dir, 10)?.into(),
+                };
+                inode.dir = Some((position, u16_at(&dir, 8)?.into()));
+            }
+            // extended directory
+            8 => {
+                let dir = self.inodes.take(&mut at, 24)?;
+                let position = Position {
+                    block: u32_at(&dir, 8)?.into(),
+                    offset: u16_at(&dir, 18)?.into(),
+                };
+                inode.dir = Some((position, u32_at(&dir, 4)?));
+                inode.xattr = u32_at(&dir, 20)?;
+            }
+            // extended file
+            9 => inode.xattr = u32_at(&self.inodes.take(&mut at, 40)?, 36)?,
+            // extended symlink
+            10 => {
+                let symlink = self.inodes.take(&mut at, 8)?;
+                self.inodes.take(&mut at, u32_at(&symlink, 4)? as usize)?;
+                inode.xattr = u32_at(&self.inodes.take(&mut at, 4)?, 0)?;
+            }
+            // extended devices
+            11 | 12 => inode.xattr = u32_at(&self.inodes.take(&mut at, 12)?, 8)?,
+            // extended fifos and sockets
+            13 | 14 => inode.xattr = u32_at(&self.inodes.take(&mut at, 8)?, 4)?,
+            2..=7 => {}
+            kind => anyhow::bail!("unknown inode type {kind}"),
+        }
+        Ok(inode)
+    }
+
+    /// The names and inode references of the entries of a directory.
+    fn dir(&mut self, (mut at, size): (Position, u32)) -> Result<Vec<(Vec<u8>, u64)>> {
+        // the size counts the implicit . and .. entries
+        let mut remaining = size.saturating_sub(3) as usize;
+        let mut entries = Vec::new();
+        while remaining > 0 {
+            let header = self.dirs.take(&mut at, 12)?;
+            remaining = remaining.saturating_sub(12);
+            let (count, start) = (u32_at(&header, 0)?.saturating_add(1), u32_at(&header, 4)?);
+            anyhow::ensure!(
+                count <= DIR_HEADER_MAX_ENTRIES,
+                "directory header of {count} entries"
+            );
+            for _ in 0..count {
+                let entry = self.dirs.take(&mut at, 8)?;
+                let name_len = usize::from(u16_at(&entry, 6)?) + 1;
+                let name = self.dirs.take(&mut at, name_len)?;
+                remaining = remaining.saturating_sub(8 + name_len);
+                let inode = u64::from(start) << 16 | u64::from(u16_at(&entry, 0)?);
+                entries.push((name, inode));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn xattr_set(&mut self, source: &dyn SquashSource, id: u32) -> Result<Vec<Xattr>> {
+        let kv = &mut self.kv;
+        let offset = u64::from(id) * 16;
+        let mut pointer = [0; 8];
+        let pointer_at = self.lookup + offset / METADATA_BLOCK_LEN * 8;
+        source::read_exact_at(source, &mut pointer, pointer_at)
+            .with_context(|| format!("read xattr id table at offset {pointer_at}"))?;
+        let mut at = Position {
+            block: u64::from_le_bytes(pointer),
+            offset: (offset % METADATA_BLOCK_LEN) as usize,
+        };
+        let entry = self.ids.take(&mut at, 16)?;
+        let (reference, count) = (u64_at(&entry, 0)?, u32_at(&entry, 8)?);
+
+        let mut at = Position::of(reference);
+        let mut set = Vec::new();
+        for _ in 0..count {
+            let key = kv.take(&mut at, 4)?;
+            let (kind, name_len) = (u16_at(&key, 0)?, u16_at(&key, 2)?);
+            let prefix: &[u8] = match kind & 0xff {
+                0 => b"user.",
+                1 => b"trusted.",
+                2 => b"security.",
+                kind => anyhow::bail!("unknown xattr type {kind}"),
+            };
+            let name = [prefix, &kv.take(&mut at, name_len.into())?].concat();
+            let mut value = take_value(kv, &mut at)?;
+            if kind & XATTR_VALUE_OOL != 0 {
+                anyhow::ensure!(
+                    value.len() == 8,
+                    "out-of-line xattr value referenced in {} bytes",
+                    value.len()
+                );
+                let mut at = Position::of(u64_at(&value, 0)?);
+                value = take_value(kv, &mut at)?;
+            }
+            set.push(Xattr {
+                name: CString::new(name).context("nul byte in xattr name")?,
+                value,
+            });
+        }
+        Ok(set)
+    }
+}
+
+/// The length-prefixed xattr value at `at`.
+fn take_value(kv: &mut Metadata<'_>, at: &mut Position) -> Result<Vec<u8>> {
+    let len = u32_at(&kv.take(at, 4)?, 0)?;
+    anyhow::ensure!(len <= XATTR_SIZE_MAX, "xattr value of {len} bytes");
+    kv.take(at, len as usize)
+}
+
+/// A position in a table of metadata blocks: the offset of a block from the start of the
+/// table, and an offset in its decompressed contents.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    block: u64,
+    offset: usize,
+}
+
+impl Position {
+    fn of(reference: u64) -> Self {
+        Self {
+            block: reference >> 16,
+            offset: (reference & 0xffff) as usize,
+        }
+    }
+}
+
+/// Reads a table of metadata blocks, decompressing each block once.
+struct Metadata<'a> {
+    source: &'a dyn SquashSource,
+    compressor: Compressor,
+    start: u64,
+    /// Where the table ends in the archive, which no block may cross.
+    end: u64,
+    /// Decompressed blocks by offset, with the offset of the next block.
+    blocks: HashMap<u64, (Vec<u8>, u64)>,
+}
+
+impl<'a> Metadata<'a> {
+    fn new(source: &'a dyn SquashSource, compressor: Compressor, start: u64, end: u64) -> Self {
+        Self {
+            source,
+            compressor,
+            start,
+            end,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// The `len` bytes at `at`, which is moved past them.
+    fn take(&mut self, at: &mut Position, len: usize) -> Result<Vec<u8>> {
+        // grown as blocks are read, so that a bogus length fails at the end of the table rather
+        // than allocating it up front
+        let mut out = Vec::with_capacity(len.min(METADATA_BLOCK_LEN as usize));
+        while out.len() < len {
+            let (block, next) = self.block(at.block)?;
+            let available = block.get(at.offset..).unwrap_or_default();
+            let n = available.len().min(len - out.len());
+            out.extend_from_slice(&available[..n]);
+            at.offset += n;
+            if out.len() < len {
+                anyhow::ensure!(
+                    n > 0 || at.offset >= block.len(),
+                    "truncated metadata block"
+                );
+                (at.block, at.offset) = (next, at.offset - block.len());
+            }
+        }
+        Ok(out)
+    }
+
+    fn block(&mut self, block: u64) -> Result<(&[u8], u64)> {
+        if !self.blocks.contains_key(&block) {
+            let offset = self.start.saturating_add(block);
+            anyhow::ensure!(
+                offset.saturating_add(2) <= self.end,
+                "metadata block at offset {offset} past the end of its table at {}",
+                self.end
+            );
+            let mut header = [0; 2];
+            source::read_exact_at(self.source, &mut header, offset)
+                .with_context(|| format!("read metadata block at offset {offset}"))?;
+            let header = u16::from_le_bytes(header);
+            anyhow::ensure!(
+                offset + 2 + u64::from(header & 0x7fff) <= self.end,
+                "metadata block at offset {offset} crosses the end of its table at {}",
+                self.end
+            );
+            let mut raw = vec![0; usize::from(header & 0x7fff)];
+            source::read_exact_at(self.source, &mut raw, offset + 2)
+                .with_context(|| format!("read metadata block at offset {offset}"))?;
+            let data = match header & 0x8000 != 0 {
+                true => raw,
+                false => {
+                    let mut out = Vec::with_capacity(METADATA_BLOCK_LEN as usize);
+                    DefaultCompressor
+                        .decompress(&raw, &mut out, self.compressor)
+                        .with_context(|| format!("decompress metadata block at offset {offset}"))?;
+                    out
+                }
+            };
+            let next = block + 2 + u64::from(header & 0x7fff);
+            self.blocks.insert(block, (data, next));
+        }
+        let (data, next) = &self.blocks[&block];
+        Ok((data, *next))
+    }
+}
+
+fn field<const N: usize>(bytes: &[u8], at: usize) -> Result<[u8; N]> {
+    bytes
+        .get(at..at + N)
+        .and_then(|field| field.try_into().ok())
+        .with_context(|| {
+            format!(
+                "truncated metadata: no {N} bytes at {at} of {}",
+                bytes.len()
+            )
+        })
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Result<u16> {
+    field(bytes, at).map(u16::from_le_bytes)
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32> {
+    field(bytes, at).map(u32::from_le_bytes)
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Result<u64> {
+    field(bytes, at).map(u64::from_le_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Buffer;
+
+    /// Offset of the file's value in the key/value table of [`image`], given set 0 first holds
+    /// `user.shared`.
+    const SHARED_VALUE: u64 = 4 + 6;
+
+    fn key(kind: u16, name: &str) -> Vec<u8> {
+        let len = name.len() as u16;
+        [&kind.to_le_bytes()[..], &len.to_le_bytes(), name.as_bytes()].concat()
+    }
+
+    fn value(value: &[u8]) -> Vec<u8> {
+        [&(value.len() as u32).to_le_bytes()[..], value].concat()
+    }
+
+    /// A metadata block stored uncompressed.
+    fn block(data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u16 | 0x8000).to_le_bytes()[..], data].concat()
+    }
+
+    /// An archive of a root directory, with xattr set 0, holding `/file`, with xattr set 1. Set
+    /// `n` has `sets[n].1` entries starting at `sets[n].0` of the key/value table `kv`.
+    fn image(kv: &[u8], sets: [(u64, u32); 2]) -> Vec<u8> {
+        let dir = [
+            &0u32.to_le_bytes()[..],
+            &0u32.to_le_bytes(),
+            &1u32.to_le_bytes(),
+            &40u16.to_le_bytes(),
+            &1i16.to_le_bytes(),
+            &2u16.to_le_bytes(),
+            &3u16.to_le_bytes(),
+            b"file",
+        ]
+        .concat();
+        let mut inodes = vec![0; 96];
+        inodes[..2].copy_from_slice(&8u16.to_le_bytes());
+        inodes[16 + 4..16 + 8].copy_from_slice(&(3 + dir.len() as u32).to_le_bytes());
+        inodes[16 + 20..16 + 24].copy_from_slice(&0u32.to_le_bytes());
+        inodes[40..42].copy_from_slice(&9u16.to_le_bytes());
+        inodes[40 + 16 + 36..].copy_from_slice(&1u32.to_le_bytes());
+        let ids: Vec<u8> = sets
+            .iter()
+            .flat_map(|&(at, count)| {
+                [&at.to_le_bytes()[..], &count.to_le_bytes(), &[0; 4]].concat()
+            })
+            .collect();
+
+        let mut image = vec![0; 96];
+        let inode_table = image.len() as u64;
+        image.extend(block(&inodes));
+        let dir_table = image.len() as u64;
+        image.extend(block(&dir));
+        let id_blocks = image.len() as u64;
+        image.extend(block(&ids));
+        let kv_start = image.len() as u64;
+        image.extend(block(kv));
+        let xattr_table = image.len() as u64;
+        image.extend(kv_start.to_le_bytes());
+        image.extend(2u32.to_le_bytes());
+        image.extend(0u32.to_le_bytes());
+        image.extend(id_blocks.to_le_bytes());
+
+        image[..4].copy_from_slice(b"hsqs");
+        let bytes_used = image.len() as u64;
+        for (at, bound) in [
+            (32, 0),
+            (40, bytes_used),
+            (48, NOT_SET),
+            (56, xattr_table),
+            (64, inode_table),
+            (72, dir_table),
+            (80, NOT_SET),
+            (88, NOT_SET),
+        ] {
+            image[at..at + 8].copy_from_slice(&bound.to_le_bytes());
+        }
+        image
+    }
+
+    fn xattr(name: &str, value: &[u8]) -> Xattr {
+        Xattr {
+            name: CString::new(name).unwrap(),
+            value: value.to_vec(),
+        }
+    }
+
+    fn read(image: Vec<u8>) -> Result<Xattrs> {
+        Xattrs::read(&Buffer(image), Compressor::None)
+    }
+
+    /// The error reading `kv` as the key/value table of [`image`] fails with.
+    fn error(kv: &[u8], sets: [(u64, u32); 2]) -> String {
+        format!("{:#}", read(image(kv, sets)).unwrap_err())
+    }
+
+    #[test]
+    fn reads_xattrs() {
+        let shared = [key(0, "shared"), value(b"shared value")].concat();
+        let file = [
+            key(XATTR_VALUE_OOL, "ool"),
+            value(&SHARED_VALUE.to_le_bytes()),
+            key(1, "t"),
+            value(b"trusted"),
+            key(2, "s"),
+            value(b"security"),
+        ]
+        .concat();
+        let kv = [&shared[..], &file].concat();
+        let xattrs = read(image(&kv, [(0, 1), (shared.len() as u64, 3)])).unwrap();
+
+        assert_eq!(
+            xattrs.get(Path::new("/")).as_deref(),
+            Some(&[xattr("user.shared", b"shared value")][..])
+        );
+        let mut expected = vec![xattr("user.ool", b"shared value")];
+        // only root can set the other namespaces, so they are only read for it
+        #[cfg(unix)]
+        let privileged = nix::unistd::geteuid().is_root();
+        #[cfg(not(unix))]
+        let privileged = false;
+        if privileged {
+            expected.push(xattr("trusted.t", b"trusted"));
+            expected.push(xattr("security.s", b"security"));
+        }
+        assert_eq!(
+            xattrs.get(Path::new("/file")).as_deref(),
+            Some(&expected[..])
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_tables() {
+        let shared = [key(0, "shared"), value(b"shared value")].concat();
+        let sets = |count| [(0, 1), (shared.len() as u64, count)];
+
+        let short = [&shared[..], &key(XATTR_VALUE_OOL, "ool"), &value(&[0; 4])].concat();
+        assert!(error(&short, sets(1)).contains("out-of-line xattr value referenced in 4 bytes"));
+
+        let huge = [&shared[..], &key(0, "huge"), &u32::MAX.to_le_bytes()].concat();
+        assert!(error(&huge, sets(1)).contains("xattr value of 4294967295 bytes"));
+
+        let dangling = SHARED_VALUE + (1 << 20 << 16);
+        let far = [
+            &shared[..],
+            &key(XATTR_VALUE_OOL, "far"),
+            &value(&dangling.to_le_bytes()),
+        ];
+        assert!(error(&far.concat(), sets(1)).contains("past the end of its table"));
+
+        // more entries than the table holds
+        assert!(error(&shared, sets(2)).contains("past the end of its table"));
+
+        let mut truncated = image(&shared, [(0, 1), (0, 1)]);
+        truncated.truncate(truncated.len() - 4);
+        let error = format!("{:#}", read(truncated).unwrap_err());
+        assert!(error.contains("read xattr id table"), "{error}");
+    }
+
+    #[test]
+    fn rejects_directory_loops() {
+        let shared = [key(0, "shared"), value(b"shared value")].concat();
+        let mut image = image(&shared, [(0, 1), (0, 1)]);
+        // point the entry of /file at the root inode, and make it a directory
+        let inodes = 96 + 2;
+        image.copy_within(inodes..inodes + 56, inodes + 40);
+        let error = format!("{:#}", read(image).unwrap_err());
+        assert!(error.contains("listed twice"), "{error}");
+    }
+}